@@ -0,0 +1,12 @@
+// Serve an empty in-memory filesystem, for use with `examples/tcp.rs` or
+// fuse-9p without another 9P server
+
+use std::{net::TcpListener, sync::Arc};
+
+use nine_p::{BlockingServer, FidServer, Ramfs};
+
+fn main() -> std::io::Result<()> {
+    let listener = TcpListener::bind("localhost:564")?;
+    let ramfs = Arc::new(Ramfs::new());
+    BlockingServer::new().serve(listener, move || FidServer::new(ramfs.clone()))
+}
@@ -70,7 +70,7 @@ fn main() -> Result<(), nine_p::Error> {
             },
         )?;
         println!("{:?}", res);
-        if res.data.len() == 0 {
+        if res.data.is_empty() {
             break;
         }
         dir_contents.extend_from_slice(res.data);
@@ -0,0 +1,59 @@
+// Export a local directory over 9P, like u9fs or diod
+//
+// Usage: export-9p [-r] [-x] [-s | -l address] [-m msize] directory
+//
+// -r exports the tree read-only, and -x refuses to open files for execution.
+// -s serves a single connection on stdin and stdout instead of listening.
+//
+// Linux clients are served 9P2000.L, so the export can be mounted with
+//   mount -t 9p -o trans=tcp,version=9p2000.L,port=564 host /mnt
+
+use nine_p::{BlockingServer, Export, FidServer};
+use std::{env, net::TcpListener, process, sync::Arc};
+
+fn usage() -> ! {
+    eprintln!("usage: export-9p [-r] [-x] [-s | -l address] [-m msize] directory");
+    process::exit(1);
+}
+
+fn main() -> std::io::Result<()> {
+    let mut address = "0.0.0.0:564".to_string();
+    let mut msize = None;
+    let mut read_only = false;
+    let mut no_exec = false;
+    let mut stdio = false;
+    let mut root = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-r" => read_only = true,
+            "-x" => no_exec = true,
+            "-s" => stdio = true,
+            "-l" => address = args.next().unwrap_or_else(|| usage()),
+            "-m" => {
+                let arg = args.next().unwrap_or_else(|| usage());
+                msize = Some(arg.parse().unwrap_or_else(|_| usage()));
+            }
+            _ if arg.starts_with('-') || root.is_some() => usage(),
+            _ => root = Some(arg),
+        }
+    }
+    let root = root.unwrap_or_else(|| usage());
+
+    let export = Export::new(&root)?
+        .read_only(read_only)
+        .no_exec(no_exec);
+    let export = Arc::new(export);
+    let mut server = BlockingServer::new();
+    if let Some(msize) = msize {
+        server = server.msize(msize);
+    }
+    if stdio {
+        return server.serve_stdio(move || FidServer::new(export.clone()));
+    }
+
+    let listener = TcpListener::bind(&address)?;
+    eprintln!("exporting {} on {}", export.root().display(), address);
+    server.serve(listener, move || FidServer::new(export.clone()))
+}
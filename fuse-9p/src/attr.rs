@@ -0,0 +1,239 @@
+// File attributes for the kernel, from the stats of 9P2000 and 9P2000.u, or
+// the `RGetAttr` of 9P2000.L
+
+use fuser::{FileAttr, FileType};
+use nine_p::{
+    RGetAttr, Stat, DMDEVICE, DMDIR, DMNAMEDPIPE, DMSETGID, DMSETUID, DMSOCKET, DMSYMLINK,
+    GETATTR_BTIME, NONUNAME,
+};
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    mem, ptr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const BLKSIZE: u32 = 4096;
+
+// Type of a file from the mode of its stat, and for a device, the 9P2000.u
+// extension saying which kind it is
+pub fn stat_type(mode: u32, extension: &str) -> FileType {
+    if mode & DMDIR != 0 {
+        FileType::Directory
+    } else if mode & DMSYMLINK != 0 {
+        FileType::Symlink
+    } else if mode & DMDEVICE != 0 && extension.starts_with('b') {
+        FileType::BlockDevice
+    } else if mode & DMDEVICE != 0 {
+        FileType::CharDevice
+    } else if mode & DMNAMEDPIPE != 0 {
+        FileType::NamedPipe
+    } else if mode & DMSOCKET != 0 {
+        FileType::Socket
+    } else {
+        FileType::RegularFile
+    }
+}
+
+// Type of a file from the `S_IFMT` bits of a Unix mode
+pub fn mode_type(mode: u32) -> FileType {
+    match mode & libc::S_IFMT {
+        libc::S_IFDIR => FileType::Directory,
+        libc::S_IFLNK => FileType::Symlink,
+        libc::S_IFBLK => FileType::BlockDevice,
+        libc::S_IFCHR => FileType::CharDevice,
+        libc::S_IFIFO => FileType::NamedPipe,
+        libc::S_IFSOCK => FileType::Socket,
+        _ => FileType::RegularFile,
+    }
+}
+
+// Type of a file from the `d_type` of a 9P2000.L directory entry
+pub fn dirent_type(type_: u8) -> FileType {
+    match type_ {
+        libc::DT_DIR => FileType::Directory,
+        libc::DT_LNK => FileType::Symlink,
+        libc::DT_BLK => FileType::BlockDevice,
+        libc::DT_CHR => FileType::CharDevice,
+        libc::DT_FIFO => FileType::NamedPipe,
+        libc::DT_SOCK => FileType::Socket,
+        _ => FileType::RegularFile,
+    }
+}
+
+// Device number from a 9P2000.u extension of "b major minor" or
+// "c major minor"
+fn device(extension: &str) -> u32 {
+    let mut numbers = extension.split_whitespace().skip(1).map(str::parse);
+    match (numbers.next(), numbers.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => libc::makedev(major, minor) as u32,
+        _ => 0,
+    }
+}
+
+// Times too far off to represent are the epoch
+fn time(secs: u64, nsecs: u64) -> SystemTime {
+    let nsecs = nsecs.min(999_999_999) as u32;
+    UNIX_EPOCH
+        .checked_add(Duration::new(secs, nsecs))
+        .unwrap_or(UNIX_EPOCH)
+}
+
+pub fn lookup_user(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    // XXX entries that don't fit are treated as missing
+    let mut buf = vec![0; 16384];
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+    let res = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    (res == 0 && !result.is_null()).then_some(pwd.pw_uid)
+}
+
+fn lookup_group(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    let mut buf = vec![0; 16384];
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+    let res = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    (res == 0 && !result.is_null()).then_some(grp.gr_gid)
+}
+
+/// Turns the owners of files into local ids
+pub struct Owners {
+    /// Look up the names of owners in the local user and group databases
+    /// when the server doesn't send numeric ids
+    pub map_names: bool,
+    /// Given to files whose owner isn't known
+    pub uid: u32,
+    pub gid: u32,
+    /// Given to every file, whoever owns it
+    pub squash_uid: Option<u32>,
+    pub squash_gid: Option<u32>,
+    // Names looked up so far
+    users: HashMap<String, Option<u32>>,
+    groups: HashMap<String, Option<u32>>,
+}
+
+impl Owners {
+    /// Unknown owners are the user running the program
+    pub fn new(map_names: bool) -> Self {
+        Self {
+            map_names,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            squash_uid: None,
+            squash_gid: None,
+            users: HashMap::new(),
+            groups: HashMap::new(),
+        }
+    }
+
+    pub fn squash(&self, attr: &mut FileAttr) {
+        attr.uid = self.squash_uid.unwrap_or(attr.uid);
+        attr.gid = self.squash_gid.unwrap_or(attr.gid);
+    }
+
+    fn uid(&mut self, name: &str, id: Option<u32>) -> u32 {
+        match id {
+            Some(id) if id != NONUNAME => id,
+            _ if self.map_names => self
+                .users
+                .entry(name.to_string())
+                .or_insert_with(|| lookup_user(name))
+                .unwrap_or(self.uid),
+            _ => self.uid,
+        }
+    }
+
+    fn gid(&mut self, name: &str, id: Option<u32>) -> u32 {
+        match id {
+            Some(id) if id != NONUNAME => id,
+            _ if self.map_names => self
+                .groups
+                .entry(name.to_string())
+                .or_insert_with(|| lookup_group(name))
+                .unwrap_or(self.gid),
+            _ => self.gid,
+        }
+    }
+}
+
+pub fn from_stat(stat: &Stat, owners: &mut Owners) -> FileAttr {
+    let extension = stat.dotu.map_or("", |dotu| dotu.extension);
+    let kind = stat_type(stat.mode, extension);
+    let mut perm = stat.mode & 0o777;
+    if stat.mode & DMSETUID != 0 {
+        perm |= libc::S_ISUID;
+    }
+    if stat.mode & DMSETGID != 0 {
+        perm |= libc::S_ISGID;
+    }
+    let rdev = match kind {
+        FileType::BlockDevice | FileType::CharDevice => device(extension),
+        _ => 0,
+    };
+    let mtime = time(stat.mtime.into(), 0);
+    FileAttr {
+        ino: stat.qid.path,
+        size: stat.length,
+        blocks: stat.length.div_ceil(512),
+        atime: time(stat.atime.into(), 0),
+        mtime,
+        // There's no time of the last change of the stat
+        ctime: mtime,
+        crtime: UNIX_EPOCH,
+        kind,
+        perm: perm as u16,
+        nlink: 1,
+        uid: owners.uid(stat.uid, stat.dotu.map(|dotu| dotu.n_uid)),
+        gid: owners.gid(stat.gid, stat.dotu.map(|dotu| dotu.n_gid)),
+        rdev,
+        flags: 0,
+        blksize: BLKSIZE,
+    }
+}
+
+pub fn from_getattr(attr: &RGetAttr) -> FileAttr {
+    let crtime = if attr.valid & GETATTR_BTIME != 0 {
+        time(attr.btime_sec, attr.btime_nsec)
+    } else {
+        UNIX_EPOCH
+    };
+    FileAttr {
+        ino: attr.qid.path,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: time(attr.atime_sec, attr.atime_nsec),
+        mtime: time(attr.mtime_sec, attr.mtime_nsec),
+        ctime: time(attr.ctime_sec, attr.ctime_nsec),
+        crtime,
+        kind: mode_type(attr.mode),
+        perm: (attr.mode & 0o7777) as u16,
+        nlink: attr.nlink as u32,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: attr.rdev as u32,
+        flags: 0,
+        blksize: if attr.blksize > 0 {
+            attr.blksize as u32
+        } else {
+            BLKSIZE
+        },
+    }
+}
@@ -0,0 +1,215 @@
+// What's remembered of the server's files between requests: attributes,
+// and the names looked up in each directory, including those that were
+// missing. Entries are trusted for the TTL, and after that reused only if
+// the qid a walk returns has the same version. Directories whose version or
+// mtime is seen to change lose the names looked up in them.
+
+use fuser::{FileAttr, FileType};
+use nine_p::Qid;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How much is cached, as with the `cache=` option of v9fs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Every lookup and getattr goes to the server
+    None,
+    /// Attributes and lookups are cached, by the kernel too, for the TTL
+    Loose,
+    /// Like `Loose`, and the kernel also keeps the data of a file opened
+    /// again unchanged
+    Fscache,
+}
+
+impl Mode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Self::None),
+            "loose" => Some(Self::Loose),
+            "fscache" => Some(Self::Fscache),
+            _ => None,
+        }
+    }
+}
+
+struct Attr {
+    attr: FileAttr,
+    vers: u32,
+    time: Instant,
+}
+
+struct Dentry {
+    // `None` if the name was missing
+    ino: Option<u64>,
+    time: Instant,
+}
+
+#[derive(Default)]
+struct Entries {
+    // XXX those of files listed but never looked up are only dropped when
+    // the files are removed here
+    attrs: HashMap<u64, Attr>,
+    dentries: HashMap<u64, HashMap<String, Dentry>>,
+    // Version and mtime of each file when last opened
+    opened: HashMap<u64, (u32, SystemTime)>,
+}
+
+pub struct Cache {
+    pub mode: Mode,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl Cache {
+    pub fn new(mode: Mode, ttl: Duration) -> Self {
+        Self {
+            mode,
+            ttl,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// How long the kernel may keep attributes and lookups
+    pub fn ttl(&self) -> Duration {
+        match self.mode {
+            Mode::None => Duration::ZERO,
+            Mode::Loose | Mode::Fscache => self.ttl,
+        }
+    }
+
+    fn fresh(&self, time: Instant) -> bool {
+        self.mode != Mode::None && time.elapsed() < self.ttl
+    }
+
+    /// Attributes of `ino` if they're fresh
+    pub fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let entries = self.entries.lock().unwrap();
+        let attr = entries.attrs.get(&ino)?;
+        self.fresh(attr.time).then_some(attr.attr)
+    }
+
+    /// Attributes of the file `qid` is for, if they're fresh, or from the
+    /// same version of it. Servers that don't keep versions send 0.
+    pub fn attr_for(&self, qid: Qid) -> Option<FileAttr> {
+        if self.mode == Mode::None {
+            return None;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let attr = entries.attrs.get_mut(&qid.path)?;
+        if attr.vers != qid.vers || (qid.vers == 0 && !self.fresh(attr.time)) {
+            return None;
+        }
+        attr.time = Instant::now();
+        Some(attr.attr)
+    }
+
+    pub fn set_attr(&self, qid: Qid, attr: FileAttr) {
+        if self.mode == Mode::None {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let new = Attr {
+            attr,
+            vers: qid.vers,
+            time: Instant::now(),
+        };
+        if let Some(old) = entries.attrs.insert(qid.path, new) {
+            let changed = old.vers != qid.vers || old.attr.mtime != attr.mtime;
+            if attr.kind == FileType::Directory && changed {
+                entries.dentries.remove(&qid.path);
+            }
+        }
+    }
+
+    /// Forget the attributes of `ino`, which were changed here
+    pub fn invalidate(&self, ino: u64) {
+        self.entries.lock().unwrap().attrs.remove(&ino);
+    }
+
+    /// What `name` in the directory `parent` was found to be, if that's
+    /// fresh: `Some(None)` if it was missing
+    pub fn dentry(&self, parent: u64, name: &str) -> Option<Option<u64>> {
+        let entries = self.entries.lock().unwrap();
+        let dentry = entries.dentries.get(&parent)?.get(name)?;
+        self.fresh(dentry.time).then_some(dentry.ino)
+    }
+
+    pub fn set_dentry(&self, parent: u64, name: &str, ino: Option<u64>) {
+        if self.mode == Mode::None {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let dentry = Dentry {
+            ino,
+            time: Instant::now(),
+        };
+        let dir = entries.dentries.entry(parent).or_default();
+        dir.insert(name.to_string(), dentry);
+    }
+
+    /// Forget `name` in the directory `parent`, which was removed or
+    /// renamed here, and the attributes of both
+    pub fn unlink(&self, parent: u64, name: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let dentry = entries
+            .dentries
+            .get_mut(&parent)
+            .and_then(|dir| dir.remove(name));
+        if let Some(ino) = dentry.and_then(|dentry| dentry.ino) {
+            entries.attrs.remove(&ino);
+        }
+        entries.attrs.remove(&parent);
+    }
+
+    /// Whether the kernel may keep the data it has of the file being
+    /// opened, with the qid the open returned: only if it hasn't changed
+    /// since the last open, as far as can be told
+    pub fn reopened(&self, qid: Qid) -> bool {
+        if self.mode != Mode::Fscache {
+            return false;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let mtime = match entries.attrs.get(&qid.path) {
+            Some(attr) if qid.vers != 0 || self.fresh(attr.time) => attr.attr.mtime,
+            _ => {
+                entries.opened.remove(&qid.path);
+                return false;
+            }
+        };
+        let version = (qid.vers, mtime);
+        entries.opened.insert(qid.path, version) == Some(version)
+    }
+
+    /// Drop what's known of `ino`, once the kernel has forgotten it
+    pub fn forget(&self, ino: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.attrs.remove(&ino);
+        entries.dentries.remove(&ino);
+        entries.opened.remove(&ino);
+    }
+}
+
+/// The entry of a lookup that found nothing, which the kernel keeps for the
+/// TTL as a missing name
+pub fn negative() -> FileAttr {
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+        blksize: 0,
+    }
+}
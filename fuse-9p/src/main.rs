@@ -1,21 +1,69 @@
+// Mount a 9P file server with FUSE
+//
+// Usage: fuse-9p [-fr] [-u uname] [-a aname] [-m msize] [-v version]
+//                [-o option,...] address mountpoint
+//
+// `address` is a TCP address, with port 564 if none is given, or the path
+// of a Unix socket if it contains a '/'. `version` is 9P2000, 9P2000.u (the
+// default) or 9P2000.L, and the server may pick an older one. Runs in the
+// background once mounted, unless -f is given; -r mounts read-only.
+// Requests are handled by a pool of threads, 16 unless threads= is given,
+// whose requests to the server share one connection.
+//
+// cache= is none, loose (the default) or fscache, as for v9fs. With loose,
+// attributes and lookups, including of missing names, are kept for ttl=
+// seconds (1 by default), and after that reused if the server's qid version
+// of the file is the same. Listing a directory fills the cache. fscache
+// also has the kernel keep the data of a file opened again unchanged; none
+// asks the server every time.
+//
+// The flags can also be given as -o options: uname=, aname=, msize=,
+// version= and ro. uid= and gid= make every file owned by those ids, and
+// nomap doesn't look up the names of owners in the local user and group
+// databases. Options like allow_other, default_permissions or nosuid go to
+// the kernel. Options only meaningful to mount(8) are ignored, so fuse-9p
+// can be mounted from /etc/fstab as a mount.fuse helper:
+//
+//   server.example:564  /n/server  fuse.fuse-9p  uname=glenda,allow_other  0 0
+//
 // Uses Qid path as ino
-// XXX error handling?
 
+mod attr;
+mod cache;
+mod options;
+
+use attr::Owners;
+use cache::Cache;
 use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyOpen, Request,
+    consts::{FOPEN_KEEP_CACHE, FUSE_DO_READDIRPLUS, FUSE_READDIRPLUS_AUTO},
+    FileAttr, FileType, Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request,
+    TimeOrNow,
 };
-use nine_p::{Fid, Qid};
+use nine_p::{Fid, Qid, Stat, TMessage, Upstream};
+use options::Options;
 use std::{
     collections::HashMap,
+    env,
     ffi::OsStr,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
     net::TcpStream,
-    os::unix::ffi::OsStrExt,
-    str,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd},
+        net::UnixStream,
+    },
+    path::Path,
+    process, str,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-const TTL: Duration = Duration::from_secs(1);
 const IOHDRSZ: u32 = 24;
 
 // An Inode is added by `lookup`, and at start for root node
@@ -26,33 +74,147 @@ struct Inode {
     lookups: u64,
 }
 
-fn file_type(type_: u8) -> FileType {
-    if type_ & 0x80 != 0 {
-        FileType::Directory
+// Version of the protocol agreed with the server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Dialect {
+    P2000,
+    U,
+    L,
+}
+
+impl Dialect {
+    fn from_version(version: &str) -> Option<Self> {
+        match version {
+            "9P2000" => Some(Self::P2000),
+            "9P2000.u" => Some(Self::U),
+            "9P2000.L" => Some(Self::L),
+            _ => None,
+        }
+    }
+
+    fn version(self) -> &'static str {
+        match self {
+            Self::P2000 => "9P2000",
+            Self::U => "9P2000.u",
+            Self::L => "9P2000.L",
+        }
+    }
+}
+
+// Connect over TCP or a Unix socket, and negotiate a version
+fn connect(address: &str, msize: u32, version: &str) -> Result<Upstream, nine_p::Error> {
+    if address.contains('/') {
+        return Upstream::with_version(UnixStream::connect(address)?, msize, version);
+    }
+    let stream = if address.contains(':') {
+        TcpStream::connect(address)?
     } else {
-        FileType::RegularFile
+        TcpStream::connect((address, 564))?
+    };
+    stream.set_nodelay(true)?;
+    Upstream::with_version(stream, msize, version)
+}
+
+// Carry on in a child process, as mount(8) waits for its helper to exit.
+// This happens before connecting, as only the forking thread would be left
+// in the child. The parent exits once the child calls `detach`, or with a
+// failure if the child exits first, so errors are still seen.
+fn daemonize() -> io::Result<File> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(reader);
+            unsafe { libc::setsid() };
+            Ok(writer)
+        }
+        _ => {
+            drop(writer);
+            let mut ready = [0];
+            let mounted = (&reader).read(&mut ready).is_ok_and(|len| len == 1);
+            process::exit(if mounted { 0 } else { 1 })
+        }
+    }
+}
+
+// Let the parent exit once mounted, and leave the terminal
+fn detach(ready: File) -> io::Result<()> {
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        unsafe { libc::dup2(null.as_raw_fd(), fd) };
+    }
+    env::set_current_dir("/")?;
+    (&ready).write_all(&[0])
+}
+
+// Mode for `TOpen` and `TCreate` from the flags of `open(2)`
+fn open_mode(flags: i32) -> u8 {
+    let mut mode = match flags & libc::O_ACCMODE {
+        libc::O_WRONLY => nine_p::OWRITE,
+        libc::O_RDWR => nine_p::ORDWR,
+        _ => nine_p::OREAD,
+    };
+    if flags & libc::O_TRUNC != 0 {
+        mode |= nine_p::OTRUNC;
     }
+    mode
 }
 
-fn attr_from_stat(stat: &nine_p::Stat) -> FileAttr {
-    FileAttr {
-        ino: stat.qid.path,
-        size: stat.length,
-        blocks: (stat.length + 4096 - 1) / 4096,
-        atime: UNIX_EPOCH + Duration::from_secs(stat.atime as u64),
-        mtime: UNIX_EPOCH + Duration::from_secs(stat.mtime as u64),
-        ctime: UNIX_EPOCH,
-        crtime: UNIX_EPOCH,
-        kind: file_type(stat.qid.type_),
-        perm: 0o755,
-        // TODO use .u extension, if available
-        nlink: 1,
-        uid: 0,
-        gid: 0,
-        rdev: 0,
-        flags: 0,
-        blksize: 4096,
+// Flags for `TLOpen` and `TLCreate` from the flags of `open(2)`
+fn lopen_flags(flags: i32) -> u32 {
+    let mut lflags = (flags & libc::O_ACCMODE) as u32;
+    if flags & libc::O_TRUNC != 0 {
+        lflags |= nine_p::DOTL_TRUNC;
     }
+    if flags & libc::O_APPEND != 0 {
+        lflags |= nine_p::DOTL_APPEND;
+    }
+    lflags
+}
+
+fn unix_time(time: TimeOrNow) -> u32 {
+    let time = match time {
+        TimeOrNow::SpecificTime(time) => time,
+        TimeOrNow::Now => SystemTime::now(),
+    };
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as u32)
+}
+
+// Seconds and nanoseconds for `TSetAttr`
+fn timespec(time: SystemTime) -> (u64, u64) {
+    time.duration_since(UNIX_EPOCH)
+        .map_or((0, 0), |time| (time.as_secs(), time.subsec_nanos().into()))
+}
+
+// Errno to reply with when a request to the server fails
+fn errno(err: &nine_p::Error) -> i32 {
+    match err {
+        nine_p::Error::Io(err) => match err.kind() {
+            // The connection to the server failed
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected => libc::EIO,
+            // From `RLError`, or the errno of a 9P2000.u `RError`
+            _ => err.raw_os_error().unwrap_or(libc::EIO),
+        },
+        nine_p::Error::Protocol(ename) => nine_p::ename_errno(ename) as i32,
+        // Replies that couldn't be understood
+        _ => libc::EIO,
+    }
+}
+
+fn not_found() -> nine_p::Error {
+    nine_p::Error::Protocol("file does not exist".to_string())
 }
 
 struct DirEntry {
@@ -66,78 +228,144 @@ struct OpenFile {
     fid: Fid,
 }
 
+// Shared by the threads handling requests, which send to the server over
+// the same connection at once
 struct FS {
-    client: nine_p::SyncClient<TcpStream>,
-    next_id: Fid,
-    next_fh: u64,
-    inodes: HashMap<u64, Inode>,
-    open_files: HashMap<u64, OpenFile>,
+    client: Upstream,
+    next_id: AtomicU32,
+    next_fh: AtomicU64,
+    inodes: Mutex<HashMap<u64, Inode>>,
+    open_files: Mutex<HashMap<u64, OpenFile>>,
     root_ino: u64,
     msize: u32,
+    dialect: Dialect,
+    owners: Mutex<Owners>,
+    cache: Cache,
 }
 
 impl FS {
-    fn inode(&self, ino: u64) -> Option<&Inode> {
+    fn ino(&self, ino: u64) -> u64 {
         // XXX could 9p use 1 as a qid path?
         if ino == 1 {
-            self.inodes.get(&self.root_ino)
+            self.root_ino
         } else {
-            self.inodes.get(&ino)
+            ino
         }
     }
 
-    fn inode_mut(&mut self, ino: u64) -> Option<&mut Inode> {
-        if ino == 1 {
-            self.inodes.get_mut(&self.root_ino)
-        } else {
-            self.inodes.get_mut(&ino)
-        }
+    fn fid(&self, ino: u64) -> Option<Fid> {
+        let ino = self.ino(ino);
+        self.inodes.lock().unwrap().get(&ino).map(|inode| inode.fid)
     }
-}
 
-impl fuser::Filesystem for FS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let Ok(name) = str::from_utf8(name.as_bytes()) else {
-            reply.error(libc::ENOENT);
-            return;
+    fn file_fid(&self, fh: u64) -> Option<Fid> {
+        let open_files = self.open_files.lock().unwrap();
+        open_files.get(&fh).map(|open_file| open_file.fid)
+    }
+
+    fn new_fid(&self) -> Fid {
+        Fid(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn add_open_file(&self, fid: Fid, dir_entries: Vec<DirEntry>) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        let open_file = OpenFile { dir_entries, fid };
+        self.open_files.lock().unwrap().insert(fh, open_file);
+        fh
+    }
+
+    // Send a request whose reply has nothing needed
+    fn call<'a, T: TMessage<'a>>(&self, request: T) -> Result<(), nine_p::Error> {
+        self.client.send(request, &mut Vec::new()).map(|_| ())
+    }
+
+    fn clunk(&self, fid: Fid) {
+        let _ = self.call(nine_p::TClunk { fid });
+    }
+
+    // Walk a new fid from `fid` to `wnames`, returning the qid of the last
+    fn walk_qid(&self, fid: Fid, wnames: Vec<&str>) -> Result<(Fid, Option<Qid>), nine_p::Error> {
+        let newfid = self.new_fid();
+        let len = wnames.len();
+        let mut buf = Vec::new();
+        let twalk = nine_p::TWalk {
+            fid,
+            newfid,
+            wnames,
         };
+        let res = self.client.send(twalk, &mut buf)?;
+        // The new fid isn't created unless every name is walked
+        if res.qids.len() < len {
+            return Err(not_found());
+        }
+        Ok((newfid, res.qids.last().copied()))
+    }
+
+    fn walk(&self, fid: Fid, wnames: Vec<&str>) -> Result<Fid, nine_p::Error> {
+        Ok(self.walk_qid(fid, wnames)?.0)
+    }
 
-        eprintln!("lookup: {name}");
+    // Stat for `TWStat` with every field set to "don't touch"
+    fn wstat_unchanged(&self) -> Stat<'static> {
+        Stat::unchanged(self.dialect == Dialect::U)
+    }
+
+    fn wstat(&self, fid: Fid, stat: &Stat) -> Result<(), nine_p::Error> {
+        let mut buf = Vec::new();
+        stat.write(&mut buf)?;
+        self.call(nine_p::TWStat { fid, stat: &buf })
+    }
 
-        let parent_fid = if let Some(parent_inode) = self.inode_mut(parent) {
-            parent_inode.fid
+    // Attributes of the file `fid` refers to, from `TGetAttr` on 9P2000.L
+    // and `TStat` otherwise
+    fn stat(&self, fid: Fid) -> Result<(Qid, FileAttr), nine_p::Error> {
+        let mut buf = Vec::new();
+        if self.dialect == Dialect::L {
+            let request_mask = nine_p::GETATTR_BASIC | nine_p::GETATTR_BTIME;
+            let tgetattr = nine_p::TGetAttr { fid, request_mask };
+            let res = self.client.send(tgetattr, &mut buf)?;
+            let mut attr = attr::from_getattr(&res);
+            self.owners.lock().unwrap().squash(&mut attr);
+            self.cache.set_attr(res.qid, attr);
+            Ok((res.qid, attr))
         } else {
-            reply.error(libc::ENOENT);
-            return;
-        };
+            let stat = self.client.send(nine_p::TStat { fid }, &mut buf)?.stat;
+            let mut owners = self.owners.lock().unwrap();
+            let mut attr = attr::from_stat(&stat, &mut owners);
+            owners.squash(&mut attr);
+            self.cache.set_attr(stat.qid, attr);
+            Ok((stat.qid, attr))
+        }
+    }
 
-        let res = self
-            .client
-            .send(
-                0,
-                nine_p::TWalk {
-                    fid: parent_fid,
-                    newfid: self.next_id,
-                    wnames: vec![name],
-                },
-            )
-            .unwrap();
-        let mut fid = self.next_id;
-        self.next_id.0 += 1;
-
-        // XXX panic?
-        let qid = res.qids[0];
-        let ino = qid.path;
-        if let Some(inode) = self.inode_mut(ino) {
+    // Look up `name` in the directory `parent`, adding an inode for it or
+    // counting another lookup of an existing one. The attributes are only
+    // fetched if the cache doesn't have them for the qid walked to.
+    fn entry(&self, parent: u64, name: &str) -> Result<FileAttr, nine_p::Error> {
+        let parent = self.ino(parent);
+        let parent_fid = self.fid(parent).ok_or_else(not_found)?;
+        let (fid, qid) = self.walk_qid(parent_fid, vec![name])?;
+        let cached = qid.and_then(|qid| Some((qid, self.cache.attr_for(qid)?)));
+        let (qid, attr) = match cached {
+            Some(res) => res,
+            None => match self.stat(fid) {
+                Ok(res) => res,
+                Err(err) => {
+                    self.clunk(fid);
+                    return Err(err);
+                }
+            },
+        };
+        self.cache.set_dentry(parent, name, Some(qid.path));
+        let mut inodes = self.inodes.lock().unwrap();
+        if let Some(inode) = inodes.get_mut(&qid.path) {
             inode.lookups += 1;
-
+            drop(inodes);
             // We already have an fid for this qid/ino, so we don't need another
-            let existing_fid = inode.fid;
-            let res = self.client.send(0, nine_p::TClunk { fid }).unwrap();
-            fid = existing_fid;
+            self.clunk(fid);
         } else {
-            self.inodes.insert(
-                ino,
+            inodes.insert(
+                qid.path,
                 Inode {
                     qid,
                     fid,
@@ -145,139 +373,794 @@ impl fuser::Filesystem for FS {
                 },
             );
         }
+        Ok(attr)
+    }
 
-        let stat = self.client.send(0, nine_p::TStat { fid }).unwrap().stat;
-        let mut attr = attr_from_stat(&stat);
+    // Like `entry`, but answered from the cache if it has fresh attributes
+    // for the name, and remembering a name that's missing
+    fn cached_entry(&self, parent: u64, name: &str) -> Result<FileAttr, nine_p::Error> {
+        let parent = self.ino(parent);
+        match self.cache.dentry(parent, name) {
+            Some(None) => return Err(not_found()),
+            Some(Some(ino)) => {
+                let attr = self.cache.attr(ino);
+                let mut inodes = self.inodes.lock().unwrap();
+                if let (Some(attr), Some(inode)) = (attr, inodes.get_mut(&ino)) {
+                    inode.lookups += 1;
+                    return Ok(attr);
+                }
+            }
+            None => {}
+        }
+        let res = self.entry(parent, name);
+        if let Err(err) = &res {
+            if errno(err) == libc::ENOENT {
+                self.cache.set_dentry(parent, name, None);
+            }
+        }
+        res
+    }
 
-        reply.entry(&TTL, &attr, 0); // XXX generation?
+    // Open a new fid for the file `fid` refers to, returning its qid
+    fn open_fid(&self, fid: Fid, flags: i32) -> Result<(Fid, Qid), nine_p::Error> {
+        let newfid = self.walk(fid, vec![])?;
+        let mut buf = Vec::new();
+        let res = if self.dialect == Dialect::L {
+            let flags = lopen_flags(flags);
+            let topen = nine_p::TLOpen { fid: newfid, flags };
+            self.client.send(topen, &mut buf).map(|res| res.qid)
+        } else {
+            let mode = open_mode(flags);
+            let topen = nine_p::TOpen { fid: newfid, mode };
+            self.client.send(topen, &mut buf).map(|res| res.qid)
+        };
+        match res {
+            Ok(qid) => Ok((newfid, qid)),
+            Err(err) => {
+                self.clunk(newfid);
+                Err(err)
+            }
+        }
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
-        println!("open");
+    // Read all the entries of the open directory `fid`, which is `ino`,
+    // caching what they say
+    fn read_dir(&self, ino: u64, fid: Fid) -> Result<Vec<DirEntry>, nine_p::Error> {
+        let count = self.msize - IOHDRSZ;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        let mut buf = Vec::new();
+        if self.dialect == Dialect::L {
+            loop {
+                let treaddir = nine_p::TReadDir { fid, offset, count };
+                let res = self.client.send(treaddir, &mut buf)?;
+                let dirents = nine_p::parse_dir_entries(res.data)?;
+                let Some(last) = dirents.last() else {
+                    break;
+                };
+                offset = last.offset;
+                // Linux servers list "." and "..", which 9P2000 servers
+                // don't
+                let dirents = dirents
+                    .iter()
+                    .filter(|dirent| dirent.name != "." && dirent.name != "..");
+                for dirent in dirents {
+                    self.cache
+                        .set_dentry(ino, dirent.name, Some(dirent.qid.path));
+                    entries.push(DirEntry {
+                        ino: dirent.qid.path,
+                        kind: attr::dirent_type(dirent.type_),
+                        name: dirent.name.to_string(),
+                    });
+                }
+            }
+        } else {
+            let mut dir_contents = Vec::new();
+            loop {
+                let tread = nine_p::TRead { fid, offset, count };
+                let res = self.client.send(tread, &mut buf)?;
+                if res.data.is_empty() {
+                    break;
+                }
+                dir_contents.extend_from_slice(res.data);
+                offset += res.data.len() as u64;
+            }
+            for stat in nine_p::parse_dir(&dir_contents)? {
+                // The stats are as good as those of a `TStat`
+                if self.cache.mode != cache::Mode::None {
+                    let mut owners = self.owners.lock().unwrap();
+                    let mut attr = attr::from_stat(&stat, &mut owners);
+                    owners.squash(&mut attr);
+                    self.cache.set_attr(stat.qid, attr);
+                }
+                self.cache.set_dentry(ino, stat.name, Some(stat.qid.path));
+                let extension = stat.dotu.map_or("", |dotu| dotu.extension);
+                entries.push(DirEntry {
+                    ino: stat.qid.path,
+                    kind: attr::stat_type(stat.mode, extension),
+                    name: stat.name.to_string(),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    // Create `name` in the directory `parent`, returning a fid open with
+    // `flags`
+    fn create_file(
+        &self,
+        parent: Fid,
+        name: &str,
+        perm: u32,
+        flags: i32,
+        gid: u32,
+    ) -> Result<Fid, nine_p::Error> {
+        let fid = self.walk(parent, vec![])?;
+        let res = if self.dialect == Dialect::L {
+            self.call(nine_p::TLCreate {
+                fid,
+                name,
+                flags: lopen_flags(flags),
+                mode: perm,
+                gid,
+            })
+        } else {
+            self.call(nine_p::TCreate {
+                fid,
+                name,
+                perm,
+                mode: open_mode(flags),
+            })
+        };
+        if let Err(err) = res {
+            self.clunk(fid);
+            return Err(err);
+        }
+        Ok(fid)
+    }
+
+    // Create a file of the type in the `S_IFMT` bits of `mode`, without
+    // opening it, and look it up
+    fn make(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        gid: u32,
+    ) -> Result<FileAttr, i32> {
+        let Ok(name) = str::from_utf8(name.as_bytes()) else {
+            return Err(libc::EINVAL);
+        };
+        let Some(dir) = self.fid(parent) else {
+            return Err(libc::ENOENT);
+        };
+        if self.dialect == Dialect::L {
+            let res = if mode & libc::S_IFMT == libc::S_IFDIR {
+                self.call(nine_p::TMkDir {
+                    dfid: dir,
+                    name,
+                    mode: mode & 0o7777,
+                    gid,
+                })
+            } else {
+                self.call(nine_p::TMkNod {
+                    dfid: dir,
+                    name,
+                    mode,
+                    major: libc::major(rdev.into()),
+                    minor: libc::minor(rdev.into()),
+                    gid,
+                })
+            };
+            res.map_err(|err| errno(&err))?;
+        } else {
+            let perm = match mode & libc::S_IFMT {
+                libc::S_IFDIR => nine_p::DMDIR | (mode & 0o777),
+                libc::S_IFREG => mode & 0o777,
+                // XXX 9P2000.u makes the others with an extension, which
+                // `TCreate` doesn't have
+                _ => return Err(libc::EPERM),
+            };
+            let fid = self
+                .create_file(dir, name, perm, libc::O_RDONLY, gid)
+                .map_err(|err| errno(&err))?;
+            self.clunk(fid);
+        }
+        self.cache.invalidate(self.ino(parent));
+        self.entry(parent, name).map_err(|err| errno(&err))
+    }
+
+    fn remove(&self, parent: u64, name: &OsStr, is_dir: bool) -> Result<(), i32> {
+        let Ok(name) = str::from_utf8(name.as_bytes()) else {
+            return Err(libc::ENOENT);
+        };
+        let Some(dir) = self.fid(parent) else {
+            return Err(libc::ENOENT);
+        };
+        // Whether or not it works, what was cached of the name is stale
+        let res = self.remove_name(dir, name, is_dir);
+        self.cache.unlink(self.ino(parent), name);
+        res.map_err(|err| errno(&err))
+    }
+
+    fn remove_name(&self, dir: Fid, name: &str, is_dir: bool) -> Result<(), nine_p::Error> {
+        if self.dialect == Dialect::L {
+            let flags = if is_dir { nine_p::DOTL_AT_REMOVEDIR } else { 0 };
+            let tunlinkat = nine_p::TUnlinkAt {
+                dirfid: dir,
+                name,
+                flags,
+            };
+            return self.call(tunlinkat);
+        }
+        let (fid, qid) = self.walk_qid(dir, vec![name])?;
+        // `TRemove` removes files and empty directories alike
+        let dir_qid = qid.is_some_and(|qid| qid.type_ & nine_p::QTDIR != 0);
+        if dir_qid != is_dir {
+            self.clunk(fid);
+            let errno = if is_dir { libc::ENOTDIR } else { libc::EISDIR };
+            return Err(io::Error::from_raw_os_error(errno).into());
+        }
+        // The fid is clunked even if the remove fails
+        self.call(nine_p::TRemove { fid })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn set_attr(
+        &self,
+        fid: Fid,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> Result<FileAttr, nine_p::Error> {
+        if self.dialect == Dialect::L {
+            let mut tsetattr = nine_p::TSetAttr {
+                fid,
+                valid: 0,
+                mode: 0,
+                uid: 0,
+                gid: 0,
+                size: 0,
+                atime_sec: 0,
+                atime_nsec: 0,
+                mtime_sec: 0,
+                mtime_nsec: 0,
+            };
+            if let Some(mode) = mode {
+                tsetattr.valid |= nine_p::SETATTR_MODE;
+                tsetattr.mode = mode;
+            }
+            if let Some(uid) = uid {
+                tsetattr.valid |= nine_p::SETATTR_UID;
+                tsetattr.uid = uid;
+            }
+            if let Some(gid) = gid {
+                tsetattr.valid |= nine_p::SETATTR_GID;
+                tsetattr.gid = gid;
+            }
+            if let Some(size) = size {
+                tsetattr.valid |= nine_p::SETATTR_SIZE;
+                tsetattr.size = size;
+            }
+            // Without the `_SET` bits, the server uses its current time
+            if let Some(atime) = atime {
+                tsetattr.valid |= nine_p::SETATTR_ATIME;
+                if let TimeOrNow::SpecificTime(time) = atime {
+                    tsetattr.valid |= nine_p::SETATTR_ATIME_SET;
+                    (tsetattr.atime_sec, tsetattr.atime_nsec) = timespec(time);
+                }
+            }
+            if let Some(mtime) = mtime {
+                tsetattr.valid |= nine_p::SETATTR_MTIME;
+                if let TimeOrNow::SpecificTime(time) = mtime {
+                    tsetattr.valid |= nine_p::SETATTR_MTIME_SET;
+                    (tsetattr.mtime_sec, tsetattr.mtime_nsec) = timespec(time);
+                }
+            }
+            self.call(tsetattr)?;
+            return Ok(self.stat(fid)?.1);
+        }
+
+        let mut stat = self.wstat_unchanged();
+        if let Some(mode) = mode {
+            // The server doesn't allow the type bits to change
+            let mut buf = Vec::new();
+            let old = self.client.send(nine_p::TStat { fid }, &mut buf)?.stat.mode;
+            stat.mode = (old & !0o777) | (mode & 0o777);
+        }
+        if let Some(size) = size {
+            stat.length = size;
+        }
+        if let Some(atime) = atime {
+            stat.atime = unix_time(atime);
+        }
+        if let Some(mtime) = mtime {
+            stat.mtime = unix_time(mtime);
+        }
+        self.wstat(fid, &stat)?;
+        Ok(self.stat(fid)?.1)
+    }
 
-        // TODO flags
+    fn lookup(&self, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let Ok(name) = str::from_utf8(name.as_bytes()) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
 
-        if let Some(inode) = self.inode(ino) {
+        let ttl = self.cache.ttl();
+        match self.cached_entry(parent, name) {
+            Ok(attr) => reply.entry(&ttl, &attr, 0), // XXX generation?
+            // The kernel remembers the name is missing for the TTL
+            Err(err) if errno(&err) == libc::ENOENT && !ttl.is_zero() => {
+                reply.entry(&ttl, &cache::negative(), 0)
+            }
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn open(&self, ino: u64, flags: i32, reply: ReplyOpen) {
+        // XXX O_APPEND writes at the offset the kernel gives
+
+        let Some(fid) = self.fid(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        match self.open_fid(fid, flags) {
+            Ok((newfid, qid)) => {
+                let flags = if self.cache.reopened(qid) {
+                    FOPEN_KEEP_CACHE
+                } else {
+                    0
+                };
+                reply.opened(self.add_open_file(newfid, vec![]), flags)
+            }
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn opendir(&self, ino: u64, reply: ReplyOpen) {
+        let Some(fid) = self.fid(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        let newfid = match self.open_fid(fid, libc::O_RDONLY) {
+            Ok((newfid, _)) => newfid,
+            Err(err) => {
+                reply.error(errno(&err));
+                return;
+            }
+        };
+        let dir_entries = match self.read_dir(self.ino(ino), newfid) {
+            Ok(dir_entries) => dir_entries,
+            Err(err) => {
+                self.clunk(newfid);
+                reply.error(errno(&err));
+                return;
+            }
+        };
+        reply.opened(self.add_open_file(newfid, dir_entries), 0);
+    }
+
+    // Both `release` and `releasedir`
+    fn release(&self, fh: u64, reply: ReplyEmpty) {
+        let open_file = self.open_files.lock().unwrap().remove(&fh);
+        if let Some(open_file) = open_file {
+            self.clunk(open_file.fid);
+        }
+        reply.ok();
+    }
+
+    fn forget(&self, ino: u64, nlookup: u64) {
+        let ino = self.ino(ino);
+        let mut inodes = self.inodes.lock().unwrap();
+        let Some(inode) = inodes.get_mut(&ino) else {
+            return;
+        };
+        inode.lookups = inode.lookups.saturating_sub(nlookup);
+        if inode.lookups == 0 {
             let fid = inode.fid;
+            inodes.remove(&ino);
+            drop(inodes);
+            self.cache.forget(ino);
+            self.clunk(fid);
+        }
+    }
 
-            let newfid = self.next_id;
-            let res = self
-                .client
-                .send(
-                    0,
-                    nine_p::TWalk {
-                        fid,
-                        newfid,
-                        wnames: vec![],
-                    },
-                )
-                .unwrap();
-            self.next_id.0 += 1;
-
-            let res = self
-                .client
-                .send(
-                    0,
-                    nine_p::TOpen {
-                        fid: newfid,
-                        mode: 0, // XXX
-                    },
-                )
-                .unwrap(); // XXX
-
-            let fh = self.next_fh;
-            self.next_fh += 1;
-
-            self.open_files.insert(
-                fh,
-                OpenFile {
-                    dir_entries: vec![],
-                    fid: newfid,
-                },
-            );
+    fn getattr(&self, ino: u64, reply: ReplyAttr) {
+        let ttl = self.cache.ttl();
+        if let Some(attr) = self.cache.attr(self.ino(ino)) {
+            reply.attr(&ttl, &attr);
+        } else if let Some(fid) = self.fid(ino) {
+            match self.stat(fid) {
+                Ok((_, attr)) => reply.attr(&ttl, &attr),
+                Err(err) => reply.error(errno(&err)),
+            }
+        } else {
+            reply.error(libc::ENOENT);
+        }
+    }
 
-            reply.opened(fh, 0);
+    fn read(&self, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        let Some(fid) = self.file_fid(fh) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        let mut buf = Vec::new();
+        let mut body = Vec::new();
+        loop {
+            let count = (size - buf.len() as u32).min(self.msize - IOHDRSZ);
+            let tread = nine_p::TRead {
+                fid,
+                offset: offset as u64 + buf.len() as u64,
+                count,
+            };
+            match self.client.send(tread, &mut body) {
+                Ok(res) => {
+                    buf.extend_from_slice(res.data);
+                    if res.data.is_empty() || buf.len() as u32 >= size {
+                        break;
+                    }
+                }
+                // Return what was read before the error
+                Err(_) if !buf.is_empty() => break,
+                Err(err) => {
+                    reply.error(errno(&err));
+                    return;
+                }
+            }
+        }
+        reply.data(&buf);
+    }
+
+    fn readdir(&self, fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let open_files = self.open_files.lock().unwrap();
+        if let Some(open_file) = open_files.get(&fh) {
+            let entries = open_file.dir_entries.get(offset as usize..).unwrap_or(&[]);
+            for (i, entry) in entries.iter().enumerate() {
+                reply.add(entry.ino, offset + i as i64 + 1, entry.kind, &entry.name);
+            }
+            reply.ok();
         } else {
             reply.error(libc::ENOENT);
         }
     }
 
-    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
-        println!("opendir");
-        if let Some(inode) = self.inode(ino) {
-            let fid = inode.fid;
+    // Entries are looked up as they're listed, from the cache when it has
+    // them, so the kernel needn't look each up itself
+    fn readdirplus(&self, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectoryPlus) {
+        let names: Vec<String> = {
+            let open_files = self.open_files.lock().unwrap();
+            let Some(open_file) = open_files.get(&fh) else {
+                reply.error(libc::ENOENT);
+                return;
+            };
+            let entries = open_file.dir_entries.get(offset as usize..).unwrap_or(&[]);
+            entries.iter().map(|entry| entry.name.clone()).collect()
+        };
+        let ttl = self.cache.ttl();
+        for (i, name) in names.iter().enumerate() {
+            // Removed since the directory was read
+            let Ok(attr) = self.cached_entry(ino, name) else {
+                continue;
+            };
+            if reply.add(attr.ino, offset + i as i64 + 1, name, &ttl, &attr, 0) {
+                // It didn't fit, so the kernel won't count the lookup
+                self.forget(attr.ino, 1);
+                break;
+            }
+        }
+        reply.ok();
+    }
 
-            let newfid = self.next_id;
-            let res = self
-                .client
-                .send(
-                    0,
-                    nine_p::TWalk {
-                        fid,
-                        newfid,
-                        wnames: vec![],
-                    },
-                )
-                .unwrap();
-            self.next_id.0 += 1;
-
-            let res = self
-                .client
-                .send(
-                    0,
-                    nine_p::TOpen {
-                        fid: newfid,
-                        mode: 0, // XXX
-                    },
-                )
-                .unwrap(); // XXX
-
-            let mut dir_contents = Vec::new(); // XXX
-            let mut offset = 0;
-            loop {
-                let res = self
-                    .client
-                    .send(
-                        0,
-                        nine_p::TRead {
-                            fid: newfid,
-                            offset,
-                            count: self.msize - IOHDRSZ,
-                        },
-                    )
-                    .unwrap(); // XXX
-                if res.data.len() == 0 {
-                    break;
+    fn write(&self, ino: u64, fh: u64, offset: i64, data: &[u8], reply: ReplyWrite) {
+        let Some(fid) = self.file_fid(fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+
+        let mut written = 0;
+        let mut buf = Vec::new();
+        while written < data.len() {
+            let len = (data.len() - written).min((self.msize - IOHDRSZ) as usize);
+            let twrite = nine_p::TWrite {
+                fid,
+                offset: offset as u64 + written as u64,
+                data: &data[written..written + len],
+            };
+            match self.client.send(twrite, &mut buf) {
+                Ok(res) => {
+                    written += res.count as usize;
+                    if (res.count as usize) < len {
+                        break;
+                    }
+                }
+                // Report what was written before the error
+                Err(_) if written > 0 => break,
+                Err(err) => {
+                    reply.error(errno(&err));
+                    return;
                 }
-                dir_contents.extend_from_slice(res.data);
-                offset += res.data.len() as u64;
             }
+        }
+        // The size and mtime changed
+        self.cache.invalidate(self.ino(ino));
+        reply.written(written as u32);
+    }
 
-            let stats = nine_p::parse_dir(&dir_contents).unwrap(); // XXX
-            let dir_entries = stats
-                .iter()
-                .map(|stat| DirEntry {
-                    ino: stat.qid.path,
-                    kind: file_type(stat.qid.type_),
-                    name: stat.name.to_string(),
-                })
-                .collect();
+    fn create(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        perm: u32,
+        flags: i32,
+        gid: u32,
+        reply: ReplyCreate,
+    ) {
+        let Ok(name) = str::from_utf8(name.as_bytes()) else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+        let Some(dir) = self.fid(parent) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        let fid = match self.create_file(dir, name, perm, flags, gid) {
+            Ok(fid) => fid,
+            Err(err) => {
+                reply.error(errno(&err));
+                return;
+            }
+        };
+        self.cache.invalidate(self.ino(parent));
+        let attr = match self.entry(parent, name) {
+            Ok(attr) => attr,
+            Err(err) => {
+                self.clunk(fid);
+                reply.error(errno(&err));
+                return;
+            }
+        };
+        reply.created(
+            &self.cache.ttl(),
+            &attr,
+            0,
+            self.add_open_file(fid, vec![]),
+            0,
+        );
+    }
 
-            let fh = self.next_fh;
-            self.next_fh += 1;
+    fn mknod(&self, parent: u64, name: &OsStr, mode: u32, rdev: u32, gid: u32, reply: ReplyEntry) {
+        match self.make(parent, name, mode, rdev, gid) {
+            Ok(attr) => reply.entry(&self.cache.ttl(), &attr, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
 
-            self.open_files.insert(
-                fh,
-                OpenFile {
-                    dir_entries,
-                    fid: newfid,
-                },
-            );
+    fn unlink(&self, parent: u64, name: &OsStr, dir: bool, reply: ReplyEmpty) {
+        match self.remove(parent, name, dir) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn setattr(
+        &self,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        reply: ReplyAttr,
+    ) {
+        // XXX only 9P2000.L has numeric ids to change ownership to
+        if self.dialect != Dialect::L && (uid.is_some() || gid.is_some()) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        let Some(fid) = self.fid(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        match self.set_attr(fid, mode, uid, gid, size, atime, mtime) {
+            Ok(attr) => reply.attr(&self.cache.ttl(), &attr),
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn rename(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let (Ok(name), Ok(newname)) = (
+            str::from_utf8(name.as_bytes()),
+            str::from_utf8(newname.as_bytes()),
+        ) else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let res = self.rename_name(parent, name, newparent, newname, flags);
+        self.cache.unlink(self.ino(parent), name);
+        self.cache.unlink(self.ino(newparent), newname);
+        match res {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn rename_name(
+        &self,
+        parent: u64,
+        name: &str,
+        newparent: u64,
+        newname: &str,
+        flags: u32,
+    ) -> Result<(), i32> {
+        let Some(dir) = self.fid(parent) else {
+            return Err(libc::ENOENT);
+        };
+        if self.dialect == Dialect::L {
+            let Some(newdir) = self.fid(newparent) else {
+                return Err(libc::ENOENT);
+            };
+            // XXX races with another client creating the target
+            if flags & libc::RENAME_NOREPLACE != 0 {
+                if let Ok(target) = self.walk(newdir, vec![newname]) {
+                    self.clunk(target);
+                    return Err(libc::EEXIST);
+                }
+            }
+            let trenameat = nine_p::TRenameAt {
+                olddirfid: dir,
+                oldname: name,
+                newdirfid: newdir,
+                newname,
+            };
+            return self.call(trenameat).map_err(|err| errno(&err));
+        }
+        // wstat only renames within a directory, so anything else is
+        // copied by the caller
+        if self.fid(newparent) != Some(dir) {
+            return Err(libc::EXDEV);
+        }
+
+        let fid = self.walk(dir, vec![name]).map_err(|err| errno(&err))?;
+        let mut stat = self.wstat_unchanged();
+        stat.name = newname;
+        let mut res = self.wstat(fid, &stat).map_err(|err| errno(&err));
+        // wstat won't replace an existing file, so if that's why it failed,
+        // the target is removed and the rename tried again
+        // XXX not atomic
+        if res == Err(libc::EEXIST) && flags & libc::RENAME_NOREPLACE == 0 {
+            if let Ok(target) = self.walk(dir, vec![newname]) {
+                res = self
+                    .call(nine_p::TRemove { fid: target })
+                    .and_then(|()| self.wstat(fid, &stat))
+                    .map_err(|err| errno(&err));
+            }
+        }
+        self.clunk(fid);
+        res
+    }
 
-            reply.opened(fh, 0);
+    fn fsync(&self, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let Some(fid) = self.file_fid(fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        let res = if self.dialect == Dialect::L {
+            self.call(nine_p::TFSync {
+                fid,
+                datasync: datasync.into(),
+            })
         } else {
+            // A wstat changing nothing asks the server to commit the file
+            // to stable storage
+            self.wstat(fid, &self.wstat_unchanged())
+        };
+        match res {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn readlink(&self, ino: u64, reply: ReplyData) {
+        let Some(fid) = self.fid(ino) else {
             reply.error(libc::ENOENT);
+            return;
+        };
+        let mut buf = Vec::new();
+        match self.dialect {
+            Dialect::L => match self.client.send(nine_p::TReadLink { fid }, &mut buf) {
+                Ok(res) => reply.data(res.target.as_bytes()),
+                Err(err) => reply.error(errno(&err)),
+            },
+            // The target is the extension of the stat
+            Dialect::U => match self.client.send(nine_p::TStat { fid }, &mut buf) {
+                Ok(res) => match res.stat.dotu {
+                    Some(dotu) => reply.data(dotu.extension.as_bytes()),
+                    None => reply.error(libc::EIO),
+                },
+                Err(err) => reply.error(errno(&err)),
+            },
+            Dialect::P2000 => reply.error(libc::EINVAL),
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce(&FS) + Send>;
+
+// The filesystem as the FUSE session sees it. Requests are handed to a pool
+// of threads, so one waiting on the server doesn't hold up the others, and
+// the session's thread only reads the next request.
+struct Mount {
+    fs: Arc<FS>,
+    sender: mpsc::Sender<Job>,
+}
+
+impl Mount {
+    fn new(fs: FS, threads: usize) -> Self {
+        let fs = Arc::new(fs);
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let fs = fs.clone();
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                let job = receiver.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(&fs),
+                    Err(_) => break,
+                }
+            });
         }
+        Self { fs, sender }
+    }
+
+    fn spawn(&self, job: impl FnOnce(&FS) + Send + 'static) {
+        // The workers only stop once the sender is dropped
+        let _ = self.sender.send(Box::new(job));
+    }
+}
+
+impl Filesystem for Mount {
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), libc::c_int> {
+        // Listings fill the cache, which the kernel can then have with them
+        if self.fs.cache.mode != cache::Mode::None {
+            let _ = config.add_capabilities(FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO);
+        }
+        Ok(())
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name = name.to_owned();
+        self.spawn(move |fs| fs.lookup(parent, &name, reply));
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.spawn(move |fs| fs.open(ino, flags, reply));
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        self.spawn(move |fs| fs.opendir(ino, reply));
     }
 
     fn release(
@@ -290,13 +1173,7 @@ impl fuser::Filesystem for FS {
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        if let Some(open_file) = self.open_files.remove(&fh) {
-            let res = self
-                .client
-                .send(0, nine_p::TClunk { fid: open_file.fid })
-                .unwrap();
-        }
-        reply.ok();
+        self.spawn(move |fs| fs.release(fh, reply));
     }
 
     fn releasedir(
@@ -307,48 +1184,21 @@ impl fuser::Filesystem for FS {
         _flags: i32,
         reply: ReplyEmpty,
     ) {
-        if let Some(open_file) = self.open_files.remove(&fh) {
-            let res = self
-                .client
-                .send(0, nine_p::TClunk { fid: open_file.fid })
-                .unwrap();
-        }
-        reply.ok();
+        self.spawn(move |fs| fs.release(fh, reply));
     }
 
     fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
-        if let Some(inode) = self.inode_mut(ino) {
-            inode.lookups -= nlookup;
-            if inode.lookups == 0 {
-                let fid = inode.fid;
-                let res = self.client.send(0, nine_p::TClunk { fid }).unwrap();
-                self.inodes.remove(&ino);
-            }
-        }
+        self.spawn(move |fs| fs.forget(ino, nlookup));
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        eprintln!("getattr: {ino}");
-        if let Some(inode) = self.inode(ino) {
-            let stat = self
-                .client
-                .send(0, nine_p::TStat { fid: inode.fid })
-                .unwrap()
-                .stat;
-            dbg!(stat);
-            let mut attr = attr_from_stat(&stat);
-
-            reply.attr(&TTL, &attr);
-        } else {
-            println!("FOO!");
-            reply.error(libc::ENOENT);
-        }
+        self.spawn(move |fs| fs.getattr(ino, reply));
     }
 
     fn read(
         &mut self,
         _req: &Request,
-        ino: u64,
+        _ino: u64,
         fh: u64,
         offset: i64,
         size: u32,
@@ -356,115 +1206,222 @@ impl fuser::Filesystem for FS {
         _lock: Option<u64>,
         reply: ReplyData,
     ) {
-        eprintln!("read");
+        self.spawn(move |fs| fs.read(fh, offset, size, reply));
+    }
 
-        if let Some(open_file) = self.open_files.get(&fh) {
-            let fid = open_file.fid;
+    // Entries were read when the directory was opened, so this doesn't wait
+    // on the server
+    fn readdir(&mut self, _req: &Request, _ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        self.fs.readdir(fh, offset, reply);
+    }
 
-            let mut buf = Vec::new();
-            loop {
-                let count = (size - buf.len() as u32).min(self.msize - IOHDRSZ);
-                let res = self
-                    .client
-                    .send(
-                        0,
-                        nine_p::TRead {
-                            fid,
-                            offset: offset as u64,
-                            count,
-                        },
-                    )
-                    .unwrap();
-                buf.extend_from_slice(res.data);
-                if res.data.is_empty() || buf.len() as u32 >= size {
-                    break;
-                }
-            }
-            reply.data(&buf);
-        } else {
-            reply.error(libc::ENOENT);
-        }
+    fn readdirplus(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectoryPlus,
+    ) {
+        self.spawn(move |fs| fs.readdirplus(ino, fh, offset, reply));
     }
 
-    fn readdir(
+    fn write(
         &mut self,
-        _req: &Request,
+        _req: &Request<'_>,
         ino: u64,
         fh: u64,
         offset: i64,
-        mut reply: ReplyDirectory,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
     ) {
-        eprintln!("readdir");
-        if let Some(open_file) = self.open_files.get(&fh) {
-            let entries = &open_file.dir_entries[offset as usize..];
-            for (i, entry) in entries.iter().enumerate() {
-                reply.add(entry.ino, offset + i as i64 + 1, entry.kind, &entry.name);
-            }
-            reply.ok();
-        } else {
-            reply.error(libc::ENOENT);
-        }
+        let data = data.to_vec();
+        self.spawn(move |fs| fs.write(ino, fh, offset, &data, reply));
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let name = name.to_owned();
+        let perm = mode & !umask & 0o777;
+        let gid = req.gid();
+        self.spawn(move |fs| fs.create(parent, &name, perm, flags, gid, reply));
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let name = name.to_owned();
+        let gid = req.gid();
+        self.spawn(move |fs| fs.mknod(parent, &name, mode & !umask, rdev, gid, reply));
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let name = name.to_owned();
+        let mode = libc::S_IFDIR | (mode & !umask & 0o7777);
+        let gid = req.gid();
+        self.spawn(move |fs| fs.mknod(parent, &name, mode, 0, gid, reply));
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = name.to_owned();
+        self.spawn(move |fs| fs.unlink(parent, &name, false, reply));
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = name.to_owned();
+        self.spawn(move |fs| fs.unlink(parent, &name, true, reply));
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        self.spawn(move |fs| fs.setattr(ino, mode, uid, gid, size, atime, mtime, reply));
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let name = name.to_owned();
+        let newname = newname.to_owned();
+        self.spawn(move |fs| fs.rename(parent, &name, newparent, &newname, flags, reply));
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.spawn(move |fs| fs.fsync(fh, datasync, reply));
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        self.spawn(move |fs| fs.readlink(ino, reply));
     }
 
     // statfs
-    // write
-    // unlink
-    // setattr
-    // rmdir
-    // rename
-    // (symlinks?)
-    // readdirplus
-    // mkdir
+    // symlink, link
     // lseek
-    // copy_file_range? fallocate? fsync?
+    // copy_file_range? fallocate?
 }
 
-fn main() {
-    let stream = TcpStream::connect("localhost:564").unwrap();
-    let mut client = nine_p::SyncClient::new(stream);
-
-    let res = client
-        .send(
-            65535,
-            nine_p::TVersion {
-                msize: 8192 + IOHDRSZ,
-                version: "9P2000.u",
-            },
-        )
-        .unwrap();
-    let msize = res.msize;
-    println!("{:?}", res);
+fn main() -> io::Result<()> {
+    let options = Options::parse();
+    let ready = if options.foreground {
+        None
+    } else {
+        Some(daemonize()?)
+    };
+    let client = connect(&options.address, options.msize, options.dialect.version())?;
+    let msize = client.msize();
+    let Some(dialect) = Dialect::from_version(client.version()) else {
+        let err = format!("unsupported version {}", client.version());
+        return Err(io::Error::other(err));
+    };
 
-    let res = client
-        .send(
-            0,
-            nine_p::TAttach {
-                fid: Fid(0),
-                afid: Fid(u32::MAX),
-                uname: "",
-                aname: "",
-            },
-        )
-        .unwrap();
+    // The extensions also send the uid of the user, which is looked up if
+    // another user is named
+    let (uname, n_uname) = match options.uname {
+        Some(uname) => {
+            let n_uname = attr::lookup_user(&uname).unwrap_or(nine_p::NONUNAME);
+            (uname, n_uname)
+        }
+        None => {
+            let uname = env::var("USER").unwrap_or_default();
+            (uname, unsafe { libc::getuid() })
+        }
+    };
+    let mut buf = Vec::new();
+    let qid = if dialect == Dialect::P2000 {
+        let tattach = nine_p::TAttach {
+            fid: Fid(0),
+            afid: Fid::NOFID,
+            uname: &uname,
+            aname: &options.aname,
+        };
+        client.send(tattach, &mut buf)?.qid
+    } else {
+        let tattach = nine_p::TLAttach {
+            fid: Fid(0),
+            afid: Fid::NOFID,
+            uname: &uname,
+            aname: &options.aname,
+            n_uname,
+        };
+        client.send(tattach, &mut buf)?.qid
+    };
 
     let root_inode = Inode {
-        qid: res.qid,
+        qid,
         fid: Fid(0),
         lookups: 1,
     };
     let mut inodes = HashMap::new();
-    inodes.insert(res.qid.path, root_inode);
-
-    println!("{:?}", res);
+    inodes.insert(qid.path, root_inode);
 
+    let mut owners = Owners::new(options.map_names);
+    owners.squash_uid = options.uid;
+    owners.squash_gid = options.gid;
     let fs = FS {
         client,
-        next_id: Fid(1),
-        next_fh: 0,
-        inodes,
-        open_files: HashMap::new(),
-        root_ino: res.qid.path,
+        next_id: AtomicU32::new(1),
+        next_fh: AtomicU64::new(0),
+        inodes: Mutex::new(inodes),
+        open_files: Mutex::new(HashMap::new()),
+        root_ino: qid.path,
         msize,
+        dialect,
+        owners: Mutex::new(owners),
+        cache: Cache::new(options.cache, options.ttl),
     };
-    fuser::mount2(fs, "mnt", &[]).unwrap();
+    let mountpoint = Path::new(&options.mountpoint);
+    let mount = Mount::new(fs, options.threads);
+    let mut session = fuser::Session::new(mount, mountpoint, &options.mount_options)?;
+    if let Some(ready) = ready {
+        detach(ready)?;
+    }
+    session.run()
 }
@@ -0,0 +1,180 @@
+// The command line, which also takes the `-o` options mount(8) passes to a
+// mount.fuse helper, so fuse-9p can be mounted from /etc/fstab
+
+use crate::{cache, Dialect};
+use fuser::MountOption;
+use std::{env, process, time::Duration};
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: fuse-9p [-fr] [-u uname] [-a aname] [-m msize] [-v version] [-o option,...] \
+         address mountpoint"
+    );
+    process::exit(1);
+}
+
+pub struct Options {
+    pub address: String,
+    pub mountpoint: String,
+    /// The user running fuse-9p if not given
+    pub uname: Option<String>,
+    pub aname: String,
+    pub msize: u32,
+    pub dialect: Dialect,
+    pub foreground: bool,
+    /// Look up the names of owners in the local user and group databases
+    pub map_names: bool,
+    /// Owners given to every file, whoever owns it on the server
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Requests handled at once
+    pub threads: usize,
+    pub cache: cache::Mode,
+    /// How long attributes and lookups are trusted
+    pub ttl: Duration,
+    pub mount_options: Vec<MountOption>,
+}
+
+impl Options {
+    pub fn parse() -> Self {
+        let mut options = Self {
+            address: String::new(),
+            mountpoint: String::new(),
+            uname: None,
+            aname: String::new(),
+            msize: nine_p::DEFAULT_MSIZE,
+            dialect: Dialect::U,
+            foreground: false,
+            map_names: true,
+            uid: None,
+            gid: None,
+            threads: 16,
+            cache: cache::Mode::Loose,
+            ttl: Duration::from_secs(1),
+            mount_options: Vec::new(),
+        };
+        let mut paths = Vec::new();
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-f" => options.foreground = true,
+                "-r" => options.set("ro", None),
+                "-u" | "-a" | "-m" | "-v" => {
+                    let key = match arg.as_str() {
+                        "-u" => "uname",
+                        "-a" => "aname",
+                        "-m" => "msize",
+                        _ => "version",
+                    };
+                    let value = args.next().unwrap_or_else(|| usage());
+                    options.set(key, Some(&value));
+                }
+                "-o" => {
+                    let list = args.next().unwrap_or_else(|| usage());
+                    options.set_list(&list);
+                }
+                _ if arg.starts_with("-o") => options.set_list(&arg[2..]),
+                _ if arg.starts_with('-') => usage(),
+                _ => paths.push(arg),
+            }
+        }
+        let [address, mountpoint] = <[String; 2]>::try_from(paths).unwrap_or_else(|_| usage());
+        options
+            .mount_options
+            .push(MountOption::FSName(address.clone()));
+        options
+            .mount_options
+            .push(MountOption::Subtype("9p".to_string()));
+        options.address = address;
+        options.mountpoint = mountpoint;
+        options
+    }
+
+    fn set_list(&mut self, list: &str) {
+        for option in list.split(',').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some((key, value)) => self.set(key, Some(value)),
+                None => self.set(option, None),
+            }
+        }
+    }
+
+    fn set(&mut self, key: &str, value: Option<&str>) {
+        let mount_option = match (key, value) {
+            ("uname", Some(uname)) => {
+                self.uname = Some(uname.to_string());
+                return;
+            }
+            ("aname", Some(aname)) => {
+                self.aname = aname.to_string();
+                return;
+            }
+            ("msize", Some(msize)) => {
+                self.msize = msize.parse().unwrap_or_else(|_| usage());
+                return;
+            }
+            ("version", Some(version)) => {
+                self.dialect = Dialect::from_version(version).unwrap_or_else(|| usage());
+                return;
+            }
+            ("uid", Some(uid)) => {
+                self.uid = Some(uid.parse().unwrap_or_else(|_| usage()));
+                return;
+            }
+            ("gid", Some(gid)) => {
+                self.gid = Some(gid.parse().unwrap_or_else(|_| usage()));
+                return;
+            }
+            ("threads", Some(threads)) => {
+                self.threads = threads.parse().unwrap_or_else(|_| usage());
+                self.threads = self.threads.max(1);
+                return;
+            }
+            ("cache", Some(mode)) => {
+                self.cache = cache::Mode::from_name(mode).unwrap_or_else(|| usage());
+                return;
+            }
+            ("ttl", Some(ttl)) => {
+                let secs = ttl.parse().unwrap_or_else(|_| usage());
+                self.ttl = Duration::try_from_secs_f64(secs).unwrap_or_else(|_| usage());
+                return;
+            }
+            ("nomap", None) => {
+                self.map_names = false;
+                return;
+            }
+            // Only meaningful to mount(8)
+            (
+                "defaults" | "auto" | "noauto" | "user" | "nouser" | "users" | "_netdev" | "nofail",
+                None,
+            ) => return,
+            (
+                "uname" | "aname" | "msize" | "version" | "uid" | "gid" | "threads" | "cache"
+                | "ttl",
+                None,
+            ) => usage(),
+            ("ro", None) => MountOption::RO,
+            ("rw", None) => MountOption::RW,
+            ("allow_other", None) => MountOption::AllowOther,
+            ("allow_root", None) => MountOption::AllowRoot,
+            ("auto_unmount", None) => MountOption::AutoUnmount,
+            ("default_permissions", None) => MountOption::DefaultPermissions,
+            ("dev", None) => MountOption::Dev,
+            ("nodev", None) => MountOption::NoDev,
+            ("suid", None) => MountOption::Suid,
+            ("nosuid", None) => MountOption::NoSuid,
+            ("exec", None) => MountOption::Exec,
+            ("noexec", None) => MountOption::NoExec,
+            ("atime", None) => MountOption::Atime,
+            ("noatime", None) => MountOption::NoAtime,
+            ("sync", None) => MountOption::Sync,
+            ("async", None) => MountOption::Async,
+            ("dirsync", None) => MountOption::DirSync,
+            // Anything else is left to fusermount and the kernel
+            (key, None) => MountOption::CUSTOM(key.to_string()),
+            (key, Some(value)) => MountOption::CUSTOM(format!("{}={}", key, value)),
+        };
+        self.mount_options.push(mount_option);
+    }
+}
@@ -0,0 +1,97 @@
+// Share one connection to a 9P server between many local clients, like
+// plan9port's 9pserve
+//
+// Usage: mux-9p [-m msize] socket upstream
+//
+// Clients connect to the Unix socket at `socket`. Their sessions are all
+// forwarded over a single connection to `upstream`, a TCP address or the
+// path of a Unix socket if it contains a '/', with their tags and fids
+// renumbered so they don't conflict. The version is negotiated with the
+// server once, and clients are offered at most the same `msize`. The fids
+// of a client are clunked when it disconnects. Exits when the server closes
+// the connection.
+//
+// Clients are served by tokio, so requests waiting on the server, such as
+// reads that block, don't hold up those of other clients.
+
+use nine_p::{Proxy, TokioServer, Upstream};
+use std::{
+    env, fs, io,
+    net::TcpStream,
+    os::unix::net::{UnixListener, UnixStream},
+    process,
+    sync::Arc,
+    thread,
+};
+
+fn usage() -> ! {
+    eprintln!("usage: mux-9p [-m msize] socket upstream");
+    process::exit(1);
+}
+
+fn connect(upstream: &str, msize: u32) -> Result<Upstream, nine_p::Error> {
+    if upstream.contains('/') {
+        Upstream::new(UnixStream::connect(upstream)?, msize)
+    } else {
+        let stream = TcpStream::connect(upstream)?;
+        stream.set_nodelay(true)?;
+        Upstream::new(stream, msize)
+    }
+}
+
+// Bind `path`, replacing a socket left behind by a server that has exited
+fn bind(path: &str) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(err);
+            }
+            fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        res => res,
+    }
+}
+
+fn main() -> io::Result<()> {
+    let mut msize = nine_p::DEFAULT_MSIZE;
+    let mut paths = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-m" => {
+                let arg = args.next().unwrap_or_else(|| usage());
+                msize = arg.parse().unwrap_or_else(|_| usage());
+            }
+            _ if arg.starts_with('-') => usage(),
+            _ => paths.push(arg),
+        }
+    }
+    let [socket, upstream] = <[String; 2]>::try_from(paths).unwrap_or_else(|_| usage());
+
+    let conn = Arc::new(connect(&upstream, msize)?);
+    let listener = bind(&socket)?;
+    eprintln!("serving {} on {}, msize {}", upstream, socket, conn.msize());
+
+    let watched = conn.clone();
+    let socket_path = socket.clone();
+    thread::spawn(move || {
+        watched.wait_closed();
+        eprintln!("{} hung up", upstream);
+        let _ = fs::remove_file(&socket_path);
+        process::exit(0);
+    });
+
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?;
+    runtime.block_on(async {
+        let listener = tokio::net::UnixListener::from_std(listener)?;
+        TokioServer::new()
+            .msize(conn.msize())
+            .serve_unix(listener, move || Proxy::new(conn.clone()))
+            .await
+    })
+}
@@ -0,0 +1,139 @@
+// Clients sharing a server through mux-9p
+
+use nine_p::{
+    text_file, BlockingServer, Events, Fid, FidServer, SyncClient, SynthTree, TAttach, TOpen,
+    TRead, TVersion, TWalk, NOTAG, OREAD,
+};
+use std::{
+    env,
+    net::TcpListener,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+// Killed when the test ends
+struct Mux {
+    child: Child,
+    socket: PathBuf,
+}
+
+impl Mux {
+    fn start(tree: SynthTree) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tree = Arc::new(tree);
+        thread::spawn(move || {
+            BlockingServer::new()
+                .workers(64)
+                .serve(listener, move || FidServer::new(tree.clone()))
+        });
+
+        let socket = env::temp_dir().join(format!("mux-9p-{}", std::process::id()));
+        let child = Command::new(env!("CARGO_BIN_EXE_mux-9p"))
+            .arg(&socket)
+            .arg(addr.to_string())
+            .spawn()
+            .unwrap();
+        Self { child, socket }
+    }
+}
+
+impl Drop for Mux {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
+
+// Open `name` in the root, as fid 1
+fn open(socket: &Path, name: &str) -> SyncClient<UnixStream> {
+    let stream = loop {
+        match UnixStream::connect(socket) {
+            Ok(stream) => break stream,
+            // Not listening yet
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    // Fail rather than hang if requests are held up
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut client = SyncClient::new(stream);
+    let tversion = TVersion {
+        msize: 8192,
+        version: "9P2000",
+    };
+    client.send(NOTAG, tversion).unwrap();
+    let tattach = TAttach {
+        fid: Fid(0),
+        afid: Fid::NOFID,
+        uname: "glenda",
+        aname: "",
+    };
+    client.send(0, tattach).unwrap();
+    let twalk = TWalk {
+        fid: Fid(0),
+        newfid: Fid(1),
+        wnames: vec![name],
+    };
+    client.send(0, twalk).unwrap();
+    let topen = TOpen {
+        fid: Fid(1),
+        mode: OREAD,
+    };
+    client.send(0, topen).unwrap();
+    client
+}
+
+fn read(client: &mut SyncClient<UnixStream>) -> Vec<u8> {
+    let tread = TRead {
+        fid: Fid(1),
+        offset: 0,
+        count: 1024,
+    };
+    client.send(0, tread).unwrap().data.to_vec()
+}
+
+#[test]
+fn blocked_reads_dont_hold_up_others() {
+    let events = Events::new();
+    let tree = SynthTree::new("glenda");
+    tree.root()
+        .create_file("events", 0o444, events.clone())
+        .unwrap();
+    let file = text_file(|| "hello".to_string());
+    tree.root().create_file("file", 0o444, file).unwrap();
+    let mux = Mux::start(tree);
+
+    // More reads waiting on the server than mux-9p ever had workers
+    let (opened, wait) = mpsc::channel();
+    let readers: Vec<_> = (0..32)
+        .map(|_| {
+            let socket = mux.socket.clone();
+            let opened = opened.clone();
+            thread::spawn(move || {
+                let mut client = open(&socket, "events");
+                opened.send(()).unwrap();
+                read(&mut client)
+            })
+        })
+        .collect();
+    for _ in &readers {
+        wait.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+    // Give the reads time to reach the server
+    thread::sleep(Duration::from_millis(100));
+
+    let mut client = open(&mux.socket, "file");
+    assert_eq!(read(&mut client), b"hello");
+
+    events.send("event");
+    for reader in readers {
+        assert_eq!(reader.join().unwrap(), b"event");
+    }
+}
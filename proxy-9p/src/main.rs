@@ -0,0 +1,133 @@
+// Forward 9P connections to another server, logging their messages
+//
+// Usage: proxy-9p [-r] [-x path]... [-u uname] [-l address] [-m msize] upstream
+//
+// Each client connection gets its own connection to `upstream`, which is a
+// TCP address, or the path of a Unix socket if it contains a '/'. Messages
+// are logged to stderr, as the client sees them, other than TVersion and
+// TFlush, which are handled by the server without being forwarded.
+//
+// -r refuses requests that change files, -x hides a path (and everything
+// under it) from clients, and -u attaches upstream as `uname` whoever the
+// client claims to be.
+
+use nine_p::{BlockingServer, Policy, Proxy, Upstream};
+use std::{
+    env, fmt, io,
+    net::{TcpListener, TcpStream},
+    os::unix::net::UnixStream,
+    process,
+    sync::Arc,
+    thread,
+};
+
+fn usage() -> ! {
+    eprintln!("usage: proxy-9p [-r] [-x path]... [-u uname] [-l address] [-m msize] upstream");
+    process::exit(1);
+}
+
+struct Rules {
+    client: String,
+    read_only: bool,
+    hidden: Arc<Vec<Vec<String>>>,
+    uname: Option<String>,
+}
+
+impl Policy for Rules {
+    fn log(&self, tag: u16, message: &dyn fmt::Debug) {
+        eprintln!("{} {}: {:?}", self.client, tag, message);
+    }
+
+    fn uname(&self, uname: &str) -> String {
+        self.uname.as_deref().unwrap_or(uname).to_string()
+    }
+
+    fn visible(&self, path: &[String]) -> bool {
+        !self.hidden.iter().any(|hidden| path.starts_with(hidden))
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+fn connect(upstream: &str, msize: u32) -> Result<Upstream, nine_p::Error> {
+    if upstream.contains('/') {
+        Upstream::new(UnixStream::connect(upstream)?, msize)
+    } else {
+        let stream = TcpStream::connect(upstream)?;
+        stream.set_nodelay(true)?;
+        Upstream::new(stream, msize)
+    }
+}
+
+fn main() -> io::Result<()> {
+    let mut address = "0.0.0.0:564".to_string();
+    let mut msize = nine_p::DEFAULT_MSIZE;
+    let mut read_only = false;
+    let mut hidden = Vec::new();
+    let mut uname = None;
+    let mut upstream = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-r" => read_only = true,
+            "-x" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                let path = path.split('/').filter(|name| !name.is_empty());
+                hidden.push(path.map(str::to_string).collect());
+            }
+            "-u" => uname = Some(args.next().unwrap_or_else(|| usage())),
+            "-l" => address = args.next().unwrap_or_else(|| usage()),
+            "-m" => {
+                let arg = args.next().unwrap_or_else(|| usage());
+                msize = arg.parse().unwrap_or_else(|_| usage());
+            }
+            _ if arg.starts_with('-') || upstream.is_some() => usage(),
+            _ => upstream = Some(arg),
+        }
+    }
+    let upstream = upstream.unwrap_or_else(|| usage());
+    let hidden = Arc::new(hidden);
+
+    let listener = TcpListener::bind(&address)?;
+    eprintln!("proxying {} to {}", address, upstream);
+    let server = BlockingServer::new().workers(8);
+    for stream in listener.incoming() {
+        // Errors with one client, such as it resetting the connection before
+        // it's accepted, don't stop the others
+        let accepted = stream.and_then(|stream| {
+            let client = stream.peer_addr()?.to_string();
+            let reader = stream.try_clone()?;
+            Ok((stream, reader, client))
+        });
+        let (stream, reader, client) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                eprintln!("accept: {}", err);
+                continue;
+            }
+        };
+        let conn = match connect(&upstream, msize) {
+            Ok(conn) => Arc::new(conn),
+            Err(err) => {
+                eprintln!("{}: can't connect to {}: {}", client, upstream, err);
+                continue;
+            }
+        };
+        let policy = Arc::new(Rules {
+            client,
+            read_only,
+            hidden: hidden.clone(),
+            uname: uname.clone(),
+        });
+        let server = server.clone().msize(conn.msize());
+        thread::spawn(move || {
+            let _ = server.serve_connection(reader, stream, move || {
+                Proxy::with_policy(conn.clone(), policy.clone())
+            });
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,341 @@
+// Client that multiplexes concurrent requests over one connection, using only
+// the `futures-io` traits so it works under any executor.
+//
+// There's no background task: whichever request future is polled drives the
+// connection, writing queued requests and reading replies. A reply for
+// another tag is stored in that request's slot and its waker is woken.
+
+use futures_io::{AsyncRead, AsyncWrite};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    io,
+    marker::PhantomData,
+    mem,
+    pin::Pin,
+    sync::Mutex,
+    task::{ready, Context, Poll, Waker},
+};
+
+use crate::{sync_client::parse_reply, Error, Header, Message, MessageType, TMessage, NOTAG};
+
+#[derive(Default)]
+struct Slot {
+    reply: Option<(Header, Vec<u8>)>,
+    waker: Option<Waker>,
+    // Future was dropped after the request was sent; discard the reply
+    abandoned: bool,
+}
+
+struct ReadState<R> {
+    reader: R,
+    header: [u8; 7],
+    body: Vec<u8>,
+    // Bytes of the current message read so far, including header
+    pos: usize,
+}
+
+impl<R: AsyncRead + Unpin> ReadState<R> {
+    fn poll_message(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(Header, Vec<u8>)>> {
+        while self.pos < 7 {
+            let n = ready!(Pin::new(&mut self.reader).poll_read(cx, &mut self.header[self.pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.pos += n;
+        }
+        let header = Header::from_array(self.header);
+        let Some(body_len) = (header.size as usize).checked_sub(7) else {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message size smaller than header",
+            )));
+        };
+        self.body.resize(body_len, 0);
+        while self.pos - 7 < body_len {
+            let n =
+                ready!(Pin::new(&mut self.reader).poll_read(cx, &mut self.body[self.pos - 7..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.pos += n;
+        }
+        self.pos = 0;
+        Poll::Ready(Ok((header, mem::take(&mut self.body))))
+    }
+}
+
+struct WriteState<W> {
+    writer: W,
+    // Serialized requests, with the tag they were sent with
+    queue: VecDeque<(u16, Vec<u8>)>,
+    // Bytes of the front of `queue` written so far
+    pos: usize,
+    needs_flush: bool,
+}
+
+impl<W: AsyncWrite + Unpin> WriteState<W> {
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some((_, buf)) = self.queue.front() {
+            while self.pos < buf.len() {
+                let n = ready!(Pin::new(&mut self.writer).poll_write(cx, &buf[self.pos..]))?;
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                self.pos += n;
+            }
+            self.queue.pop_front();
+            self.pos = 0;
+            self.needs_flush = true;
+        }
+        if self.needs_flush {
+            ready!(Pin::new(&mut self.writer).poll_flush(cx))?;
+            self.needs_flush = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W> WriteState<W> {
+    /// Remove request from queue, if none of it has been written yet
+    fn unqueue(&mut self, tag: u16) -> bool {
+        let Some(idx) = self.queue.iter().position(|(t, _)| *t == tag) else {
+            return false;
+        };
+        if idx == 0 && self.pos != 0 {
+            return false;
+        }
+        self.queue.remove(idx);
+        true
+    }
+}
+
+struct Inner<R, W> {
+    read: ReadState<R>,
+    write: WriteState<W>,
+    slots: HashMap<u16, Slot>,
+    next_tag: u16,
+    // Set once the connection fails; every outstanding and later request fails
+    broken: Option<(io::ErrorKind, String)>,
+}
+
+impl<R, W> Inner<R, W> {
+    fn alloc_tag(&mut self) -> Option<u16> {
+        for _ in 0..NOTAG {
+            let tag = self.next_tag;
+            self.next_tag = self.next_tag.wrapping_add(1) % NOTAG;
+            if !self.slots.contains_key(&tag) {
+                return Some(tag);
+            }
+        }
+        None
+    }
+
+    fn broken_error(&self) -> Option<io::Error> {
+        let (kind, msg) = self.broken.as_ref()?;
+        Some(io::Error::new(*kind, msg.clone()))
+    }
+
+    fn set_broken(&mut self, err: &io::Error) {
+        self.broken = Some((err.kind(), err.to_string()));
+        self.wake_all();
+    }
+
+    // Another future may be waiting on I/O that was registered with our
+    // waker, so let all of them poll again.
+    fn wake_all(&mut self) {
+        for slot in self.slots.values_mut() {
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn dispatch(&mut self, header: Header, body: Vec<u8>) {
+        // XXX reply with unknown tag is ignored
+        if let Some(slot) = self.slots.get_mut(&header.tag) {
+            if slot.abandoned {
+                self.slots.remove(&header.tag);
+            } else {
+                slot.reply = Some((header, body));
+                if let Some(waker) = slot.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+/// Async client that can have multiple requests in flight at once
+///
+/// Tags are allocated automatically. Requests may be sent concurrently from
+/// several tasks; replies are matched to requests by tag. Only the
+/// `futures-io` traits are required of the transport, so any executor can be
+/// used.
+pub struct AsyncClient<R, W> {
+    inner: Mutex<Inner<R, W>>,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> AsyncClient<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                read: ReadState {
+                    reader,
+                    header: [0; 7],
+                    body: Vec::new(),
+                    pos: 0,
+                },
+                write: WriteState {
+                    writer,
+                    queue: VecDeque::new(),
+                    pos: 0,
+                    needs_flush: false,
+                },
+                slots: HashMap::new(),
+                next_tag: 0,
+                broken: None,
+            }),
+        }
+    }
+
+    /// Send a request, returning a future for the reply
+    ///
+    /// The request is queued immediately, so requests are sent in the order
+    /// `send` is called. The reply body is stored in `buf`, which the
+    /// returned message borrows. `TVersion` is sent with `NOTAG`; it should
+    /// not be sent while other requests are in flight.
+    pub fn send<'a, 'b, Req: TMessage<'a>>(
+        &'b self,
+        request: Req,
+        buf: &'b mut Vec<u8>,
+    ) -> SendFuture<'b, R, W, Req::RMessage<'b>> {
+        SendFuture {
+            reply: Some(self.request(&request)),
+            buf: Some(buf),
+            _marker: PhantomData,
+        }
+    }
+
+    fn request<'a, Req: Message<'a>>(&self, request: &Req) -> Result<ReplyFuture<'_, R, W>, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(err) = inner.broken_error() {
+            return Err(err.into());
+        }
+        let tag = if Req::TYPE as u8 == MessageType::TVersion as u8 {
+            NOTAG
+        } else {
+            inner
+                .alloc_tag()
+                .ok_or_else(|| io::Error::other("no free tags"))?
+        };
+        let header = Header::for_message(request, tag);
+        let mut buf = Vec::with_capacity(header.size as usize);
+        header.write(&mut buf)?;
+        request.write(&mut buf)?;
+        inner.write.queue.push_back((tag, buf));
+        inner.slots.insert(tag, Slot::default());
+        Ok(ReplyFuture {
+            client: self,
+            tag,
+            done: false,
+        })
+    }
+}
+
+/// Future returned by [`AsyncClient::send`]
+pub struct SendFuture<'b, R, W, Reply> {
+    reply: Option<Result<ReplyFuture<'b, R, W>, Error>>,
+    buf: Option<&'b mut Vec<u8>>,
+    _marker: PhantomData<fn() -> Reply>,
+}
+
+impl<'b, R, W, Reply> Future for SendFuture<'b, R, W, Reply>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    Reply: Message<'b>,
+{
+    type Output = Result<Reply, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(Err(_)) = &self.reply {
+            let Some(Err(err)) = self.reply.take() else {
+                unreachable!()
+            };
+            return Poll::Ready(Err(err));
+        }
+        let Some(Ok(fut)) = &mut self.reply else {
+            panic!("`SendFuture` polled after completion");
+        };
+        let res = ready!(Pin::new(fut).poll(cx));
+        self.reply = None;
+        let (header, body) = res?;
+        let buf = self.buf.take().unwrap();
+        *buf = body;
+        Poll::Ready(parse_reply(&header, buf))
+    }
+}
+
+struct ReplyFuture<'c, R, W> {
+    client: &'c AsyncClient<R, W>,
+    tag: u16,
+    done: bool,
+}
+
+impl<'c, R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Future for ReplyFuture<'c, R, W> {
+    type Output = io::Result<(Header, Vec<u8>)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let tag = self.tag;
+        let mut inner = self.client.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        if let Poll::Ready(Err(err)) = inner.write.poll_send(cx) {
+            inner.set_broken(&err);
+        }
+
+        loop {
+            let slot = inner.slots.get_mut(&tag).unwrap();
+            if let Some(reply) = slot.reply.take() {
+                inner.slots.remove(&tag);
+                inner.wake_all();
+                self.done = true;
+                return Poll::Ready(Ok(reply));
+            }
+            if let Some(err) = inner.broken_error() {
+                inner.slots.remove(&tag);
+                self.done = true;
+                return Poll::Ready(Err(err));
+            }
+            match inner.read.poll_message(cx) {
+                Poll::Ready(Ok((header, body))) => inner.dispatch(header, body),
+                Poll::Ready(Err(err)) => inner.set_broken(&err),
+                Poll::Pending => {
+                    let slot = inner.slots.get_mut(&tag).unwrap();
+                    slot.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl<'c, R, W> Drop for ReplyFuture<'c, R, W> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let Ok(mut inner) = self.client.inner.lock() else {
+            return;
+        };
+        let replied = inner.slots.get(&self.tag).is_none_or(|s| s.reply.is_some());
+        if replied || inner.broken.is_some() || inner.write.unqueue(self.tag) {
+            inner.slots.remove(&self.tag);
+        } else if let Some(slot) = inner.slots.get_mut(&self.tag) {
+            slot.abandoned = true;
+            slot.waker = None;
+        }
+        inner.wake_all();
+    }
+}
@@ -0,0 +1,103 @@
+// Authentication for servers using `FidServer`. An `Authenticator` runs the
+// conversation on an afid and decides who each attach is made as; the
+// resulting `Identity` is then passed to every `FileHandler` call on fids
+// walked from that attach.
+
+use std::{convert::Infallible, future::Future};
+
+use crate::{fid::err, Error};
+
+/// The user a fid was attached as
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub uname: String,
+    /// Numeric user id, if the [`Authenticator`] knows one
+    ///
+    /// Otherwise it's the `n_uname` of a 9P2000.L `TLAttach`, if not
+    /// `NONUNAME`. 9P2000.u isn't negotiated, so `TAttach` never has one.
+    pub uid: Option<u32>,
+    /// Tree that was attached to
+    pub aname: String,
+}
+
+impl Identity {
+    pub fn new(uname: &str, aname: &str) -> Self {
+        Self {
+            uname: uname.to_string(),
+            uid: None,
+            aname: aname.to_string(),
+        }
+    }
+}
+
+/// Authentication protocol for a [`FidServer`](crate::FidServer)
+///
+/// A client starts a conversation with `TAuth`, reads and writes the afid to
+/// run the protocol, then passes the afid to `TAttach`.
+pub trait Authenticator: Send + Sync + 'static {
+    /// State of the conversation on one afid
+    type Conversation: Send + Sync + 'static;
+
+    /// Start a conversation, for `uname` to attach to `aname`
+    fn start(
+        &self,
+        uname: &str,
+        aname: &str,
+    ) -> impl Future<Output = Result<Self::Conversation, Error>> + Send;
+
+    fn read(
+        &self,
+        conv: &Self::Conversation,
+        offset: u64,
+        count: u32,
+    ) -> impl Future<Output = Result<Vec<u8>, Error>> + Send;
+
+    fn write(
+        &self,
+        conv: &Self::Conversation,
+        offset: u64,
+        data: &[u8],
+    ) -> impl Future<Output = Result<u32, Error>> + Send;
+
+    /// Decide who to attach `uname` to `aname` as, given the conversation on
+    /// the afid, or `None` if the client didn't authenticate
+    fn attach(
+        &self,
+        conv: Option<&Self::Conversation>,
+        uname: &str,
+        aname: &str,
+    ) -> Result<Identity, Error>;
+}
+
+/// [`Authenticator`] that doesn't require authentication, and lets clients
+/// attach as whoever they claim to be
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoAuth;
+
+impl Authenticator for NoAuth {
+    type Conversation = Infallible;
+
+    async fn start(&self, _uname: &str, _aname: &str) -> Result<Infallible, Error> {
+        err("authentication not required")
+    }
+
+    async fn read(&self, conv: &Infallible, _offset: u64, _count: u32) -> Result<Vec<u8>, Error> {
+        match *conv {}
+    }
+
+    async fn write(&self, conv: &Infallible, _offset: u64, _data: &[u8]) -> Result<u32, Error> {
+        match *conv {}
+    }
+
+    fn attach(
+        &self,
+        conv: Option<&Infallible>,
+        uname: &str,
+        aname: &str,
+    ) -> Result<Identity, Error> {
+        match conv {
+            Some(conv) => match *conv {},
+            None => Ok(Identity::new(uname, aname)),
+        }
+    }
+}
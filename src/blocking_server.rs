@@ -0,0 +1,324 @@
+// Server using only std: a thread per connection reads requests, and a
+// thread per connection writes replies. Handler futures are run with a
+// minimal executor that parks the thread, either inline on the connection
+// thread or on a shared pool of worker threads.
+
+#[cfg(unix)]
+use std::{
+    fs::File,
+    os::fd::OwnedFd,
+    os::unix::net::{UnixListener, UnixStream},
+};
+use std::{
+    future::Future,
+    io::{self, BufWriter, Read, Write},
+    net::TcpListener,
+    pin::pin,
+    sync::{mpsc, Arc, Mutex},
+    task::{Context, Poll, Wake, Waker},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    server::{self, Connection, Limits, Session, Sink, Slots},
+    Filesystem, Header, Metrics,
+};
+
+impl Sink for mpsc::Sender<Vec<u8>> {
+    fn send(&self, message: Vec<u8>) {
+        // Fails only if the connection is closed, in which case the reply
+        // can be discarded.
+        let _ = mpsc::Sender::send(self, message);
+    }
+}
+
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run a future to completion on the current thread
+pub(crate) fn block_on<T>(fut: impl Future<Output = T>) -> T {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = pin!(fut);
+    loop {
+        if let Poll::Ready(value) = fut.as_mut().poll(&mut cx) {
+            return value;
+        }
+        thread::park();
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct Pool {
+    sender: mpsc::Sender<Job>,
+}
+
+impl Pool {
+    fn new(workers: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..workers {
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                let job = receiver.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(),
+                    // Pool has been dropped
+                    Err(_) => break,
+                }
+            });
+        }
+        Self { sender }
+    }
+
+    fn execute(&self, job: Job) {
+        self.sender.send(job).unwrap();
+    }
+}
+
+/// 9P server using blocking I/O and threads, without an async runtime
+///
+/// By default, each connection handles one request at a time, on the thread
+/// reading from it. With [`BlockingServer::workers`], requests are instead
+/// run concurrently on a pool of threads shared by all connections. Without
+/// workers, a request that blocks can't be flushed, since nothing else is
+/// read from the connection until it finishes.
+///
+/// Clients sending more requests than the limits allow are throttled by
+/// not reading from them until some of their requests finish.
+#[derive(Clone)]
+pub struct BlockingServer {
+    limits: Limits,
+    pool: Option<Arc<Pool>>,
+}
+
+impl Default for BlockingServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockingServer {
+    pub fn new() -> Self {
+        Self {
+            limits: Limits::default(),
+            pool: None,
+        }
+    }
+
+    /// Set the largest `msize` to accept in `TVersion`
+    pub fn msize(mut self, msize: u32) -> Self {
+        self.limits.msize = msize;
+        self
+    }
+
+    /// Limit the number of outstanding requests on each connection
+    pub fn max_requests(mut self, max: usize) -> Self {
+        self.limits.requests = max;
+        self
+    }
+
+    /// Limit the number of outstanding requests on all connections together
+    pub fn max_total_requests(mut self, max: usize) -> Self {
+        self.limits.total_requests = Some(Arc::new(Slots::new(max)));
+        self
+    }
+
+    /// Close connections accepted by [`BlockingServer::serve`] that have no
+    /// outstanding requests, and send nothing for `timeout`
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Count the requests of every connection in `metrics`
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.limits.metrics = Some(metrics);
+        self
+    }
+
+    /// Handle requests on a pool of `workers` threads
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.pool = (workers > 0).then(|| Arc::new(Pool::new(workers)));
+        self
+    }
+
+    /// Accept connections on `listener`, serving each on its own thread and
+    /// calling `new_fs` to create a handler for it
+    pub fn serve<F, N>(&self, listener: TcpListener, new_fs: N) -> io::Result<()>
+    where
+        F: Filesystem,
+        N: Fn() -> F + Send + Sync + 'static,
+    {
+        let new_fs = Arc::new(new_fs);
+        for stream in listener.incoming() {
+            let stream = stream?;
+            stream.set_read_timeout(self.limits.idle_timeout)?;
+            let reader = stream.try_clone()?;
+            let server = self.clone();
+            let new_fs = new_fs.clone();
+            thread::spawn(move || {
+                // XXX report errors?
+                let _ = server.serve_connection(reader, stream, move || new_fs());
+            });
+        }
+        Ok(())
+    }
+
+    /// Accept connections on a Unix domain socket, like
+    /// [`BlockingServer::serve`]
+    #[cfg(unix)]
+    pub fn serve_unix<F, N>(&self, listener: UnixListener, new_fs: N) -> io::Result<()>
+    where
+        F: Filesystem,
+        N: Fn() -> F + Send + Sync + 'static,
+    {
+        let new_fs = Arc::new(new_fs);
+        for stream in listener.incoming() {
+            let stream = stream?;
+            stream.set_read_timeout(self.limits.idle_timeout)?;
+            let reader = stream.try_clone()?;
+            let server = self.clone();
+            let new_fs = new_fs.clone();
+            thread::spawn(move || {
+                // XXX report errors?
+                let _ = server.serve_connection(reader, stream, move || new_fs());
+            });
+        }
+        Ok(())
+    }
+
+    /// Serve a single connection over stdin and stdout, such as for a server
+    /// started by its client
+    pub fn serve_stdio<F: Filesystem>(&self, new_fs: impl Fn() -> F) -> io::Result<()> {
+        self.serve_connection(io::stdin(), io::stdout(), new_fs)
+    }
+
+    /// Serve a single connection over inherited file descriptors, reading
+    /// requests from `rfd` and writing replies to `wfd`
+    ///
+    /// This is the other end of Linux's `trans=fd,rfdno=,wfdno=` mount
+    /// option. For a socket, `wfd` can be a duplicate of `rfd`.
+    #[cfg(unix)]
+    pub fn serve_fds<F: Filesystem>(
+        &self,
+        rfd: OwnedFd,
+        wfd: OwnedFd,
+        new_fs: impl Fn() -> F,
+    ) -> io::Result<()> {
+        self.serve_connection(File::from(rfd), File::from(wfd), new_fs)
+    }
+
+    /// Serve a connection over one end of a new socket pair, on another
+    /// thread, and return the other end for the client
+    ///
+    /// The socket can be handed to a subprocess. Like all sockets created by
+    /// std, it is close-on-exec.
+    #[cfg(unix)]
+    pub fn serve_socketpair<F, N>(&self, new_fs: N) -> io::Result<UnixStream>
+    where
+        F: Filesystem,
+        N: Fn() -> F + Send + 'static,
+    {
+        let (client, stream) = UnixStream::pair()?;
+        let reader = stream.try_clone()?;
+        let server = self.clone();
+        thread::spawn(move || {
+            // XXX report errors?
+            let _ = server.serve_connection(reader, stream, new_fs);
+        });
+        Ok(client)
+    }
+
+    /// Serve a single connection on the current thread, until the client
+    /// disconnects
+    ///
+    /// `reader` and `writer` can be the two halves of any stream, such as a
+    /// socket or stdin and stdout.
+    pub fn serve_connection<F, R, W>(
+        &self,
+        mut reader: R,
+        writer: W,
+        new_fs: impl Fn() -> F,
+    ) -> io::Result<()>
+    where
+        F: Filesystem,
+        R: Read,
+        W: Write + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || write_replies(BufWriter::new(writer), receiver));
+        let conn = Connection::new(sender, &self.limits);
+        let mut session = Session::new(conn, self.limits.msize);
+
+        loop {
+            block_on(session.reserve());
+            let mut header = [0; 7];
+            if !read_full(&mut reader, &mut header, || session.idle())? {
+                return Ok(());
+            }
+            let header = Header::from_array(header);
+            let mut body = vec![0; session.body_len(&header)?];
+            if !read_full(&mut reader, &mut body, || false)? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            if let Some((fs, replier)) = session.request(&header, &body, &new_fs) {
+                let job = move || {
+                    block_on(server::dispatch(&*fs, &header, &body, replier));
+                };
+                match &self.pool {
+                    Some(pool) => pool.execute(Box::new(job)),
+                    None => job(),
+                }
+            }
+        }
+    }
+}
+
+/// Like `read_exact`, but retrying reads that time out
+///
+/// Returns `false` at end of file, or if a read times out while `idle()`,
+/// before anything was read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8], idle: impl Fn() -> bool) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => filled += len,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                if filled == 0 && idle() {
+                    return Ok(false);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+fn write_replies<W: Write>(mut writer: W, receiver: mpsc::Receiver<Vec<u8>>) -> io::Result<()> {
+    while let Ok(message) = receiver.recv() {
+        writer.write_all(&message)?;
+        // Write everything that's ready before flushing
+        while let Ok(message) = receiver.try_recv() {
+            writer.write_all(&message)?;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,84 @@
+// Directory reads for servers. Each `RRead` of a directory must hold only
+// whole stat entries, and a read must either start at offset 0 or where the
+// previous read of the fid ended.
+
+use crate::{fid::err, Error, Stat, StatBuf};
+
+/// Packs directory entries into reads, tracking where the next read of a
+/// fid must continue from
+///
+/// Kept with the state of an open directory.
+#[derive(Debug, Default)]
+pub struct DirReader {
+    // Offset the next read must be at, unless it starts over at 0
+    offset: u64,
+    // Number of entries already returned
+    index: usize,
+}
+
+impl DirReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pack as many entries as fit in `count` bytes, for a read at `offset`
+    ///
+    /// `entries` lists the directory from the start; the entries returned
+    /// by previous reads are skipped. A read at offset 0 starts over from
+    /// the first entry, and any offset other than the end of the previous
+    /// read is an error. An empty result means the end of the directory.
+    pub fn read<'a>(
+        &mut self,
+        entries: impl IntoIterator<Item = Stat<'a>>,
+        offset: u64,
+        count: u32,
+    ) -> Result<Vec<u8>, Error> {
+        if offset == 0 {
+            self.offset = 0;
+            self.index = 0;
+        } else if offset != self.offset {
+            return err("bad offset in directory read");
+        }
+
+        let mut buf = Vec::new();
+        for entry in entries.into_iter().skip(self.index) {
+            if buf.len() + entry.size() > count as usize {
+                if buf.is_empty() {
+                    return err("directory read count too small");
+                }
+                break;
+            }
+            entry.write(&mut buf)?;
+            self.index += 1;
+        }
+        self.offset += buf.len() as u64;
+        Ok(buf)
+    }
+}
+
+/// Snapshot of a directory's entries, taken when reading from offset 0, so
+/// a sequence of reads sees a consistent listing
+#[derive(Debug, Default)]
+pub(crate) struct DirListing {
+    reader: DirReader,
+    entries: Vec<StatBuf>,
+}
+
+impl DirListing {
+    /// Read, calling `list` for the entries when starting from offset 0
+    pub(crate) fn read<E>(
+        &mut self,
+        offset: u64,
+        count: u32,
+        list: impl FnOnce() -> Result<Vec<StatBuf>, E>,
+    ) -> Result<Vec<u8>, Error>
+    where
+        Error: From<E>,
+    {
+        if offset == 0 {
+            self.entries = list()?;
+        }
+        let entries = self.entries.iter().map(StatBuf::as_stat);
+        self.reader.read(entries, offset, count)
+    }
+}
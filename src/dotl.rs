@@ -0,0 +1,616 @@
+// Messages of 9P2000.L, the dialect spoken by the Linux kernel's v9fs client,
+// diod and QEMU. It keeps the messages of 9P2000 that deal with fids (walk,
+// read, write, clunk), and replaces the others with ones modeled on Linux
+// system calls. Errors are sent as Linux errno values in `RLError`, and
+// numeric ids replace user names.
+//
+// http://github.com/chaos/diod/blob/master/protocol.md
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use crate::{end_of_message, Error, Fid, Field, Message, MessageType, Qid, Writer};
+
+/// `n_uname` of an attach that doesn't give a numeric user id
+pub const NONUNAME: u32 = !0;
+
+// Flags of `TLOpen` and `TLCreate`. These are Linux's `O_` flags on x86,
+// which servers on other systems must translate.
+pub const DOTL_RDONLY: u32 = 0o0;
+pub const DOTL_WRONLY: u32 = 0o1;
+pub const DOTL_RDWR: u32 = 0o2;
+pub const DOTL_NOACCESS: u32 = 0o3;
+pub const DOTL_CREATE: u32 = 0o100;
+pub const DOTL_EXCL: u32 = 0o200;
+pub const DOTL_NOCTTY: u32 = 0o400;
+pub const DOTL_TRUNC: u32 = 0o1000;
+pub const DOTL_APPEND: u32 = 0o2000;
+pub const DOTL_NONBLOCK: u32 = 0o4000;
+pub const DOTL_DSYNC: u32 = 0o10000;
+pub const DOTL_FASYNC: u32 = 0o20000;
+pub const DOTL_DIRECT: u32 = 0o40000;
+pub const DOTL_LARGEFILE: u32 = 0o100000;
+pub const DOTL_DIRECTORY: u32 = 0o200000;
+pub const DOTL_NOFOLLOW: u32 = 0o400000;
+pub const DOTL_NOATIME: u32 = 0o1000000;
+pub const DOTL_CLOEXEC: u32 = 0o2000000;
+pub const DOTL_SYNC: u32 = 0o4000000;
+
+/// Flag of `TUnlinkAt` to remove a directory
+pub const DOTL_AT_REMOVEDIR: u32 = 0x200;
+
+// Bits of `TGetAttr::request_mask` and `RGetAttr::valid`
+pub const GETATTR_MODE: u64 = 0x1;
+pub const GETATTR_NLINK: u64 = 0x2;
+pub const GETATTR_UID: u64 = 0x4;
+pub const GETATTR_GID: u64 = 0x8;
+pub const GETATTR_RDEV: u64 = 0x10;
+pub const GETATTR_ATIME: u64 = 0x20;
+pub const GETATTR_MTIME: u64 = 0x40;
+pub const GETATTR_CTIME: u64 = 0x80;
+pub const GETATTR_INO: u64 = 0x100;
+pub const GETATTR_SIZE: u64 = 0x200;
+pub const GETATTR_BLOCKS: u64 = 0x400;
+pub const GETATTR_BTIME: u64 = 0x800;
+pub const GETATTR_GEN: u64 = 0x1000;
+pub const GETATTR_DATA_VERSION: u64 = 0x2000;
+/// Everything `stat()` returns
+pub const GETATTR_BASIC: u64 = 0x7ff;
+pub const GETATTR_ALL: u64 = 0x3fff;
+
+// Bits of `TSetAttr::valid`
+pub const SETATTR_MODE: u32 = 0x1;
+pub const SETATTR_UID: u32 = 0x2;
+pub const SETATTR_GID: u32 = 0x4;
+pub const SETATTR_SIZE: u32 = 0x8;
+pub const SETATTR_ATIME: u32 = 0x10;
+pub const SETATTR_MTIME: u32 = 0x20;
+pub const SETATTR_CTIME: u32 = 0x40;
+/// Set the atime to `atime_sec` and `atime_nsec`, rather than the current
+/// time
+pub const SETATTR_ATIME_SET: u32 = 0x80;
+pub const SETATTR_MTIME_SET: u32 = 0x100;
+
+// Lock types of `TLock` and `TGetLock`
+pub const LOCK_TYPE_RDLCK: u8 = 0;
+pub const LOCK_TYPE_WRLCK: u8 = 1;
+pub const LOCK_TYPE_UNLCK: u8 = 2;
+
+// Bits of `TLock::flags`
+pub const LOCK_FLAGS_BLOCK: u32 = 1;
+pub const LOCK_FLAGS_RECLAIM: u32 = 2;
+
+// `RLock::status`
+pub const LOCK_SUCCESS: u8 = 0;
+pub const LOCK_BLOCKED: u8 = 1;
+pub const LOCK_ERROR: u8 = 2;
+pub const LOCK_GRACE: u8 = 3;
+
+// Flags of `TXattrCreate`, as for `setxattr()`
+pub const XATTR_CREATE: u32 = 1;
+pub const XATTR_REPLACE: u32 = 2;
+
+/// Serialization of a field, the counterpart of `Field::parse`
+trait Encode {
+    fn size(&self) -> usize;
+    fn encode<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err>;
+}
+
+macro_rules! impl_encode_le_bytes {
+    ($type:ty) => {
+        impl Encode for $type {
+            fn size(&self) -> usize {
+                core::mem::size_of::<$type>()
+            }
+
+            fn encode<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+                writer.write(&self.to_le_bytes())
+            }
+        }
+    };
+}
+impl_encode_le_bytes!(u8);
+impl_encode_le_bytes!(u16);
+impl_encode_le_bytes!(u32);
+impl_encode_le_bytes!(u64);
+
+impl Encode for Fid {
+    fn size(&self) -> usize {
+        4
+    }
+
+    fn encode<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        writer.write(&self.0.to_le_bytes())
+    }
+}
+
+impl Encode for Qid {
+    fn size(&self) -> usize {
+        13
+    }
+
+    fn encode<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        self.write(writer)
+    }
+}
+
+impl Encode for &str {
+    fn size(&self) -> usize {
+        2 + self.len()
+    }
+
+    fn encode<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        writer.write(&(self.len() as u16).to_le_bytes())?;
+        writer.write(self.as_bytes())
+    }
+}
+
+impl Encode for &[u8] {
+    fn size(&self) -> usize {
+        4 + self.len()
+    }
+
+    fn encode<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        writer.write(&(self.len() as u32).to_le_bytes())?;
+        writer.write(self)
+    }
+}
+
+// Define a message consisting of the given fields, in order
+macro_rules! message {
+    ($(#[$meta:meta])* $name:ident = $type:ident;) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, Default)]
+        pub struct $name;
+
+        impl<'a> Message<'a> for $name {
+            const TYPE: MessageType = MessageType::$type;
+
+            fn parse(body: &'a [u8]) -> Result<Self, Error> {
+                end_of_message(body, $name)
+            }
+
+            fn size(&self) -> usize {
+                0
+            }
+
+            fn write<T: Writer>(&self, _writer: &mut T) -> Result<(), T::Err> {
+                Ok(())
+            }
+        }
+    };
+    (
+        $(#[$meta:meta])* $name:ident $(<$lt:lifetime>)? = $type:ident {
+            $($(#[$field_meta:meta])* $field:ident: $ty:ty,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, Default)]
+        pub struct $name $(<$lt>)? {
+            $($(#[$field_meta])* pub $field: $ty,)*
+        }
+
+        impl<'a> Message<'a> for $name $(<$lt>)? {
+            const TYPE: MessageType = MessageType::$type;
+
+            fn parse(body: &'a [u8]) -> Result<Self, Error> {
+                $(let (body, $field) = <$ty as Field<'a>>::parse(body)?;)*
+                end_of_message(body, Self { $($field),* })
+            }
+
+            fn size(&self) -> usize {
+                [$(Encode::size(&self.$field)),*].into_iter().sum()
+            }
+
+            fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+                $(Encode::encode(&self.$field, writer)?;)*
+                Ok(())
+            }
+        }
+    };
+}
+
+message! {
+    /// Error reply of 9P2000.L, in place of `RError`
+    RLError = RLError {
+        /// Linux errno
+        ecode: u32,
+    }
+}
+
+message! {
+    /// `TAuth` with a numeric user id
+    TLAuth<'a> = TAuth {
+        afid: Fid,
+        uname: &'a str,
+        aname: &'a str,
+        n_uname: u32,
+    }
+}
+
+message! {
+    /// `TAttach` with a numeric user id
+    TLAttach<'a> = TAttach {
+        fid: Fid,
+        afid: Fid,
+        uname: &'a str,
+        aname: &'a str,
+        /// Takes precedence over `uname` unless it's [`NONUNAME`]
+        n_uname: u32,
+    }
+}
+
+message! {
+    TStatFs = TStatFs {
+        fid: Fid,
+    }
+}
+
+message! {
+    /// The fields of `statfs()`
+    RStatFs = RStatFs {
+        type_: u32,
+        bsize: u32,
+        blocks: u64,
+        bfree: u64,
+        bavail: u64,
+        files: u64,
+        ffree: u64,
+        fsid: u64,
+        namelen: u32,
+    }
+}
+
+message! {
+    TLOpen = TLOpen {
+        fid: Fid,
+        /// `DOTL_` flags
+        flags: u32,
+    }
+}
+
+message! {
+    RLOpen = RLOpen {
+        qid: Qid,
+        iounit: u32,
+    }
+}
+
+message! {
+    /// Create and open a regular file, after which `fid` refers to it
+    TLCreate<'a> = TLCreate {
+        fid: Fid,
+        name: &'a str,
+        flags: u32,
+        mode: u32,
+        gid: u32,
+    }
+}
+
+message! {
+    RLCreate = RLCreate {
+        qid: Qid,
+        iounit: u32,
+    }
+}
+
+message! {
+    TSymlink<'a> = TSymlink {
+        dfid: Fid,
+        name: &'a str,
+        symtgt: &'a str,
+        gid: u32,
+    }
+}
+
+message! {
+    RSymlink = RSymlink {
+        qid: Qid,
+    }
+}
+
+message! {
+    TMkNod<'a> = TMkNod {
+        dfid: Fid,
+        name: &'a str,
+        /// Includes the file type, such as `S_IFIFO`
+        mode: u32,
+        major: u32,
+        minor: u32,
+        gid: u32,
+    }
+}
+
+message! {
+    RMkNod = RMkNod {
+        qid: Qid,
+    }
+}
+
+message! {
+    /// Move the file `fid` refers to into `dfid`, as `name`
+    TRename<'a> = TRename {
+        fid: Fid,
+        dfid: Fid,
+        name: &'a str,
+    }
+}
+
+message! {
+    RRename = RRename;
+}
+
+message! {
+    TReadLink = TReadLink {
+        fid: Fid,
+    }
+}
+
+message! {
+    RReadLink<'a> = RReadLink {
+        target: &'a str,
+    }
+}
+
+message! {
+    TGetAttr = TGetAttr {
+        fid: Fid,
+        /// `GETATTR_` bits of the fields wanted
+        request_mask: u64,
+    }
+}
+
+message! {
+    /// The fields of `stat()`, and a few more
+    RGetAttr = RGetAttr {
+        /// `GETATTR_` bits of the fields that are set
+        valid: u64,
+        qid: Qid,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        nlink: u64,
+        rdev: u64,
+        size: u64,
+        blksize: u64,
+        blocks: u64,
+        atime_sec: u64,
+        atime_nsec: u64,
+        mtime_sec: u64,
+        mtime_nsec: u64,
+        ctime_sec: u64,
+        ctime_nsec: u64,
+        btime_sec: u64,
+        btime_nsec: u64,
+        gen: u64,
+        data_version: u64,
+    }
+}
+
+message! {
+    TSetAttr = TSetAttr {
+        fid: Fid,
+        /// `SETATTR_` bits of the fields to change
+        valid: u32,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        size: u64,
+        atime_sec: u64,
+        atime_nsec: u64,
+        mtime_sec: u64,
+        mtime_nsec: u64,
+    }
+}
+
+message! {
+    RSetAttr = RSetAttr;
+}
+
+message! {
+    /// Make `newfid` an xattr fid for reading attribute `name` of `fid`, or
+    /// the list of attribute names if it's empty
+    TXattrWalk<'a> = TXattrWalk {
+        fid: Fid,
+        newfid: Fid,
+        name: &'a str,
+    }
+}
+
+message! {
+    RXattrWalk = RXattrWalk {
+        size: u64,
+    }
+}
+
+message! {
+    /// Make `fid` an xattr fid for writing attribute `name`, which is set
+    /// when it's clunked
+    TXattrCreate<'a> = TXattrCreate {
+        fid: Fid,
+        name: &'a str,
+        attr_size: u64,
+        /// `XATTR_` flags
+        flags: u32,
+    }
+}
+
+message! {
+    RXattrCreate = RXattrCreate;
+}
+
+message! {
+    /// Read the entries of an open directory, starting after the one whose
+    /// `offset` is given, or at the start if it's 0
+    TReadDir = TReadDir {
+        fid: Fid,
+        offset: u64,
+        count: u32,
+    }
+}
+
+message! {
+    /// A sequence of [`DirEntry`]
+    RReadDir<'a> = RReadDir {
+        data: &'a [u8],
+    }
+}
+
+message! {
+    TFSync = TFSync {
+        fid: Fid,
+        /// Only sync data, as for `fdatasync()`
+        datasync: u32,
+    }
+}
+
+message! {
+    RFSync = RFSync;
+}
+
+message! {
+    TLock<'a> = TLock {
+        fid: Fid,
+        type_: u8,
+        flags: u32,
+        start: u64,
+        length: u64,
+        proc_id: u32,
+        client_id: &'a str,
+    }
+}
+
+message! {
+    RLock = RLock {
+        status: u8,
+    }
+}
+
+message! {
+    TGetLock<'a> = TGetLock {
+        fid: Fid,
+        type_: u8,
+        start: u64,
+        length: u64,
+        proc_id: u32,
+        client_id: &'a str,
+    }
+}
+
+message! {
+    /// The lock that would conflict with the one asked about, or the same
+    /// lock with type `LOCK_TYPE_UNLCK` if there's none
+    RGetLock<'a> = RGetLock {
+        type_: u8,
+        start: u64,
+        length: u64,
+        proc_id: u32,
+        client_id: &'a str,
+    }
+}
+
+message! {
+    /// Create a hard link `name` in `dfid` to the file `fid` refers to
+    TLink<'a> = TLink {
+        dfid: Fid,
+        fid: Fid,
+        name: &'a str,
+    }
+}
+
+message! {
+    RLink = RLink;
+}
+
+message! {
+    TMkDir<'a> = TMkDir {
+        dfid: Fid,
+        name: &'a str,
+        mode: u32,
+        gid: u32,
+    }
+}
+
+message! {
+    RMkDir = RMkDir {
+        qid: Qid,
+    }
+}
+
+message! {
+    TRenameAt<'a> = TRenameAt {
+        olddirfid: Fid,
+        oldname: &'a str,
+        newdirfid: Fid,
+        newname: &'a str,
+    }
+}
+
+message! {
+    RRenameAt = RRenameAt;
+}
+
+message! {
+    TUnlinkAt<'a> = TUnlinkAt {
+        dirfid: Fid,
+        name: &'a str,
+        /// `DOTL_AT_REMOVEDIR` or 0
+        flags: u32,
+    }
+}
+
+message! {
+    RUnlinkAt = RUnlinkAt;
+}
+
+/// Entry of the data of `RReadDir`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DirEntry<'a> {
+    pub qid: Qid,
+    /// Passed to `TReadDir` to continue after this entry
+    pub offset: u64,
+    /// File type, as in `d_type` of `readdir()`
+    pub type_: u8,
+    pub name: &'a str,
+}
+
+impl<'a> Field<'a> for DirEntry<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<(&'a [u8], Self), Error> {
+        let (bytes, qid) = Qid::parse(bytes)?;
+        let (bytes, offset) = u64::parse(bytes)?;
+        let (bytes, type_) = u8::parse(bytes)?;
+        let (bytes, name) = <&str>::parse(bytes)?;
+        let entry = DirEntry {
+            qid,
+            offset,
+            type_,
+            name,
+        };
+        Ok((bytes, entry))
+    }
+}
+
+impl DirEntry<'_> {
+    /// Byte length of serialized entry
+    pub fn size(&self) -> usize {
+        13 + 8 + 1 + 2 + self.name.len()
+    }
+
+    pub fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        self.qid.write(writer)?;
+        writer.write(&self.offset.to_le_bytes())?;
+        writer.write(&[self.type_])?;
+        Encode::encode(&self.name, writer)
+    }
+}
+
+/// Parse the data of an `RReadDir`
+pub fn parse_dir_entries(mut bytes: &[u8]) -> Result<Vec<DirEntry<'_>>, Error> {
+    let mut entries = Vec::new();
+    while !bytes.is_empty() {
+        let entry;
+        (bytes, entry) = DirEntry::parse(bytes)?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
@@ -29,6 +29,16 @@ impl From<std::io::Error> for Error {
     }
 }
 
+#[cfg(feature = "std")]
+impl From<Error> for std::io::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Io(error) => error,
+            error => Self::other(error),
+        }
+    }
+}
+
 impl From<str::Utf8Error> for Error {
     fn from(error: str::Utf8Error) -> Self {
         Self::Utf8(error)
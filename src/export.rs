@@ -0,0 +1,1261 @@
+// Server backend exporting a directory of the local filesystem, like u9fs or
+// diod. Qids are derived from inode numbers and modification times.
+//
+// Clients must not be able to reach anything outside the export. Rather than
+// resolving paths, each directory a fid reaches is held open, and everything
+// under it is opened relative to that handle, openat-style, one component at
+// a time and without following symlinks. A `..` walk goes back to the parent
+// handle held from the walk down, so it can't leave the root, and renaming
+// or replacing directories on the host while a walk is in progress can't
+// redirect it outside the tree.
+//
+// Besides 9P2000, the export speaks 9P2000.L, so it can serve the Linux
+// kernel's v9fs client, with the attributes, links, device nodes and
+// extended attributes of the host files passed through.
+
+// Field types of `libc::stat` differ between platforms
+#![allow(clippy::unnecessary_cast)]
+
+use std::{
+    ffi::{CStr, CString},
+    fs::File,
+    io,
+    mem::MaybeUninit,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::fs::FileExt,
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    dir_reader::DirListing, fid::err, DirEntry, Error, FileHandler, Identity, Qid, RCreate,
+    RGetAttr, RLCreate, RLOpen, ROpen, RStatFs, Stat, StatBuf, TLCreate, TMkNod, TSetAttr, DMDIR,
+    DOTL_APPEND, DOTL_AT_REMOVEDIR, DOTL_DSYNC, DOTL_EXCL, DOTL_NONBLOCK, DOTL_RDWR, DOTL_SYNC,
+    DOTL_TRUNC, DOTL_WRONLY, GETATTR_BASIC, OEXEC, ORCLOSE, OREAD, OTRUNC, OWRITE, QTDIR, QTFILE,
+    QTSYMLINK, SETATTR_ATIME, SETATTR_ATIME_SET, SETATTR_GID, SETATTR_MODE, SETATTR_MTIME,
+    SETATTR_MTIME_SET, SETATTR_SIZE, SETATTR_UID,
+};
+
+// Flags directories are held open with. `O_PATH` allows walking through
+// directories that can be searched but not read.
+#[cfg(target_os = "linux")]
+const DIR_FLAGS: libc::c_int = libc::O_PATH | libc::O_DIRECTORY;
+#[cfg(not(target_os = "linux"))]
+const DIR_FLAGS: libc::c_int = libc::O_RDONLY | libc::O_DIRECTORY;
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Open `name` in `dir`, failing if it is a symlink
+fn openat(dir: &impl AsRawFd, name: &CStr, flags: libc::c_int, mode: u32) -> io::Result<OwnedFd> {
+    let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd =
+        cvt(unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags, mode as libc::c_uint) })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn fstat(fd: &impl AsRawFd) -> io::Result<libc::stat> {
+    let mut st = MaybeUninit::uninit();
+    cvt(unsafe { libc::fstat(fd.as_raw_fd(), st.as_mut_ptr()) })?;
+    Ok(unsafe { st.assume_init() })
+}
+
+/// Stat `name` in `dir`, without following symlinks
+fn fstatat(dir: &OwnedFd, name: &CStr) -> io::Result<libc::stat> {
+    let mut st = MaybeUninit::uninit();
+    cvt(unsafe {
+        libc::fstatat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            st.as_mut_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })?;
+    Ok(unsafe { st.assume_init() })
+}
+
+fn file_type(st: &libc::stat) -> u32 {
+    st.st_mode as u32 & libc::S_IFMT as u32
+}
+
+fn is_dir(st: &libc::stat) -> bool {
+    file_type(st) == libc::S_IFDIR as u32
+}
+
+fn is_link(st: &libc::stat) -> bool {
+    file_type(st) == libc::S_IFLNK as u32
+}
+
+/// Stat of the filesystem containing `fd`
+fn fstatvfs(fd: &impl AsRawFd) -> io::Result<libc::statvfs> {
+    let mut st = MaybeUninit::uninit();
+    cvt(unsafe { libc::fstatvfs(fd.as_raw_fd(), st.as_mut_ptr()) })?;
+    Ok(unsafe { st.assume_init() })
+}
+
+/// Translate `DOTL_` open flags to the host's
+fn dotl_open_flags(flags: u32) -> libc::c_int {
+    let mut host = match flags & 3 {
+        DOTL_WRONLY => libc::O_WRONLY,
+        DOTL_RDWR => libc::O_RDWR,
+        _ => libc::O_RDONLY,
+    };
+    for (dotl, flag) in [
+        (DOTL_EXCL, libc::O_EXCL),
+        (DOTL_TRUNC, libc::O_TRUNC),
+        (DOTL_APPEND, libc::O_APPEND),
+        (DOTL_NONBLOCK, libc::O_NONBLOCK),
+        (DOTL_DSYNC, libc::O_DSYNC),
+        (DOTL_SYNC, libc::O_SYNC),
+    ] {
+        if flags & dotl != 0 {
+            host |= flag;
+        }
+    }
+    host
+}
+
+/// `utimensat` time for a time of `TSetAttr`
+fn set_time(valid: u32, change: u32, set: u32, sec: u64, nsec: u64) -> libc::timespec {
+    if valid & change == 0 {
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        }
+    } else if valid & set == 0 {
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_NOW,
+        }
+    } else {
+        libc::timespec {
+            tv_sec: sec as libc::time_t,
+            tv_nsec: nsec as _,
+        }
+    }
+}
+
+fn cname(name: &str) -> Result<CString, Error> {
+    CString::new(name).or_else(|_| err("file name syntax"))
+}
+
+fn read_only() -> Result<(), Error> {
+    err("read-only file system")
+}
+
+/// [`FileHandler`] serving the files under a local directory
+///
+/// Symlinks in the tree are listed, and can be removed or renamed, but are
+/// never followed.
+///
+/// Files are accessed as the user running the server, whoever clients attach
+/// as, and the group ids sent by 9P2000.L clients for new files are ignored.
+/// Files are referred to by name in their directory, so other fids for a
+/// file renamed by `TRenameAt` lose track of it, as do fids of a file
+/// renamed or replaced on the host.
+///
+/// As with the `nodev` and `nosuid` mount options, device nodes can't be
+/// created or opened, and set-user-ID and set-group-ID bits are dropped from
+/// modes set by clients, unless allowed by [`devices`](Self::devices) and
+/// [`setuid`](Self::setuid).
+pub struct Export {
+    path: PathBuf,
+    root: ExportFile,
+    read_only: bool,
+    no_exec: bool,
+    devices: bool,
+    setuid: bool,
+}
+
+impl Export {
+    /// Export the directory `root`
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        let path = root.as_ref().canonicalize()?;
+        let fd = File::open(&path)?;
+        if !fd.metadata()?.is_dir() {
+            return Err(io::ErrorKind::NotADirectory.into());
+        }
+        let fd = openat(&fd, c".", DIR_FLAGS, 0)?;
+        let root = ExportFile(Arc::new(Node {
+            link: Mutex::new(Link {
+                parent: None,
+                name: CString::from(c"/"),
+            }),
+            dir: Some(fd),
+        }));
+        Ok(Self {
+            path,
+            root,
+            read_only: false,
+            no_exec: false,
+            devices: false,
+            setuid: false,
+        })
+    }
+
+    /// Refuse any request that would modify the tree
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Refuse to open files for execution, and hide execute permission on
+    /// files
+    pub fn no_exec(mut self, no_exec: bool) -> Self {
+        self.no_exec = no_exec;
+        self
+    }
+
+    /// Allow creating and opening block and character devices
+    pub fn devices(mut self, devices: bool) -> Self {
+        self.devices = devices;
+        self
+    }
+
+    /// Allow clients to set the set-user-ID and set-group-ID bits
+    pub fn setuid(mut self, setuid: bool) -> Self {
+        self.setuid = setuid;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.path
+    }
+
+    fn qid(&self, st: &libc::stat) -> Qid {
+        let type_ = if is_dir(st) {
+            QTDIR
+        } else if is_link(st) {
+            QTSYMLINK
+        } else {
+            QTFILE
+        };
+        Qid {
+            type_,
+            // Changes within the same second still change the version
+            vers: st.st_mtime as u32 ^ st.st_mtime_nsec as u32,
+            path: st.st_ino as u64,
+        }
+    }
+
+    fn stat(&self, name: &CStr, st: &libc::stat) -> StatBuf {
+        let mut mode = st.st_mode as u32 & 0o777;
+        if is_dir(st) {
+            mode |= DMDIR;
+        } else if self.no_exec {
+            mode &= !0o111;
+        }
+        // XXX map to user and group names
+        StatBuf {
+            type_: 0,
+            dev: 0,
+            qid: self.qid(st),
+            mode,
+            atime: st.st_atime as u32,
+            mtime: st.st_mtime as u32,
+            length: if is_dir(st) { 0 } else { st.st_size as u64 },
+            name: name.to_string_lossy().into_owned(),
+            uid: st.st_uid.to_string(),
+            gid: st.st_gid.to_string(),
+            muid: st.st_uid.to_string(),
+        }
+    }
+
+    fn getattr(&self, st: &libc::stat) -> RGetAttr {
+        let mut mode = st.st_mode as u32;
+        if self.no_exec && !is_dir(st) {
+            mode &= !0o111;
+        }
+        RGetAttr {
+            valid: GETATTR_BASIC,
+            qid: self.qid(st),
+            mode,
+            uid: st.st_uid as u32,
+            gid: st.st_gid as u32,
+            nlink: st.st_nlink as u64,
+            rdev: st.st_rdev as u64,
+            size: st.st_size as u64,
+            blksize: st.st_blksize as u64,
+            blocks: st.st_blocks as u64,
+            atime_sec: st.st_atime as u64,
+            atime_nsec: st.st_atime_nsec as u64,
+            mtime_sec: st.st_mtime as u64,
+            mtime_nsec: st.st_mtime_nsec as u64,
+            ctime_sec: st.st_ctime as u64,
+            ctime_nsec: st.st_ctime_nsec as u64,
+            ..RGetAttr::default()
+        }
+    }
+
+    fn listing(&self, dir: &OwnedFd) -> io::Result<Vec<StatBuf>> {
+        let entries = read_dir(dir)?;
+        Ok(entries
+            .iter()
+            .map(|(name, st)| self.stat(name, st))
+            .collect())
+    }
+
+    // Entries for `TReadDir`, including "." and ".."
+    fn dir_entries(&self, dir: &ExportFile) -> io::Result<Vec<(Qid, u8, String)>> {
+        let parent = dir.parent().unwrap_or_else(|| dir.clone());
+        let mut entries = vec![
+            (CString::from(c"."), dir.attr()?),
+            (CString::from(c".."), parent.attr()?),
+        ];
+        entries.extend(read_dir(dir.dir())?);
+        Ok(entries
+            .iter()
+            .map(|(name, st)| {
+                // `d_type` is the file type bits of the mode
+                let type_ = (file_type(st) >> 12) as u8;
+                (self.qid(st), type_, name.to_string_lossy().into_owned())
+            })
+            .collect())
+    }
+
+    fn check_open(&self, mode: u8) -> Result<(), Error> {
+        if self.read_only
+            && (mode & 3 != OREAD && mode & 3 != OEXEC || mode & (OTRUNC | ORCLOSE) != 0)
+        {
+            return read_only();
+        }
+        if self.no_exec && mode & 3 == OEXEC {
+            return err("permission denied");
+        }
+        Ok(())
+    }
+
+    fn check_write(&self) -> Result<(), Error> {
+        if self.read_only {
+            read_only()
+        } else {
+            Ok(())
+        }
+    }
+
+    // Refuse devices, by the file type bits of `mode`
+    fn check_device(&self, mode: u32) -> Result<(), Error> {
+        let type_ = mode & libc::S_IFMT as u32;
+        if !self.devices && (type_ == libc::S_IFBLK as u32 || type_ == libc::S_IFCHR as u32) {
+            return Err(io::Error::from_raw_os_error(libc::EPERM).into());
+        }
+        Ok(())
+    }
+
+    /// Permission bits of `mode` a client may set
+    fn mode(&self, mode: u32) -> u32 {
+        if self.setuid {
+            mode & 0o7777
+        } else {
+            mode & 0o1777
+        }
+    }
+
+    /// Open `file`, which isn't a directory, refusing devices before and
+    /// after opening in case it was replaced in between
+    fn open_file(
+        &self,
+        file: &ExportFile,
+        flags: libc::c_int,
+    ) -> Result<(File, libc::stat), Error> {
+        self.check_device(file.attr()?.st_mode as u32)?;
+        let f = file.open(flags, 0)?;
+        let st = fstat(&f)?;
+        self.check_device(st.st_mode as u32)?;
+        Ok((f, st))
+    }
+}
+
+/// Names and attributes of the entries of a directory, other than "." and
+/// ".."
+fn read_dir(dir: &OwnedFd) -> io::Result<Vec<(CString, libc::stat)>> {
+    let fd = openat(dir, c".", libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+    let stream = unsafe { libc::fdopendir(fd.as_raw_fd()) };
+    if stream.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    // Now owned by `stream`
+    std::mem::forget(fd);
+
+    let mut listing = Vec::new();
+    loop {
+        let entry = unsafe { libc::readdir(stream) };
+        if entry.is_null() {
+            break;
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        if name == c"." || name == c".." {
+            continue;
+        }
+        // Entries may disappear while listing
+        let Ok(st) = fstatat(dir, name) else {
+            continue;
+        };
+        listing.push((name.to_owned(), st));
+    }
+    unsafe { libc::closedir(stream) };
+    Ok(listing)
+}
+
+struct Node {
+    link: Mutex<Link>,
+    // Held open for directories
+    dir: Option<OwnedFd>,
+}
+
+// Where a node is in the tree
+struct Link {
+    // `None` for the root
+    parent: Option<ExportFile>,
+    name: CString,
+}
+
+/// A file in an [`Export`]
+///
+/// Directories are held open, so fids keep referring to the same directory
+/// if it is renamed. Other files are referred to by name in their parent.
+#[derive(Clone)]
+pub struct ExportFile(Arc<Node>);
+
+impl ExportFile {
+    fn name(&self) -> CString {
+        self.0.link.lock().unwrap().name.clone()
+    }
+
+    fn parent(&self) -> Option<ExportFile> {
+        self.0.link.lock().unwrap().parent.clone()
+    }
+
+    fn attr(&self) -> io::Result<libc::stat> {
+        match &self.0.dir {
+            Some(fd) => fstat(fd),
+            None => {
+                let (dir, name) = self.at();
+                fstatat(dir.dir(), &name)
+            }
+        }
+    }
+
+    // Only called on directories, which `FidServer` ensures for walk and
+    // create
+    fn dir(&self) -> &OwnedFd {
+        self.0.dir.as_ref().unwrap()
+    }
+
+    /// Directory and name to pass to `*at()` calls to refer to this file,
+    /// which for directories are the directory itself and "."
+    fn at(&self) -> (ExportFile, CString) {
+        if self.0.dir.is_some() {
+            return (self.clone(), CString::from(c"."));
+        }
+        let link = self.0.link.lock().unwrap();
+        (link.parent.clone().unwrap(), link.name.clone())
+    }
+
+    /// Open a file that isn't a directory
+    fn open(&self, flags: libc::c_int, mode: u32) -> io::Result<File> {
+        let (dir, name) = self.at();
+        Ok(openat(dir.dir(), &name, flags, mode)?.into())
+    }
+
+    fn child(&self, name: CString) -> io::Result<(ExportFile, libc::stat)> {
+        let mut st = fstatat(self.dir(), &name)?;
+        let dir = if is_dir(&st) {
+            // Fails if replaced by a symlink since `fstatat`
+            let fd = openat(self.dir(), &name, DIR_FLAGS, 0)?;
+            st = fstat(&fd)?;
+            Some(fd)
+        } else {
+            None
+        };
+        Ok((self.new_child(name, dir), st))
+    }
+
+    fn new_child(&self, name: CString, dir: Option<OwnedFd>) -> ExportFile {
+        let link = Link {
+            parent: Some(self.clone()),
+            name,
+        };
+        ExportFile(Arc::new(Node {
+            link: Mutex::new(link),
+            dir,
+        }))
+    }
+
+    fn remove(&self) -> Result<(), Error> {
+        let Some(parent) = self.parent() else {
+            return err("cannot remove root");
+        };
+        let flags = if self.0.dir.is_some() {
+            libc::AT_REMOVEDIR
+        } else {
+            0
+        };
+        let name = self.name();
+        cvt(unsafe { libc::unlinkat(parent.dir().as_raw_fd(), name.as_ptr(), flags) })?;
+        Ok(())
+    }
+
+    fn chmod(&self, open: Option<&ExportOpen>, mode: u32) -> io::Result<()> {
+        let mode = mode as libc::mode_t;
+        match (&self.0.dir, open.map(|open| &open.kind)) {
+            // "." is never a symlink
+            (Some(fd), _) => cvt(unsafe { libc::fchmodat(fd.as_raw_fd(), c".".as_ptr(), mode, 0) }),
+            (None, Some(OpenKind::File(f))) => cvt(unsafe { libc::fchmod(f.as_raw_fd(), mode) }),
+            // Without opening the file, which may be unreadable, or not
+            // something that can be opened
+            (None, _) => self.chmod_at(mode),
+        }?;
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn chmod_at(&self, mode: libc::mode_t) -> io::Result<libc::c_int> {
+        let (fd, path) = self.proc_path()?;
+        // Would be followed through /proc
+        if is_link(&fstat(&fd)?) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        cvt(unsafe { libc::fchmodat(libc::AT_FDCWD, path.as_ptr(), mode, 0) })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn chmod_at(&self, mode: libc::mode_t) -> io::Result<libc::c_int> {
+        let (dir, name) = self.at();
+        let flags = libc::AT_SYMLINK_NOFOLLOW;
+        cvt(unsafe { libc::fchmodat(dir.dir().as_raw_fd(), name.as_ptr(), mode, flags) })
+    }
+
+    fn truncate(&self, open: Option<&ExportOpen>, size: u64) -> io::Result<()> {
+        match open.map(|open| &open.kind) {
+            Some(OpenKind::File(f)) => f.set_len(size),
+            _ if self.0.dir.is_some() => Err(io::ErrorKind::IsADirectory.into()),
+            _ => self
+                .open(libc::O_WRONLY | libc::O_NONBLOCK, 0)?
+                .set_len(size),
+        }
+    }
+
+    fn utimens(&self, times: &[libc::timespec; 2]) -> io::Result<()> {
+        let (dir, name) = self.at();
+        cvt(unsafe {
+            libc::utimensat(
+                dir.dir().as_raw_fd(),
+                name.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Move to `name` in `dir`
+    fn rename(&self, dir: &ExportFile, name: CString) -> Result<(), Error> {
+        let mut link = self.0.link.lock().unwrap();
+        let Some(parent) = &link.parent else {
+            return err("cannot rename root");
+        };
+        cvt(unsafe {
+            libc::renameat(
+                parent.dir().as_raw_fd(),
+                link.name.as_ptr(),
+                dir.dir().as_raw_fd(),
+                name.as_ptr(),
+            )
+        })?;
+        link.parent = Some(dir.clone());
+        link.name = name;
+        Ok(())
+    }
+
+    // Path through /proc that refers to the file itself, for the calls on
+    // extended attributes, which have no `*at()` versions, and for chmod
+    // without opening the file. `fd` must be kept open while it's used.
+    #[cfg(target_os = "linux")]
+    fn proc_path(&self) -> io::Result<(OwnedFd, CString)> {
+        let fd = match &self.0.dir {
+            Some(fd) => fd.try_clone()?,
+            None => {
+                let (dir, name) = self.at();
+                openat(dir.dir(), &name, libc::O_PATH, 0)?
+            }
+        };
+        let path = format!("/proc/self/fd/{}", fd.as_raw_fd());
+        Ok((fd, CString::new(path).unwrap()))
+    }
+}
+
+/// Call `f` with a buffer and its size, as for `getxattr()`, until the
+/// buffer is large enough
+#[cfg(target_os = "linux")]
+fn xattr_buf(f: impl Fn(*mut libc::c_void, libc::size_t) -> libc::ssize_t) -> io::Result<Vec<u8>> {
+    loop {
+        let len = f(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; len as usize];
+        let len = f(buf.as_mut_ptr().cast(), buf.len());
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        // Grew since its size was read
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+/// An open [`ExportFile`]
+pub struct ExportOpen {
+    kind: OpenKind,
+    remove_on_clunk: bool,
+}
+
+enum OpenKind {
+    File(File),
+    Dir {
+        // Listing for `TRead`, read again at offset 0
+        listing: Mutex<DirListing>,
+        // Qids, types and names for `TReadDir`, also read again at offset 0
+        entries: Mutex<Vec<(Qid, u8, String)>>,
+    },
+}
+
+impl OpenKind {
+    fn dir() -> Self {
+        Self::Dir {
+            listing: Mutex::default(),
+            entries: Mutex::default(),
+        }
+    }
+}
+
+fn open_flags(mode: u8) -> libc::c_int {
+    let mut flags = match mode & 3 {
+        OREAD | OEXEC => libc::O_RDONLY,
+        OWRITE => libc::O_WRONLY,
+        _ => libc::O_RDWR,
+    };
+    if mode & OTRUNC != 0 {
+        flags |= libc::O_TRUNC;
+    }
+    flags
+}
+
+impl FileHandler for Export {
+    type File = ExportFile;
+    type Open = ExportOpen;
+
+    const DOTL: bool = true;
+
+    async fn attach(&self, _user: &Identity) -> Result<(ExportFile, Qid), Error> {
+        let st = self.root.attr()?;
+        Ok((self.root.clone(), self.qid(&st)))
+    }
+
+    async fn walk(
+        &self,
+        _user: &Identity,
+        dir: &ExportFile,
+        name: &str,
+    ) -> Result<(ExportFile, Qid), Error> {
+        if name == ".." {
+            // ".." of the root is the root
+            let parent = dir.parent().unwrap_or_else(|| dir.clone());
+            let st = parent.attr()?;
+            return Ok((parent, self.qid(&st)));
+        }
+        let (file, st) = dir.child(cname(name)?)?;
+        Ok((file, self.qid(&st)))
+    }
+
+    async fn open(
+        &self,
+        _user: &Identity,
+        file: &ExportFile,
+        mode: u8,
+    ) -> Result<(ExportOpen, ROpen), Error> {
+        self.check_open(mode)?;
+        let (kind, st) = if let Some(fd) = &file.0.dir {
+            // Check the directory can be listed
+            openat(fd, c".", libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+            (OpenKind::dir(), fstat(fd)?)
+        } else {
+            let (f, st) = self.open_file(file, open_flags(mode))?;
+            (OpenKind::File(f), st)
+        };
+        let open = ExportOpen {
+            kind,
+            remove_on_clunk: mode & ORCLOSE != 0,
+        };
+        let ropen = ROpen {
+            qid: self.qid(&st),
+            iounit: 0,
+        };
+        Ok((open, ropen))
+    }
+
+    async fn create(
+        &self,
+        _user: &Identity,
+        dir: &ExportFile,
+        name: &str,
+        perm: u32,
+        mode: u8,
+    ) -> Result<(ExportFile, ExportOpen, RCreate), Error> {
+        self.check_write()?;
+        self.check_open(mode)?;
+        let name = cname(name)?;
+        let dir_mode = dir.attr()?.st_mode as u32;
+        let (file, kind, st) = if perm & DMDIR != 0 {
+            // Permissions are limited by those of the parent, as in Plan 9
+            let perm = perm & (!0o777 | dir_mode) & 0o777;
+            cvt(unsafe {
+                libc::mkdirat(dir.dir().as_raw_fd(), name.as_ptr(), perm as libc::mode_t)
+            })?;
+            let (file, st) = dir.child(name)?;
+            (file, OpenKind::dir(), st)
+        } else {
+            let perm = perm & (!0o666 | dir_mode) & 0o777;
+            let flags = open_flags(mode) | libc::O_CREAT | libc::O_EXCL;
+            let fd = openat(dir.dir(), &name, flags, perm)?;
+            let st = fstat(&fd)?;
+            (dir.new_child(name, None), OpenKind::File(fd.into()), st)
+        };
+        let open = ExportOpen {
+            kind,
+            remove_on_clunk: mode & ORCLOSE != 0,
+        };
+        let rcreate = RCreate {
+            qid: self.qid(&st),
+            iounit: 0,
+        };
+        Ok((file, open, rcreate))
+    }
+
+    async fn read(
+        &self,
+        _user: &Identity,
+        file: &ExportFile,
+        open: &ExportOpen,
+        offset: u64,
+        count: u32,
+    ) -> Result<Vec<u8>, Error> {
+        match &open.kind {
+            OpenKind::File(f) => {
+                let mut buf = vec![0; count as usize];
+                let len = f.read_at(&mut buf, offset)?;
+                buf.truncate(len);
+                Ok(buf)
+            }
+            OpenKind::Dir { listing, .. } => {
+                let mut listing = listing.lock().unwrap();
+                listing.read(offset, count, || self.listing(file.dir()))
+            }
+        }
+    }
+
+    async fn write(
+        &self,
+        _user: &Identity,
+        _file: &ExportFile,
+        open: &ExportOpen,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<u32, Error> {
+        match &open.kind {
+            OpenKind::File(f) => Ok(f.write_at(data, offset)? as u32),
+            OpenKind::Dir { .. } => err("is a directory"),
+        }
+    }
+
+    fn clunk(&self, _user: &Identity, file: &ExportFile, open: Option<&ExportOpen>) {
+        if open.is_some_and(|open| open.remove_on_clunk) {
+            let _ = file.remove();
+        }
+    }
+
+    async fn remove(
+        &self,
+        _user: &Identity,
+        file: &ExportFile,
+        _open: Option<&ExportOpen>,
+    ) -> Result<(), Error> {
+        self.check_write()?;
+        file.remove()
+    }
+
+    async fn stat(&self, _user: &Identity, file: &ExportFile) -> Result<StatBuf, Error> {
+        let st = file.attr()?;
+        Ok(self.stat(&file.name(), &st))
+    }
+
+    async fn wstat(
+        &self,
+        _user: &Identity,
+        file: &ExportFile,
+        stat: &Stat<'_>,
+    ) -> Result<(), Error> {
+        self.check_write()?;
+        let st = file.attr()?;
+
+        // Check everything before changing anything. Fields to leave
+        // unchanged are `!0` or empty.
+        if !stat.uid.is_empty() || !stat.gid.is_empty() {
+            return err("changing owner not supported");
+        }
+        if stat.mode != !0 && (stat.mode & DMDIR != 0) != is_dir(&st) {
+            return err("can't change directory bit");
+        }
+        if stat.length != !0 && (is_dir(&st) || is_link(&st)) {
+            return err("can't truncate a directory or symlink");
+        }
+        if stat.mode != !0 && is_link(&st) {
+            return err("can't change mode of a symlink");
+        }
+        let name = cname(stat.name)?;
+        let rename = !stat.name.is_empty() && name != file.name();
+        if rename {
+            if file.parent().is_none() {
+                return err("cannot rename root");
+            }
+            if stat.name == "." || stat.name == ".." || stat.name.contains('/') {
+                return err("illegal name");
+            }
+            // Unlike `rename()`, wstat doesn't replace existing files
+            if fstatat(file.parent().unwrap().dir(), &name).is_ok() {
+                return err("file already exists");
+            }
+        }
+
+        if stat.length != !0 {
+            file.truncate(None, stat.length)?;
+        }
+        if stat.mode != !0 {
+            file.chmod(None, stat.mode & 0o777)?;
+        }
+        if stat.mtime != !0 {
+            let times = [
+                libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_OMIT,
+                },
+                libc::timespec {
+                    tv_sec: stat.mtime as libc::time_t,
+                    tv_nsec: 0,
+                },
+            ];
+            file.utimens(&times)?;
+        }
+        if rename {
+            let parent = file.parent().unwrap();
+            file.rename(&parent, name)?;
+        }
+        Ok(())
+    }
+
+    async fn lopen(
+        &self,
+        _user: &Identity,
+        file: &ExportFile,
+        flags: u32,
+    ) -> Result<(ExportOpen, RLOpen), Error> {
+        if flags & 3 != 0 || flags & DOTL_TRUNC != 0 {
+            self.check_write()?;
+        }
+        let (kind, st) = if let Some(fd) = &file.0.dir {
+            openat(fd, c".", libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+            (OpenKind::dir(), fstat(fd)?)
+        } else {
+            let (f, st) = self.open_file(file, dotl_open_flags(flags & !DOTL_EXCL))?;
+            (OpenKind::File(f), st)
+        };
+        let open = ExportOpen {
+            kind,
+            remove_on_clunk: false,
+        };
+        let rlopen = RLOpen {
+            qid: self.qid(&st),
+            iounit: 0,
+        };
+        Ok((open, rlopen))
+    }
+
+    async fn lcreate(
+        &self,
+        _user: &Identity,
+        dir: &ExportFile,
+        tlcreate: &TLCreate<'_>,
+    ) -> Result<(ExportFile, ExportOpen, RLCreate), Error> {
+        self.check_write()?;
+        let name = cname(tlcreate.name)?;
+        let flags = dotl_open_flags(tlcreate.flags) | libc::O_CREAT;
+        // Without `O_EXCL` an existing file is opened
+        if let Ok(st) = fstatat(dir.dir(), &name) {
+            self.check_device(st.st_mode as u32)?;
+        }
+        let fd = openat(dir.dir(), &name, flags, self.mode(tlcreate.mode))?;
+        let st = fstat(&fd)?;
+        self.check_device(st.st_mode as u32)?;
+        let open = ExportOpen {
+            kind: OpenKind::File(fd.into()),
+            remove_on_clunk: false,
+        };
+        let rlcreate = RLCreate {
+            qid: self.qid(&st),
+            iounit: 0,
+        };
+        Ok((dir.new_child(name, None), open, rlcreate))
+    }
+
+    async fn getattr(
+        &self,
+        _user: &Identity,
+        file: &ExportFile,
+        open: Option<&ExportOpen>,
+        _request_mask: u64,
+    ) -> Result<RGetAttr, Error> {
+        // An open file may have been renamed by another fid
+        let st = match open.map(|open| &open.kind) {
+            Some(OpenKind::File(f)) => fstat(f)?,
+            _ => file.attr()?,
+        };
+        Ok(self.getattr(&st))
+    }
+
+    async fn setattr(
+        &self,
+        _user: &Identity,
+        file: &ExportFile,
+        open: Option<&ExportOpen>,
+        tsetattr: &TSetAttr,
+    ) -> Result<(), Error> {
+        self.check_write()?;
+        let valid = tsetattr.valid;
+        if valid & SETATTR_MODE != 0 {
+            if is_link(&file.attr()?) {
+                return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP).into());
+            }
+            file.chmod(open, self.mode(tsetattr.mode))?;
+        }
+        if valid & (SETATTR_UID | SETATTR_GID) != 0 {
+            // -1 leaves either unchanged
+            let uid = if valid & SETATTR_UID != 0 {
+                tsetattr.uid
+            } else {
+                !0
+            };
+            let gid = if valid & SETATTR_GID != 0 {
+                tsetattr.gid
+            } else {
+                !0
+            };
+            let (dir, name) = file.at();
+            cvt(unsafe {
+                libc::fchownat(
+                    dir.dir().as_raw_fd(),
+                    name.as_ptr(),
+                    uid as libc::uid_t,
+                    gid as libc::gid_t,
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            })?;
+        }
+        if valid & SETATTR_SIZE != 0 {
+            file.truncate(open, tsetattr.size)?;
+        }
+        if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+            let times = [
+                set_time(
+                    valid,
+                    SETATTR_ATIME,
+                    SETATTR_ATIME_SET,
+                    tsetattr.atime_sec,
+                    tsetattr.atime_nsec,
+                ),
+                set_time(
+                    valid,
+                    SETATTR_MTIME,
+                    SETATTR_MTIME_SET,
+                    tsetattr.mtime_sec,
+                    tsetattr.mtime_nsec,
+                ),
+            ];
+            file.utimens(&times)?;
+        }
+        // The ctime is changed by any of the above
+        Ok(())
+    }
+
+    async fn readdir(
+        &self,
+        _user: &Identity,
+        file: &ExportFile,
+        open: &ExportOpen,
+        offset: u64,
+        count: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let OpenKind::Dir { entries, .. } = &open.kind else {
+            return err("not a directory");
+        };
+        let mut entries = entries.lock().unwrap();
+        if offset == 0 || entries.is_empty() {
+            *entries = self.dir_entries(file)?;
+        }
+        // The offset of each entry is the index of the next
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        let mut buf = Vec::new();
+        for (i, (qid, type_, name)) in entries.iter().enumerate().skip(start) {
+            let entry = DirEntry {
+                qid: *qid,
+                offset: i as u64 + 1,
+                type_: *type_,
+                name,
+            };
+            if buf.len() + entry.size() > count as usize {
+                if buf.is_empty() {
+                    return err("directory read count too small");
+                }
+                break;
+            }
+            entry.write(&mut buf).unwrap();
+        }
+        Ok(buf)
+    }
+
+    async fn statfs(&self, _user: &Identity, file: &ExportFile) -> Result<RStatFs, Error> {
+        let (dir, _) = file.at();
+        let st = fstatvfs(dir.dir())?;
+        Ok(RStatFs {
+            // V9FS_MAGIC, as diod and QEMU reply
+            type_: 0x01021997,
+            bsize: st.f_bsize as u32,
+            blocks: st.f_blocks as u64,
+            bfree: st.f_bfree as u64,
+            bavail: st.f_bavail as u64,
+            files: st.f_files as u64,
+            ffree: st.f_ffree as u64,
+            fsid: st.f_fsid as u64,
+            namelen: st.f_namemax as u32,
+        })
+    }
+
+    async fn symlink(
+        &self,
+        _user: &Identity,
+        dir: &ExportFile,
+        name: &str,
+        target: &str,
+        _gid: u32,
+    ) -> Result<Qid, Error> {
+        self.check_write()?;
+        let name = cname(name)?;
+        let target = cname(target)?;
+        let fd = dir.dir().as_raw_fd();
+        cvt(unsafe { libc::symlinkat(target.as_ptr(), fd, name.as_ptr()) })?;
+        Ok(self.qid(&fstatat(dir.dir(), &name)?))
+    }
+
+    async fn mknod(
+        &self,
+        _user: &Identity,
+        dir: &ExportFile,
+        tmknod: &TMkNod<'_>,
+    ) -> Result<Qid, Error> {
+        self.check_write()?;
+        let name = cname(tmknod.name)?;
+        self.check_device(tmknod.mode)?;
+        let mode = tmknod.mode & libc::S_IFMT as u32 | self.mode(tmknod.mode);
+        let dev = libc::makedev(tmknod.major as _, tmknod.minor as _);
+        cvt(unsafe {
+            libc::mknodat(
+                dir.dir().as_raw_fd(),
+                name.as_ptr(),
+                mode as libc::mode_t,
+                dev,
+            )
+        })?;
+        Ok(self.qid(&fstatat(dir.dir(), &name)?))
+    }
+
+    async fn mkdir(
+        &self,
+        _user: &Identity,
+        dir: &ExportFile,
+        name: &str,
+        mode: u32,
+        _gid: u32,
+    ) -> Result<Qid, Error> {
+        self.check_write()?;
+        let name = cname(name)?;
+        let mode = self.mode(mode) as libc::mode_t;
+        cvt(unsafe { libc::mkdirat(dir.dir().as_raw_fd(), name.as_ptr(), mode) })?;
+        Ok(self.qid(&fstatat(dir.dir(), &name)?))
+    }
+
+    async fn readlink(&self, _user: &Identity, file: &ExportFile) -> Result<String, Error> {
+        let (dir, name) = file.at();
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        let len = unsafe {
+            libc::readlinkat(
+                dir.dir().as_raw_fd(),
+                name.as_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        buf.truncate(len as usize);
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    async fn link(
+        &self,
+        _user: &Identity,
+        dir: &ExportFile,
+        file: &ExportFile,
+        name: &str,
+    ) -> Result<(), Error> {
+        self.check_write()?;
+        let name = cname(name)?;
+        let (old_dir, old_name) = file.at();
+        cvt(unsafe {
+            libc::linkat(
+                old_dir.dir().as_raw_fd(),
+                old_name.as_ptr(),
+                dir.dir().as_raw_fd(),
+                name.as_ptr(),
+                0,
+            )
+        })?;
+        Ok(())
+    }
+
+    async fn rename(
+        &self,
+        _user: &Identity,
+        file: &ExportFile,
+        dir: &ExportFile,
+        name: &str,
+    ) -> Result<(), Error> {
+        self.check_write()?;
+        file.rename(dir, cname(name)?)
+    }
+
+    async fn renameat(
+        &self,
+        _user: &Identity,
+        olddir: &ExportFile,
+        oldname: &str,
+        newdir: &ExportFile,
+        newname: &str,
+    ) -> Result<(), Error> {
+        self.check_write()?;
+        let oldname = cname(oldname)?;
+        let newname = cname(newname)?;
+        cvt(unsafe {
+            libc::renameat(
+                olddir.dir().as_raw_fd(),
+                oldname.as_ptr(),
+                newdir.dir().as_raw_fd(),
+                newname.as_ptr(),
+            )
+        })?;
+        Ok(())
+    }
+
+    async fn unlinkat(
+        &self,
+        _user: &Identity,
+        dir: &ExportFile,
+        name: &str,
+        flags: u32,
+    ) -> Result<(), Error> {
+        self.check_write()?;
+        let name = cname(name)?;
+        let flags = if flags & DOTL_AT_REMOVEDIR != 0 {
+            libc::AT_REMOVEDIR
+        } else {
+            0
+        };
+        cvt(unsafe { libc::unlinkat(dir.dir().as_raw_fd(), name.as_ptr(), flags) })?;
+        Ok(())
+    }
+
+    async fn fsync(
+        &self,
+        _user: &Identity,
+        file: &ExportFile,
+        open: &ExportOpen,
+        datasync: bool,
+    ) -> Result<(), Error> {
+        match &open.kind {
+            OpenKind::File(f) if datasync => f.sync_data()?,
+            OpenKind::File(f) => f.sync_all()?,
+            OpenKind::Dir { .. } => {
+                let fd = openat(file.dir(), c".", libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+                File::from(fd).sync_all()?
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn xattr_get(
+        &self,
+        _user: &Identity,
+        file: &ExportFile,
+        name: &str,
+    ) -> Result<Vec<u8>, Error> {
+        let (_fd, path) = file.proc_path()?;
+        if name.is_empty() {
+            return Ok(xattr_buf(|buf, size| unsafe {
+                libc::listxattr(path.as_ptr(), buf.cast(), size)
+            })?);
+        }
+        let name = cname(name)?;
+        Ok(xattr_buf(|buf, size| unsafe {
+            libc::getxattr(path.as_ptr(), name.as_ptr(), buf, size)
+        })?)
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn xattr_set(
+        &self,
+        _user: &Identity,
+        file: &ExportFile,
+        name: &str,
+        value: Option<&[u8]>,
+        flags: u32,
+    ) -> Result<(), Error> {
+        self.check_write()?;
+        let (_fd, path) = file.proc_path()?;
+        let name = cname(name)?;
+        // `XATTR_` flags are the same as Linux's
+        cvt(match value {
+            Some(value) => unsafe {
+                libc::setxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                    flags as libc::c_int,
+                )
+            },
+            None => unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) },
+        })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1326 @@
+// Fid bookkeeping shared by servers. A `FileHandler` implements operations on
+// files, and `FidServer` maps each connection's fids to them, enforcing the
+// rules of the protocol around walking, opening and clunking fids. Afids are
+// passed to an `Authenticator` instead. Handlers that implement the 9P2000.L
+// methods can also be used with 9P2000.L clients, in which case extended
+// attributes are read and written through xattr fids kept here.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    Authenticator, Error, Fid, Filesystem, Identity, Metrics, NoAuth, Qid, RAttach, RAuth, RClunk,
+    RCreate, RFSync, RGetAttr, RGetLock, RLCreate, RLOpen, RLink, RLock, RMkDir, RMkNod, ROpen,
+    RRead, RReadDir, RReadLink, RRemove, RRename, RRenameAt, RSetAttr, RStat, RStatFs, RSymlink,
+    RUnlinkAt, RWStat, RWalk, RWrite, RXattrCreate, RXattrWalk, Replied, Replier, Stat, StatBuf,
+    TAttach, TAuth, TClunk, TCreate, TFSync, TGetAttr, TGetLock, TLAttach, TLAuth, TLCreate,
+    TLOpen, TLink, TLock, TMkDir, TMkNod, TOpen, TRead, TReadDir, TReadLink, TRemove, TRename,
+    TRenameAt, TSetAttr, TStat, TStatFs, TSymlink, TUnlinkAt, TWStat, TWalk, TWrite, TXattrCreate,
+    TXattrWalk, DOTL_TRUNC, LOCK_SUCCESS, LOCK_TYPE_UNLCK, MAXWELEM, NONUNAME, OEXEC, ORDWR, OREAD,
+    OTRUNC, OWRITE, QTAUTH,
+};
+
+// Largest xattr value a client can create, as on Linux
+const XATTR_SIZE_MAX: u64 = 64 * 1024;
+
+pub(crate) fn err<T>(ename: &str) -> Result<T, Error> {
+    Err(Error::Protocol(ename.to_string()))
+}
+
+/// Operations on the files of a server using [`FidServer`]
+///
+/// Methods are only called when the protocol allows them: `walk` and
+/// `create` on directories, `read` and `write` on files opened with a
+/// suitable mode, and nothing on a fid after it is clunked. Each is passed
+/// the [`Identity`] the fid was attached as.
+///
+/// Handlers that set `DOTL` implement the 9P2000.L methods, which are used
+/// in place of `open`, `create`, `stat` and `wstat` with 9P2000.L clients.
+pub trait FileHandler: Send + Sync + 'static {
+    /// A file a fid refers to, such as a path or a node in a tree
+    type File: Clone + Send + Sync + 'static;
+    /// State of an open fid
+    type Open: Send + Sync + 'static;
+
+    /// Whether 9P2000.L is supported
+    const DOTL: bool = false;
+
+    /// Get the root of the tree named by `user.aname`
+    fn attach(
+        &self,
+        user: &Identity,
+    ) -> impl Future<Output = Result<(Self::File, Qid), Error>> + Send;
+
+    /// Look up `name`, which may be "..", in directory `dir`
+    ///
+    /// Names that are empty, "." or contain '/' are rejected before this is
+    /// called.
+    fn walk(
+        &self,
+        user: &Identity,
+        dir: &Self::File,
+        name: &str,
+    ) -> impl Future<Output = Result<(Self::File, Qid), Error>> + Send;
+
+    fn open(
+        &self,
+        user: &Identity,
+        file: &Self::File,
+        mode: u8,
+    ) -> impl Future<Output = Result<(Self::Open, ROpen), Error>> + Send;
+
+    /// Create and open `name` in directory `dir`
+    fn create(
+        &self,
+        _user: &Identity,
+        _dir: &Self::File,
+        _name: &str,
+        _perm: u32,
+        _mode: u8,
+    ) -> impl Future<Output = Result<(Self::File, Self::Open, RCreate), Error>> + Send {
+        async { err("create prohibited") }
+    }
+
+    /// Read at most `count` bytes at `offset`
+    fn read(
+        &self,
+        user: &Identity,
+        file: &Self::File,
+        open: &Self::Open,
+        offset: u64,
+        count: u32,
+    ) -> impl Future<Output = Result<Vec<u8>, Error>> + Send;
+
+    fn write(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+        _open: &Self::Open,
+        _offset: u64,
+        _data: &[u8],
+    ) -> impl Future<Output = Result<u32, Error>> + Send {
+        async { err("write prohibited") }
+    }
+
+    /// Called when a fid stops referring to `file`, because it was clunked,
+    /// removed, walked elsewhere or the connection closed
+    ///
+    /// Files returned by `walk` for intermediate elements of a path are
+    /// never held by a fid, and are just dropped.
+    fn clunk(&self, _user: &Identity, _file: &Self::File, _open: Option<&Self::Open>) {}
+
+    /// Remove `file`
+    ///
+    /// The fid is clunked afterwards, whether or not this succeeds.
+    fn remove(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+        _open: Option<&Self::Open>,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { err("remove prohibited") }
+    }
+
+    fn stat(
+        &self,
+        user: &Identity,
+        file: &Self::File,
+    ) -> impl Future<Output = Result<StatBuf, Error>> + Send;
+
+    fn wstat(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+        _stat: &Stat<'_>,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { err("wstat prohibited") }
+    }
+
+    /// Open with the `DOTL_` flags of `TLOpen`
+    ///
+    /// Directories are only opened read-only.
+    fn lopen(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+        _flags: u32,
+    ) -> impl Future<Output = Result<(Self::Open, RLOpen), Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    /// Create and open the regular file `name` in directory `dir`
+    fn lcreate(
+        &self,
+        _user: &Identity,
+        _dir: &Self::File,
+        _tlcreate: &TLCreate<'_>,
+    ) -> impl Future<Output = Result<(Self::File, Self::Open, RLCreate), Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    /// Get at least the attributes in `request_mask`
+    ///
+    /// `open` is set if the fid is open.
+    fn getattr(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+        _open: Option<&Self::Open>,
+        _request_mask: u64,
+    ) -> impl Future<Output = Result<RGetAttr, Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    fn setattr(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+        _open: Option<&Self::Open>,
+        _tsetattr: &TSetAttr,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    /// Read serialized [`DirEntry`](crate::DirEntry)s of at most `count`
+    /// bytes, starting after the entry with `offset`
+    fn readdir(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+        _open: &Self::Open,
+        _offset: u64,
+        _count: u32,
+    ) -> impl Future<Output = Result<Vec<u8>, Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    fn statfs(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+    ) -> impl Future<Output = Result<RStatFs, Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    fn symlink(
+        &self,
+        _user: &Identity,
+        _dir: &Self::File,
+        _name: &str,
+        _target: &str,
+        _gid: u32,
+    ) -> impl Future<Output = Result<Qid, Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    fn mknod(
+        &self,
+        _user: &Identity,
+        _dir: &Self::File,
+        _tmknod: &TMkNod<'_>,
+    ) -> impl Future<Output = Result<Qid, Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    fn mkdir(
+        &self,
+        _user: &Identity,
+        _dir: &Self::File,
+        _name: &str,
+        _mode: u32,
+        _gid: u32,
+    ) -> impl Future<Output = Result<Qid, Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    fn readlink(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+    ) -> impl Future<Output = Result<String, Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    /// Create a hard link to `file` named `name` in directory `dir`
+    fn link(
+        &self,
+        _user: &Identity,
+        _dir: &Self::File,
+        _file: &Self::File,
+        _name: &str,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    /// Move `file` into directory `dir` as `name`, replacing any file
+    /// already there
+    ///
+    /// `file` is the one held by the fid, and should refer to the file in its
+    /// new place afterwards.
+    fn rename(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+        _dir: &Self::File,
+        _name: &str,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    fn renameat(
+        &self,
+        _user: &Identity,
+        _olddir: &Self::File,
+        _oldname: &str,
+        _newdir: &Self::File,
+        _newname: &str,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    /// Remove `name` from directory `dir`, with the flags of `TUnlinkAt`
+    fn unlinkat(
+        &self,
+        _user: &Identity,
+        _dir: &Self::File,
+        _name: &str,
+        _flags: u32,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    /// By default, does nothing
+    fn fsync(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+        _open: &Self::Open,
+        _datasync: bool,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Take or release a POSIX record lock, returning a `LOCK_` status
+    ///
+    /// By default every lock succeeds, leaving clients to only check locks
+    /// among their own processes.
+    fn lock(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+        _open: &Self::Open,
+        _tlock: &TLock<'_>,
+    ) -> impl Future<Output = Result<u8, Error>> + Send {
+        async { Ok(LOCK_SUCCESS) }
+    }
+
+    /// By default, there are never conflicting locks
+    fn getlock(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+        _open: &Self::Open,
+        tgetlock: &TGetLock<'_>,
+    ) -> impl Future<Output = Result<RGetLock<'static>, Error>> + Send {
+        let rgetlock = RGetLock {
+            type_: LOCK_TYPE_UNLCK,
+            start: tgetlock.start,
+            length: tgetlock.length,
+            proc_id: tgetlock.proc_id,
+            client_id: "",
+        };
+        async { Ok(rgetlock) }
+    }
+
+    /// Get the value of extended attribute `name`, or if `name` is empty, the
+    /// list of attribute names, each followed by a nul byte
+    fn xattr_get(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+        _name: &str,
+    ) -> impl Future<Output = Result<Vec<u8>, Error>> + Send {
+        async { err("operation not supported") }
+    }
+
+    /// Set extended attribute `name`, with the `XATTR_` flags, or remove it
+    /// if `value` is `None`
+    fn xattr_set(
+        &self,
+        _user: &Identity,
+        _file: &Self::File,
+        _name: &str,
+        _value: Option<&[u8]>,
+        _flags: u32,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { err("operation not supported") }
+    }
+}
+
+// Mode and state of an open fid
+type OpenState<H> = (u8, Arc<<H as FileHandler>::Open>);
+
+struct FidEntry<H: FileHandler> {
+    user: Arc<Identity>,
+    file: H::File,
+    qid: Qid,
+    open: Option<OpenState<H>>,
+}
+
+impl<H: FileHandler> Clone for FidEntry<H> {
+    fn clone(&self) -> Self {
+        Self {
+            user: self.user.clone(),
+            file: self.file.clone(),
+            qid: self.qid,
+            open: self.open.clone(),
+        }
+    }
+}
+
+/// Fid made by `TXattrWalk` or `TXattrCreate`
+struct XattrFid<H: FileHandler> {
+    user: Arc<Identity>,
+    file: H::File,
+    kind: XattrKind,
+}
+
+enum XattrKind {
+    // Value or list of names, read when the fid was made
+    Read(Vec<u8>),
+    // Attribute set when the fid is clunked
+    Write {
+        name: String,
+        size: u64,
+        flags: u32,
+        value: Mutex<Vec<u8>>,
+    },
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && !name.contains('/')
+}
+
+/// Limit on the fids of several [`FidServer`]s together, such as those of
+/// every connection to a server
+#[derive(Clone, Debug)]
+pub struct FidLimit {
+    max: usize,
+    used: Arc<AtomicUsize>,
+}
+
+impl FidLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            used: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn acquire(&self) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                (used < self.max).then_some(used + 1)
+            })
+            .is_ok()
+    }
+
+    fn release(&self) {
+        self.used.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// [`Filesystem`] that tracks the fids of a connection, and passes requests
+/// on to a [`FileHandler`]
+///
+/// One is created per connection, sharing the same handler. Clients
+/// authenticate with the [`Authenticator`] `A`, which by default doesn't
+/// require authentication.
+pub struct FidServer<H: FileHandler, A: Authenticator = NoAuth> {
+    handler: Arc<H>,
+    auth: Arc<A>,
+    fids: Mutex<HashMap<Fid, FidEntry<H>>>,
+    // Locked after `fids` when both are needed
+    afids: Mutex<HashMap<Fid, Arc<A::Conversation>>>,
+    // Locked after `afids`
+    xattrs: Mutex<HashMap<Fid, Arc<XattrFid<H>>>>,
+    max_fids: usize,
+    limit: Option<FidLimit>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl<H: FileHandler> FidServer<H> {
+    pub fn new(handler: Arc<H>) -> Self {
+        Self::with_auth(handler, Arc::new(NoAuth))
+    }
+}
+
+impl<H: FileHandler, A: Authenticator> FidServer<H, A> {
+    pub fn with_auth(handler: Arc<H>, auth: Arc<A>) -> Self {
+        Self {
+            handler,
+            auth,
+            fids: Mutex::new(HashMap::new()),
+            afids: Mutex::new(HashMap::new()),
+            xattrs: Mutex::new(HashMap::new()),
+            max_fids: usize::MAX,
+            limit: None,
+            metrics: None,
+        }
+    }
+
+    /// Limit the number of fids the connection can have at once
+    pub fn max_fids(mut self, max: usize) -> Self {
+        self.max_fids = max;
+        self
+    }
+
+    /// Count fids against a limit shared with other connections
+    pub fn fid_limit(mut self, limit: FidLimit) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Count the fids in use in `metrics`
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn handler(&self) -> &Arc<H> {
+        &self.handler
+    }
+
+    fn get(&self, fid: Fid) -> Result<FidEntry<H>, Error> {
+        match self.fids.lock().unwrap().get(&fid) {
+            Some(entry) => Ok(entry.clone()),
+            None if self.afids.lock().unwrap().contains_key(&fid) => err("fid is an auth fid"),
+            None if self.xattrs.lock().unwrap().contains_key(&fid) => err("fid is an xattr fid"),
+            None => err("unknown fid"),
+        }
+    }
+
+    // Get an entry and its open mode and state
+    fn get_open(&self, fid: Fid) -> Result<(FidEntry<H>, OpenState<H>), Error> {
+        let entry = self.get(fid)?;
+        match entry.open.clone() {
+            Some(open) => Ok((entry, open)),
+            None => err("fid not open"),
+        }
+    }
+
+    fn get_auth(&self, fid: Fid) -> Option<Arc<A::Conversation>> {
+        self.afids.lock().unwrap().get(&fid).cloned()
+    }
+
+    // Get a directory to make a file in, checking the name of the file
+    fn get_dir(&self, fid: Fid, name: &str) -> Result<FidEntry<H>, Error> {
+        let entry = self.get(fid)?;
+        if !entry.qid.is_dir() {
+            err("not a directory")
+        } else if !valid_name(name) || name == ".." {
+            err("illegal name")
+        } else {
+            Ok(entry)
+        }
+    }
+
+    fn get_xattr(&self, fid: Fid) -> Option<Arc<XattrFid<H>>> {
+        self.xattrs.lock().unwrap().get(&fid).cloned()
+    }
+
+    fn in_use(&self, fid: Fid) -> bool {
+        let fids = self.fids.lock().unwrap();
+        fids.contains_key(&fid)
+            || self.afids.lock().unwrap().contains_key(&fid)
+            || self.xattrs.lock().unwrap().contains_key(&fid)
+    }
+
+    // Check that a new fid can be added, and count it against the limits
+    fn add_fid(&self, fids: &HashMap<Fid, FidEntry<H>>, fid: Fid) -> Result<(), Error> {
+        let afids = self.afids.lock().unwrap();
+        let xattrs = self.xattrs.lock().unwrap();
+        if fids.contains_key(&fid) || afids.contains_key(&fid) || xattrs.contains_key(&fid) {
+            err("duplicate fid")
+        } else if fids.len() + afids.len() + xattrs.len() >= self.max_fids {
+            err("too many fids")
+        } else if self.limit.as_ref().is_some_and(|limit| !limit.acquire()) {
+            err("too many fids on server")
+        } else {
+            if let Some(metrics) = &self.metrics {
+                metrics.fid_added();
+            }
+            Ok(())
+        }
+    }
+
+    // Stop counting a fid that has been removed
+    fn release_fid(&self) {
+        if let Some(limit) = &self.limit {
+            limit.release();
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.fid_removed();
+        }
+    }
+
+    // Insert an entry for a newly created fid
+    fn insert_new(&self, fid: Fid, entry: FidEntry<H>) -> Result<(), Error> {
+        let mut fids = self.fids.lock().unwrap();
+        if let Err(err) = self.add_fid(&fids, fid) {
+            drop(fids);
+            self.clunk_entry(&entry);
+            return Err(err);
+        }
+        fids.insert(fid, entry);
+        Ok(())
+    }
+
+    // Remove an entry, without clunking it
+    fn remove_entry(&self, fid: Fid) -> Option<FidEntry<H>> {
+        let entry = self.fids.lock().unwrap().remove(&fid)?;
+        self.release_fid();
+        Some(entry)
+    }
+
+    fn remove_auth(&self, fid: Fid) -> Option<Arc<A::Conversation>> {
+        let conv = self.afids.lock().unwrap().remove(&fid)?;
+        self.release_fid();
+        Some(conv)
+    }
+
+    fn remove_xattr(&self, fid: Fid) -> Option<Arc<XattrFid<H>>> {
+        let xattr = self.xattrs.lock().unwrap().remove(&fid)?;
+        self.release_fid();
+        Some(xattr)
+    }
+
+    // Replace the entry for an existing fid
+    fn replace(&self, fid: Fid, entry: FidEntry<H>) -> Result<(), Error> {
+        let mut fids = self.fids.lock().unwrap();
+        let Some(old) = fids.get_mut(&fid) else {
+            // Clunked while the request was being handled
+            drop(fids);
+            self.clunk_entry(&entry);
+            return err("unknown fid");
+        };
+        let old = std::mem::replace(old, entry);
+        drop(fids);
+        self.clunk_entry(&old);
+        Ok(())
+    }
+
+    fn clunk_entry(&self, entry: &FidEntry<H>) {
+        let open = entry.open.as_ref().map(|(_, open)| &**open);
+        self.handler.clunk(&entry.user, &entry.file, open);
+    }
+
+    async fn do_auth(&self, tauth: &TAuth<'_>) -> Result<RAuth, Error> {
+        if self.in_use(tauth.afid) {
+            return err("duplicate fid");
+        }
+        let conv = self.auth.start(tauth.uname, tauth.aname).await?;
+        let fids = self.fids.lock().unwrap();
+        self.add_fid(&fids, tauth.afid)?;
+        self.afids
+            .lock()
+            .unwrap()
+            .insert(tauth.afid, Arc::new(conv));
+        let aqid = Qid {
+            type_: QTAUTH,
+            vers: 0,
+            path: 0,
+        };
+        Ok(RAuth { aqid })
+    }
+
+    async fn do_attach(&self, tattach: &TLAttach<'_>) -> Result<RAttach, Error> {
+        if self.in_use(tattach.fid) {
+            return err("duplicate fid");
+        }
+        let conv = if tattach.afid == Fid::NOFID {
+            None
+        } else {
+            match self.get_auth(tattach.afid) {
+                Some(conv) => Some(conv),
+                None => return err("unknown afid"),
+            }
+        };
+        let mut user = self
+            .auth
+            .attach(conv.as_deref(), tattach.uname, tattach.aname)?;
+        if user.uid.is_none() && tattach.n_uname != NONUNAME {
+            user.uid = Some(tattach.n_uname);
+        }
+        let (file, qid) = self.handler.attach(&user).await?;
+        let entry = FidEntry {
+            user: Arc::new(user),
+            file,
+            qid,
+            open: None,
+        };
+        self.insert_new(tattach.fid, entry)?;
+        Ok(RAttach { qid })
+    }
+
+    async fn do_walk(&self, twalk: &TWalk<'_>) -> Result<RWalk, Error> {
+        if twalk.wnames.len() > MAXWELEM {
+            return err("too many names in walk");
+        }
+        let entry = self.get(twalk.fid)?;
+        if entry.open.is_some() {
+            return err("cannot walk an open fid");
+        }
+        if twalk.newfid != twalk.fid && self.in_use(twalk.newfid) {
+            return err("duplicate fid");
+        }
+
+        let mut file = entry.file.clone();
+        let mut qid = entry.qid;
+        let mut qids = Vec::new();
+        for name in &twalk.wnames {
+            let res = if !qid.is_dir() {
+                err("walk in non-directory")
+            } else if !valid_name(name) {
+                err("file not found")
+            } else {
+                self.handler.walk(&entry.user, &file, name).await
+            };
+            match res {
+                Ok((next_file, next_qid)) => {
+                    file = next_file;
+                    qid = next_qid;
+                    qids.push(qid);
+                }
+                Err(err) if qids.is_empty() => return Err(err),
+                // Partial walk; newfid isn't affected
+                Err(_) => return Ok(RWalk { qids }),
+            }
+        }
+
+        let entry = FidEntry {
+            user: entry.user,
+            file,
+            qid,
+            open: None,
+        };
+        if twalk.newfid != twalk.fid {
+            self.insert_new(twalk.newfid, entry)?;
+        } else if !twalk.wnames.is_empty() {
+            self.replace(twalk.fid, entry)?;
+        }
+        Ok(RWalk { qids })
+    }
+
+    async fn do_open(&self, topen: &TOpen) -> Result<ROpen, Error> {
+        let entry = self.get(topen.fid)?;
+        if entry.open.is_some() {
+            return err("fid already open");
+        }
+        let rw = topen.mode & 3;
+        if entry.qid.is_dir() && ((rw != OREAD && rw != OEXEC) || topen.mode & OTRUNC != 0) {
+            return err("is a directory");
+        }
+        let (open, ropen) = self
+            .handler
+            .open(&entry.user, &entry.file, topen.mode)
+            .await?;
+        let open = Arc::new(open);
+
+        let mut fids = self.fids.lock().unwrap();
+        match fids.get_mut(&topen.fid) {
+            Some(entry) if entry.open.is_none() => {
+                entry.open = Some((topen.mode, open));
+                entry.qid = ropen.qid;
+                Ok(ropen)
+            }
+            _ => {
+                drop(fids);
+                self.handler.clunk(&entry.user, &entry.file, Some(&open));
+                err("fid changed while opening")
+            }
+        }
+    }
+
+    async fn do_create(&self, tcreate: &TCreate<'_>) -> Result<RCreate, Error> {
+        let entry = self.get(tcreate.fid)?;
+        if entry.open.is_some() {
+            return err("fid already open");
+        }
+        if !entry.qid.is_dir() {
+            return err("create in non-directory");
+        }
+        if !valid_name(tcreate.name) || tcreate.name == ".." {
+            return err("illegal name");
+        }
+        let (file, open, rcreate) = self
+            .handler
+            .create(
+                &entry.user,
+                &entry.file,
+                tcreate.name,
+                tcreate.perm,
+                tcreate.mode,
+            )
+            .await?;
+        let entry = FidEntry {
+            user: entry.user,
+            file,
+            qid: rcreate.qid,
+            open: Some((tcreate.mode, Arc::new(open))),
+        };
+        self.replace(tcreate.fid, entry)?;
+        Ok(rcreate)
+    }
+
+    async fn do_read(&self, tread: &TRead) -> Result<Vec<u8>, Error> {
+        if let Some(conv) = self.get_auth(tread.fid) {
+            return self.auth.read(&conv, tread.offset, tread.count).await;
+        }
+        if let Some(xattr) = self.get_xattr(tread.fid) {
+            let XattrKind::Read(value) = &xattr.kind else {
+                return err("fid not open for reading");
+            };
+            let start = value
+                .len()
+                .min(tread.offset.try_into().unwrap_or(usize::MAX));
+            let end = value.len().min(start + tread.count as usize);
+            return Ok(value[start..end].to_vec());
+        }
+        let (entry, (mode, open)) = self.get_open(tread.fid)?;
+        if mode & 3 == OWRITE {
+            return err("fid not open for reading");
+        }
+        let mut data = self
+            .handler
+            .read(&entry.user, &entry.file, &open, tread.offset, tread.count)
+            .await?;
+        data.truncate(tread.count as usize);
+        Ok(data)
+    }
+
+    async fn do_write(&self, twrite: &TWrite<'_>) -> Result<RWrite, Error> {
+        if let Some(conv) = self.get_auth(twrite.fid) {
+            let count = self.auth.write(&conv, twrite.offset, twrite.data).await?;
+            return Ok(RWrite { count });
+        }
+        if let Some(xattr) = self.get_xattr(twrite.fid) {
+            let XattrKind::Write { size, value, .. } = &xattr.kind else {
+                return err("fid not open for writing");
+            };
+            let end = twrite.offset.checked_add(twrite.data.len() as u64);
+            let Some(end) = end.filter(|end| end <= size) else {
+                return err("xattr value too long");
+            };
+            let mut value = value.lock().unwrap();
+            if value.len() < end as usize {
+                value.resize(end as usize, 0);
+            }
+            value[twrite.offset as usize..end as usize].copy_from_slice(twrite.data);
+            return Ok(RWrite {
+                count: twrite.data.len() as u32,
+            });
+        }
+        let (entry, (mode, open)) = self.get_open(twrite.fid)?;
+        if mode & 3 != OWRITE && mode & 3 != ORDWR {
+            return err("fid not open for writing");
+        }
+        let count = self
+            .handler
+            .write(&entry.user, &entry.file, &open, twrite.offset, twrite.data)
+            .await?;
+        Ok(RWrite { count })
+    }
+
+    async fn do_remove(&self, tremove: &TRemove) -> Result<RRemove, Error> {
+        if self.remove_auth(tremove.fid).is_some() {
+            return err("cannot remove an auth fid");
+        }
+        if let Some(xattr) = self.remove_xattr(tremove.fid) {
+            self.handler.clunk(&xattr.user, &xattr.file, None);
+            return err("cannot remove an xattr fid");
+        }
+        let Some(entry) = self.remove_entry(tremove.fid) else {
+            return err("unknown fid");
+        };
+        let open = entry.open.as_ref().map(|(_, open)| &**open);
+        let res = self.handler.remove(&entry.user, &entry.file, open).await;
+        self.clunk_entry(&entry);
+        res.map(|()| RRemove)
+    }
+
+    async fn do_stat(&self, tstat: &TStat) -> Result<StatBuf, Error> {
+        let entry = self.get(tstat.fid)?;
+        self.handler.stat(&entry.user, &entry.file).await
+    }
+
+    async fn do_wstat(&self, twstat: &TWStat<'_>) -> Result<RWStat, Error> {
+        let entry = self.get(twstat.fid)?;
+        let stat = Stat::from_bytes(twstat.stat)?;
+        self.handler.wstat(&entry.user, &entry.file, &stat).await?;
+        Ok(RWStat)
+    }
+
+    async fn do_clunk(&self, tclunk: &TClunk) -> Result<RClunk, Error> {
+        if self.remove_auth(tclunk.fid).is_some() {
+            return Ok(RClunk);
+        }
+        if let Some(xattr) = self.remove_xattr(tclunk.fid) {
+            let res = self.set_xattr(&xattr).await;
+            self.handler.clunk(&xattr.user, &xattr.file, None);
+            return res.map(|()| RClunk);
+        }
+        match self.remove_entry(tclunk.fid) {
+            Some(entry) => {
+                self.clunk_entry(&entry);
+                Ok(RClunk)
+            }
+            None => err("unknown fid"),
+        }
+    }
+
+    // Set the attribute written to an xattr fid made by `TXattrCreate`
+    async fn set_xattr(&self, xattr: &XattrFid<H>) -> Result<(), Error> {
+        let XattrKind::Write {
+            name,
+            size,
+            flags,
+            value,
+        } = &xattr.kind
+        else {
+            return Ok(());
+        };
+        let value = std::mem::take(&mut *value.lock().unwrap());
+        if value.len() as u64 != *size {
+            return err("xattr value too short");
+        }
+        // Linux removes attributes by setting them with no value
+        let value = (*size != 0).then_some(&value[..]);
+        self.handler
+            .xattr_set(&xattr.user, &xattr.file, name, value, *flags)
+            .await
+    }
+
+    async fn do_lopen(&self, tlopen: &TLOpen) -> Result<RLOpen, Error> {
+        let entry = self.get(tlopen.fid)?;
+        if entry.open.is_some() {
+            return err("fid already open");
+        }
+        // The access mode has the same values as that of `TOpen`
+        let mode = (tlopen.flags & 3) as u8;
+        if entry.qid.is_dir() && (mode != OREAD || tlopen.flags & DOTL_TRUNC != 0) {
+            return err("is a directory");
+        }
+        let (open, rlopen) = self
+            .handler
+            .lopen(&entry.user, &entry.file, tlopen.flags)
+            .await?;
+        let open = Arc::new(open);
+
+        let mut fids = self.fids.lock().unwrap();
+        match fids.get_mut(&tlopen.fid) {
+            Some(entry) if entry.open.is_none() => {
+                entry.open = Some((mode, open));
+                entry.qid = rlopen.qid;
+                Ok(rlopen)
+            }
+            _ => {
+                drop(fids);
+                self.handler.clunk(&entry.user, &entry.file, Some(&open));
+                err("fid changed while opening")
+            }
+        }
+    }
+
+    async fn do_lcreate(&self, tlcreate: &TLCreate<'_>) -> Result<RLCreate, Error> {
+        let entry = self.get_dir(tlcreate.fid, tlcreate.name)?;
+        if entry.open.is_some() {
+            return err("fid already open");
+        }
+        let (file, open, rlcreate) = self
+            .handler
+            .lcreate(&entry.user, &entry.file, tlcreate)
+            .await?;
+        let entry = FidEntry {
+            user: entry.user,
+            file,
+            qid: rlcreate.qid,
+            open: Some(((tlcreate.flags & 3) as u8, Arc::new(open))),
+        };
+        self.replace(tlcreate.fid, entry)?;
+        Ok(rlcreate)
+    }
+
+    async fn do_getattr(&self, tgetattr: &TGetAttr) -> Result<RGetAttr, Error> {
+        let entry = self.get(tgetattr.fid)?;
+        let open = entry.open.as_ref().map(|(_, open)| &**open);
+        self.handler
+            .getattr(&entry.user, &entry.file, open, tgetattr.request_mask)
+            .await
+    }
+
+    async fn do_setattr(&self, tsetattr: &TSetAttr) -> Result<RSetAttr, Error> {
+        let entry = self.get(tsetattr.fid)?;
+        let open = entry.open.as_ref().map(|(_, open)| &**open);
+        self.handler
+            .setattr(&entry.user, &entry.file, open, tsetattr)
+            .await?;
+        Ok(RSetAttr)
+    }
+
+    async fn do_readdir(&self, treaddir: &TReadDir) -> Result<Vec<u8>, Error> {
+        let (entry, (_, open)) = self.get_open(treaddir.fid)?;
+        if !entry.qid.is_dir() {
+            return err("not a directory");
+        }
+        self.handler
+            .readdir(
+                &entry.user,
+                &entry.file,
+                &open,
+                treaddir.offset,
+                treaddir.count,
+            )
+            .await
+    }
+
+    async fn do_statfs(&self, tstatfs: &TStatFs) -> Result<RStatFs, Error> {
+        let entry = self.get(tstatfs.fid)?;
+        self.handler.statfs(&entry.user, &entry.file).await
+    }
+
+    async fn do_symlink(&self, tsymlink: &TSymlink<'_>) -> Result<RSymlink, Error> {
+        let dir = self.get_dir(tsymlink.dfid, tsymlink.name)?;
+        let qid = self
+            .handler
+            .symlink(
+                &dir.user,
+                &dir.file,
+                tsymlink.name,
+                tsymlink.symtgt,
+                tsymlink.gid,
+            )
+            .await?;
+        Ok(RSymlink { qid })
+    }
+
+    async fn do_mknod(&self, tmknod: &TMkNod<'_>) -> Result<RMkNod, Error> {
+        let dir = self.get_dir(tmknod.dfid, tmknod.name)?;
+        let qid = self.handler.mknod(&dir.user, &dir.file, tmknod).await?;
+        Ok(RMkNod { qid })
+    }
+
+    async fn do_mkdir(&self, tmkdir: &TMkDir<'_>) -> Result<RMkDir, Error> {
+        let dir = self.get_dir(tmkdir.dfid, tmkdir.name)?;
+        let qid = self
+            .handler
+            .mkdir(&dir.user, &dir.file, tmkdir.name, tmkdir.mode, tmkdir.gid)
+            .await?;
+        Ok(RMkDir { qid })
+    }
+
+    async fn do_readlink(&self, treadlink: &TReadLink) -> Result<String, Error> {
+        let entry = self.get(treadlink.fid)?;
+        self.handler.readlink(&entry.user, &entry.file).await
+    }
+
+    async fn do_link(&self, tlink: &TLink<'_>) -> Result<RLink, Error> {
+        let dir = self.get_dir(tlink.dfid, tlink.name)?;
+        let entry = self.get(tlink.fid)?;
+        self.handler
+            .link(&dir.user, &dir.file, &entry.file, tlink.name)
+            .await?;
+        Ok(RLink)
+    }
+
+    async fn do_rename(&self, trename: &TRename<'_>) -> Result<RRename, Error> {
+        let entry = self.get(trename.fid)?;
+        let dir = self.get_dir(trename.dfid, trename.name)?;
+        self.handler
+            .rename(&entry.user, &entry.file, &dir.file, trename.name)
+            .await?;
+        Ok(RRename)
+    }
+
+    async fn do_renameat(&self, trenameat: &TRenameAt<'_>) -> Result<RRenameAt, Error> {
+        let olddir = self.get_dir(trenameat.olddirfid, trenameat.oldname)?;
+        let newdir = self.get_dir(trenameat.newdirfid, trenameat.newname)?;
+        self.handler
+            .renameat(
+                &olddir.user,
+                &olddir.file,
+                trenameat.oldname,
+                &newdir.file,
+                trenameat.newname,
+            )
+            .await?;
+        Ok(RRenameAt)
+    }
+
+    async fn do_unlinkat(&self, tunlinkat: &TUnlinkAt<'_>) -> Result<RUnlinkAt, Error> {
+        let dir = self.get_dir(tunlinkat.dirfid, tunlinkat.name)?;
+        self.handler
+            .unlinkat(&dir.user, &dir.file, tunlinkat.name, tunlinkat.flags)
+            .await?;
+        Ok(RUnlinkAt)
+    }
+
+    async fn do_fsync(&self, tfsync: &TFSync) -> Result<RFSync, Error> {
+        let (entry, (_, open)) = self.get_open(tfsync.fid)?;
+        self.handler
+            .fsync(&entry.user, &entry.file, &open, tfsync.datasync != 0)
+            .await?;
+        Ok(RFSync)
+    }
+
+    async fn do_lock(&self, tlock: &TLock<'_>) -> Result<RLock, Error> {
+        let (entry, (_, open)) = self.get_open(tlock.fid)?;
+        let status = self
+            .handler
+            .lock(&entry.user, &entry.file, &open, tlock)
+            .await?;
+        Ok(RLock { status })
+    }
+
+    async fn do_getlock(&self, tgetlock: &TGetLock<'_>) -> Result<RGetLock<'static>, Error> {
+        let (entry, (_, open)) = self.get_open(tgetlock.fid)?;
+        self.handler
+            .getlock(&entry.user, &entry.file, &open, tgetlock)
+            .await
+    }
+
+    async fn do_xattrwalk(&self, txattrwalk: &TXattrWalk<'_>) -> Result<RXattrWalk, Error> {
+        let entry = self.get(txattrwalk.fid)?;
+        if self.in_use(txattrwalk.newfid) {
+            return err("duplicate fid");
+        }
+        let value = self
+            .handler
+            .xattr_get(&entry.user, &entry.file, txattrwalk.name)
+            .await?;
+        let size = value.len() as u64;
+        let xattr = XattrFid {
+            user: entry.user,
+            file: entry.file,
+            kind: XattrKind::Read(value),
+        };
+        let fids = self.fids.lock().unwrap();
+        self.add_fid(&fids, txattrwalk.newfid)?;
+        self.xattrs
+            .lock()
+            .unwrap()
+            .insert(txattrwalk.newfid, Arc::new(xattr));
+        Ok(RXattrWalk { size })
+    }
+
+    async fn do_xattrcreate(&self, txattrcreate: &TXattrCreate<'_>) -> Result<RXattrCreate, Error> {
+        if txattrcreate.attr_size > XATTR_SIZE_MAX {
+            return err("xattr value too long");
+        }
+        let mut fids = self.fids.lock().unwrap();
+        match fids.get(&txattrcreate.fid) {
+            Some(entry) if entry.open.is_none() => {}
+            Some(_) => return err("fid already open"),
+            None => return err("unknown fid"),
+        }
+        // The fid becomes an xattr fid, without being counted again
+        let entry = fids.remove(&txattrcreate.fid).unwrap();
+        let xattr = XattrFid {
+            user: entry.user,
+            file: entry.file,
+            kind: XattrKind::Write {
+                name: txattrcreate.name.to_string(),
+                size: txattrcreate.attr_size,
+                flags: txattrcreate.flags,
+                value: Mutex::new(Vec::new()),
+            },
+        };
+        let _afids = self.afids.lock().unwrap();
+        self.xattrs
+            .lock()
+            .unwrap()
+            .insert(txattrcreate.fid, Arc::new(xattr));
+        Ok(RXattrCreate)
+    }
+}
+
+impl<H: FileHandler, A: Authenticator> Drop for FidServer<H, A> {
+    fn drop(&mut self) {
+        for _ in std::mem::take(self.afids.get_mut().unwrap()) {
+            self.release_fid();
+        }
+        for (_, xattr) in std::mem::take(self.xattrs.get_mut().unwrap()) {
+            self.release_fid();
+            self.handler.clunk(&xattr.user, &xattr.file, None);
+        }
+        for (_, entry) in std::mem::take(self.fids.get_mut().unwrap()) {
+            self.release_fid();
+            let open = entry.open.as_ref().map(|(_, open)| &**open);
+            self.handler.clunk(&entry.user, &entry.file, open);
+        }
+    }
+}
+
+impl<H: FileHandler, A: Authenticator> Filesystem for FidServer<H, A> {
+    const DOTL: bool = H::DOTL;
+
+    async fn auth(&self, tauth: TAuth<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_auth(&tauth).await)
+    }
+
+    async fn attach(&self, tattach: TAttach<'_>, replier: Replier) -> Replied {
+        let tlattach = TLAttach {
+            fid: tattach.fid,
+            afid: tattach.afid,
+            uname: tattach.uname,
+            aname: tattach.aname,
+            n_uname: NONUNAME,
+        };
+        replier.result(self.do_attach(&tlattach).await)
+    }
+
+    async fn walk(&self, twalk: TWalk<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_walk(&twalk).await)
+    }
+
+    async fn open(&self, topen: TOpen, replier: Replier) -> Replied {
+        replier.result(self.do_open(&topen).await)
+    }
+
+    async fn create(&self, tcreate: TCreate<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_create(&tcreate).await)
+    }
+
+    async fn read(&self, tread: TRead, replier: Replier) -> Replied {
+        match self.do_read(&tread).await {
+            Ok(data) => replier.reply(RRead { data: &data }),
+            Err(err) => replier.result::<RRead>(Err(err)),
+        }
+    }
+
+    async fn write(&self, twrite: TWrite<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_write(&twrite).await)
+    }
+
+    async fn clunk(&self, tclunk: TClunk, replier: Replier) -> Replied {
+        replier.result(self.do_clunk(&tclunk).await)
+    }
+
+    async fn remove(&self, tremove: TRemove, replier: Replier) -> Replied {
+        replier.result(self.do_remove(&tremove).await)
+    }
+
+    async fn stat(&self, tstat: TStat, replier: Replier) -> Replied {
+        match self.do_stat(&tstat).await {
+            Ok(stat) => replier.reply(RStat {
+                stat: stat.as_stat(),
+            }),
+            Err(err) => replier.result::<RStat>(Err(err)),
+        }
+    }
+
+    async fn wstat(&self, twstat: TWStat<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_wstat(&twstat).await)
+    }
+
+    async fn lauth(&self, tlauth: TLAuth<'_>, replier: Replier) -> Replied {
+        let tauth = TAuth {
+            afid: tlauth.afid,
+            uname: tlauth.uname,
+            aname: tlauth.aname,
+        };
+        replier.result(self.do_auth(&tauth).await)
+    }
+
+    async fn lattach(&self, tlattach: TLAttach<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_attach(&tlattach).await)
+    }
+
+    async fn statfs(&self, tstatfs: TStatFs, replier: Replier) -> Replied {
+        replier.result(self.do_statfs(&tstatfs).await)
+    }
+
+    async fn lopen(&self, tlopen: TLOpen, replier: Replier) -> Replied {
+        replier.result(self.do_lopen(&tlopen).await)
+    }
+
+    async fn lcreate(&self, tlcreate: TLCreate<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_lcreate(&tlcreate).await)
+    }
+
+    async fn symlink(&self, tsymlink: TSymlink<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_symlink(&tsymlink).await)
+    }
+
+    async fn mknod(&self, tmknod: TMkNod<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_mknod(&tmknod).await)
+    }
+
+    async fn rename(&self, trename: TRename<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_rename(&trename).await)
+    }
+
+    async fn readlink(&self, treadlink: TReadLink, replier: Replier) -> Replied {
+        match self.do_readlink(&treadlink).await {
+            Ok(target) => replier.reply(RReadLink { target: &target }),
+            Err(err) => replier.result::<RReadLink>(Err(err)),
+        }
+    }
+
+    async fn getattr(&self, tgetattr: TGetAttr, replier: Replier) -> Replied {
+        replier.result(self.do_getattr(&tgetattr).await)
+    }
+
+    async fn setattr(&self, tsetattr: TSetAttr, replier: Replier) -> Replied {
+        replier.result(self.do_setattr(&tsetattr).await)
+    }
+
+    async fn xattrwalk(&self, txattrwalk: TXattrWalk<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_xattrwalk(&txattrwalk).await)
+    }
+
+    async fn xattrcreate(&self, txattrcreate: TXattrCreate<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_xattrcreate(&txattrcreate).await)
+    }
+
+    async fn readdir(&self, treaddir: TReadDir, replier: Replier) -> Replied {
+        match self.do_readdir(&treaddir).await {
+            Ok(data) => replier.reply(RReadDir { data: &data }),
+            Err(err) => replier.result::<RReadDir>(Err(err)),
+        }
+    }
+
+    async fn fsync(&self, tfsync: TFSync, replier: Replier) -> Replied {
+        replier.result(self.do_fsync(&tfsync).await)
+    }
+
+    async fn lock(&self, tlock: TLock<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_lock(&tlock).await)
+    }
+
+    async fn getlock(&self, tgetlock: TGetLock<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_getlock(&tgetlock).await)
+    }
+
+    async fn link(&self, tlink: TLink<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_link(&tlink).await)
+    }
+
+    async fn mkdir(&self, tmkdir: TMkDir<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_mkdir(&tmkdir).await)
+    }
+
+    async fn renameat(&self, trenameat: TRenameAt<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_renameat(&trenameat).await)
+    }
+
+    async fn unlinkat(&self, tunlinkat: TUnlinkAt<'_>, replier: Replier) -> Replied {
+        replier.result(self.do_unlinkat(&tunlinkat).await)
+    }
+}
@@ -37,7 +37,7 @@ impl Header {
     pub async fn async_write<T: tokio::io::AsyncWrite + Unpin>(
         &self,
         mut writer: T,
-    ) -> io::Result<()> {
+    ) -> std::io::Result<()> {
         use tokio::io::AsyncWriteExt;
         writer.write_all(&self.size.to_le_bytes()).await?;
         writer.write_all(&[self.type_]).await?;
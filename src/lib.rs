@@ -13,19 +13,122 @@
 extern crate alloc;
 
 #[cfg(not(feature = "std"))]
-use alloc::vec::Vec;
+use alloc::{string::String, vec::Vec};
 use core::str;
 
 mod error;
 pub use error::Error;
 mod header;
 pub use header::Header;
+mod dotl;
+pub use dotl::*;
 #[cfg(feature = "std")]
 mod sync_client;
 #[cfg(feature = "std")]
 pub use sync_client::SyncClient;
+#[cfg(feature = "futures-io")]
+mod async_client;
+#[cfg(feature = "std")]
+mod server;
+#[cfg(feature = "std")]
+pub use server::{ename_errno, CancelToken, Filesystem, Replied, Replier, DEFAULT_MSIZE};
+#[cfg(feature = "std")]
+mod metrics;
+#[cfg(feature = "std")]
+pub use metrics::{Metrics, OpStats, LATENCY_BOUNDS};
+#[cfg(feature = "std")]
+mod auth;
+#[cfg(feature = "std")]
+pub use auth::{Authenticator, Identity, NoAuth};
+#[cfg(feature = "std")]
+mod fid;
+#[cfg(feature = "std")]
+pub use fid::{FidLimit, FidServer, FileHandler};
+#[cfg(feature = "std")]
+mod dir_reader;
+#[cfg(feature = "std")]
+pub use dir_reader::DirReader;
+#[cfg(feature = "std")]
+mod ramfs;
+#[cfg(feature = "std")]
+pub use ramfs::{RamFile, RamOpen, Ramfs};
+#[cfg(feature = "std")]
+mod synthetic;
+#[cfg(feature = "std")]
+pub use synthetic::{
+    ctl_file, text_file, BoxFuture, Events, FileOps, OpenFile, SynthFile, SynthOpen, SynthTree,
+};
+#[cfg(feature = "std")]
+mod namespace;
+#[cfg(feature = "std")]
+pub use namespace::{MountFlag, Namespace, NsFile, NsOpen};
+#[cfg(feature = "std")]
+mod upstream;
+#[cfg(feature = "std")]
+pub use upstream::{Stream, Upstream};
+#[cfg(feature = "std")]
+mod proxy;
+#[cfg(feature = "std")]
+pub use proxy::{NoPolicy, Policy, Proxy};
+#[cfg(all(feature = "export", unix))]
+mod export;
+#[cfg(all(feature = "export", unix))]
+pub use export::{Export, ExportFile, ExportOpen};
+#[cfg(feature = "std")]
+mod blocking_server;
+#[cfg(feature = "std")]
+pub use blocking_server::BlockingServer;
 #[cfg(feature = "tokio")]
 mod tokio_server;
+#[cfg(feature = "futures-io")]
+pub use async_client::AsyncClient;
+#[cfg(feature = "tokio")]
+pub use tokio_server::TokioServer;
+#[cfg(all(feature = "futures-io", feature = "tokio"))]
+mod remote_file;
+#[cfg(all(feature = "futures-io", feature = "tokio"))]
+pub use remote_file::{RemoteFile, TokioCompat};
+
+/// Tag used for `TVersion`
+pub const NOTAG: u16 = 0xffff;
+/// Maximum number of names in a `TWalk`
+pub const MAXWELEM: usize = 16;
+
+// Open modes
+pub const OREAD: u8 = 0;
+pub const OWRITE: u8 = 1;
+pub const ORDWR: u8 = 2;
+pub const OEXEC: u8 = 3;
+pub const OTRUNC: u8 = 0x10;
+pub const ORCLOSE: u8 = 0x40;
+
+// Bits of `Qid::type_`
+pub const QTDIR: u8 = 0x80;
+pub const QTAPPEND: u8 = 0x40;
+pub const QTEXCL: u8 = 0x20;
+pub const QTAUTH: u8 = 0x08;
+pub const QTTMP: u8 = 0x04;
+/// Symlink, in 9P2000.u and 9P2000.L
+pub const QTSYMLINK: u8 = 0x02;
+pub const QTFILE: u8 = 0x00;
+
+// Bits of `Stat::mode`; the high bits match those of `Qid::type_`
+pub const DMDIR: u32 = 0x8000_0000;
+pub const DMAPPEND: u32 = 0x4000_0000;
+pub const DMEXCL: u32 = 0x2000_0000;
+pub const DMAUTH: u32 = 0x0800_0000;
+pub const DMTMP: u32 = 0x0400_0000;
+// File types and modes added by 9P2000.u
+pub const DMSYMLINK: u32 = 0x0200_0000;
+pub const DMDEVICE: u32 = 0x0080_0000;
+pub const DMNAMEDPIPE: u32 = 0x0020_0000;
+pub const DMSOCKET: u32 = 0x0010_0000;
+pub const DMSETUID: u32 = 0x0008_0000;
+pub const DMSETGID: u32 = 0x0004_0000;
+
+/// Space reserved for the header of a `TWrite` or `RRead`; the largest payload
+/// that fits in a message is `msize - IOHDRSZ`
+pub const IOHDRSZ: u32 = 24;
 
 // XXX
 pub fn parse_dir(mut bytes: &[u8]) -> Result<Vec<Stat<'_>>, Error> {
@@ -110,9 +213,50 @@ fn end_of_message<T>(bytes: &[u8], value: T) -> Result<T, Error> {
     }
 }
 
-// Defined by fcall.h
+// Defined by fcall.h, and Linux's net/9p/9p.h for 9P2000.L
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum MessageType {
+    // Tlerror (6) is invalid
+    RLError = 7,
+    TStatFs = 8,
+    RStatFs = 9,
+    TLOpen = 12,
+    RLOpen = 13,
+    TLCreate = 14,
+    RLCreate = 15,
+    TSymlink = 16,
+    RSymlink = 17,
+    TMkNod = 18,
+    RMkNod = 19,
+    TRename = 20,
+    RRename = 21,
+    TReadLink = 22,
+    RReadLink = 23,
+    TGetAttr = 24,
+    RGetAttr = 25,
+    TSetAttr = 26,
+    RSetAttr = 27,
+    TXattrWalk = 30,
+    RXattrWalk = 31,
+    TXattrCreate = 32,
+    RXattrCreate = 33,
+    TReadDir = 40,
+    RReadDir = 41,
+    TFSync = 50,
+    RFSync = 51,
+    TLock = 52,
+    RLock = 53,
+    TGetLock = 54,
+    RGetLock = 55,
+    TLink = 70,
+    RLink = 71,
+    TMkDir = 72,
+    RMkDir = 73,
+    TRenameAt = 74,
+    RRenameAt = 75,
+    TUnlinkAt = 76,
+    RUnlinkAt = 77,
     TVersion = 100,
     RVersion = 101,
     TAuth = 102,
@@ -143,6 +287,82 @@ pub enum MessageType {
     RWStat = 127,
 }
 
+impl TryFrom<u8> for MessageType {
+    type Error = Error;
+
+    fn try_from(type_: u8) -> Result<Self, Error> {
+        Ok(match type_ {
+            7 => Self::RLError,
+            8 => Self::TStatFs,
+            9 => Self::RStatFs,
+            12 => Self::TLOpen,
+            13 => Self::RLOpen,
+            14 => Self::TLCreate,
+            15 => Self::RLCreate,
+            16 => Self::TSymlink,
+            17 => Self::RSymlink,
+            18 => Self::TMkNod,
+            19 => Self::RMkNod,
+            20 => Self::TRename,
+            21 => Self::RRename,
+            22 => Self::TReadLink,
+            23 => Self::RReadLink,
+            24 => Self::TGetAttr,
+            25 => Self::RGetAttr,
+            26 => Self::TSetAttr,
+            27 => Self::RSetAttr,
+            30 => Self::TXattrWalk,
+            31 => Self::RXattrWalk,
+            32 => Self::TXattrCreate,
+            33 => Self::RXattrCreate,
+            40 => Self::TReadDir,
+            41 => Self::RReadDir,
+            50 => Self::TFSync,
+            51 => Self::RFSync,
+            52 => Self::TLock,
+            53 => Self::RLock,
+            54 => Self::TGetLock,
+            55 => Self::RGetLock,
+            70 => Self::TLink,
+            71 => Self::RLink,
+            72 => Self::TMkDir,
+            73 => Self::RMkDir,
+            74 => Self::TRenameAt,
+            75 => Self::RRenameAt,
+            76 => Self::TUnlinkAt,
+            77 => Self::RUnlinkAt,
+            100 => Self::TVersion,
+            101 => Self::RVersion,
+            102 => Self::TAuth,
+            103 => Self::RAuth,
+            104 => Self::TAttach,
+            105 => Self::RAttach,
+            107 => Self::RError,
+            108 => Self::TFlush,
+            109 => Self::RFlush,
+            110 => Self::TWalk,
+            111 => Self::RWalk,
+            112 => Self::TOpen,
+            113 => Self::ROpen,
+            114 => Self::TCreate,
+            115 => Self::RCreate,
+            116 => Self::TRead,
+            117 => Self::RRead,
+            118 => Self::TWrite,
+            119 => Self::RWrite,
+            120 => Self::TClunk,
+            121 => Self::RClunk,
+            122 => Self::TRemove,
+            123 => Self::RRemove,
+            124 => Self::TStat,
+            125 => Self::RStat,
+            126 => Self::TWStat,
+            127 => Self::RWStat,
+            _ => return Err(Error::UnexpectedType(type_)),
+        })
+    }
+}
+
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Qid {
     pub type_: u8,
@@ -152,7 +372,14 @@ pub struct Qid {
 
 impl Qid {
     pub fn is_dir(&self) -> bool {
-        self.type_ & 0x80 != 0
+        self.type_ & QTDIR != 0
+    }
+
+    fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        writer.write(&[self.type_])?;
+        writer.write(&self.vers.to_le_bytes())?;
+        writer.write(&self.path.to_le_bytes())?;
+        Ok(())
     }
 }
 
@@ -178,18 +405,28 @@ pub struct Stat<'a> {
     pub uid: &'a str,
     pub gid: &'a str,
     pub muid: &'a str,
-    /* 9p2000.u
-    extension: &'a str,
-    n_uid: u32,
-    n_gid: u32,
-    n_muid: u32,
-    */
+    /// Only sent by 9P2000.u servers
+    pub dotu: Option<StatDotu<'a>>,
+}
+
+/// Fields 9P2000.u adds to the end of [`Stat`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StatDotu<'a> {
+    /// Target of a symlink, or "b major minor" or "c major minor" for a
+    /// device
+    pub extension: &'a str,
+    pub n_uid: u32,
+    pub n_gid: u32,
+    pub n_muid: u32,
 }
 
 impl<'a> Field<'a> for Stat<'a> {
-    fn parse(bytes: &'a [u8]) -> Result<(&[u8], Self), Error> {
-        let (bytes, size) = u16::parse(bytes)?; // TODO
-        let rest = &bytes[size as usize..];
+    fn parse(bytes: &'a [u8]) -> Result<(&'a [u8], Self), Error> {
+        let (bytes, size) = u16::parse(bytes)?;
+        if bytes.len() < size as usize {
+            return Err(Error::MessageLength);
+        }
+        let (bytes, rest) = bytes.split_at(size as usize);
         let (bytes, type_) = u16::parse(bytes)?;
         let (bytes, dev) = u32::parse(bytes)?;
         let (bytes, qid) = Qid::parse(bytes)?;
@@ -201,6 +438,20 @@ impl<'a> Field<'a> for Stat<'a> {
         let (bytes, uid) = <&str>::parse(bytes)?;
         let (bytes, gid) = <&str>::parse(bytes)?;
         let (bytes, muid) = <&str>::parse(bytes)?;
+        let dotu = if bytes.is_empty() {
+            None
+        } else {
+            let (bytes, extension) = <&str>::parse(bytes)?;
+            let (bytes, n_uid) = u32::parse(bytes)?;
+            let (bytes, n_gid) = u32::parse(bytes)?;
+            let (_bytes, n_muid) = u32::parse(bytes)?;
+            Some(StatDotu {
+                extension,
+                n_uid,
+                n_gid,
+                n_muid,
+            })
+        };
         Ok((
             rest,
             Stat {
@@ -215,14 +466,153 @@ impl<'a> Field<'a> for Stat<'a> {
                 uid,
                 gid,
                 muid,
+                dotu,
             },
         ))
     }
 }
 
+impl<'a> Stat<'a> {
+    /// A stat for `TWStat` with every field set to "don't touch", with the
+    /// 9P2000.u fields if `dotu`, which a 9P2000.u server expects
+    pub fn unchanged(dotu: bool) -> Self {
+        Self {
+            type_: !0,
+            dev: !0,
+            qid: Qid {
+                type_: !0,
+                vers: !0,
+                path: !0,
+            },
+            mode: !0,
+            atime: !0,
+            mtime: !0,
+            length: !0,
+            name: "",
+            uid: "",
+            gid: "",
+            muid: "",
+            dotu: dotu.then_some(StatDotu {
+                extension: "",
+                n_uid: !0,
+                n_gid: !0,
+                n_muid: !0,
+            }),
+        }
+    }
+
+    /// Parse a single stat entry, as found in `TWStat`
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, Error> {
+        let (bytes, stat) = Stat::parse(bytes)?;
+        end_of_message(bytes, stat)
+    }
+
+    /// Byte length of serialized stat, including its size field
+    pub fn size(&self) -> usize {
+        2 + 2
+            + 4
+            + 13
+            + 4
+            + 4
+            + 4
+            + 8
+            + 2
+            + self.name.len()
+            + 2
+            + self.uid.len()
+            + 2
+            + self.gid.len()
+            + 2
+            + self.muid.len()
+            + self.dotu.map_or(0, |dotu| 2 + dotu.extension.len() + 12)
+    }
+
+    /// Write serialized stat, including its size field
+    pub fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        writer.write(&(self.size() as u16 - 2).to_le_bytes())?;
+        writer.write(&self.type_.to_le_bytes())?;
+        writer.write(&self.dev.to_le_bytes())?;
+        self.qid.write(writer)?;
+        writer.write(&self.mode.to_le_bytes())?;
+        writer.write(&self.atime.to_le_bytes())?;
+        writer.write(&self.mtime.to_le_bytes())?;
+        writer.write(&self.length.to_le_bytes())?;
+        for s in [self.name, self.uid, self.gid, self.muid] {
+            writer.write(&(s.len() as u16).to_le_bytes())?;
+            writer.write(s.as_bytes())?;
+        }
+        if let Some(dotu) = &self.dotu {
+            writer.write(&(dotu.extension.len() as u16).to_le_bytes())?;
+            writer.write(dotu.extension.as_bytes())?;
+            writer.write(&dotu.n_uid.to_le_bytes())?;
+            writer.write(&dotu.n_gid.to_le_bytes())?;
+            writer.write(&dotu.n_muid.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Owned version of [`Stat`]
+#[derive(Clone, Debug, Default)]
+pub struct StatBuf {
+    pub type_: u16,
+    pub dev: u32,
+    pub qid: Qid,
+    pub mode: u32,
+    pub atime: u32,
+    pub mtime: u32,
+    pub length: u64,
+    pub name: String,
+    pub uid: String,
+    pub gid: String,
+    pub muid: String,
+}
+
+impl StatBuf {
+    pub fn as_stat(&self) -> Stat<'_> {
+        Stat {
+            type_: self.type_,
+            dev: self.dev,
+            qid: self.qid,
+            mode: self.mode,
+            atime: self.atime,
+            mtime: self.mtime,
+            length: self.length,
+            name: &self.name,
+            uid: &self.uid,
+            gid: &self.gid,
+            muid: &self.muid,
+            dotu: None,
+        }
+    }
+}
+
+impl From<&Stat<'_>> for StatBuf {
+    fn from(stat: &Stat<'_>) -> Self {
+        Self {
+            type_: stat.type_,
+            dev: stat.dev,
+            qid: stat.qid,
+            mode: stat.mode,
+            atime: stat.atime,
+            mtime: stat.mtime,
+            length: stat.length,
+            name: stat.name.into(),
+            uid: stat.uid.into(),
+            gid: stat.gid.into(),
+            muid: stat.muid.into(),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
 pub struct Fid(pub u32);
 
+impl Fid {
+    /// No fid, as used for `afid` when not authenticating
+    pub const NOFID: Fid = Fid(u32::MAX);
+}
+
 impl<'a> Field<'a> for Fid {
     fn parse(bytes: &'a [u8]) -> Result<(&'a [u8], Self), Error> {
         let (bytes, value) = u32::parse(bytes)?;
@@ -269,8 +659,10 @@ pub struct TVersion<'a> {
 impl<'a> Message<'a> for TVersion<'a> {
     const TYPE: MessageType = MessageType::TVersion;
 
-    fn parse(_body: &'a [u8]) -> Result<Self, Error> {
-        todo!()
+    fn parse(body: &'a [u8]) -> Result<Self, Error> {
+        let (body, msize) = u32::parse(body)?;
+        let (body, version) = <&str>::parse(body)?;
+        end_of_message(body, Self { msize, version })
     }
 
     fn size(&self) -> usize {
@@ -325,8 +717,11 @@ pub struct TAuth<'a> {
 impl<'a> Message<'a> for TAuth<'a> {
     const TYPE: MessageType = MessageType::TAuth;
 
-    fn parse(_body: &'a [u8]) -> Result<Self, Error> {
-        todo!()
+    fn parse(body: &'a [u8]) -> Result<Self, Error> {
+        let (body, afid) = Fid::parse(body)?;
+        let (body, uname) = <&str>::parse(body)?;
+        let (body, aname) = <&str>::parse(body)?;
+        end_of_message(body, Self { afid, uname, aname })
     }
 
     fn size(&self) -> usize {
@@ -357,17 +752,19 @@ impl<'a> Message<'a> for RAuth {
     }
 
     fn size(&self) -> usize {
-        todo!()
+        13
     }
 
-    fn write<T: Writer>(&self, _writer: &mut T) -> Result<(), T::Err> {
-        todo!()
+    fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        self.aqid.write(writer)
     }
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct RError<'a> {
     pub ename: &'a str,
+    /// Only sent by 9P2000.u servers
+    pub errno: Option<u32>,
 }
 
 impl<'a> Message<'a> for RError<'a> {
@@ -375,23 +772,57 @@ impl<'a> Message<'a> for RError<'a> {
 
     fn parse(body: &'a [u8]) -> Result<Self, Error> {
         let (mut body, ename) = <&str>::parse(body)?;
-        // XXX 9p2000.u
+        let mut errno = None;
         if body.len() == 4 {
-            let _errno;
-            (body, _errno) = u32::parse(body)?;
+            let n;
+            (body, n) = u32::parse(body)?;
+            errno = Some(n);
         }
-        end_of_message(body, RError { ename })
+        end_of_message(body, RError { ename, errno })
     }
 
     fn size(&self) -> usize {
-        todo!()
+        2 + self.ename.len() + self.errno.map_or(0, |_| 4)
     }
 
-    fn write<T: Writer>(&self, _writer: &mut T) -> Result<(), T::Err> {
-        todo!()
+    fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        writer.write(&(self.ename.len() as u16).to_le_bytes())?;
+        writer.write(self.ename.as_bytes())?;
+        if let Some(errno) = self.errno {
+            writer.write(&errno.to_le_bytes())?;
+        }
+        Ok(())
     }
 }
 
+#[derive(Clone, Debug, Default)]
+pub struct TFlush {
+    pub oldtag: u16,
+}
+
+impl<'a> Message<'a> for TFlush {
+    const TYPE: MessageType = MessageType::TFlush;
+
+    fn parse(body: &'a [u8]) -> Result<Self, Error> {
+        let (body, oldtag) = u16::parse(body)?;
+        end_of_message(body, TFlush { oldtag })
+    }
+
+    fn size(&self) -> usize {
+        2
+    }
+
+    fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        writer.write(&self.oldtag.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RFlush;
+
+impl_empty_message!(RFlush, MessageType::RFlush);
+
 #[derive(Clone, Debug, Default)]
 pub struct TAttach<'a> {
     pub fid: Fid,
@@ -407,7 +838,19 @@ impl<'a> Message<'a> for TAttach<'a> {
     const TYPE: MessageType = MessageType::TAttach;
 
     fn parse(body: &'a [u8]) -> Result<Self, Error> {
-        todo!()
+        let (body, fid) = Fid::parse(body)?;
+        let (body, afid) = Fid::parse(body)?;
+        let (body, uname) = <&str>::parse(body)?;
+        let (body, aname) = <&str>::parse(body)?;
+        end_of_message(
+            body,
+            Self {
+                fid,
+                afid,
+                uname,
+                aname,
+            },
+        )
     }
 
     fn size(&self) -> usize {
@@ -439,11 +882,11 @@ impl<'a> Message<'a> for RAttach {
     }
 
     fn size(&self) -> usize {
-        todo!()
+        13
     }
 
-    fn write<T: Writer>(&self, _writer: &mut T) -> Result<(), T::Err> {
-        todo!()
+    fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        self.qid.write(writer)
     }
 }
 
@@ -459,7 +902,23 @@ impl<'a> Message<'a> for TWalk<'a> {
     const TYPE: MessageType = MessageType::TWalk;
 
     fn parse(body: &'a [u8]) -> Result<Self, Error> {
-        todo!()
+        let (body, fid) = Fid::parse(body)?;
+        let (body, newfid) = Fid::parse(body)?;
+        let (mut body, len) = u16::parse(body)?;
+        let mut wnames = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let wname;
+            (body, wname) = <&str>::parse(body)?;
+            wnames.push(wname);
+        }
+        end_of_message(
+            body,
+            TWalk {
+                fid,
+                newfid,
+                wnames,
+            },
+        )
     }
 
     fn size(&self) -> usize {
@@ -501,11 +960,15 @@ impl<'a> Message<'a> for RWalk {
     }
 
     fn size(&self) -> usize {
-        todo!()
+        2 + 13 * self.qids.len()
     }
 
-    fn write<T: Writer>(&self, _writer: &mut T) -> Result<(), T::Err> {
-        todo!()
+    fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        writer.write(&(self.qids.len() as u16).to_le_bytes())?;
+        for qid in &self.qids {
+            qid.write(writer)?;
+        }
+        Ok(())
     }
 }
 
@@ -519,7 +982,9 @@ impl<'a> Message<'a> for TOpen {
     const TYPE: MessageType = MessageType::TOpen;
 
     fn parse(body: &'a [u8]) -> Result<Self, Error> {
-        todo!()
+        let (body, fid) = Fid::parse(body)?;
+        let (body, mode) = u8::parse(body)?;
+        end_of_message(body, TOpen { fid, mode })
     }
 
     fn size(&self) -> usize {
@@ -549,11 +1014,13 @@ impl<'a> Message<'a> for ROpen {
     }
 
     fn size(&self) -> usize {
-        todo!()
+        13 + 4
     }
 
-    fn write<T: Writer>(&self, _writer: &mut T) -> Result<(), T::Err> {
-        todo!()
+    fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        self.qid.write(writer)?;
+        writer.write(&self.iounit.to_le_bytes())?;
+        Ok(())
     }
 }
 
@@ -572,7 +1039,19 @@ impl<'a> Message<'a> for TCreate<'a> {
     const TYPE: MessageType = MessageType::TCreate;
 
     fn parse(body: &'a [u8]) -> Result<Self, Error> {
-        todo!()
+        let (body, fid) = Fid::parse(body)?;
+        let (body, name) = <&str>::parse(body)?;
+        let (body, perm) = u32::parse(body)?;
+        let (body, mode) = u8::parse(body)?;
+        end_of_message(
+            body,
+            TCreate {
+                fid,
+                name,
+                perm,
+                mode,
+            },
+        )
     }
 
     fn size(&self) -> usize {
@@ -605,11 +1084,13 @@ impl<'a> Message<'a> for RCreate {
     }
 
     fn size(&self) -> usize {
-        todo!()
+        13 + 4
     }
 
-    fn write<T: Writer>(&self, _writer: &mut T) -> Result<(), T::Err> {
-        todo!()
+    fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        self.qid.write(writer)?;
+        writer.write(&self.iounit.to_le_bytes())?;
+        Ok(())
     }
 }
 
@@ -656,11 +1137,13 @@ impl<'a> Message<'a> for RRead<'a> {
     }
 
     fn size(&self) -> usize {
-        todo!()
+        4 + self.data.len()
     }
 
-    fn write<T: Writer>(&self, _writer: &mut T) -> Result<(), T::Err> {
-        todo!()
+    fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        writer.write(&(self.data.len() as u32).to_le_bytes())?;
+        writer.write(self.data)?;
+        Ok(())
     }
 }
 
@@ -675,17 +1158,21 @@ impl<'a> Message<'a> for TWrite<'a> {
     const TYPE: MessageType = MessageType::TWrite;
 
     fn parse(body: &'a [u8]) -> Result<Self, Error> {
-        todo!()
+        let (body, fid) = Fid::parse(body)?;
+        let (body, offset) = u64::parse(body)?;
+        let (body, data) = <&[u8]>::parse(body)?;
+        end_of_message(body, TWrite { fid, offset, data })
     }
 
     fn size(&self) -> usize {
-        4 + 8 + 2 + self.data.len()
+        4 + 8 + 4 + self.data.len()
     }
 
     fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
         writer.write(&self.fid.0.to_le_bytes())?;
         writer.write(&self.offset.to_le_bytes())?;
-        writer.write(&self.data)?;
+        writer.write(&(self.data.len() as u32).to_le_bytes())?;
+        writer.write(self.data)?;
         Ok(())
     }
 }
@@ -722,7 +1209,8 @@ impl<'a> Message<'a> for TClunk {
     const TYPE: MessageType = MessageType::TClunk;
 
     fn parse(body: &'a [u8]) -> Result<Self, Error> {
-        todo!()
+        let (body, fid) = Fid::parse(body)?;
+        end_of_message(body, TClunk { fid })
     }
 
     fn size(&self) -> usize {
@@ -749,7 +1237,8 @@ impl<'a> Message<'a> for TRemove {
     const TYPE: MessageType = MessageType::TRemove;
 
     fn parse(body: &'a [u8]) -> Result<Self, Error> {
-        todo!()
+        let (body, fid) = Fid::parse(body)?;
+        end_of_message(body, TRemove { fid })
     }
 
     fn size(&self) -> usize {
@@ -776,7 +1265,8 @@ impl<'a> Message<'a> for TStat {
     const TYPE: MessageType = MessageType::TStat;
 
     fn parse(body: &'a [u8]) -> Result<Self, Error> {
-        todo!()
+        let (body, fid) = Fid::parse(body)?;
+        end_of_message(body, TStat { fid })
     }
 
     fn size(&self) -> usize {
@@ -799,17 +1289,18 @@ impl<'a> Message<'a> for RStat<'a> {
 
     fn parse(body: &'a [u8]) -> Result<Self, Error> {
         let (body, _len) = u16::parse(body)?;
-        let (body, stat) = Stat::parse(body)?;
+        let (_body, stat) = Stat::parse(body)?;
         //end_of_message(body, RStat { stat })
         Ok(RStat { stat }) // XXX?
     }
 
     fn size(&self) -> usize {
-        todo!()
+        2 + self.stat.size()
     }
 
-    fn write<T: Writer>(&self, _writer: &mut T) -> Result<(), T::Err> {
-        todo!()
+    fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
+        writer.write(&(self.stat.size() as u16).to_le_bytes())?;
+        self.stat.write(writer)
     }
 }
 
@@ -823,7 +1314,10 @@ impl<'a> Message<'a> for TWStat<'a> {
     const TYPE: MessageType = MessageType::TWStat;
 
     fn parse(body: &'a [u8]) -> Result<Self, Error> {
-        todo!()
+        let (body, fid) = Fid::parse(body)?;
+        let (body, len) = u16::parse(body)?;
+        let stat = body.get(..len as usize).ok_or(Error::MessageLength)?;
+        end_of_message(&body[len as usize..], TWStat { fid, stat })
     }
 
     fn size(&self) -> usize {
@@ -833,7 +1327,7 @@ impl<'a> Message<'a> for TWStat<'a> {
     fn write<T: Writer>(&self, writer: &mut T) -> Result<(), T::Err> {
         writer.write(&self.fid.0.to_le_bytes())?;
         writer.write(&(self.stat.len() as u16).to_le_bytes())?;
-        writer.write(&self.stat)?;
+        writer.write(self.stat)?;
         Ok(())
     }
 }
@@ -857,6 +1351,7 @@ macro_rules! impl_tmessage_rmessage {
 
 impl_tmessage_rmessage!(TVersion<'a>, RVersion<'b>);
 impl_tmessage_rmessage!(TAuth<'a>, RAuth);
+impl_tmessage_rmessage!(TFlush, RFlush);
 impl_tmessage_rmessage!(TAttach<'a>, RAttach);
 impl_tmessage_rmessage!(TWalk<'a>, RWalk);
 impl_tmessage_rmessage!(TOpen, ROpen);
@@ -867,3 +1362,24 @@ impl_tmessage_rmessage!(TClunk, RClunk);
 impl_tmessage_rmessage!(TRemove, RRemove);
 impl_tmessage_rmessage!(TStat, RStat<'b>);
 impl_tmessage_rmessage!(TWStat<'a>, RWStat);
+impl_tmessage_rmessage!(TLAuth<'a>, RAuth);
+impl_tmessage_rmessage!(TLAttach<'a>, RAttach);
+impl_tmessage_rmessage!(TStatFs, RStatFs);
+impl_tmessage_rmessage!(TLOpen, RLOpen);
+impl_tmessage_rmessage!(TLCreate<'a>, RLCreate);
+impl_tmessage_rmessage!(TSymlink<'a>, RSymlink);
+impl_tmessage_rmessage!(TMkNod<'a>, RMkNod);
+impl_tmessage_rmessage!(TRename<'a>, RRename);
+impl_tmessage_rmessage!(TReadLink, RReadLink<'b>);
+impl_tmessage_rmessage!(TGetAttr, RGetAttr);
+impl_tmessage_rmessage!(TSetAttr, RSetAttr);
+impl_tmessage_rmessage!(TXattrWalk<'a>, RXattrWalk);
+impl_tmessage_rmessage!(TXattrCreate<'a>, RXattrCreate);
+impl_tmessage_rmessage!(TReadDir, RReadDir<'b>);
+impl_tmessage_rmessage!(TFSync, RFSync);
+impl_tmessage_rmessage!(TLock<'a>, RLock);
+impl_tmessage_rmessage!(TGetLock<'a>, RGetLock<'b>);
+impl_tmessage_rmessage!(TLink<'a>, RLink);
+impl_tmessage_rmessage!(TMkDir<'a>, RMkDir);
+impl_tmessage_rmessage!(TRenameAt<'a>, RRenameAt);
+impl_tmessage_rmessage!(TUnlinkAt<'a>, RUnlinkAt);
@@ -0,0 +1,235 @@
+// Counters kept by the servers while they run: requests of each type with
+// their latencies, bytes transferred, connections and fids. They are plain
+// atomics, read either directly or as the text of a synthetic file.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{synthetic::text_file, FileOps, MessageType};
+
+/// Upper bounds of the buckets of the latency histograms
+///
+/// Requests taking longer than the last bound are counted in one more
+/// bucket.
+pub const LATENCY_BOUNDS: [Duration; 6] = [
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+];
+
+const BUCKETS: usize = LATENCY_BOUNDS.len() + 1;
+
+// Requests have even message types, so each has a slot at half its type
+const OPS: usize = 128;
+
+/// How a request finished
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Replied,
+    Error,
+    Flushed,
+}
+
+#[derive(Default)]
+struct OpCounters {
+    count: AtomicU64,
+    errors: AtomicU64,
+    flushed: AtomicU64,
+    nanos: AtomicU64,
+    latency: [AtomicU64; BUCKETS],
+}
+
+/// Counters for one type of request, as returned by [`Metrics::op`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpStats {
+    /// Requests finished, including those that failed or were flushed
+    pub count: u64,
+    pub errors: u64,
+    pub flushed: u64,
+    /// Time spent on all of them together
+    pub total: Duration,
+    /// Requests in each bucket of [`LATENCY_BOUNDS`]
+    pub latency: [u64; BUCKETS],
+}
+
+/// Statistics about the requests handled by a server
+///
+/// Shared by the connections of a server through
+/// [`BlockingServer::metrics`](crate::BlockingServer::metrics), and with
+/// [`FidServer::metrics`](crate::FidServer::metrics), which counts the fids
+/// in use. Clients can read them from [`Metrics::stats_file`].
+pub struct Metrics {
+    ops: [OpCounters; OPS],
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    connections: AtomicU64,
+    fids: AtomicU64,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics")
+            .field("connections", &self.connections())
+            .field("fids", &self.fids())
+            .field("bytes_in", &self.bytes_in())
+            .field("bytes_out", &self.bytes_out())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            ops: std::array::from_fn(|_| OpCounters::default()),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            fids: AtomicU64::new(0),
+        }
+    }
+
+    /// Connections currently open
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Fids currently in use, on all connections
+    pub fn fids(&self) -> u64 {
+        self.fids.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of requests received
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of replies sent
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// Counters for requests of type `type_`
+    pub fn op(&self, type_: MessageType) -> OpStats {
+        let Some(op) = self.ops.get(type_ as usize / 2) else {
+            return OpStats::default();
+        };
+        OpStats {
+            count: op.count.load(Ordering::Relaxed),
+            errors: op.errors.load(Ordering::Relaxed),
+            flushed: op.flushed.load(Ordering::Relaxed),
+            total: Duration::from_nanos(op.nanos.load(Ordering::Relaxed)),
+            latency: std::array::from_fn(|i| op.latency[i].load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Counters for each type of request that has been seen
+    pub fn ops(&self) -> Vec<(MessageType, OpStats)> {
+        (0..OPS)
+            .filter_map(|i| MessageType::try_from(i as u8 * 2).ok())
+            .map(|type_| (type_, self.op(type_)))
+            .filter(|(_, stats)| stats.count > 0)
+            .collect()
+    }
+
+    /// A read-only synthetic file with the statistics as text, to be
+    /// mounted in a [`SynthTree`](crate::SynthTree), such as `/.stats` in a
+    /// [`Namespace`](crate::Namespace)
+    pub fn stats_file(self: &Arc<Self>) -> impl FileOps {
+        let metrics = self.clone();
+        text_file(move || metrics.to_string())
+    }
+
+    pub(crate) fn record(&self, type_: u8, elapsed: Duration, outcome: Outcome) {
+        let Some(op) = self.ops.get(type_ as usize / 2) else {
+            return;
+        };
+        op.count.fetch_add(1, Ordering::Relaxed);
+        match outcome {
+            Outcome::Replied => {}
+            Outcome::Error => {
+                op.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            Outcome::Flushed => {
+                op.flushed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let nanos = elapsed.as_nanos().try_into().unwrap_or(u64::MAX);
+        op.nanos.fetch_add(nanos, Ordering::Relaxed);
+        let bucket = LATENCY_BOUNDS
+            .iter()
+            .position(|&bound| elapsed < bound)
+            .unwrap_or(BUCKETS - 1);
+        op.latency[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, len: u32) {
+        self.bytes_in.fetch_add(len.into(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self, len: usize) {
+        self.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn disconnected(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn fid_added(&self) {
+        self.fids.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn fid_removed(&self) {
+        self.fids.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The statistics as text: a line for each counter, then a table with a
+/// line for each type of request, giving times in microseconds
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "connections {}", self.connections())?;
+        writeln!(f, "fids {}", self.fids())?;
+        writeln!(f, "bytes-in {}", self.bytes_in())?;
+        writeln!(f, "bytes-out {}", self.bytes_out())?;
+        write!(f, "op count errors flushed total-us")?;
+        for bound in LATENCY_BOUNDS {
+            write!(f, " <{}us", bound.as_micros())?;
+        }
+        writeln!(f, " more")?;
+        for (type_, stats) in self.ops() {
+            write!(
+                f,
+                "{:?} {} {} {} {}",
+                type_,
+                stats.count,
+                stats.errors,
+                stats.flushed,
+                stats.total.as_micros()
+            )?;
+            for count in stats.latency {
+                write!(f, " {}", count)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
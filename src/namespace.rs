@@ -0,0 +1,680 @@
+// A namespace built from several file servers, in the style of Plan 9's
+// mount table. Each backend is a `FileHandler` mounted at a path; mounting
+// several at one path makes a union directory, whose members are searched
+// in order on walks and whose listings are merged. Directories on the way to
+// a mount point exist even if no backend provides them.
+
+use std::{
+    any::Any,
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    dir_reader::DirListing, fid::err, parse_dir, BoxFuture, Error, FileHandler, Identity, Qid,
+    RCreate, ROpen, Stat, StatBuf, DMDIR, QTDIR,
+};
+
+type AnyFile = Arc<dyn Any + Send + Sync>;
+type AnyOpen = Box<dyn Any + Send + Sync>;
+
+// Qid paths of a backend are kept in the low bits, with the backend in the
+// high byte; 0 there is for directories leading to mount points
+const BACKEND_SHIFT: u32 = 56;
+const PATH_MASK: u64 = (1 << BACKEND_SHIFT) - 1;
+const MAX_MOUNTS: usize = 0xff;
+
+/// How a mount combines with what is already at its path, like the flags of
+/// Plan 9's mount(2)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MountFlag {
+    /// Hide what was there
+    Replace,
+    /// Make a union, searched before what was there
+    Before,
+    /// Make a union, searched after what was there
+    After,
+}
+
+// `FileHandler` with the files and opens type-erased, so handlers of
+// different types can be mounted together
+trait Backend: Send + Sync {
+    fn attach<'a>(&'a self, user: &'a Identity) -> BoxFuture<'a, Result<(AnyFile, Qid), Error>>;
+
+    fn walk<'a>(
+        &'a self,
+        user: &'a Identity,
+        dir: &'a AnyFile,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<(AnyFile, Qid), Error>>;
+
+    fn open<'a>(
+        &'a self,
+        user: &'a Identity,
+        file: &'a AnyFile,
+        mode: u8,
+    ) -> BoxFuture<'a, Result<(AnyOpen, ROpen), Error>>;
+
+    fn create<'a>(
+        &'a self,
+        user: &'a Identity,
+        dir: &'a AnyFile,
+        name: &'a str,
+        perm: u32,
+        mode: u8,
+    ) -> BoxFuture<'a, Result<(AnyFile, AnyOpen, RCreate), Error>>;
+
+    fn read<'a>(
+        &'a self,
+        user: &'a Identity,
+        file: &'a AnyFile,
+        open: &'a AnyOpen,
+        offset: u64,
+        count: u32,
+    ) -> BoxFuture<'a, Result<Vec<u8>, Error>>;
+
+    fn write<'a>(
+        &'a self,
+        user: &'a Identity,
+        file: &'a AnyFile,
+        open: &'a AnyOpen,
+        offset: u64,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<u32, Error>>;
+
+    fn clunk(&self, user: &Identity, file: &AnyFile, open: Option<&AnyOpen>);
+
+    fn remove<'a>(
+        &'a self,
+        user: &'a Identity,
+        file: &'a AnyFile,
+        open: Option<&'a AnyOpen>,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn stat<'a>(
+        &'a self,
+        user: &'a Identity,
+        file: &'a AnyFile,
+    ) -> BoxFuture<'a, Result<StatBuf, Error>>;
+
+    fn wstat<'a>(
+        &'a self,
+        user: &'a Identity,
+        file: &'a AnyFile,
+        stat: &'a Stat<'a>,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+struct Handler<H>(Arc<H>);
+
+fn file<H: FileHandler>(file: &AnyFile) -> &H::File {
+    file.downcast_ref().expect("file of another backend")
+}
+
+fn open<H: FileHandler>(open: &AnyOpen) -> &H::Open {
+    open.downcast_ref().expect("open of another backend")
+}
+
+impl<H: FileHandler> Backend for Handler<H> {
+    fn attach<'a>(&'a self, user: &'a Identity) -> BoxFuture<'a, Result<(AnyFile, Qid), Error>> {
+        Box::pin(async move {
+            let (file, qid) = self.0.attach(user).await?;
+            Ok((Arc::new(file) as AnyFile, qid))
+        })
+    }
+
+    fn walk<'a>(
+        &'a self,
+        user: &'a Identity,
+        dir: &'a AnyFile,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<(AnyFile, Qid), Error>> {
+        Box::pin(async move {
+            let (file, qid) = self.0.walk(user, file::<H>(dir), name).await?;
+            Ok((Arc::new(file) as AnyFile, qid))
+        })
+    }
+
+    fn open<'a>(
+        &'a self,
+        user: &'a Identity,
+        file: &'a AnyFile,
+        mode: u8,
+    ) -> BoxFuture<'a, Result<(AnyOpen, ROpen), Error>> {
+        Box::pin(async move {
+            let (open, ropen) = self.0.open(user, self::file::<H>(file), mode).await?;
+            Ok((Box::new(open) as AnyOpen, ropen))
+        })
+    }
+
+    fn create<'a>(
+        &'a self,
+        user: &'a Identity,
+        dir: &'a AnyFile,
+        name: &'a str,
+        perm: u32,
+        mode: u8,
+    ) -> BoxFuture<'a, Result<(AnyFile, AnyOpen, RCreate), Error>> {
+        Box::pin(async move {
+            let (file, open, rcreate) = self
+                .0
+                .create(user, file::<H>(dir), name, perm, mode)
+                .await?;
+            Ok((
+                Arc::new(file) as AnyFile,
+                Box::new(open) as AnyOpen,
+                rcreate,
+            ))
+        })
+    }
+
+    fn read<'a>(
+        &'a self,
+        user: &'a Identity,
+        file: &'a AnyFile,
+        open: &'a AnyOpen,
+        offset: u64,
+        count: u32,
+    ) -> BoxFuture<'a, Result<Vec<u8>, Error>> {
+        Box::pin(self.0.read(
+            user,
+            self::file::<H>(file),
+            self::open::<H>(open),
+            offset,
+            count,
+        ))
+    }
+
+    fn write<'a>(
+        &'a self,
+        user: &'a Identity,
+        file: &'a AnyFile,
+        open: &'a AnyOpen,
+        offset: u64,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<u32, Error>> {
+        Box::pin(self.0.write(
+            user,
+            self::file::<H>(file),
+            self::open::<H>(open),
+            offset,
+            data,
+        ))
+    }
+
+    fn clunk(&self, user: &Identity, file: &AnyFile, open: Option<&AnyOpen>) {
+        self.0
+            .clunk(user, self::file::<H>(file), open.map(self::open::<H>))
+    }
+
+    fn remove<'a>(
+        &'a self,
+        user: &'a Identity,
+        file: &'a AnyFile,
+        open: Option<&'a AnyOpen>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(
+            self.0
+                .remove(user, self::file::<H>(file), open.map(self::open::<H>)),
+        )
+    }
+
+    fn stat<'a>(
+        &'a self,
+        user: &'a Identity,
+        file: &'a AnyFile,
+    ) -> BoxFuture<'a, Result<StatBuf, Error>> {
+        Box::pin(self.0.stat(user, self::file::<H>(file)))
+    }
+
+    fn wstat<'a>(
+        &'a self,
+        user: &'a Identity,
+        file: &'a AnyFile,
+        stat: &'a Stat<'a>,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(self.0.wstat(user, self::file::<H>(file), stat))
+    }
+}
+
+struct Mount {
+    path: Vec<String>,
+    flag: MountFlag,
+    backend: Box<dyn Backend>,
+}
+
+fn split_path(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+        .map(str::to_string)
+        .collect()
+}
+
+/// [`FileHandler`] serving a namespace of other handlers, each mounted at a
+/// path
+///
+/// Walks are routed to the backends mounted along the path. Where a mount
+/// is made with [`MountFlag::Before`] or [`MountFlag::After`], the directory
+/// is a union: names are looked up in each member in turn, reads list the
+/// entries of all of them with earlier members hiding later ones of the same
+/// name, and files are created in the first member. Directories leading to
+/// a mount point appear in listings even if no backend provides them.
+///
+/// Qids are made unique by keeping each backend's qid paths in the low 56
+/// bits, and the mount in the high byte.
+///
+/// Only 9P2000 is served.
+// XXX 9P2000.L, when all backends support it
+#[derive(Default)]
+pub struct Namespace {
+    mounts: Vec<Mount>,
+}
+
+impl Namespace {
+    /// Create a namespace with an empty root directory
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mount the tree `handler` serves at `path`, after any earlier mounts
+    /// at the same path
+    ///
+    /// Backends are attached with the identity of the client attaching to
+    /// the namespace, including its aname.
+    ///
+    /// # Panics
+    ///
+    /// If there are already 255 mounts.
+    pub fn mount<H: FileHandler>(mut self, path: &str, handler: Arc<H>, flag: MountFlag) -> Self {
+        assert!(self.mounts.len() < MAX_MOUNTS, "too many mounts");
+        self.mounts.push(Mount {
+            path: split_path(path),
+            flag,
+            backend: Box::new(Handler(handler)),
+        });
+        self
+    }
+
+    // Whether `path` is a mount point, or a directory leading to one
+    fn is_union(&self, path: &[String]) -> bool {
+        self.mounts.iter().any(|mount| mount.path.starts_with(path))
+    }
+
+    // Apply the mounts at `path` to what was found there by walking
+    async fn mounted(
+        &self,
+        user: &Identity,
+        path: &[String],
+        mut members: Vec<Member>,
+    ) -> Result<Vec<Member>, Error> {
+        // Mounting over a file hides it
+        members.retain(|member| member.qid.is_dir());
+        for (backend, mount) in self.mounts.iter().enumerate() {
+            if mount.path != path {
+                continue;
+            }
+            let (file, qid) = mount.backend.attach(user).await?;
+            let member = Member { backend, file, qid };
+            match mount.flag {
+                MountFlag::Replace => members = vec![member],
+                MountFlag::Before => members.insert(0, member),
+                MountFlag::After => members.push(member),
+            }
+        }
+        Ok(members)
+    }
+
+    fn node(
+        &self,
+        path: Vec<String>,
+        parent: Option<NsFile>,
+        members: Vec<Member>,
+        union: bool,
+    ) -> NsFile {
+        let qid = match members.first() {
+            Some(member) => remap(member.backend, member.qid),
+            None => {
+                // Named by the first mount below it and its depth
+                let index = self
+                    .mounts
+                    .iter()
+                    .position(|mount| mount.path.starts_with(&path))
+                    .unwrap_or(0);
+                Qid {
+                    type_: QTDIR,
+                    vers: 0,
+                    path: (path.len() as u64) << 32 | index as u64,
+                }
+            }
+        };
+        NsFile(Arc::new(Node {
+            path,
+            parent,
+            members,
+            union,
+            qid,
+        }))
+    }
+
+    fn backend(&self, member: &Member) -> &dyn Backend {
+        &*self.mounts[member.backend].backend
+    }
+
+    // Names of the directories in `dir` that lead to mount points
+    fn mount_names(&self, dir: &[String]) -> Vec<&str> {
+        let mut names = Vec::new();
+        for mount in &self.mounts {
+            if mount.path.len() > dir.len() && mount.path.starts_with(dir) {
+                let name = mount.path[dir.len()].as_str();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    async fn stat_file(&self, user: &Identity, file: &NsFile) -> Result<StatBuf, Error> {
+        let name = file.0.path.last().map_or("/", String::as_str);
+        let Some(member) = file.0.members.first() else {
+            return Ok(StatBuf {
+                qid: file.0.qid,
+                mode: DMDIR | 0o555,
+                name: name.to_string(),
+                uid: user.uname.clone(),
+                gid: user.uname.clone(),
+                muid: user.uname.clone(),
+                ..StatBuf::default()
+            });
+        };
+        let mut stat = self.backend(member).stat(user, &member.file).await?;
+        stat.qid = remap(member.backend, stat.qid);
+        stat.name = name.to_string();
+        Ok(stat)
+    }
+
+    // Entries of a union directory, read from each of its members
+    async fn listing(
+        &self,
+        user: &Identity,
+        dir: &NsFile,
+        open: &NsOpen,
+    ) -> Result<Vec<StatBuf>, Error> {
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+        for (member, member_open) in dir.0.members.iter().zip(&open.opens) {
+            let backend = self.backend(member);
+            let mut offset = 0;
+            loop {
+                let data = backend
+                    .read(user, &member.file, member_open, offset, 8192)
+                    .await?;
+                if data.is_empty() {
+                    break;
+                }
+                offset += data.len() as u64;
+                for stat in parse_dir(&data)? {
+                    if seen.insert(stat.name.to_string()) {
+                        let mut entry = StatBuf::from(&stat);
+                        entry.qid = remap(member.backend, stat.qid);
+                        entries.push(entry);
+                    }
+                }
+            }
+        }
+
+        // Mount points show what is mounted on them
+        for name in self.mount_names(&dir.0.path) {
+            let (file, _) = self.walk(user, dir, name).await?;
+            let stat = self.stat_file(user, &file).await?;
+            self.clunk(user, &file, None);
+            match entries.iter_mut().find(|entry| entry.name == name) {
+                Some(entry) => *entry = stat,
+                None => entries.push(stat),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+fn remap(backend: usize, qid: Qid) -> Qid {
+    Qid {
+        path: (backend as u64 + 1) << BACKEND_SHIFT | qid.path & PATH_MASK,
+        ..qid
+    }
+}
+
+#[derive(Clone)]
+struct Member {
+    // Index of the mount
+    backend: usize,
+    file: AnyFile,
+    // As the backend reported it
+    qid: Qid,
+}
+
+struct Node {
+    // Names walked from the root
+    path: Vec<String>,
+    // The directory this was walked from; root has none
+    parent: Option<NsFile>,
+    // Backend files; one for files outside unions, and any number for
+    // union directories
+    members: Vec<Member>,
+    union: bool,
+    qid: Qid,
+}
+
+/// A file or directory in a [`Namespace`]
+#[derive(Clone)]
+pub struct NsFile(Arc<Node>);
+
+/// An open [`NsFile`]
+pub struct NsOpen {
+    // Backend opens, one for each member
+    opens: Vec<AnyOpen>,
+    // Listing of a union directory, generated again at offset 0
+    listing: Mutex<DirListing>,
+}
+
+impl FileHandler for Namespace {
+    type File = NsFile;
+    type Open = NsOpen;
+
+    async fn attach(&self, user: &Identity) -> Result<(NsFile, Qid), Error> {
+        let members = self.mounted(user, &[], Vec::new()).await?;
+        let root = self.node(Vec::new(), None, members, true);
+        let qid = root.0.qid;
+        Ok((root, qid))
+    }
+
+    async fn walk(
+        &self,
+        user: &Identity,
+        dir: &NsFile,
+        name: &str,
+    ) -> Result<(NsFile, Qid), Error> {
+        if name == ".." {
+            let parent = dir.0.parent.clone().unwrap_or_else(|| dir.clone());
+            let qid = parent.0.qid;
+            return Ok((parent, qid));
+        }
+
+        let mut found = Vec::new();
+        let mut error = None;
+        for member in &dir.0.members {
+            match self.backend(member).walk(user, &member.file, name).await {
+                Ok((file, qid)) => {
+                    found.push(Member {
+                        backend: member.backend,
+                        file,
+                        qid,
+                    });
+                    break;
+                }
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+
+        let mut path = dir.0.path.clone();
+        path.push(name.to_string());
+        let union = self.is_union(&path);
+        if union {
+            found = self.mounted(user, &path, found).await?;
+        } else if found.is_empty() {
+            return match error {
+                Some(error) => Err(error),
+                None => err("file does not exist"),
+            };
+        }
+        let file = self.node(path, Some(dir.clone()), found, union);
+        let qid = file.0.qid;
+        Ok((file, qid))
+    }
+
+    async fn open(
+        &self,
+        user: &Identity,
+        file: &NsFile,
+        mode: u8,
+    ) -> Result<(NsOpen, ROpen), Error> {
+        let mut opens = Vec::new();
+        let mut iounit = 0;
+        for member in &file.0.members {
+            match self.backend(member).open(user, &member.file, mode).await {
+                Ok((open, ropen)) => {
+                    opens.push(open);
+                    iounit = ropen.iounit;
+                }
+                Err(err) => {
+                    for (member, open) in file.0.members.iter().zip(&opens) {
+                        self.backend(member).clunk(user, &member.file, Some(open));
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        let ropen = ROpen {
+            qid: file.0.qid,
+            iounit: if file.0.union { 0 } else { iounit },
+        };
+        let open = NsOpen {
+            opens,
+            listing: Mutex::default(),
+        };
+        Ok((open, ropen))
+    }
+
+    async fn create(
+        &self,
+        user: &Identity,
+        dir: &NsFile,
+        name: &str,
+        perm: u32,
+        mode: u8,
+    ) -> Result<(NsFile, NsOpen, RCreate), Error> {
+        let mut path = dir.0.path.clone();
+        path.push(name.to_string());
+        if self.is_union(&path) {
+            return err("file already exists");
+        }
+        let Some(member) = dir.0.members.first() else {
+            return err("create prohibited");
+        };
+        let (file, open, rcreate) = self
+            .backend(member)
+            .create(user, &member.file, name, perm, mode)
+            .await?;
+        let member = Member {
+            backend: member.backend,
+            file,
+            qid: rcreate.qid,
+        };
+        let file = self.node(path, Some(dir.clone()), vec![member], false);
+        let rcreate = RCreate {
+            qid: file.0.qid,
+            iounit: rcreate.iounit,
+        };
+        let open = NsOpen {
+            opens: vec![open],
+            listing: Mutex::default(),
+        };
+        Ok((file, open, rcreate))
+    }
+
+    async fn read(
+        &self,
+        user: &Identity,
+        file: &NsFile,
+        open: &NsOpen,
+        offset: u64,
+        count: u32,
+    ) -> Result<Vec<u8>, Error> {
+        if !file.0.union {
+            let member = &file.0.members[0];
+            return self
+                .backend(member)
+                .read(user, &member.file, &open.opens[0], offset, count)
+                .await;
+        }
+        let entries = if offset == 0 {
+            self.listing(user, file, open).await?
+        } else {
+            Vec::new()
+        };
+        let mut listing = open.listing.lock().unwrap();
+        listing.read(offset, count, || Ok::<_, Error>(entries))
+    }
+
+    async fn write(
+        &self,
+        user: &Identity,
+        file: &NsFile,
+        open: &NsOpen,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<u32, Error> {
+        match (file.0.members.first(), open.opens.first()) {
+            (Some(member), Some(open)) if !file.0.union => {
+                let backend = self.backend(member);
+                backend.write(user, &member.file, open, offset, data).await
+            }
+            _ => err("is a directory"),
+        }
+    }
+
+    fn clunk(&self, user: &Identity, file: &NsFile, open: Option<&NsOpen>) {
+        for (i, member) in file.0.members.iter().enumerate() {
+            let open = open.and_then(|open| open.opens.get(i));
+            self.backend(member).clunk(user, &member.file, open);
+        }
+    }
+
+    async fn remove(
+        &self,
+        user: &Identity,
+        file: &NsFile,
+        open: Option<&NsOpen>,
+    ) -> Result<(), Error> {
+        if file.0.union {
+            return err("mount point busy");
+        }
+        let member = &file.0.members[0];
+        let open = open.and_then(|open| open.opens.first());
+        self.backend(member).remove(user, &member.file, open).await
+    }
+
+    async fn stat(&self, user: &Identity, file: &NsFile) -> Result<StatBuf, Error> {
+        self.stat_file(user, file).await
+    }
+
+    async fn wstat(&self, user: &Identity, file: &NsFile, stat: &Stat<'_>) -> Result<(), Error> {
+        if file.0.union {
+            return err("mount point busy");
+        }
+        let member = &file.0.members[0];
+        self.backend(member).wstat(user, &member.file, stat).await
+    }
+}
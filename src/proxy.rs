@@ -0,0 +1,545 @@
+// A `Filesystem` forwarding each request to an upstream server, with the
+// client's fids mapped to ones allocated on the `Upstream` connection. A
+// `Policy` sees every request and reply that reaches the `Filesystem`, and
+// can refuse changes, hide files and rewrite the user attaching.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    fid::err,
+    parse_dir,
+    server::ename,
+    sync_client::parse_reply,
+    upstream::{Orphan, Upstream},
+    Error, Fid, Filesystem, Header, Message, Qid, RAttach, RAuth, RClunk, RError, RRead, RRemove,
+    RWStat, RWalk, Replied, Replier, Stat, TAttach, TAuth, TClunk, TCreate, TMessage, TOpen, TRead,
+    TRemove, TStat, TWStat, TWalk, TWrite, ORCLOSE, ORDWR, OTRUNC, OWRITE,
+};
+
+/// Hooks deciding what a [`Proxy`] lets through
+///
+/// Paths are the names walked from the root of an attach, with ".."
+/// resolved.
+pub trait Policy: Send + Sync + 'static {
+    /// Called with each request from the client, and the reply to it
+    ///
+    /// `TVersion` and `TFlush` are answered by the server, without reaching
+    /// the [`Proxy`], so they aren't logged.
+    fn log(&self, _tag: u16, _message: &dyn fmt::Debug) {}
+
+    /// User to attach to the upstream server as, for a client attaching as
+    /// `uname`
+    fn uname(&self, uname: &str) -> String {
+        uname.to_string()
+    }
+
+    /// Whether the file at `path` can be walked to and appears in listings
+    fn visible(&self, _path: &[String]) -> bool {
+        true
+    }
+
+    /// Whether to refuse requests that change files
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+/// [`Policy`] that lets everything through, without logging
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoPolicy;
+
+impl Policy for NoPolicy {}
+
+fn read_only<T>() -> Result<T, Error> {
+    err("read-only file system")
+}
+
+#[derive(Clone)]
+struct ProxyFid {
+    ufid: Fid,
+    path: Vec<String>,
+    // Offsets of the next read of an open directory, as the client and the
+    // upstream server see them, since hidden entries are left out
+    listing: Option<Arc<Mutex<(u64, u64)>>>,
+}
+
+fn parse<'b, 'a, T: TMessage<'a>>(reply: &'b (Header, Vec<u8>)) -> Result<T::RMessage<'b>, Error> {
+    parse_reply(&reply.0, &reply.1)
+}
+
+/// [`Filesystem`] forwarding one client session to an [`Upstream`] server
+///
+/// The client's fids are mapped to fids allocated on the upstream
+/// connection, which may be shared with other sessions; tags are allocated
+/// by [`Upstream`]. Flushed requests are flushed upstream, and the fids
+/// still open are clunked when the session ends.
+///
+/// Only 9P2000 is forwarded.
+// XXX 9P2000.L, when the upstream server supports it
+pub struct Proxy<P: Policy = NoPolicy> {
+    upstream: Arc<Upstream>,
+    policy: Arc<P>,
+    fids: Mutex<HashMap<Fid, ProxyFid>>,
+}
+
+impl Proxy {
+    pub fn new(upstream: Arc<Upstream>) -> Self {
+        Self::with_policy(upstream, Arc::new(NoPolicy))
+    }
+}
+
+impl<P: Policy> Proxy<P> {
+    pub fn with_policy(upstream: Arc<Upstream>, policy: Arc<P>) -> Self {
+        Self {
+            upstream,
+            policy,
+            fids: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, fid: Fid) -> Result<ProxyFid, Error> {
+        match self.fids.lock().unwrap().get(&fid) {
+            Some(entry) => Ok(entry.clone()),
+            None => err("unknown fid"),
+        }
+    }
+
+    fn check_unused(&self, fid: Fid) -> Result<(), Error> {
+        if self.fids.lock().unwrap().contains_key(&fid) {
+            return err("duplicate fid");
+        }
+        Ok(())
+    }
+
+    fn take(&self, fid: Fid) -> Result<ProxyFid, Error> {
+        match self.fids.lock().unwrap().remove(&fid) {
+            Some(entry) => Ok(entry),
+            None => err("unknown fid"),
+        }
+    }
+
+    // Clunk a fid upstream, without waiting
+    fn clunk_upstream(&self, ufid: Fid) {
+        self.upstream
+            .forget(&TClunk { fid: ufid }, Orphan::Clunks(ufid));
+    }
+
+    fn reply<'a, T: fmt::Debug + Message<'a>>(&self, replier: Replier, message: T) -> Replied {
+        self.policy.log(replier.tag(), &message);
+        replier.reply(message)
+    }
+
+    fn error(&self, replier: Replier, err: Error) -> Replied {
+        let ename = ename(&err);
+        self.policy.log(
+            replier.tag(),
+            &RError {
+                ename: &ename,
+                errno: None,
+            },
+        );
+        replier.error(&ename)
+    }
+
+    fn result<'a, T: fmt::Debug + Message<'a>>(
+        &self,
+        replier: Replier,
+        result: Result<T, Error>,
+    ) -> Replied {
+        match result {
+            Ok(message) => self.reply(replier, message),
+            Err(err) => self.error(replier, err),
+        }
+    }
+
+    // Attach or authenticate a new fid, with `request` using `ufid`
+    async fn new_fid<'a, T: TMessage<'a>>(
+        &self,
+        fid: Fid,
+        ufid: Fid,
+        request: &T,
+    ) -> Result<(Header, Vec<u8>), Error> {
+        let res = self.upstream.call(request, Orphan::NewFid(ufid)).await;
+        let res = res.and_then(|reply| {
+            parse::<T>(&reply)?;
+            Ok(reply)
+        });
+        let reply = match res {
+            Ok(reply) => reply,
+            Err(err) => {
+                self.upstream.free_fid(ufid);
+                return Err(err);
+            }
+        };
+        let entry = ProxyFid {
+            ufid,
+            path: Vec::new(),
+            listing: None,
+        };
+        match self.fids.lock().unwrap().entry(fid) {
+            Entry::Occupied(_) => {}
+            Entry::Vacant(vacant) => {
+                vacant.insert(entry);
+                return Ok(reply);
+            }
+        }
+        self.clunk_upstream(ufid);
+        err("duplicate fid")
+    }
+
+    async fn do_auth(&self, tauth: &TAuth<'_>) -> Result<RAuth, Error> {
+        self.check_unused(tauth.afid)?;
+        let ufid = self.upstream.alloc_fid()?;
+        let uname = self.policy.uname(tauth.uname);
+        let request = TAuth {
+            afid: ufid,
+            uname: &uname,
+            aname: tauth.aname,
+        };
+        let reply = self.new_fid(tauth.afid, ufid, &request).await?;
+        parse::<TAuth>(&reply)
+    }
+
+    async fn do_attach(&self, tattach: &TAttach<'_>) -> Result<RAttach, Error> {
+        self.check_unused(tattach.fid)?;
+        let afid = if tattach.afid == Fid::NOFID {
+            Fid::NOFID
+        } else {
+            self.get(tattach.afid)?.ufid
+        };
+        let ufid = self.upstream.alloc_fid()?;
+        let uname = self.policy.uname(tattach.uname);
+        let request = TAttach {
+            fid: ufid,
+            afid,
+            uname: &uname,
+            aname: tattach.aname,
+        };
+        let reply = self.new_fid(tattach.fid, ufid, &request).await?;
+        parse::<TAttach>(&reply)
+    }
+
+    async fn do_walk(&self, twalk: &TWalk<'_>) -> Result<RWalk, Error> {
+        let entry = self.get(twalk.fid)?;
+        if twalk.newfid != twalk.fid {
+            self.check_unused(twalk.newfid)?;
+        }
+
+        // Only walk as far as is visible
+        let mut path = entry.path.clone();
+        let mut allowed = 0;
+        for name in &twalk.wnames {
+            if *name == ".." {
+                path.pop();
+            } else {
+                path.push(name.to_string());
+            }
+            if !self.policy.visible(&path) {
+                break;
+            }
+            allowed += 1;
+        }
+        if allowed == 0 && !twalk.wnames.is_empty() {
+            return err("file does not exist");
+        }
+
+        // Always walked to a new fid, so the old one is unchanged if the walk
+        // isn't complete, even when `newfid` is `fid`
+        let ufid = self.upstream.alloc_fid()?;
+        let request = TWalk {
+            fid: entry.ufid,
+            newfid: ufid,
+            wnames: twalk.wnames[..allowed].to_vec(),
+        };
+        let reply = self.upstream.call(&request, Orphan::NewFid(ufid)).await;
+        let rwalk = match reply.and_then(|reply| parse::<TWalk>(&reply)) {
+            Ok(rwalk) => rwalk,
+            Err(err) => {
+                self.upstream.free_fid(ufid);
+                return Err(err);
+            }
+        };
+        if rwalk.qids.len() != twalk.wnames.len() {
+            if rwalk.qids.len() == allowed {
+                self.clunk_upstream(ufid);
+            } else {
+                self.upstream.free_fid(ufid);
+            }
+            return Ok(rwalk);
+        }
+
+        let new = ProxyFid {
+            ufid,
+            path,
+            listing: None,
+        };
+        let mut fids = self.fids.lock().unwrap();
+        if twalk.newfid == twalk.fid {
+            match fids.get_mut(&twalk.fid) {
+                Some(old) if old.ufid == entry.ufid => *old = new,
+                _ => {
+                    drop(fids);
+                    self.clunk_upstream(ufid);
+                    return err("fid changed while walking");
+                }
+            }
+            drop(fids);
+            self.clunk_upstream(entry.ufid);
+        } else if let Entry::Vacant(vacant) = fids.entry(twalk.newfid) {
+            vacant.insert(new);
+        } else {
+            drop(fids);
+            self.clunk_upstream(ufid);
+            return err("duplicate fid");
+        }
+        Ok(rwalk)
+    }
+
+    // Mark `fid` as open, with `qid`
+    fn opened(&self, fid: Fid, qid: &Qid, name: Option<&str>) {
+        if let Some(entry) = self.fids.lock().unwrap().get_mut(&fid) {
+            if let Some(name) = name {
+                entry.path.push(name.to_string());
+            }
+            if qid.is_dir() {
+                entry.listing = Some(Arc::default());
+            }
+        }
+    }
+
+    async fn do_open(&self, topen: &TOpen) -> Result<(Header, Vec<u8>), Error> {
+        let entry = self.get(topen.fid)?;
+        let write = matches!(topen.mode & 3, OWRITE | ORDWR);
+        if self.policy.read_only() && (write || topen.mode & (OTRUNC | ORCLOSE) != 0) {
+            return read_only();
+        }
+        let request = TOpen {
+            fid: entry.ufid,
+            mode: topen.mode,
+        };
+        let reply = self.upstream.call(&request, Orphan::None).await?;
+        let ropen = parse::<TOpen>(&reply)?;
+        self.opened(topen.fid, &ropen.qid, None);
+        Ok(reply)
+    }
+
+    async fn do_create(&self, tcreate: &TCreate<'_>) -> Result<(Header, Vec<u8>), Error> {
+        let entry = self.get(tcreate.fid)?;
+        if self.policy.read_only() {
+            return read_only();
+        }
+        let mut path = entry.path.clone();
+        path.push(tcreate.name.to_string());
+        if !self.policy.visible(&path) {
+            return err("permission denied");
+        }
+        let request = TCreate {
+            fid: entry.ufid,
+            ..tcreate.clone()
+        };
+        let reply = self.upstream.call(&request, Orphan::None).await?;
+        let rcreate = parse::<TCreate>(&reply)?;
+        self.opened(tcreate.fid, &rcreate.qid, Some(tcreate.name));
+        Ok(reply)
+    }
+
+    async fn do_read(&self, tread: &TRead) -> Result<Vec<u8>, Error> {
+        let entry = self.get(tread.fid)?;
+        let Some(listing) = entry.listing else {
+            let request = TRead {
+                fid: entry.ufid,
+                ..*tread
+            };
+            let reply = self.upstream.call(&request, Orphan::None).await?;
+            return Ok(parse::<TRead>(&reply)?.data.to_vec());
+        };
+
+        let (offset, mut uoffset) = *listing.lock().unwrap();
+        if tread.offset == 0 {
+            uoffset = 0;
+        } else if tread.offset != offset {
+            return err("bad offset in directory read");
+        }
+        // Read until something is visible, or the end
+        let mut data = Vec::new();
+        loop {
+            let request = TRead {
+                fid: entry.ufid,
+                offset: uoffset,
+                count: tread.count,
+            };
+            let reply = self.upstream.call(&request, Orphan::None).await?;
+            let rread = parse::<TRead>(&reply)?;
+            if rread.data.is_empty() {
+                break;
+            }
+            uoffset += rread.data.len() as u64;
+            let mut path = entry.path.clone();
+            for stat in parse_dir(rread.data)? {
+                path.push(stat.name.to_string());
+                if self.policy.visible(&path) {
+                    stat.write(&mut data)?;
+                }
+                path.pop();
+            }
+            if !data.is_empty() {
+                break;
+            }
+        }
+        *listing.lock().unwrap() = (tread.offset + data.len() as u64, uoffset);
+        Ok(data)
+    }
+
+    async fn do_remove(&self, tremove: &TRemove) -> Result<(), Error> {
+        // The fid is clunked even if the remove fails
+        let entry = self.take(tremove.fid)?;
+        if self.policy.read_only() {
+            self.clunk_upstream(entry.ufid);
+            return read_only();
+        }
+        let request = TRemove { fid: entry.ufid };
+        let reply = self
+            .upstream
+            .call(&request, Orphan::Clunks(entry.ufid))
+            .await;
+        self.upstream.free_fid(entry.ufid);
+        parse::<TRemove>(&reply?)?;
+        Ok(())
+    }
+
+    async fn do_wstat(&self, twstat: &TWStat<'_>) -> Result<(), Error> {
+        let entry = self.get(twstat.fid)?;
+        if self.policy.read_only() {
+            return read_only();
+        }
+        let stat = Stat::from_bytes(twstat.stat)?;
+        if !stat.name.is_empty() {
+            let mut path = entry.path.clone();
+            path.pop();
+            path.push(stat.name.to_string());
+            if !self.policy.visible(&path) {
+                return err("permission denied");
+            }
+        }
+        let request = TWStat {
+            fid: entry.ufid,
+            stat: twstat.stat,
+        };
+        let reply = self.upstream.call(&request, Orphan::None).await?;
+        parse::<TWStat>(&reply)?;
+        if !stat.name.is_empty() {
+            if let Some(entry) = self.fids.lock().unwrap().get_mut(&twstat.fid) {
+                entry.path.pop();
+                entry.path.push(stat.name.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    // Forward a request that only refers to `fid`, replying with what the
+    // upstream server replies
+    async fn forward<'a, T, F>(&self, fid: Fid, request: F, replier: Replier) -> Replied
+    where
+        T: TMessage<'a>,
+        for<'b> T::RMessage<'b>: fmt::Debug,
+        F: FnOnce(Fid) -> T,
+    {
+        let entry = match self.get(fid) {
+            Ok(entry) => entry,
+            Err(err) => return self.error(replier, err),
+        };
+        match self.upstream.call(&request(entry.ufid), Orphan::None).await {
+            Ok(reply) => self.result(replier, parse::<T>(&reply)),
+            Err(err) => self.error(replier, err),
+        }
+    }
+}
+
+impl<P: Policy> Filesystem for Proxy<P> {
+    async fn auth(&self, tauth: TAuth<'_>, replier: Replier) -> Replied {
+        self.policy.log(replier.tag(), &tauth);
+        let res = self.do_auth(&tauth).await;
+        self.result(replier, res)
+    }
+
+    async fn attach(&self, tattach: TAttach<'_>, replier: Replier) -> Replied {
+        self.policy.log(replier.tag(), &tattach);
+        let res = self.do_attach(&tattach).await;
+        self.result(replier, res)
+    }
+
+    async fn walk(&self, twalk: TWalk<'_>, replier: Replier) -> Replied {
+        self.policy.log(replier.tag(), &twalk);
+        let res = self.do_walk(&twalk).await;
+        self.result(replier, res)
+    }
+
+    async fn open(&self, topen: TOpen, replier: Replier) -> Replied {
+        self.policy.log(replier.tag(), &topen);
+        match self.do_open(&topen).await {
+            Ok(reply) => self.result(replier, parse::<TOpen>(&reply)),
+            Err(err) => self.error(replier, err),
+        }
+    }
+
+    async fn create(&self, tcreate: TCreate<'_>, replier: Replier) -> Replied {
+        self.policy.log(replier.tag(), &tcreate);
+        match self.do_create(&tcreate).await {
+            Ok(reply) => self.result(replier, parse::<TCreate>(&reply)),
+            Err(err) => self.error(replier, err),
+        }
+    }
+
+    async fn read(&self, tread: TRead, replier: Replier) -> Replied {
+        self.policy.log(replier.tag(), &tread);
+        match self.do_read(&tread).await {
+            Ok(data) => self.reply(replier, RRead { data: &data }),
+            Err(err) => self.error(replier, err),
+        }
+    }
+
+    async fn write(&self, twrite: TWrite<'_>, replier: Replier) -> Replied {
+        self.policy.log(replier.tag(), &twrite);
+        let request = |fid| TWrite { fid, ..twrite };
+        self.forward(twrite.fid, request, replier).await
+    }
+
+    async fn clunk(&self, tclunk: TClunk, replier: Replier) -> Replied {
+        self.policy.log(replier.tag(), &tclunk);
+        let res = self.take(tclunk.fid).map(|entry| {
+            self.clunk_upstream(entry.ufid);
+            RClunk
+        });
+        self.result(replier, res)
+    }
+
+    async fn remove(&self, tremove: TRemove, replier: Replier) -> Replied {
+        self.policy.log(replier.tag(), &tremove);
+        let res = self.do_remove(&tremove).await;
+        self.result(replier, res.map(|()| RRemove))
+    }
+
+    async fn stat(&self, tstat: TStat, replier: Replier) -> Replied {
+        self.policy.log(replier.tag(), &tstat);
+        self.forward(tstat.fid, |fid| TStat { fid }, replier).await
+    }
+
+    async fn wstat(&self, twstat: TWStat<'_>, replier: Replier) -> Replied {
+        self.policy.log(replier.tag(), &twstat);
+        let res = self.do_wstat(&twstat).await;
+        self.result(replier, res.map(|()| RWStat))
+    }
+}
+
+impl<P: Policy> Drop for Proxy<P> {
+    fn drop(&mut self) {
+        for (_, entry) in self.fids.get_mut().unwrap().drain() {
+            self.upstream
+                .forget(&TClunk { fid: entry.ufid }, Orphan::Clunks(entry.ufid));
+        }
+    }
+}
@@ -0,0 +1,423 @@
+// In-memory filesystem, mostly for testing clients against a real server
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    dir_reader::DirListing, fid::err, Error, FileHandler, Identity, Qid, RCreate, ROpen, Stat,
+    StatBuf, DMAPPEND, DMDIR, DMEXCL, ORCLOSE, OTRUNC, QTAPPEND, QTDIR, QTEXCL, QTFILE,
+};
+
+const ROOT: u64 = 0;
+// Largest a file can grow, as everything is kept in memory
+const MAX_SIZE: u64 = 128 * 1024 * 1024;
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as u32)
+}
+
+fn qid_type(mode: u32) -> u8 {
+    let mut type_ = QTFILE;
+    if mode & DMDIR != 0 {
+        type_ |= QTDIR;
+    }
+    if mode & DMAPPEND != 0 {
+        type_ |= QTAPPEND;
+    }
+    if mode & DMEXCL != 0 {
+        type_ |= QTEXCL;
+    }
+    type_
+}
+
+struct Node {
+    parent: u64,
+    name: String,
+    mode: u32,
+    vers: u32,
+    atime: u32,
+    mtime: u32,
+    uid: String,
+    gid: String,
+    muid: String,
+    data: Vec<u8>,
+    children: BTreeMap<String, u64>,
+    // Number of fids open, to enforce `DMEXCL`
+    opens: usize,
+}
+
+impl Node {
+    fn new(parent: u64, name: &str, mode: u32, uid: &str, gid: &str) -> Self {
+        let time = now();
+        Self {
+            parent,
+            name: name.to_string(),
+            mode,
+            vers: 0,
+            atime: time,
+            mtime: time,
+            uid: uid.to_string(),
+            gid: gid.to_string(),
+            muid: uid.to_string(),
+            data: Vec::new(),
+            children: BTreeMap::new(),
+            opens: 0,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode & DMDIR != 0
+    }
+
+    fn qid(&self, path: u64) -> Qid {
+        Qid {
+            type_: qid_type(self.mode),
+            vers: self.vers,
+            path,
+        }
+    }
+
+    fn stat(&self, path: u64) -> StatBuf {
+        StatBuf {
+            type_: 0,
+            dev: 0,
+            qid: self.qid(path),
+            mode: self.mode,
+            atime: self.atime,
+            mtime: self.mtime,
+            length: if self.is_dir() {
+                0
+            } else {
+                self.data.len() as u64
+            },
+            name: self.name.clone(),
+            uid: self.uid.clone(),
+            gid: self.gid.clone(),
+            muid: self.muid.clone(),
+        }
+    }
+
+    fn modified(&mut self, uname: &str) {
+        self.vers = self.vers.wrapping_add(1);
+        self.mtime = now();
+        self.muid = uname.to_string();
+    }
+}
+
+struct Tree {
+    // Keyed by qid path, which is never reused
+    nodes: HashMap<u64, Node>,
+    next_path: u64,
+}
+
+impl Tree {
+    fn get(&self, path: u64) -> Result<&Node, Error> {
+        match self.nodes.get(&path) {
+            Some(node) => Ok(node),
+            None => err("file has been removed"),
+        }
+    }
+
+    fn get_mut(&mut self, path: u64) -> Result<&mut Node, Error> {
+        match self.nodes.get_mut(&path) {
+            Some(node) => Ok(node),
+            None => err("file has been removed"),
+        }
+    }
+
+    fn remove(&mut self, path: u64) -> Result<(), Error> {
+        let node = self.get(path)?;
+        if path == ROOT {
+            return err("cannot remove root");
+        }
+        if !node.children.is_empty() {
+            return err("directory not empty");
+        }
+        let node = self.nodes.remove(&path).unwrap();
+        if let Some(parent) = self.nodes.get_mut(&node.parent) {
+            parent.children.remove(&node.name);
+            parent.mtime = now();
+        }
+        Ok(())
+    }
+}
+
+/// [`FileHandler`] keeping a tree of files in memory
+///
+/// Supports creating, removing, renaming and truncating files, as well as
+/// `ORCLOSE` and files with `DMAPPEND` or `DMEXCL` set. Qid versions change
+/// whenever a file is modified. Permissions aren't checked.
+pub struct Ramfs {
+    tree: Mutex<Tree>,
+}
+
+impl Default for Ramfs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ramfs {
+    /// Create a filesystem containing an empty root directory
+    pub fn new() -> Self {
+        let root = Node::new(ROOT, "/", DMDIR | 0o777, "none", "none");
+        Self {
+            tree: Mutex::new(Tree {
+                nodes: HashMap::from([(ROOT, root)]),
+                next_path: ROOT + 1,
+            }),
+        }
+    }
+
+    fn listing(&self, path: u64) -> Result<Vec<StatBuf>, Error> {
+        let tree = self.tree.lock().unwrap();
+        let children = tree.get(path)?.children.values();
+        Ok(children
+            .map(|&child| tree.nodes[&child].stat(child))
+            .collect())
+    }
+}
+
+/// A file in a [`Ramfs`]
+#[derive(Clone)]
+pub struct RamFile {
+    path: u64,
+}
+
+/// An open [`RamFile`]
+pub struct RamOpen {
+    remove_on_clunk: bool,
+    // Directory listing, generated again at offset 0
+    listing: Mutex<DirListing>,
+}
+
+impl FileHandler for Ramfs {
+    type File = RamFile;
+    type Open = RamOpen;
+
+    async fn attach(&self, _user: &Identity) -> Result<(RamFile, Qid), Error> {
+        let tree = self.tree.lock().unwrap();
+        Ok((RamFile { path: ROOT }, tree.get(ROOT)?.qid(ROOT)))
+    }
+
+    async fn walk(
+        &self,
+        _user: &Identity,
+        dir: &RamFile,
+        name: &str,
+    ) -> Result<(RamFile, Qid), Error> {
+        let tree = self.tree.lock().unwrap();
+        let node = tree.get(dir.path)?;
+        let path = if name == ".." {
+            node.parent
+        } else {
+            match node.children.get(name) {
+                Some(&path) => path,
+                None => return err("file does not exist"),
+            }
+        };
+        Ok((RamFile { path }, tree.get(path)?.qid(path)))
+    }
+
+    async fn open(
+        &self,
+        user: &Identity,
+        file: &RamFile,
+        mode: u8,
+    ) -> Result<(RamOpen, ROpen), Error> {
+        let mut tree = self.tree.lock().unwrap();
+        let node = tree.get_mut(file.path)?;
+        if node.mode & DMEXCL != 0 && node.opens > 0 {
+            return err("exclusive use file already open");
+        }
+        if mode & OTRUNC != 0 {
+            node.data.clear();
+            node.modified(&user.uname);
+        }
+        node.opens += 1;
+        node.atime = now();
+        let open = RamOpen {
+            remove_on_clunk: mode & ORCLOSE != 0,
+            listing: Mutex::default(),
+        };
+        let ropen = ROpen {
+            qid: node.qid(file.path),
+            iounit: 0,
+        };
+        Ok((open, ropen))
+    }
+
+    async fn create(
+        &self,
+        user: &Identity,
+        dir: &RamFile,
+        name: &str,
+        perm: u32,
+        mode: u8,
+    ) -> Result<(RamFile, RamOpen, RCreate), Error> {
+        let mut tree = self.tree.lock().unwrap();
+        let parent = tree.get(dir.path)?;
+        if parent.children.contains_key(name) {
+            return err("file already exists");
+        }
+        // Permissions are limited by those of the parent, as in Plan 9
+        let perm = if perm & DMDIR != 0 {
+            perm & (!0o777 | (parent.mode & 0o777))
+        } else {
+            perm & (!0o666 | (parent.mode & 0o666))
+        };
+        let mut node = Node::new(dir.path, name, perm, &user.uname, &parent.gid);
+        node.opens = 1;
+
+        let path = tree.next_path;
+        tree.next_path += 1;
+        let qid = node.qid(path);
+        tree.nodes.insert(path, node);
+        let parent = tree.get_mut(dir.path)?;
+        parent.children.insert(name.to_string(), path);
+        parent.mtime = now();
+
+        let file = RamFile { path };
+        let open = RamOpen {
+            remove_on_clunk: mode & ORCLOSE != 0,
+            listing: Mutex::default(),
+        };
+        Ok((file, open, RCreate { qid, iounit: 0 }))
+    }
+
+    async fn read(
+        &self,
+        _user: &Identity,
+        file: &RamFile,
+        open: &RamOpen,
+        offset: u64,
+        count: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let mut tree = self.tree.lock().unwrap();
+        let node = tree.get_mut(file.path)?;
+        node.atime = now();
+        if node.is_dir() {
+            drop(tree);
+            let mut listing = open.listing.lock().unwrap();
+            return listing.read(offset, count, || self.listing(file.path));
+        }
+        let start = (offset as usize).min(node.data.len());
+        let end = start.saturating_add(count as usize).min(node.data.len());
+        Ok(node.data[start..end].to_vec())
+    }
+
+    async fn write(
+        &self,
+        user: &Identity,
+        file: &RamFile,
+        _open: &RamOpen,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<u32, Error> {
+        let mut tree = self.tree.lock().unwrap();
+        let node = tree.get_mut(file.path)?;
+        let offset = if node.mode & DMAPPEND != 0 {
+            node.data.len() as u64
+        } else {
+            offset
+        };
+        let end = offset.checked_add(data.len() as u64);
+        let Some(end) = end.filter(|&end| end <= MAX_SIZE) else {
+            return err("file too large");
+        };
+        let (offset, end) = (offset as usize, end as usize);
+        if node.data.len() < end {
+            node.data.resize(end, 0);
+        }
+        node.data[offset..end].copy_from_slice(data);
+        node.modified(&user.uname);
+        Ok(data.len() as u32)
+    }
+
+    fn clunk(&self, _user: &Identity, file: &RamFile, open: Option<&RamOpen>) {
+        let Some(open) = open else {
+            return;
+        };
+        let mut tree = self.tree.lock().unwrap();
+        if let Ok(node) = tree.get_mut(file.path) {
+            node.opens -= 1;
+        }
+        if open.remove_on_clunk {
+            let _ = tree.remove(file.path);
+        }
+    }
+
+    async fn remove(
+        &self,
+        _user: &Identity,
+        file: &RamFile,
+        _open: Option<&RamOpen>,
+    ) -> Result<(), Error> {
+        self.tree.lock().unwrap().remove(file.path)
+    }
+
+    async fn stat(&self, _user: &Identity, file: &RamFile) -> Result<StatBuf, Error> {
+        let tree = self.tree.lock().unwrap();
+        Ok(tree.get(file.path)?.stat(file.path))
+    }
+
+    async fn wstat(&self, user: &Identity, file: &RamFile, stat: &Stat<'_>) -> Result<(), Error> {
+        let mut tree = self.tree.lock().unwrap();
+        let node = tree.get(file.path)?;
+
+        // Check everything before changing anything. Fields to leave
+        // unchanged are `!0` or empty.
+        if stat.mode != !0 && (stat.mode & DMDIR) != (node.mode & DMDIR) {
+            return err("can't change directory bit");
+        }
+        if stat.length != !0 && node.is_dir() {
+            return err("can't truncate a directory");
+        }
+        if stat.length != !0 && stat.length > MAX_SIZE {
+            return err("file too large");
+        }
+        let rename = !stat.name.is_empty() && stat.name != node.name;
+        if rename {
+            if file.path == ROOT {
+                return err("cannot rename root");
+            }
+            if stat.name == "." || stat.name == ".." || stat.name.contains('/') {
+                return err("illegal name");
+            }
+            if tree.get(node.parent)?.children.contains_key(stat.name) {
+                return err("file already exists");
+            }
+        }
+
+        let node = tree.get_mut(file.path)?;
+        if stat.length != !0 {
+            node.data.resize(stat.length as usize, 0);
+            node.modified(&user.uname);
+        }
+        if stat.mode != !0 {
+            node.mode = stat.mode;
+        }
+        if stat.mtime != !0 {
+            node.mtime = stat.mtime;
+        }
+        if !stat.uid.is_empty() {
+            node.uid = stat.uid.to_string();
+        }
+        if !stat.gid.is_empty() {
+            node.gid = stat.gid.to_string();
+        }
+        if rename {
+            let old_name = std::mem::replace(&mut node.name, stat.name.to_string());
+            let parent = node.parent;
+            let children = &mut tree.get_mut(parent)?.children;
+            children.remove(&old_name);
+            children.insert(stat.name.to_string(), file.path);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+// Remote file handle implementing tokio's I/O traits on top of `AsyncClient`
+
+use futures_io::{AsyncRead, AsyncWrite};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead as TokioAsyncRead, AsyncWrite as TokioAsyncWrite, ReadBuf};
+
+use crate::{AsyncClient, Fid};
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send>>;
+
+enum InFlight {
+    Pending(BoxFuture<Vec<u8>>),
+    Done(io::Result<Vec<u8>>),
+}
+
+struct ReadAhead {
+    offset: u64,
+    count: u32,
+    state: InFlight,
+}
+
+enum Seek {
+    Done(u64),
+    // Waiting for `TStat` to find the length of the file
+    End(BoxFuture<u64>, i64),
+}
+
+/// An open fid, usable with `AsyncRead`, `AsyncWrite` and `AsyncSeek`
+///
+/// Reads are split into `TRead`s of at most `iounit` bytes. With read-ahead
+/// enabled, several consecutive `TRead`s are kept in flight and their
+/// replies are returned in order, so sequential reads aren't limited by
+/// round-trip latency. A short read is treated as reaching the end of the
+/// data, and any read-ahead past it is discarded.
+///
+/// As with most `AsyncWrite` implementations, after `poll_write` returns
+/// `Pending` it must be called again with the same data.
+///
+/// The fid is not clunked on drop; use [`RemoteFile::clunk`].
+pub struct RemoteFile<R, W> {
+    client: Arc<AsyncClient<R, W>>,
+    fid: Fid,
+    iounit: u32,
+    readahead: usize,
+    // Position of next byte returned by `poll_read` or written by `poll_write`
+    pos: u64,
+    // Data already read from offset `pos`
+    buffer: Vec<u8>,
+    buffer_pos: usize,
+    inflight: VecDeque<ReadAhead>,
+    // Offset to issue the next read-ahead at
+    next_offset: u64,
+    write: Option<BoxFuture<u32>>,
+    seek: Option<Seek>,
+}
+
+impl<R, W> RemoteFile<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    /// Wrap a fid that has already been opened
+    ///
+    /// `iounit` should be the value from `ROpen`/`RCreate`, or
+    /// `msize - IOHDRSZ` if that was 0. Panics if `iounit` is 0, since
+    /// nothing could be read or written.
+    pub fn new(client: Arc<AsyncClient<R, W>>, fid: Fid, iounit: u32) -> Self {
+        assert!(iounit > 0, "iounit must not be 0");
+        Self {
+            client,
+            fid,
+            iounit,
+            readahead: 1,
+            pos: 0,
+            buffer: Vec::new(),
+            buffer_pos: 0,
+            inflight: VecDeque::new(),
+            next_offset: 0,
+            write: None,
+            seek: None,
+        }
+    }
+
+    /// Set the number of `TRead`s to keep in flight when reading
+    ///
+    /// The default is 1, which reads one `iounit` at a time.
+    pub fn readahead(mut self, readahead: usize) -> Self {
+        self.readahead = readahead.max(1);
+        self
+    }
+
+    pub fn fid(&self) -> Fid {
+        self.fid
+    }
+
+    /// Clunk the fid
+    pub async fn clunk(self) -> io::Result<()> {
+        let mut buf = Vec::new();
+        self.client
+            .send(crate::TClunk { fid: self.fid }, &mut buf)
+            .await?;
+        Ok(())
+    }
+
+    fn read_at(&self, offset: u64, count: u32) -> BoxFuture<Vec<u8>> {
+        let client = self.client.clone();
+        let fid = self.fid;
+        Box::pin(async move {
+            let mut buf = Vec::new();
+            let len = client
+                .send(crate::TRead { fid, offset, count }, &mut buf)
+                .await?
+                .data
+                .len();
+            // Strip count field of `RRead`
+            buf.drain(..4);
+            buf.truncate(len);
+            Ok(buf)
+        })
+    }
+
+    fn write_at(&self, offset: u64, data: Vec<u8>) -> BoxFuture<u32> {
+        let client = self.client.clone();
+        let fid = self.fid;
+        Box::pin(async move {
+            let mut buf = Vec::new();
+            let res = client
+                .send(
+                    crate::TWrite {
+                        fid,
+                        offset,
+                        data: &data,
+                    },
+                    &mut buf,
+                )
+                .await?;
+            Ok(res.count)
+        })
+    }
+
+    fn length(&self) -> BoxFuture<u64> {
+        let client = self.client.clone();
+        let fid = self.fid;
+        Box::pin(async move {
+            let mut buf = Vec::new();
+            let res = client.send(crate::TStat { fid }, &mut buf).await?;
+            Ok(res.stat.length)
+        })
+    }
+
+    /// Discard buffered and read-ahead data, after a seek or write
+    fn reset_read(&mut self) {
+        self.buffer.clear();
+        self.buffer_pos = 0;
+        self.inflight.clear();
+        self.next_offset = self.pos;
+    }
+
+    fn copy_buffered(&mut self, buf: &mut ReadBuf<'_>) {
+        let data = &self.buffer[self.buffer_pos..];
+        let len = data.len().min(buf.remaining());
+        buf.put_slice(&data[..len]);
+        self.buffer_pos += len;
+        self.pos += len as u64;
+    }
+}
+
+impl<R, W> TokioAsyncRead for RemoteFile<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.buffer_pos < this.buffer.len() || buf.remaining() == 0 {
+            this.copy_buffered(buf);
+            return Poll::Ready(Ok(()));
+        }
+
+        while this.inflight.len() < this.readahead {
+            let (offset, count) = (this.next_offset, this.iounit);
+            this.inflight.push_back(ReadAhead {
+                offset,
+                count,
+                state: InFlight::Pending(this.read_at(offset, count)),
+            });
+            this.next_offset += count as u64;
+        }
+
+        // Poll all reads, so they are sent, but only the first is returned
+        for read in &mut this.inflight {
+            if let InFlight::Pending(fut) = &mut read.state {
+                if let Poll::Ready(res) = fut.as_mut().poll(cx) {
+                    read.state = InFlight::Done(res);
+                }
+            }
+        }
+        if matches!(this.inflight[0].state, InFlight::Pending(_)) {
+            return Poll::Pending;
+        }
+
+        let read = this.inflight.pop_front().unwrap();
+        let InFlight::Done(res) = read.state else {
+            unreachable!()
+        };
+        match res {
+            Ok(data) => {
+                if data.len() < read.count as usize {
+                    // End of file, or at least of what the server will give
+                    // us for now. Later read-ahead is at the wrong offset.
+                    this.inflight.clear();
+                    this.next_offset = read.offset + data.len() as u64;
+                }
+                this.buffer = data;
+                this.buffer_pos = 0;
+                this.copy_buffered(buf);
+                Poll::Ready(Ok(()))
+            }
+            Err(err) => {
+                this.reset_read();
+                Poll::Ready(Err(err))
+            }
+        }
+    }
+}
+
+impl<R, W> TokioAsyncWrite for RemoteFile<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.write.is_none() {
+            this.reset_read();
+            let len = data.len().min(this.iounit as usize);
+            this.write = Some(this.write_at(this.pos, data[..len].to_vec()));
+        }
+        let res = ready!(this.write.as_mut().unwrap().as_mut().poll(cx));
+        this.write = None;
+        let count = res?;
+        this.pos += count as u64;
+        this.next_offset = this.pos;
+        Poll::Ready(Ok(count as usize))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if let Some(write) = &mut this.write {
+            let res = ready!(write.as_mut().poll(cx));
+            this.write = None;
+            this.pos += res? as u64;
+            this.next_offset = this.pos;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl<R, W> tokio::io::AsyncSeek for RemoteFile<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        if self.seek.is_some() {
+            return Err(io::Error::other("other seek in progress"));
+        }
+        let seek = match position {
+            io::SeekFrom::Start(offset) => Seek::Done(offset),
+            io::SeekFrom::Current(delta) => Seek::Done(checked_offset(self.pos, delta)?),
+            io::SeekFrom::End(delta) => Seek::End(self.length(), delta),
+        };
+        self.seek = Some(seek);
+        Ok(())
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = &mut *self;
+        let offset = match &mut this.seek {
+            None => return Poll::Ready(Ok(this.pos)),
+            Some(Seek::Done(offset)) => Ok(*offset),
+            Some(Seek::End(fut, delta)) => {
+                let delta = *delta;
+                ready!(fut.as_mut().poll(cx)).and_then(|len| checked_offset(len, delta))
+            }
+        };
+        this.seek = None;
+        if let Ok(offset) = offset {
+            if offset != this.pos {
+                this.pos = offset;
+                this.reset_read();
+            }
+        }
+        Poll::Ready(offset)
+    }
+}
+
+fn checked_offset(base: u64, delta: i64) -> io::Result<u64> {
+    base.checked_add_signed(delta).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
+
+/// Adapter implementing the `futures-io` traits for a tokio I/O type, so it
+/// can be used as the transport of an [`AsyncClient`]
+pub struct TokioCompat<T>(pub T);
+
+impl<T: TokioAsyncRead + Unpin> AsyncRead for TokioCompat<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut buf = ReadBuf::new(buf);
+        ready!(Pin::new(&mut self.0).poll_read(cx, &mut buf))?;
+        Poll::Ready(Ok(buf.filled().len()))
+    }
+}
+
+impl<T: TokioAsyncWrite + Unpin> AsyncWrite for TokioCompat<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,987 @@
+// Transport-independent parts of the server: the `Filesystem` trait that
+// handlers implement, `Replier`, and dispatching requests to the trait.
+
+use std::{
+    collections::HashMap,
+    future::{poll_fn, Future},
+    io,
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    metrics::{Metrics, Outcome},
+    Error, Header, Message, MessageType, RError, RFlush, RLError, RVersion, TAttach, TAuth, TClunk,
+    TCreate, TFSync, TFlush, TGetAttr, TGetLock, TLAttach, TLAuth, TLCreate, TLOpen, TLink, TLock,
+    TMkDir, TMkNod, TOpen, TRead, TReadDir, TReadLink, TRemove, TRename, TRenameAt, TSetAttr,
+    TStat, TStatFs, TSymlink, TUnlinkAt, TVersion, TWStat, TWalk, TWrite, TXattrCreate, TXattrWalk,
+    IOHDRSZ,
+};
+
+/// Default largest message size the server accepts
+pub const DEFAULT_MSIZE: u32 = 128 * 1024;
+
+/// Handler for the requests on one connection
+///
+/// A new handler is created for each connection (and when a connection
+/// renegotiates with `TVersion`), so fids can be tracked in `self`. Requests
+/// are handled concurrently, and each must be answered exactly once through
+/// its `Replier`, with either the corresponding R-message or an `RError`.
+///
+/// `TVersion` and `TFlush` are handled by the server. A flushed request is
+/// aborted by dropping its future at the next point it yields, and its
+/// `RFlush` is sent once that's happened, or once it has replied. Handlers
+/// that block without yielding can check [`Replier::cancel_token`].
+///
+/// Handlers that set `DOTL` also speak 9P2000.L with clients that ask for
+/// it. In a 9P2000.L session the 9P2000.L methods are called in place of
+/// `auth`, `attach`, `open`, `create`, `stat` and `wstat`, and errors are
+/// sent as `RLError`.
+pub trait Filesystem: Send + Sync + 'static {
+    /// Whether 9P2000.L is supported
+    const DOTL: bool = false;
+
+    /// By default, replies that authentication is not required
+    fn auth(&self, tauth: TAuth<'_>, replier: Replier) -> impl Future<Output = Replied> + Send {
+        let _ = tauth;
+        async move { replier.error("authentication not required") }
+    }
+
+    fn attach(
+        &self,
+        tattach: TAttach<'_>,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send;
+
+    fn walk(&self, twalk: TWalk<'_>, replier: Replier) -> impl Future<Output = Replied> + Send;
+
+    fn open(&self, topen: TOpen, replier: Replier) -> impl Future<Output = Replied> + Send;
+
+    fn create(
+        &self,
+        tcreate: TCreate<'_>,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send;
+
+    fn read(&self, tread: TRead, replier: Replier) -> impl Future<Output = Replied> + Send;
+
+    fn write(&self, twrite: TWrite<'_>, replier: Replier) -> impl Future<Output = Replied> + Send;
+
+    fn clunk(&self, tclunk: TClunk, replier: Replier) -> impl Future<Output = Replied> + Send;
+
+    fn remove(&self, tremove: TRemove, replier: Replier) -> impl Future<Output = Replied> + Send;
+
+    fn stat(&self, tstat: TStat, replier: Replier) -> impl Future<Output = Replied> + Send;
+
+    fn wstat(&self, twstat: TWStat<'_>, replier: Replier) -> impl Future<Output = Replied> + Send;
+
+    /// By default, passed on to `auth`, without the numeric user id
+    fn lauth(&self, tlauth: TLAuth<'_>, replier: Replier) -> impl Future<Output = Replied> + Send {
+        let tauth = TAuth {
+            afid: tlauth.afid,
+            uname: tlauth.uname,
+            aname: tlauth.aname,
+        };
+        self.auth(tauth, replier)
+    }
+
+    /// By default, passed on to `attach`, without the numeric user id
+    fn lattach(
+        &self,
+        tlattach: TLAttach<'_>,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send {
+        let tattach = TAttach {
+            fid: tlattach.fid,
+            afid: tlattach.afid,
+            uname: tlattach.uname,
+            aname: tlattach.aname,
+        };
+        self.attach(tattach, replier)
+    }
+
+    fn statfs(&self, tstatfs: TStatFs, replier: Replier) -> impl Future<Output = Replied> + Send {
+        let _ = tstatfs;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn lopen(&self, tlopen: TLOpen, replier: Replier) -> impl Future<Output = Replied> + Send {
+        let _ = tlopen;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn lcreate(
+        &self,
+        tlcreate: TLCreate<'_>,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send {
+        let _ = tlcreate;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn symlink(
+        &self,
+        tsymlink: TSymlink<'_>,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send {
+        let _ = tsymlink;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn mknod(&self, tmknod: TMkNod<'_>, replier: Replier) -> impl Future<Output = Replied> + Send {
+        let _ = tmknod;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn rename(
+        &self,
+        trename: TRename<'_>,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send {
+        let _ = trename;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn readlink(
+        &self,
+        treadlink: TReadLink,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send {
+        let _ = treadlink;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn getattr(
+        &self,
+        tgetattr: TGetAttr,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send {
+        let _ = tgetattr;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn setattr(
+        &self,
+        tsetattr: TSetAttr,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send {
+        let _ = tsetattr;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn xattrwalk(
+        &self,
+        txattrwalk: TXattrWalk<'_>,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send {
+        let _ = txattrwalk;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn xattrcreate(
+        &self,
+        txattrcreate: TXattrCreate<'_>,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send {
+        let _ = txattrcreate;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn readdir(
+        &self,
+        treaddir: TReadDir,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send {
+        let _ = treaddir;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn fsync(&self, tfsync: TFSync, replier: Replier) -> impl Future<Output = Replied> + Send {
+        let _ = tfsync;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn lock(&self, tlock: TLock<'_>, replier: Replier) -> impl Future<Output = Replied> + Send {
+        let _ = tlock;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn getlock(
+        &self,
+        tgetlock: TGetLock<'_>,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send {
+        let _ = tgetlock;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn link(&self, tlink: TLink<'_>, replier: Replier) -> impl Future<Output = Replied> + Send {
+        let _ = tlink;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn mkdir(&self, tmkdir: TMkDir<'_>, replier: Replier) -> impl Future<Output = Replied> + Send {
+        let _ = tmkdir;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn renameat(
+        &self,
+        trenameat: TRenameAt<'_>,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send {
+        let _ = trenameat;
+        async move { replier.error("operation not supported") }
+    }
+
+    fn unlinkat(
+        &self,
+        tunlinkat: TUnlinkAt<'_>,
+        replier: Replier,
+    ) -> impl Future<Output = Replied> + Send {
+        let _ = tunlinkat;
+        async move { replier.error("operation not supported") }
+    }
+}
+
+/// Proof that a request has been replied to
+pub struct Replied(());
+
+/// Limits on what clients can use, and where to count what they do, set
+/// through the servers' builders
+#[derive(Clone, Debug)]
+pub(crate) struct Limits {
+    pub(crate) msize: u32,
+    // Outstanding requests on each connection
+    pub(crate) requests: usize,
+    // Outstanding requests on all connections together
+    pub(crate) total_requests: Option<Arc<Slots>>,
+    // How long a connection can go without requests before it's closed
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) metrics: Option<Arc<Metrics>>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            msize: DEFAULT_MSIZE,
+            requests: usize::MAX,
+            total_requests: None,
+            idle_timeout: None,
+            metrics: None,
+        }
+    }
+}
+
+/// Counts outstanding requests against a limit
+#[derive(Debug)]
+pub(crate) struct Slots {
+    max: usize,
+    state: Mutex<SlotState>,
+}
+
+#[derive(Debug)]
+struct SlotState {
+    used: usize,
+    // Readers waiting for a request to finish
+    wakers: Vec<Waker>,
+}
+
+impl Slots {
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            max,
+            state: Mutex::new(SlotState {
+                used: 0,
+                wakers: Vec::new(),
+            }),
+        }
+    }
+
+    fn poll_acquire(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.used < self.max {
+            state.used += 1;
+            return Poll::Ready(());
+        }
+        if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            state.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.used -= 1;
+        for waker in state.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Where a connection's serialized replies are sent to be written
+pub(crate) trait Sink: Send + Sync {
+    fn send(&self, message: Vec<u8>);
+}
+
+/// Signals that a request has been flushed, or that its session has ended,
+/// by a new `TVersion` or the client disconnecting
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<Mutex<CancelState>>);
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: bool,
+    wakers: Vec<Waker>,
+}
+
+impl CancelToken {
+    pub fn is_cancelled(&self) -> bool {
+        self.0.lock().unwrap().cancelled
+    }
+
+    /// Wait until the request is cancelled
+    pub fn cancelled(&self) -> impl Future<Output = ()> + Send + '_ {
+        poll_fn(|cx| self.poll_cancelled(cx))
+    }
+
+    fn poll_cancelled(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.0.lock().unwrap();
+        if state.cancelled {
+            return Poll::Ready(());
+        }
+        if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            state.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    fn cancel(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.cancelled = true;
+        for waker in state.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// A request that hasn't been replied to yet
+struct Pending {
+    // Tells it apart from later requests with the same tag
+    id: u64,
+    type_: u8,
+    received: Instant,
+    cancel: CancelToken,
+    // Tags of the `TFlush` requests waiting for it, and when they arrived
+    flushes: Vec<(u16, Instant)>,
+}
+
+#[derive(Default)]
+struct Requests {
+    by_tag: HashMap<u16, Pending>,
+    // Those of ended sessions, by id: nothing may be sent for them, and
+    // their tags can be used again
+    aborted: HashMap<u64, Pending>,
+    next_id: u64,
+}
+
+/// State shared by a connection's reader and every `Replier`
+pub(crate) struct Connection {
+    sink: Box<dyn Sink>,
+    pending: Mutex<Requests>,
+    // Taken by the reader before reading each request, and given back when
+    // the request is finished
+    requests: Slots,
+    total_requests: Option<Arc<Slots>>,
+    // Whether 9P2000.L was negotiated, so errors are sent as `RLError`
+    dotl: AtomicBool,
+    // Most data a reply can carry with the negotiated `msize`
+    iounit: AtomicU32,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Connection {
+    pub(crate) fn new(sink: impl Sink + 'static, limits: &Limits) -> Arc<Self> {
+        if let Some(metrics) = &limits.metrics {
+            metrics.connected();
+        }
+        Arc::new(Self {
+            sink: Box::new(sink),
+            pending: Mutex::new(Requests::default()),
+            requests: Slots::new(limits.requests),
+            total_requests: limits.total_requests.clone(),
+            dotl: AtomicBool::new(false),
+            iounit: AtomicU32::new(limits.msize.saturating_sub(IOHDRSZ)),
+            metrics: limits.metrics.clone(),
+        })
+    }
+
+    /// Track a new request, or return `None` if its tag is already in use
+    fn replier(self: &Arc<Self>, tag: u16, type_: u8, received: Instant) -> Option<Replier> {
+        let mut requests = self.pending.lock().unwrap();
+        if requests.by_tag.contains_key(&tag) {
+            return None;
+        }
+        let id = requests.next_id;
+        requests.next_id += 1;
+        let cancel = CancelToken::default();
+        let pending = Pending {
+            id,
+            type_,
+            received,
+            cancel: cancel.clone(),
+            flushes: Vec::new(),
+        };
+        requests.by_tag.insert(tag, pending);
+        Some(Replier {
+            conn: self.clone(),
+            tag,
+            id,
+            cancel,
+            replied: false,
+        })
+    }
+
+    /// Send a reply that isn't for a tracked request
+    fn send<'a, T: Message<'a>>(&self, tag: u16, message: &T) {
+        self.write(serialize(tag, message));
+    }
+
+    /// Send an error that isn't for a tracked request
+    fn send_error(&self, tag: u16, ename: &str) {
+        self.write(serialize_error(self.dotl(), tag, ename));
+    }
+
+    fn write(&self, message: Vec<u8>) {
+        if let Some(metrics) = &self.metrics {
+            metrics.sent(message.len());
+        }
+        self.sink.send(message);
+    }
+
+    /// Count a request the server answered itself
+    fn record(&self, type_: MessageType, received: Instant) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record(type_ as u8, received.elapsed(), Outcome::Replied);
+        }
+    }
+
+    fn dotl(&self) -> bool {
+        self.dotl.load(Ordering::Relaxed)
+    }
+
+    fn iounit(&self) -> u32 {
+        self.iounit.load(Ordering::Relaxed)
+    }
+
+    /// Cancel the request with `oldtag`, and reply to the `TFlush` with
+    /// `tag` once it's finished
+    fn flush(&self, tag: u16, oldtag: u16, received: Instant) {
+        let mut requests = self.pending.lock().unwrap();
+        match requests.by_tag.get_mut(&oldtag) {
+            Some(pending) => {
+                pending.flushes.push((tag, received));
+                pending.cancel.cancel();
+            }
+            // Already replied to, or never existed
+            None => {
+                self.send(tag, &RFlush);
+                self.record(MessageType::TFlush, received);
+            }
+        }
+    }
+
+    /// Cancel every request, discarding their replies
+    fn abort_all(&self) {
+        let mut requests = self.pending.lock().unwrap();
+        for pending in std::mem::take(&mut requests.by_tag).into_values() {
+            pending.cancel.cancel();
+            requests.aborted.insert(pending.id, pending);
+        }
+    }
+
+    /// Send the reply to a request, if any, followed by the `RFlush`
+    /// replies of anything flushing it
+    fn finish(&self, tag: u16, id: u64, reply: Option<Vec<u8>>) {
+        let (pending, discard) = {
+            let mut requests = self.pending.lock().unwrap();
+            match requests.by_tag.get(&tag) {
+                Some(pending) if pending.id == id => (requests.by_tag.remove(&tag).unwrap(), false),
+                _ => match requests.aborted.remove(&id) {
+                    Some(pending) => (pending, true),
+                    None => return,
+                },
+            }
+        };
+        self.requests.release();
+        if let Some(total) = &self.total_requests {
+            total.release();
+        }
+        if let Some(metrics) = &self.metrics {
+            let outcome = match &reply {
+                _ if discard => Outcome::Flushed,
+                None => Outcome::Flushed,
+                Some(reply) if is_error(reply) => Outcome::Error,
+                Some(_) => Outcome::Replied,
+            };
+            metrics.record(pending.type_, pending.received.elapsed(), outcome);
+        }
+        if discard {
+            return;
+        }
+        if let Some(reply) = reply {
+            self.write(reply);
+        }
+        for (tag, received) in pending.flushes {
+            self.send(tag, &RFlush);
+            self.record(MessageType::TFlush, received);
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.disconnected();
+        }
+    }
+}
+
+// Whether a serialized reply is an `RError` or `RLError`
+fn is_error(reply: &[u8]) -> bool {
+    [MessageType::RError as u8, MessageType::RLError as u8].contains(&reply[4])
+}
+
+fn serialize<'a, T: Message<'a>>(tag: u16, message: &T) -> Vec<u8> {
+    let header = Header::for_message(message, tag);
+    let mut buf = Vec::with_capacity(header.size as usize);
+    header.write(&mut buf).unwrap();
+    message.write(&mut buf).unwrap();
+    buf
+}
+
+// Serialize an `RError`, or an `RLError` for 9P2000.L
+fn serialize_error(dotl: bool, tag: u16, ename: &str) -> Vec<u8> {
+    if dotl {
+        let ecode = ename_errno(ename);
+        serialize(tag, &RLError { ecode })
+    } else {
+        serialize(tag, &RError { ename, errno: None })
+    }
+}
+
+/// Used to send the reply to one request
+///
+/// If dropped without replying, an `RError` is sent so the client isn't left
+/// waiting, unless the request has been flushed.
+pub struct Replier {
+    conn: Arc<Connection>,
+    tag: u16,
+    id: u64,
+    cancel: CancelToken,
+    replied: bool,
+}
+
+impl Replier {
+    pub fn tag(&self) -> u16 {
+        self.tag
+    }
+
+    /// Token cancelled when the request is flushed
+    pub fn cancel_token(&self) -> &CancelToken {
+        &self.cancel
+    }
+
+    // XXX trait for just reply messages?
+    pub fn reply<'a, T: Message<'a>>(mut self, message: T) -> Replied {
+        self.send(&message);
+        Replied(())
+    }
+
+    /// Reply with an `RError`, or in a 9P2000.L session, an `RLError` with
+    /// the errno `ename` corresponds to
+    pub fn error(mut self, ename: &str) -> Replied {
+        self.send_error(ename);
+        Replied(())
+    }
+
+    /// Reply with `message`, or an error from `Error`
+    ///
+    /// In a 9P2000.L session, the errno of OS errors is sent as is.
+    pub fn result<'a, T: Message<'a>>(self, result: Result<T, Error>) -> Replied {
+        match result {
+            Ok(message) => self.reply(message),
+            Err(err) if self.conn.dotl() => self.reply(RLError { ecode: errno(&err) }),
+            Err(err) => self.error(&ename(&err)),
+        }
+    }
+
+    fn send<'a, T: Message<'a>>(&mut self, message: &T) {
+        self.replied = true;
+        self.conn
+            .finish(self.tag, self.id, Some(serialize(self.tag, message)));
+    }
+
+    fn send_error(&mut self, ename: &str) {
+        self.replied = true;
+        let reply = serialize_error(self.conn.dotl(), self.tag, ename);
+        self.conn.finish(self.tag, self.id, Some(reply));
+    }
+}
+
+impl Drop for Replier {
+    fn drop(&mut self) {
+        if self.replied {
+            return;
+        }
+        if self.cancel.is_cancelled() {
+            // Aborted by a flush
+            self.conn.finish(self.tag, self.id, None);
+        } else {
+            self.send_error("request dropped without reply");
+        }
+    }
+}
+
+/// Error string to reply with for an `Error`
+pub(crate) fn ename(err: &Error) -> String {
+    match err {
+        Error::Protocol(ename) => ename.clone(),
+        Error::Io(err) => io_ename(err),
+        err => err.to_string(),
+    }
+}
+
+/// Error string for an OS error, using Plan 9's wording where it has one
+fn io_ename(err: &io::Error) -> String {
+    let ename = match err.kind() {
+        io::ErrorKind::NotFound => "file does not exist",
+        io::ErrorKind::PermissionDenied => "permission denied",
+        io::ErrorKind::AlreadyExists => "file already exists",
+        io::ErrorKind::NotADirectory => "not a directory",
+        io::ErrorKind::IsADirectory => "is a directory",
+        io::ErrorKind::DirectoryNotEmpty => "directory not empty",
+        io::ErrorKind::ReadOnlyFilesystem => "read-only file system",
+        io::ErrorKind::StorageFull => "file system full",
+        io::ErrorKind::CrossesDevices => "cross-device rename",
+        io::ErrorKind::InvalidFilename => "file name syntax",
+        io::ErrorKind::InvalidInput => "bad arg in system call",
+        _ => return err.to_string(),
+    };
+    ename.to_string()
+}
+
+/// Linux errno for an `Error`, for `RLError`
+fn errno(err: &Error) -> u32 {
+    match err {
+        // XXX assumes the host's errno values are Linux's
+        #[cfg(target_os = "linux")]
+        Error::Io(err) if err.raw_os_error().is_some() => err.raw_os_error().unwrap() as u32,
+        Error::Protocol(ename) => ename_errno(ename),
+        Error::Io(err) => ename_errno(&io_ename(err)),
+        _ => EIO,
+    }
+}
+
+const EIO: u32 = 5;
+
+// Linux errno values of the error strings used by this crate, and of some
+// from Plan 9
+const ERRNOS: &[(&str, u32)] = &[
+    ("permission denied", 13),
+    ("file does not exist", 2),
+    ("file not found", 2),
+    ("file already exists", 17),
+    ("file in use", 16),
+    ("not a directory", 20),
+    ("walk in non-directory", 20),
+    ("create in non-directory", 20),
+    ("is a directory", 21),
+    ("file is a directory", 21),
+    ("directory not empty", 39),
+    ("read-only file system", 30),
+    ("file system full", 28),
+    ("cross-device rename", 18),
+    ("file name syntax", 22),
+    ("illegal name", 22),
+    ("bad arg in system call", 22),
+    ("bad offset in directory read", 22),
+    ("directory read count too small", 22),
+    ("unknown fid", 9),
+    ("unknown afid", 9),
+    ("fid not open", 9),
+    ("fid already open", 9),
+    ("fid not open for reading", 9),
+    ("fid not open for writing", 9),
+    ("fid is an auth fid", 9),
+    ("fid is an xattr fid", 9),
+    ("duplicate fid", 9),
+    ("duplicate tag", 22),
+    ("too many fids", 24),
+    ("too many fids on server", 23),
+    ("too many names in walk", 36),
+    ("file name too long", 36),
+    ("file too large", 27),
+    ("interrupted", 4),
+    ("i/o error", 5),
+    ("malformed message", 71),
+    ("version not negotiated", 71),
+    ("unknown message type", 95),
+    ("operation not supported", 95),
+    ("authentication not required", 95),
+    ("create prohibited", 1),
+    ("write prohibited", 1),
+    ("remove prohibited", 1),
+    ("wstat prohibited", 1),
+    ("xattr value too long", 7),
+    ("xattr value too short", 22),
+];
+
+/// Linux errno for an error string, or `EIO` if it isn't known
+///
+/// Knows the strings this crate replies with, and the common ones from
+/// Plan 9.
+pub fn ename_errno(ename: &str) -> u32 {
+    ERRNOS
+        .iter()
+        .find(|(known, _)| *known == ename)
+        .map_or(EIO, |&(_, errno)| errno)
+}
+
+/// Reading side of a connection, shared by the different servers
+///
+/// Handles the requests answered by the server itself, and tracks the
+/// handler for the current session.
+pub(crate) struct Session<F> {
+    conn: Arc<Connection>,
+    max_msize: u32,
+    msize: u32,
+    // Set by `TVersion`
+    fs: Option<Arc<F>>,
+    // Whether slots for the next request have been taken
+    reserved: bool,
+}
+
+impl<F: Filesystem> Session<F> {
+    pub(crate) fn new(conn: Arc<Connection>, max_msize: u32) -> Self {
+        Self {
+            conn,
+            max_msize,
+            msize: max_msize,
+            fs: None,
+            reserved: false,
+        }
+    }
+
+    /// Wait until another request can be handled without exceeding the
+    /// limits, so the server stops reading from clients sending too many
+    pub(crate) async fn reserve(&mut self) {
+        if self.reserved {
+            return;
+        }
+        let conn = &self.conn;
+        poll_fn(|cx| conn.requests.poll_acquire(cx)).await;
+        if let Some(total) = &conn.total_requests {
+            poll_fn(|cx| total.poll_acquire(cx)).await;
+        }
+        self.reserved = true;
+    }
+
+    /// Whether there are no outstanding requests
+    pub(crate) fn idle(&self) -> bool {
+        let requests = self.conn.pending.lock().unwrap();
+        requests.by_tag.is_empty() && requests.aborted.is_empty()
+    }
+
+    /// Length of the body of a message with `header`, or an error if it
+    /// exceeds `msize`
+    pub(crate) fn body_len(&self, header: &Header) -> io::Result<usize> {
+        if header.size < 7 || header.size > self.msize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message size larger than msize",
+            ));
+        }
+        Ok(header.size as usize - 7)
+    }
+
+    /// Handle a request the server answers itself, or return the handler and
+    /// `Replier` to `dispatch` it with
+    pub(crate) fn request(
+        &mut self,
+        header: &Header,
+        body: &[u8],
+        new_fs: impl FnOnce() -> F,
+    ) -> Option<(Arc<F>, Replier)> {
+        let tag = header.tag;
+        let received = Instant::now();
+        if let Some(metrics) = &self.conn.metrics {
+            metrics.received(header.size);
+        }
+        match MessageType::try_from(header.type_) {
+            Ok(MessageType::TVersion) => {
+                let Ok(tversion) = TVersion::parse(body) else {
+                    self.conn.send_error(tag, "malformed message");
+                    return None;
+                };
+                // Starts a new session, with no fids or outstanding requests
+                self.conn.abort_all();
+                let rversion = version(&tversion, self.max_msize, F::DOTL);
+                self.msize = rversion.msize;
+                let iounit = rversion.msize.saturating_sub(IOHDRSZ);
+                self.conn.iounit.store(iounit, Ordering::Relaxed);
+                let dotl = rversion.version == "9P2000.L";
+                self.conn.dotl.store(dotl, Ordering::Relaxed);
+                self.fs = (rversion.version != "unknown").then(|| Arc::new(new_fs()));
+                self.conn.send(tag, &rversion);
+                self.conn.record(MessageType::TVersion, received);
+                None
+            }
+            Ok(MessageType::TFlush) => {
+                match TFlush::parse(body) {
+                    Ok(tflush) => self.conn.flush(tag, tflush.oldtag, received),
+                    Err(_) => self.conn.send_error(tag, "malformed message"),
+                }
+                None
+            }
+            _ => {
+                let Some(replier) = self.conn.replier(tag, header.type_, received) else {
+                    self.conn.send_error(tag, "duplicate tag");
+                    return None;
+                };
+                // Given back when the request is finished
+                debug_assert!(self.reserved);
+                self.reserved = false;
+                let Some(fs) = self.fs.clone() else {
+                    replier.error("version not negotiated");
+                    return None;
+                };
+                Some((fs, replier))
+            }
+        }
+    }
+}
+
+impl<F> Drop for Session<F> {
+    // The client is gone, so nothing is left to wait for the replies of
+    // requests still being handled, and the handler can be dropped
+    fn drop(&mut self) {
+        self.conn.abort_all();
+        if self.reserved {
+            if let Some(total) = &self.conn.total_requests {
+                total.release();
+            }
+        }
+    }
+}
+
+/// Reply to `TVersion`, given the largest `msize` supported by the server,
+/// and whether it supports 9P2000.L
+///
+/// The version is "unknown" if the client doesn't speak 9P2000.
+fn version(tversion: &TVersion, max_msize: u32, dotl: bool) -> RVersion<'static> {
+    let msize = tversion.msize.min(max_msize);
+    // Plan 9 defines anything starting with "9P2000" as compatible, and
+    // suffixes we don't understand are ignored.
+    let version = if dotl && tversion.version == "9P2000.L" {
+        "9P2000.L"
+    } else if tversion.version.starts_with("9P2000") {
+        "9P2000"
+    } else {
+        "unknown"
+    };
+    RVersion { msize, version }
+}
+
+/// Parse a request, and pass it to the appropriate method of `fs`, until it
+/// finishes or is cancelled
+pub(crate) async fn dispatch<F: Filesystem>(
+    fs: &F,
+    header: &Header,
+    body: &[u8],
+    replier: Replier,
+) {
+    let cancel = replier.cancel_token().clone();
+    let mut handler = pin!(handle(fs, header, body, replier));
+    poll_fn(|cx| {
+        if cancel.poll_cancelled(cx).is_ready() {
+            // Dropping the handler drops its `Replier`
+            return Poll::Ready(());
+        }
+        handler.as_mut().poll(cx).map(|_| ())
+    })
+    .await
+}
+
+async fn handle<F: Filesystem>(fs: &F, header: &Header, body: &[u8], replier: Replier) -> Replied {
+    macro_rules! call {
+        ($type:ty, $method:ident) => {
+            match <$type>::parse(body) {
+                Ok(request) => fs.$method(request, replier).await,
+                Err(_) => replier.error("malformed message"),
+            }
+        };
+        // Reads asking for more than fits in a reply get what does
+        ($type:ty, $method:ident, count) => {
+            match <$type>::parse(body) {
+                Ok(mut request) => {
+                    request.count = request.count.min(replier.conn.iounit());
+                    fs.$method(request, replier).await
+                }
+                Err(_) => replier.error("malformed message"),
+            }
+        };
+    }
+
+    if replier.conn.dotl() {
+        return match MessageType::try_from(header.type_) {
+            Ok(MessageType::TAuth) => call!(TLAuth, lauth),
+            Ok(MessageType::TAttach) => call!(TLAttach, lattach),
+            Ok(MessageType::TWalk) => call!(TWalk, walk),
+            Ok(MessageType::TRead) => call!(TRead, read, count),
+            Ok(MessageType::TWrite) => call!(TWrite, write),
+            Ok(MessageType::TClunk) => call!(TClunk, clunk),
+            Ok(MessageType::TRemove) => call!(TRemove, remove),
+            Ok(MessageType::TStatFs) => call!(TStatFs, statfs),
+            Ok(MessageType::TLOpen) => call!(TLOpen, lopen),
+            Ok(MessageType::TLCreate) => call!(TLCreate, lcreate),
+            Ok(MessageType::TSymlink) => call!(TSymlink, symlink),
+            Ok(MessageType::TMkNod) => call!(TMkNod, mknod),
+            Ok(MessageType::TRename) => call!(TRename, rename),
+            Ok(MessageType::TReadLink) => call!(TReadLink, readlink),
+            Ok(MessageType::TGetAttr) => call!(TGetAttr, getattr),
+            Ok(MessageType::TSetAttr) => call!(TSetAttr, setattr),
+            Ok(MessageType::TXattrWalk) => call!(TXattrWalk, xattrwalk),
+            Ok(MessageType::TXattrCreate) => call!(TXattrCreate, xattrcreate),
+            Ok(MessageType::TReadDir) => call!(TReadDir, readdir, count),
+            Ok(MessageType::TFSync) => call!(TFSync, fsync),
+            Ok(MessageType::TLock) => call!(TLock, lock),
+            Ok(MessageType::TGetLock) => call!(TGetLock, getlock),
+            Ok(MessageType::TLink) => call!(TLink, link),
+            Ok(MessageType::TMkDir) => call!(TMkDir, mkdir),
+            Ok(MessageType::TRenameAt) => call!(TRenameAt, renameat),
+            Ok(MessageType::TUnlinkAt) => call!(TUnlinkAt, unlinkat),
+            _ => replier.error("unknown message type"),
+        };
+    }
+
+    match MessageType::try_from(header.type_) {
+        Ok(MessageType::TAuth) => call!(TAuth, auth),
+        Ok(MessageType::TAttach) => call!(TAttach, attach),
+        Ok(MessageType::TWalk) => call!(TWalk, walk),
+        Ok(MessageType::TOpen) => call!(TOpen, open),
+        Ok(MessageType::TCreate) => call!(TCreate, create),
+        Ok(MessageType::TRead) => call!(TRead, read, count),
+        Ok(MessageType::TWrite) => call!(TWrite, write),
+        Ok(MessageType::TClunk) => call!(TClunk, clunk),
+        Ok(MessageType::TRemove) => call!(TRemove, remove),
+        Ok(MessageType::TStat) => call!(TStat, stat),
+        Ok(MessageType::TWStat) => call!(TWStat, wstat),
+        _ => replier.error("unknown message type"),
+    }
+}
@@ -1,6 +1,6 @@
 use std::io;
 
-use crate::{Error, Header, Message, RError, TMessage};
+use crate::{Error, Header, Message, RError, RLError, TMessage};
 
 /// Simple client that sends a command then blocks until it gets a reply
 pub struct SyncClient<T: io::Read + io::Write> {
@@ -10,12 +10,27 @@ pub struct SyncClient<T: io::Read + io::Write> {
 
 /// Parse a reply of type `Reply`, or a `RError`.
 ///
-/// Returns `UnexpectedType` if the message has any other type.
-fn parse_reply<'a, Reply: Message<'a>>(header: &Header, body: &'a [u8]) -> Result<Reply, Error> {
+/// An `RLError`, or an `RError` with a 9P2000.u errno, is returned as an
+/// `io::Error` with its errno. Returns `UnexpectedType` if the message has
+/// any other type.
+pub(crate) fn parse_reply<'a, Reply: Message<'a>>(
+    header: &Header,
+    body: &'a [u8],
+) -> Result<Reply, Error> {
     if header.type_ == Reply::TYPE as u8 {
         Reply::parse(body)
     } else if header.type_ == RError::TYPE as u8 {
-        Err(Error::Protocol(RError::parse(body)?.ename.to_string()))
+        let rerror = RError::parse(body)?;
+        match rerror.errno {
+            // XXX assumes the server's errno values are the host's
+            Some(errno) if errno != 0 && errno != !0 => {
+                Err(Error::Io(io::Error::from_raw_os_error(errno as i32)))
+            }
+            _ => Err(Error::Protocol(rerror.ename.to_string())),
+        }
+    } else if header.type_ == RLError::TYPE as u8 {
+        let ecode = RLError::parse(body)?.ecode;
+        Err(Error::Io(io::Error::from_raw_os_error(ecode as i32)))
     } else {
         Err(Error::UnexpectedType(header.type_))
     }
@@ -0,0 +1,447 @@
+// Trees of synthetic files, for servers exposing program state, in the style
+// of lib9p's `File` trees. Directories are kept by the library; files are
+// backed by a `FileOps` implementation, which creates per-open state that
+// handles reads and writes.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Poll, Waker},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    dir_reader::DirListing, fid::err, Error, FileHandler, Identity, Qid, ROpen, StatBuf, DMDIR,
+    OEXEC, ORDWR, OREAD, OTRUNC, OWRITE, QTDIR, QTFILE,
+};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Behavior of a synthetic file
+pub trait FileOps: Send + Sync + 'static {
+    /// Create the state for a new open of the file
+    fn open(&self, mode: u8) -> Result<Box<dyn OpenFile>, Error>;
+
+    /// Length reported by stat
+    fn length(&self) -> u64 {
+        0
+    }
+}
+
+/// An open synthetic file
+///
+/// Dropped when the fid is clunked.
+pub trait OpenFile: Send + Sync {
+    fn read(&self, _offset: u64, _count: u32) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        Box::pin(async { err("read prohibited") })
+    }
+
+    fn write<'a>(&'a self, _offset: u64, _data: &'a [u8]) -> BoxFuture<'a, Result<u32, Error>> {
+        Box::pin(async { err("write prohibited") })
+    }
+}
+
+enum Kind {
+    Dir(Mutex<BTreeMap<String, SynthFile>>),
+    File(Box<dyn FileOps>),
+}
+
+struct Node {
+    name: String,
+    mode: u32,
+    path: u64,
+    mtime: u32,
+    uid: Arc<str>,
+    // Root has none
+    parent: Weak<Node>,
+    // Shared by the whole tree
+    next_path: Arc<AtomicU64>,
+    kind: Kind,
+}
+
+/// A file or directory in a [`SynthTree`]
+#[derive(Clone)]
+pub struct SynthFile(Arc<Node>);
+
+impl SynthFile {
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self.0.kind, Kind::Dir(_))
+    }
+
+    fn qid(&self) -> Qid {
+        Qid {
+            type_: if self.is_dir() { QTDIR } else { QTFILE },
+            vers: 0,
+            path: self.0.path,
+        }
+    }
+
+    fn add(&self, name: &str, mode: u32, kind: Kind) -> Result<SynthFile, Error> {
+        let Kind::Dir(entries) = &self.0.kind else {
+            return err("not a directory");
+        };
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return err("illegal name");
+        }
+        let mut entries = entries.lock().unwrap();
+        if entries.contains_key(name) {
+            return err("file already exists");
+        }
+        let node = Node {
+            name: name.to_string(),
+            mode,
+            path: self.0.next_path.fetch_add(1, Ordering::Relaxed),
+            mtime: now(),
+            uid: self.0.uid.clone(),
+            parent: Arc::downgrade(&self.0),
+            next_path: self.0.next_path.clone(),
+            kind,
+        };
+        let file = SynthFile(Arc::new(node));
+        entries.insert(name.to_string(), file.clone());
+        Ok(file)
+    }
+
+    /// Add a directory to this directory
+    ///
+    /// `mode` is the permission bits; `DMDIR` is added.
+    pub fn create_dir(&self, name: &str, mode: u32) -> Result<SynthFile, Error> {
+        self.add(name, mode | DMDIR, Kind::Dir(Mutex::new(BTreeMap::new())))
+    }
+
+    /// Add a file to this directory
+    ///
+    /// `mode` is the permission bits, which also determine whether the file
+    /// can be opened for reading and writing.
+    pub fn create_file(
+        &self,
+        name: &str,
+        mode: u32,
+        ops: impl FileOps,
+    ) -> Result<SynthFile, Error> {
+        self.add(name, mode & !DMDIR, Kind::File(Box::new(ops)))
+    }
+
+    /// Remove from its parent directory
+    ///
+    /// Fids already referring to the file keep working.
+    pub fn remove(&self) -> Result<(), Error> {
+        let Some(parent) = self.0.parent.upgrade() else {
+            return err("cannot remove root");
+        };
+        let Kind::Dir(entries) = &parent.kind else {
+            unreachable!()
+        };
+        entries.lock().unwrap().remove(&self.0.name);
+        Ok(())
+    }
+
+    /// Look up an entry of this directory
+    pub fn lookup(&self, name: &str) -> Option<SynthFile> {
+        match &self.0.kind {
+            Kind::Dir(entries) => entries.lock().unwrap().get(name).cloned(),
+            Kind::File(_) => None,
+        }
+    }
+
+    fn stat(&self) -> StatBuf {
+        let length = match &self.0.kind {
+            Kind::Dir(_) => 0,
+            Kind::File(ops) => ops.length(),
+        };
+        StatBuf {
+            type_: 0,
+            dev: 0,
+            qid: self.qid(),
+            mode: self.0.mode,
+            atime: self.0.mtime,
+            mtime: self.0.mtime,
+            length,
+            name: self.0.name.clone(),
+            uid: self.0.uid.to_string(),
+            gid: self.0.uid.to_string(),
+            muid: self.0.uid.to_string(),
+        }
+    }
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as u32)
+}
+
+/// [`FileHandler`] serving a tree of synthetic files
+///
+/// Files are added to the tree through [`SynthTree::root`], before or while
+/// serving. Clients can't create, remove or change files.
+pub struct SynthTree {
+    root: SynthFile,
+}
+
+impl SynthTree {
+    /// Create a tree with an empty root directory, with files owned by `uid`
+    pub fn new(uid: &str) -> Self {
+        let root = Node {
+            name: "/".to_string(),
+            mode: DMDIR | 0o555,
+            path: 0,
+            mtime: now(),
+            uid: uid.into(),
+            parent: Weak::new(),
+            next_path: Arc::new(AtomicU64::new(1)),
+            kind: Kind::Dir(Mutex::new(BTreeMap::new())),
+        };
+        Self {
+            root: SynthFile(Arc::new(root)),
+        }
+    }
+
+    pub fn root(&self) -> &SynthFile {
+        &self.root
+    }
+}
+
+/// An open [`SynthFile`]
+pub struct SynthOpen {
+    file: Option<Box<dyn OpenFile>>,
+    // Directory listing, generated again at offset 0
+    listing: Mutex<DirListing>,
+}
+
+impl FileHandler for SynthTree {
+    type File = SynthFile;
+    type Open = SynthOpen;
+
+    async fn attach(&self, _user: &Identity) -> Result<(SynthFile, Qid), Error> {
+        Ok((self.root.clone(), self.root.qid()))
+    }
+
+    async fn walk(
+        &self,
+        _user: &Identity,
+        dir: &SynthFile,
+        name: &str,
+    ) -> Result<(SynthFile, Qid), Error> {
+        let file = if name == ".." {
+            match dir.0.parent.upgrade() {
+                Some(parent) => SynthFile(parent),
+                None => dir.clone(),
+            }
+        } else {
+            match dir.lookup(name) {
+                Some(file) => file,
+                None => return err("file does not exist"),
+            }
+        };
+        let qid = file.qid();
+        Ok((file, qid))
+    }
+
+    async fn open(
+        &self,
+        _user: &Identity,
+        file: &SynthFile,
+        mode: u8,
+    ) -> Result<(SynthOpen, ROpen), Error> {
+        // Identity isn't checked, so permission for anyone is enough
+        let read = matches!(mode & 3, OREAD | ORDWR);
+        let write = matches!(mode & 3, OWRITE | ORDWR) || mode & OTRUNC != 0;
+        let exec = mode & 3 == OEXEC;
+        let perm = file.0.mode;
+        if (read && perm & 0o444 == 0)
+            || (write && perm & 0o222 == 0)
+            || (exec && perm & 0o111 == 0)
+        {
+            return err("permission denied");
+        }
+        let open = match &file.0.kind {
+            Kind::Dir(_) => None,
+            Kind::File(ops) => Some(ops.open(mode)?),
+        };
+        let open = SynthOpen {
+            file: open,
+            listing: Mutex::default(),
+        };
+        let ropen = ROpen {
+            qid: file.qid(),
+            iounit: 0,
+        };
+        Ok((open, ropen))
+    }
+
+    async fn read(
+        &self,
+        _user: &Identity,
+        file: &SynthFile,
+        open: &SynthOpen,
+        offset: u64,
+        count: u32,
+    ) -> Result<Vec<u8>, Error> {
+        match (&file.0.kind, &open.file) {
+            (Kind::File(_), Some(open)) => open.read(offset, count).await,
+            (Kind::Dir(entries), _) => {
+                let mut listing = open.listing.lock().unwrap();
+                listing.read(offset, count, || {
+                    let entries = entries.lock().unwrap();
+                    Ok::<_, Error>(entries.values().map(SynthFile::stat).collect())
+                })
+            }
+            (Kind::File(_), None) => unreachable!(),
+        }
+    }
+
+    async fn write(
+        &self,
+        _user: &Identity,
+        _file: &SynthFile,
+        open: &SynthOpen,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<u32, Error> {
+        match &open.file {
+            Some(open) => open.write(offset, data).await,
+            None => err("is a directory"),
+        }
+    }
+
+    async fn stat(&self, _user: &Identity, file: &SynthFile) -> Result<StatBuf, Error> {
+        Ok(file.stat())
+    }
+}
+
+/// A read-only file with contents from `render`, called each time the file
+/// is opened
+pub fn text_file<F>(render: F) -> impl FileOps
+where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    struct Text<F>(F);
+
+    struct TextOpen(Vec<u8>);
+
+    impl<F: Fn() -> String + Send + Sync + 'static> FileOps for Text<F> {
+        fn open(&self, _mode: u8) -> Result<Box<dyn OpenFile>, Error> {
+            Ok(Box::new(TextOpen((self.0)().into_bytes())))
+        }
+    }
+
+    impl OpenFile for TextOpen {
+        fn read(&self, offset: u64, count: u32) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+            let start = (offset as usize).min(self.0.len());
+            let end = start.saturating_add(count as usize).min(self.0.len());
+            Box::pin(async move { Ok(self.0[start..end].to_vec()) })
+        }
+    }
+
+    Text(render)
+}
+
+/// A write-only file that passes each write, without a trailing newline, to
+/// `handle` as a command
+///
+/// An error from `handle` is returned to the client as the result of the
+/// write.
+pub fn ctl_file<F>(handle: F) -> impl FileOps
+where
+    F: Fn(&str) -> Result<(), Error> + Send + Sync + 'static,
+{
+    struct Ctl<F>(Arc<F>);
+
+    impl<F: Fn(&str) -> Result<(), Error> + Send + Sync + 'static> FileOps for Ctl<F> {
+        fn open(&self, _mode: u8) -> Result<Box<dyn OpenFile>, Error> {
+            Ok(Box::new(Ctl(self.0.clone())))
+        }
+    }
+
+    impl<F: Fn(&str) -> Result<(), Error> + Send + Sync + 'static> OpenFile for Ctl<F> {
+        fn write<'a>(&'a self, _offset: u64, data: &'a [u8]) -> BoxFuture<'a, Result<u32, Error>> {
+            Box::pin(async move {
+                let Ok(command) = std::str::from_utf8(data) else {
+                    return err("invalid UTF-8 in command");
+                };
+                (self.0)(command.strip_suffix('\n').unwrap_or(command))?;
+                Ok(data.len() as u32)
+            })
+        }
+    }
+
+    Ctl(Arc::new(handle))
+}
+
+#[derive(Default)]
+struct Subscriber {
+    queue: VecDeque<Vec<u8>>,
+    waker: Option<Waker>,
+}
+
+/// Source for an event file
+///
+/// Each open of the file receives the events sent after it was opened, one
+/// per read. Reads block until an event is available.
+#[derive(Clone, Default)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<Weak<Mutex<Subscriber>>>>>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send an event to every open of the file
+    pub fn send(&self, event: impl Into<Vec<u8>>) {
+        let event = event.into();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| {
+            let Some(subscriber) = subscriber.upgrade() else {
+                return false;
+            };
+            let mut subscriber = subscriber.lock().unwrap();
+            subscriber.queue.push_back(event.clone());
+            if let Some(waker) = subscriber.waker.take() {
+                waker.wake();
+            }
+            true
+        });
+    }
+}
+
+struct EventsOpen(Arc<Mutex<Subscriber>>);
+
+impl FileOps for Events {
+    fn open(&self, _mode: u8) -> Result<Box<dyn OpenFile>, Error> {
+        let subscriber = Arc::new(Mutex::new(Subscriber::default()));
+        let weak = Arc::downgrade(&subscriber);
+        self.subscribers.lock().unwrap().push(weak);
+        Ok(Box::new(EventsOpen(subscriber)))
+    }
+}
+
+impl OpenFile for EventsOpen {
+    fn read(&self, _offset: u64, count: u32) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        Box::pin(poll_fn(move |cx| {
+            let mut subscriber = self.0.lock().unwrap();
+            let Some(event) = subscriber.queue.front_mut() else {
+                subscriber.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            };
+            // An event too long for the read is returned over several
+            let event = if event.len() > count as usize {
+                event.drain(..count as usize).collect()
+            } else {
+                subscriber.queue.pop_front().unwrap()
+            };
+            Poll::Ready(Ok(event))
+        }))
+    }
+}
@@ -1,45 +1,198 @@
-// WIP
-
 // Server doesn't need to validate tag or otherwise worry about it, but can
-// just return unchanged?
-// Is a task per invocation undeseriable?
-// - in particular, having to lock TcpStream
+// just return unchanged.
+//
+// Each request is handled in its own task. Replies are serialized by the
+// handler and passed over a channel to a task that writes them, so handlers
+// never wait on each other to write.
 
-use std::{future::Future, sync::Arc};
+use std::{io, sync::Arc, time::Duration};
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    sync::Mutex,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    net::TcpListener,
+    sync::mpsc,
+    time,
 };
 
-use crate::{Header, Message};
-
-pub struct Replied(());
-
-pub struct Replier {
-    stream: Arc<Mutex<TcpStream>>,
-    tag: u16,
-}
+use crate::{
+    server::{self, Connection, Limits, Session, Sink, Slots},
+    Filesystem, Header, Metrics,
+};
 
-impl Replier {
-    // XXX trait for just reply messages?
-    async fn reply<'a, T: Message<'a>>(self, message: T) -> Replied {
-        let header = Header::for_message(&message, self.tag);
-        let mut stream = self.stream.lock().await;
-        header.async_write(&mut *stream).await;
-        // XXX send message on socket
-        Replied(())
+impl Sink for mpsc::UnboundedSender<Vec<u8>> {
+    fn send(&self, message: Vec<u8>) {
+        // Fails only if the connection is closed, in which case the reply
+        // can be discarded.
+        let _ = mpsc::UnboundedSender::send(self, message);
     }
 }
 
+/// 9P server using tokio
+///
+/// Clients sending more requests than the limits allow are throttled by
+/// not reading from them until some of their requests finish.
+#[derive(Clone, Debug)]
 pub struct TokioServer {
-    listener: TcpListener,
+    limits: Limits,
+}
+
+impl Default for TokioServer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TokioServer {
-    // XXX distinguish connections?
-    async fn serve<Fut: Future<Output = Replied>, F: Fn(Replier) -> Fut + Sync>() {}
+    pub fn new() -> Self {
+        Self {
+            limits: Limits::default(),
+        }
+    }
+
+    /// Set the largest `msize` to accept in `TVersion`
+    pub fn msize(mut self, msize: u32) -> Self {
+        self.limits.msize = msize;
+        self
+    }
+
+    /// Limit the number of outstanding requests on each connection
+    pub fn max_requests(mut self, max: usize) -> Self {
+        self.limits.requests = max;
+        self
+    }
+
+    /// Limit the number of outstanding requests on all connections together
+    pub fn max_total_requests(mut self, max: usize) -> Self {
+        self.limits.total_requests = Some(Arc::new(Slots::new(max)));
+        self
+    }
+
+    /// Close connections that have no outstanding requests, and send nothing
+    /// for `timeout`
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Count the requests of every connection in `metrics`
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.limits.metrics = Some(metrics);
+        self
+    }
+
+    /// Accept connections on `listener`, calling `new_fs` to create a handler
+    /// for each
+    pub async fn serve<F, N>(&self, listener: TcpListener, new_fs: N) -> io::Result<()>
+    where
+        F: Filesystem,
+        N: Fn() -> F + Send + Sync + 'static,
+    {
+        let new_fs = Arc::new(new_fs);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = self.clone();
+            let new_fs = new_fs.clone();
+            tokio::spawn(async move {
+                let (reader, writer) = stream.into_split();
+                // XXX report errors?
+                let _ = server
+                    .serve_connection(reader, writer, move || new_fs())
+                    .await;
+            });
+        }
+    }
+
+    /// Accept connections on a Unix domain socket, like
+    /// [`TokioServer::serve`]
+    #[cfg(unix)]
+    pub async fn serve_unix<F, N>(&self, listener: UnixListener, new_fs: N) -> io::Result<()>
+    where
+        F: Filesystem,
+        N: Fn() -> F + Send + Sync + 'static,
+    {
+        let new_fs = Arc::new(new_fs);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = self.clone();
+            let new_fs = new_fs.clone();
+            tokio::spawn(async move {
+                let (reader, writer) = stream.into_split();
+                // XXX report errors?
+                let _ = server
+                    .serve_connection(reader, writer, move || new_fs())
+                    .await;
+            });
+        }
+    }
+
+    /// Serve a single connection over stdin and stdout, such as for a server
+    /// started by its client
+    pub async fn serve_stdio<F: Filesystem>(&self, new_fs: impl Fn() -> F) -> io::Result<()> {
+        self.serve_connection(tokio::io::stdin(), tokio::io::stdout(), new_fs)
+            .await
+    }
+
+    /// Serve a single connection, until the client disconnects
+    pub async fn serve_connection<F, R, W>(
+        &self,
+        mut reader: R,
+        writer: W,
+        new_fs: impl Fn() -> F,
+    ) -> io::Result<()>
+    where
+        F: Filesystem,
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(write_replies(BufWriter::new(writer), receiver));
+        let conn = Connection::new(sender, &self.limits);
+        let mut session = Session::new(conn, self.limits.msize);
+
+        loop {
+            session.reserve().await;
+            let mut header = [0; 7];
+            // Reading a single byte can be cancelled without losing data
+            let len = loop {
+                let read = reader.read(&mut header[..1]);
+                let Some(timeout) = self.limits.idle_timeout else {
+                    break read.await?;
+                };
+                match time::timeout(timeout, read).await {
+                    Ok(len) => break len?,
+                    Err(_) if session.idle() => return Ok(()),
+                    Err(_) => {}
+                }
+            };
+            if len == 0 {
+                return Ok(());
+            }
+            reader.read_exact(&mut header[1..]).await?;
+            let header = Header::from_array(header);
+            let mut body = vec![0; session.body_len(&header)?];
+            reader.read_exact(&mut body).await?;
+
+            if let Some((fs, replier)) = session.request(&header, &body, &new_fs) {
+                tokio::spawn(async move {
+                    server::dispatch(&*fs, &header, &body, replier).await;
+                });
+            }
+        }
+    }
 }
 
-// Handle connections similarly to how a web server would?
-// - best practice for tokio web server?
+async fn write_replies<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+) -> io::Result<()> {
+    while let Some(message) = receiver.recv().await {
+        writer.write_all(&message).await?;
+        // Write everything that's ready before flushing
+        while let Ok(message) = receiver.try_recv() {
+            writer.write_all(&message).await?;
+        }
+        writer.flush().await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,422 @@
+// Connection to a server that requests from other connections are forwarded
+// over, as by a proxy, or that any number of threads send requests over
+// directly. Tags and fids are allocated here, so requests from any number of
+// clients can share the connection. A thread reads replies and hands each to
+// the request waiting for its tag. Requests given up on are flushed, and fids
+// they may have created or left behind are clunked.
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use crate::{
+    blocking_server::block_on, sync_client::parse_reply, Error, Fid, Header, Message, SyncClient,
+    TClunk, TFlush, TMessage, TVersion, NOTAG,
+};
+
+/// A stream an [`Upstream`] can be connected over
+pub trait Stream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Shut down both directions, so the thread reading replies finishes
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// What happens to an upstream fid if a request is given up on before its
+/// reply arrives
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Orphan {
+    None,
+    /// The request may create the fid, so it's clunked afterwards
+    NewFid(Fid),
+    /// The request clunks the fid if it's processed, so it's freed once
+    /// it replies, or clunked again if it's flushed first
+    Clunks(Fid),
+}
+
+#[derive(Default)]
+struct Slot {
+    reply: Option<(Header, Vec<u8>)>,
+    waker: Option<Waker>,
+    // Set once nothing waits for the reply
+    orphan: Option<Orphan>,
+    // For a `TFlush`, the tag it flushes
+    flushing: Option<u16>,
+}
+
+#[derive(Default)]
+struct State {
+    slots: HashMap<u16, Slot>,
+    next_tag: u16,
+    fids: HashSet<u32>,
+    next_fid: u32,
+    // Set once the connection fails
+    broken: Option<String>,
+}
+
+impl State {
+    fn alloc_tag(&mut self) -> Option<u16> {
+        for _ in 0..NOTAG {
+            let tag = self.next_tag;
+            self.next_tag = self.next_tag.wrapping_add(1) % NOTAG;
+            if !self.slots.contains_key(&tag) {
+                return Some(tag);
+            }
+        }
+        None
+    }
+
+    fn broken_error(&self) -> Option<Error> {
+        let msg = self.broken.as_ref()?;
+        Some(io::Error::new(io::ErrorKind::BrokenPipe, msg.clone()).into())
+    }
+}
+
+struct Shared {
+    writer: Mutex<Box<dyn Write + Send>>,
+    state: Mutex<State>,
+    // Notified when the connection fails
+    closed: Condvar,
+}
+
+impl Shared {
+    // Queue a request with a slot for its reply, and send it
+    fn send<'a, T: Message<'a>>(&self, request: &T, slot: Slot) -> Option<u16> {
+        let tag = {
+            let mut state = self.state.lock().unwrap();
+            if state.broken.is_some() {
+                return None;
+            }
+            let tag = state.alloc_tag()?;
+            state.slots.insert(tag, slot);
+            tag
+        };
+        let header = Header::for_message(request, tag);
+        let mut buf = Vec::with_capacity(header.size as usize);
+        header.write(&mut buf).unwrap();
+        request.write(&mut buf).unwrap();
+        let res = self.writer.lock().unwrap().write_all(&buf);
+        if let Err(err) = res {
+            self.set_broken(&err.to_string());
+        }
+        Some(tag)
+    }
+
+    // Send a request whose reply nobody waits for
+    fn forget<'a, T: Message<'a>>(&self, request: &T, orphan: Orphan) {
+        let slot = Slot {
+            orphan: Some(orphan),
+            ..Slot::default()
+        };
+        // If it can't be sent, the fid is never reused
+        let _ = self.send(request, slot);
+    }
+
+    // Deal with a fid left by a request given up on, once it has either
+    // replied or been flushed
+    fn finish_orphan(&self, orphan: Orphan, replied: bool) {
+        match orphan {
+            Orphan::None => {}
+            Orphan::NewFid(fid) if replied => self.forget(&TClunk { fid }, Orphan::Clunks(fid)),
+            Orphan::NewFid(fid) => self.free_fid(fid),
+            Orphan::Clunks(fid) if replied => self.free_fid(fid),
+            Orphan::Clunks(fid) => self.forget(&TClunk { fid }, Orphan::Clunks(fid)),
+        }
+    }
+
+    fn free_fid(&self, fid: Fid) {
+        self.state.lock().unwrap().fids.remove(&fid.0);
+    }
+
+    fn set_broken(&self, msg: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.broken.get_or_insert_with(|| msg.to_string());
+        self.closed.notify_all();
+        for slot in state.slots.values_mut() {
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn dispatch(&self, header: Header, body: Vec<u8>) {
+        let mut orphans = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            // XXX reply with unknown tag is ignored
+            let Some(slot) = state.slots.get_mut(&header.tag) else {
+                return;
+            };
+            let Some(orphan) = slot.orphan else {
+                slot.reply = Some((header, body));
+                if let Some(waker) = slot.waker.take() {
+                    waker.wake();
+                }
+                return;
+            };
+            let flushing = slot.flushing;
+            state.slots.remove(&header.tag);
+            orphans.push((orphan, true));
+            // Once a `TFlush` is answered, the request it flushed won't be
+            if let Some(old) = flushing.and_then(|tag| state.slots.remove(&tag)) {
+                orphans.push((old.orphan.unwrap_or(Orphan::None), false));
+            }
+        }
+        for (orphan, replied) in orphans {
+            self.finish_orphan(orphan, replied);
+        }
+    }
+
+    fn read_replies(&self, mut reader: impl Read) {
+        let err = loop {
+            let mut header = [0; 7];
+            if let Err(err) = reader.read_exact(&mut header) {
+                break err;
+            }
+            let header = Header::from_array(header);
+            let Some(len) = (header.size as usize).checked_sub(7) else {
+                break io::Error::new(io::ErrorKind::InvalidData, "message size too small");
+            };
+            let mut body = vec![0; len];
+            if let Err(err) = reader.read_exact(&mut body) {
+                break err;
+            }
+            self.dispatch(header, body);
+        };
+        self.set_broken(&format!("upstream connection lost: {}", err));
+    }
+}
+
+/// A connection to a 9P2000 server that requests are forwarded to, by
+/// [`Proxy`](crate::Proxy), or sent to with [`Upstream::send`]
+///
+/// The version is negotiated once, when connecting. Any number of proxies,
+/// for any number of client sessions, can share the connection; the tags and
+/// fids they use upstream are allocated here, so they never conflict.
+pub struct Upstream {
+    shared: Arc<Shared>,
+    msize: u32,
+    version: String,
+    shutdown: Box<dyn Fn() + Send + Sync>,
+}
+
+impl Upstream {
+    /// Negotiate a version over `stream`, asking for `msize`, and start a
+    /// thread reading replies from it
+    pub fn new<S: Stream>(stream: S, msize: u32) -> Result<Self, Error> {
+        Self::with_version(stream, msize, "9P2000")
+    }
+
+    /// Like [`Upstream::new`], but asking for `version`, such as
+    /// "9P2000.L", which the server may answer with an older version
+    ///
+    /// Only 9P2000 is understood by [`Proxy`](crate::Proxy).
+    pub fn with_version<S: Stream>(
+        mut stream: S,
+        msize: u32,
+        version: &str,
+    ) -> Result<Self, Error> {
+        let tversion = TVersion { msize, version };
+        let mut client = SyncClient::new(&mut stream);
+        let rversion = client.send(NOTAG, tversion)?;
+        if !rversion.version.starts_with("9P2000") {
+            return Err(Error::Protocol("upstream doesn't speak 9P2000".to_string()));
+        }
+        let msize = rversion.msize.min(msize);
+        let version = rversion.version.to_string();
+        drop(client);
+
+        let reader = stream.try_clone()?;
+        let closer = Mutex::new(stream.try_clone()?);
+        let shared = Arc::new(Shared {
+            writer: Mutex::new(Box::new(stream)),
+            state: Mutex::default(),
+            closed: Condvar::new(),
+        });
+        let thread_shared = shared.clone();
+        thread::spawn(move || thread_shared.read_replies(reader));
+        Ok(Self {
+            shared,
+            msize,
+            version,
+            shutdown: Box::new(move || {
+                let _ = closer.lock().unwrap().shutdown();
+            }),
+        })
+    }
+
+    /// The `msize` negotiated with the server
+    pub fn msize(&self) -> u32 {
+        self.msize
+    }
+
+    /// The version negotiated with the server
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Send a request and block until its reply arrives
+    ///
+    /// Any number of threads can send at once, each waiting only for its
+    /// own reply. The reply body is stored in `buf`, which the returned
+    /// message borrows. Fids are the caller's to allocate, and must not
+    /// conflict with those of any [`Proxy`](crate::Proxy) on the same
+    /// connection.
+    pub fn send<'a, 'b, T: TMessage<'a>>(
+        &self,
+        request: T,
+        buf: &'b mut Vec<u8>,
+    ) -> Result<T::RMessage<'b>, Error> {
+        let (header, body) = block_on(self.call(&request, Orphan::None))?;
+        *buf = body;
+        parse_reply(&header, buf)
+    }
+
+    /// Block until the connection fails or the server closes it, after which
+    /// every request fails
+    pub fn wait_closed(&self) {
+        let state = self.shared.state.lock().unwrap();
+        let _state = self
+            .shared
+            .closed
+            .wait_while(state, |state| state.broken.is_none())
+            .unwrap();
+    }
+
+    /// Send a request, returning a future for the reply
+    ///
+    /// If the future is dropped first, the request is flushed, and `orphan`
+    /// says what to do about the fid it refers to.
+    pub(crate) fn call<'a, T: TMessage<'a>>(&self, request: &T, orphan: Orphan) -> Call<'_> {
+        let tag = self.shared.send(request, Slot::default());
+        Call {
+            shared: &self.shared,
+            tag,
+            orphan,
+        }
+    }
+
+    /// Send a request without waiting for the reply
+    pub(crate) fn forget<'a, T: TMessage<'a>>(&self, request: &T, orphan: Orphan) {
+        self.shared.forget(request, orphan);
+    }
+
+    /// Allocate an unused fid
+    pub(crate) fn alloc_fid(&self) -> Result<Fid, Error> {
+        let mut state = self.shared.state.lock().unwrap();
+        for _ in 0..u32::MAX {
+            let fid = state.next_fid;
+            state.next_fid = state.next_fid.wrapping_add(1);
+            if fid != Fid::NOFID.0 && state.fids.insert(fid) {
+                return Ok(Fid(fid));
+            }
+        }
+        Err(Error::Protocol("no free fids".to_string()))
+    }
+
+    /// Free a fid that the server no longer knows of
+    pub(crate) fn free_fid(&self, fid: Fid) {
+        self.shared.free_fid(fid);
+    }
+}
+
+impl Drop for Upstream {
+    fn drop(&mut self) {
+        (self.shutdown)();
+    }
+}
+
+/// Future for the reply to a request sent by [`Upstream::call`]
+pub(crate) struct Call<'a> {
+    shared: &'a Shared,
+    // None if it couldn't be sent, or once it's finished
+    tag: Option<u16>,
+    orphan: Orphan,
+}
+
+impl Future for Call<'_> {
+    type Output = Result<(Header, Vec<u8>), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let shared = self.shared;
+        let mut state = shared.state.lock().unwrap();
+        let Some(tag) = self.tag else {
+            let err = state.broken_error();
+            return Poll::Ready(Err(
+                err.unwrap_or_else(|| io::Error::other("no free tags").into())
+            ));
+        };
+        let broken = state.broken_error();
+        let slot = state.slots.get_mut(&tag).unwrap();
+        let res = match (slot.reply.take(), broken) {
+            (Some(reply), _) => Ok(reply),
+            (None, Some(err)) => Err(err),
+            (None, None) => {
+                slot.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        };
+        state.slots.remove(&tag);
+        self.tag = None;
+        Poll::Ready(res)
+    }
+}
+
+impl Drop for Call<'_> {
+    fn drop(&mut self) {
+        let Some(oldtag) = self.tag else {
+            return;
+        };
+        let mut state = self.shared.state.lock().unwrap();
+        let broken = state.broken.is_some();
+        let Some(slot) = state.slots.get_mut(&oldtag) else {
+            return;
+        };
+        if slot.reply.is_some() || broken {
+            let replied = slot.reply.is_some();
+            state.slots.remove(&oldtag);
+            drop(state);
+            self.shared.finish_orphan(self.orphan, replied);
+            return;
+        }
+        slot.orphan = Some(self.orphan);
+        slot.waker = None;
+        drop(state);
+        let flush = Slot {
+            orphan: Some(Orphan::None),
+            flushing: Some(oldtag),
+            ..Slot::default()
+        };
+        self.shared.send(&TFlush { oldtag }, flush);
+    }
+}
@@ -0,0 +1,115 @@
+// Concurrent requests from an `AsyncClient` to a ramfs
+#![cfg(all(feature = "futures-io", feature = "tokio"))]
+
+mod common;
+
+use common::async_client::{connect, run, Client};
+use nine_p::{Fid, TClunk, TCreate, TRead, TStat, TWalk, TWrite, ORDWR};
+use std::{
+    future::{poll_fn, Future},
+    task::Poll,
+};
+
+// Create `name` in the root, holding `data`, and leave it open on `fid`
+async fn create(client: &Client, fid: u32, name: &str, data: &[u8]) {
+    let mut buf = Vec::new();
+    let twalk = TWalk {
+        fid: Fid(0),
+        newfid: Fid(fid),
+        wnames: Vec::new(),
+    };
+    client.send(twalk, &mut buf).await.unwrap();
+    let tcreate = TCreate {
+        fid: Fid(fid),
+        name,
+        perm: 0o644,
+        mode: ORDWR,
+    };
+    client.send(tcreate, &mut buf).await.unwrap();
+    let twrite = TWrite {
+        fid: Fid(fid),
+        offset: 0,
+        data,
+    };
+    client.send(twrite, &mut buf).await.unwrap();
+}
+
+async fn read(client: &Client, fid: u32) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let tread = TRead {
+        fid: Fid(fid),
+        offset: 0,
+        count: 4096,
+    };
+    client.send(tread, &mut buf).await.unwrap().data.to_vec()
+}
+
+#[test]
+fn concurrent_requests() {
+    run(async {
+        let client = connect().await;
+        for i in 1..=16 {
+            let name = format!("file{}", i);
+            create(&client, i, &name, name.as_bytes()).await;
+        }
+
+        // Each task gets the replies to its own requests, whatever order
+        // they arrive in
+        let tasks: Vec<_> = (1..=16)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let name = client
+                        .send(TStat { fid: Fid(i) }, &mut buf)
+                        .await
+                        .unwrap()
+                        .stat
+                        .name
+                        .to_string();
+                    (i, name, read(&client, i).await)
+                })
+            })
+            .collect();
+        for task in tasks {
+            let (i, name, data) = task.await.unwrap();
+            assert_eq!(name, format!("file{}", i));
+            assert_eq!(data, name.as_bytes());
+        }
+    });
+}
+
+#[test]
+fn dropped_requests() {
+    run(async {
+        let client = connect().await;
+        create(&client, 1, "stale", b"stale").await;
+        create(&client, 2, "fresh", b"fresh").await;
+
+        // Dropped before being sent
+        let mut buf = Vec::new();
+        drop(client.send(TClunk { fid: Fid(1) }, &mut buf));
+
+        // Dropped after being sent, before the reply is read
+        let tread = TRead {
+            fid: Fid(1),
+            offset: 0,
+            count: 4096,
+        };
+        let mut buf = Vec::new();
+        let mut stale = Box::pin(client.send(tread, &mut buf));
+        poll_fn(|cx| {
+            let _ = stale.as_mut().poll(cx);
+            Poll::Ready(())
+        })
+        .await;
+        drop(stale);
+
+        // Neither is mistaken for the reply to a later request, and the
+        // clunk was never sent
+        for _ in 0..4 {
+            assert_eq!(read(&client, 2).await, b"fresh");
+        }
+        assert_eq!(read(&client, 1).await, b"stale");
+    });
+}
@@ -0,0 +1,137 @@
+// Authenticating over an afid before attaching
+
+mod common;
+
+use common::{attach, ename, listen, version};
+use nine_p::{
+    Authenticator, BlockingServer, Error, Fid, FidServer, Identity, Ramfs, SyncClient, TAuth,
+    TClunk, TCreate, TRead, TStat, TWrite, OWRITE, QTAUTH,
+};
+use std::{
+    net::TcpStream,
+    sync::{Arc, Mutex},
+};
+
+// Clients write the password of the user they want to attach as
+struct Password;
+
+impl Authenticator for Password {
+    type Conversation = Mutex<Option<String>>;
+
+    async fn start(&self, _uname: &str, _aname: &str) -> Result<Self::Conversation, Error> {
+        Ok(Mutex::new(None))
+    }
+
+    async fn read(
+        &self,
+        _conv: &Self::Conversation,
+        _offset: u64,
+        _count: u32,
+    ) -> Result<Vec<u8>, Error> {
+        Ok(b"password\n".to_vec())
+    }
+
+    async fn write(
+        &self,
+        conv: &Self::Conversation,
+        _offset: u64,
+        data: &[u8],
+    ) -> Result<u32, Error> {
+        *conv.lock().unwrap() = Some(String::from_utf8_lossy(data).into_owned());
+        Ok(data.len() as u32)
+    }
+
+    fn attach(
+        &self,
+        conv: Option<&Self::Conversation>,
+        uname: &str,
+        aname: &str,
+    ) -> Result<Identity, Error> {
+        let Some(conv) = conv else {
+            return Err(Error::Protocol("authentication required".to_string()));
+        };
+        match (uname, conv.lock().unwrap().as_deref()) {
+            ("glenda", Some("secret")) => Ok(Identity {
+                uid: Some(1000),
+                ..Identity::new(uname, aname)
+            }),
+            _ => Err(Error::Protocol("authentication failed".to_string())),
+        }
+    }
+}
+
+fn connect() -> SyncClient<TcpStream> {
+    let ramfs = Arc::new(Ramfs::new());
+    let auth = Arc::new(Password);
+    let addr = listen(BlockingServer::new(), move || {
+        FidServer::with_auth(ramfs.clone(), auth.clone())
+    });
+    version(common::connect(addr), 8192, "9P2000")
+}
+
+fn auth(client: &mut SyncClient<TcpStream>, afid: u32, password: &str) {
+    let tauth = TAuth {
+        afid: Fid(afid),
+        uname: "glenda",
+        aname: "",
+    };
+    let aqid = client.send(0, tauth).unwrap().aqid;
+    assert_eq!(aqid.type_, QTAUTH);
+    let tread = TRead {
+        fid: Fid(afid),
+        offset: 0,
+        count: 100,
+    };
+    assert_eq!(client.send(0, tread).unwrap().data, b"password\n");
+    let twrite = TWrite {
+        fid: Fid(afid),
+        offset: 0,
+        data: password.as_bytes(),
+    };
+    client.send(0, twrite).unwrap();
+}
+
+#[test]
+fn password() {
+    let mut client = connect();
+    let err = attach(&mut client, 0, Fid::NOFID).unwrap_err();
+    assert_eq!(ename(err), "authentication required");
+
+    auth(&mut client, 1, "wrong");
+    let err = attach(&mut client, 0, Fid(1)).unwrap_err();
+    assert_eq!(ename(err), "authentication failed");
+    client.send(0, TClunk { fid: Fid(1) }).unwrap();
+
+    auth(&mut client, 1, "secret");
+    attach(&mut client, 0, Fid(1)).unwrap();
+    // The afid is still a fid until it's clunked
+    let err = client.send(0, TStat { fid: Fid(1) }).unwrap_err();
+    assert_eq!(ename(err), "fid is an auth fid");
+    client.send(0, TClunk { fid: Fid(1) }).unwrap();
+
+    // Files are created as the authenticated user
+    let tcreate = TCreate {
+        fid: Fid(0),
+        name: "file",
+        perm: 0o644,
+        mode: OWRITE,
+    };
+    client.send(0, tcreate).unwrap();
+    let stat = client.send(0, TStat { fid: Fid(0) }).unwrap().stat;
+    assert_eq!(stat.uid, "glenda");
+}
+
+#[test]
+fn not_required() {
+    let ramfs = Arc::new(Ramfs::new());
+    let addr = listen(BlockingServer::new(), move || FidServer::new(ramfs.clone()));
+    let mut client = version(common::connect(addr), 8192, "9P2000");
+    let tauth = TAuth {
+        afid: Fid(1),
+        uname: "glenda",
+        aname: "",
+    };
+    let err = client.send(0, tauth).unwrap_err();
+    assert_eq!(ename(err), "authentication not required");
+    attach(&mut client, 0, Fid::NOFID).unwrap();
+}
@@ -0,0 +1,43 @@
+// Connecting an `AsyncClient` to a server, for the tests using tokio
+
+use nine_p::{AsyncClient, BlockingServer, Fid, FidServer, Ramfs, TAttach, TVersion, TokioCompat};
+use std::{future::Future, sync::Arc};
+use tokio::net::{
+    tcp::{OwnedReadHalf, OwnedWriteHalf},
+    TcpStream,
+};
+
+pub type Client = AsyncClient<TokioCompat<OwnedReadHalf>, TokioCompat<OwnedWriteHalf>>;
+
+pub fn run<T>(test: impl Future<Output = T>) -> T {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(test)
+}
+
+/// Serve a new ramfs, and connect to it with the root attached as fid 0
+pub async fn connect() -> Arc<Client> {
+    let ramfs = Arc::new(Ramfs::new());
+    let addr = super::listen(BlockingServer::new().workers(4), move || {
+        FidServer::new(ramfs.clone())
+    });
+
+    let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let client = AsyncClient::new(TokioCompat(reader), TokioCompat(writer));
+    let mut buf = Vec::new();
+    let tversion = TVersion {
+        msize: 8192,
+        version: "9P2000",
+    };
+    client.send(tversion, &mut buf).await.unwrap();
+    let tattach = TAttach {
+        fid: Fid(0),
+        afid: Fid::NOFID,
+        uname: "glenda",
+        aname: "",
+    };
+    client.send(tattach, &mut buf).await.unwrap();
+    Arc::new(client)
+}
@@ -0,0 +1,225 @@
+// Client side shared by the tests: connecting to a server, attaching, and
+// sending the usual requests by fid
+
+// Each test uses only some of these
+#![allow(dead_code)]
+
+#[cfg(all(feature = "futures-io", feature = "tokio"))]
+pub mod async_client;
+
+use nine_p::{
+    parse_dir, BlockingServer, Error, Fid, Filesystem, Header, Message, Qid, Stat, StatBuf,
+    SyncClient, TAttach, TClunk, TCreate, TOpen, TRead, TRemove, TStat, TVersion, TWStat, TWalk,
+    TWrite, NOTAG, OREAD,
+};
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+};
+
+/// Serve connections to a new local port with `server`
+pub fn listen<F: Filesystem>(
+    server: BlockingServer,
+    new_fs: impl Fn() -> F + Send + Sync + 'static,
+) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || server.serve(listener, new_fs));
+    addr
+}
+
+pub fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    // `SyncClient` writes the header and body separately
+    stream.set_nodelay(true).unwrap();
+    stream
+}
+
+/// Negotiate `version` over `stream`, which the server must accept
+pub fn version<T: Read + Write>(stream: T, msize: u32, version: &str) -> SyncClient<T> {
+    let mut client = SyncClient::new(stream);
+    let tversion = TVersion { msize, version };
+    assert_eq!(client.send(NOTAG, tversion).unwrap().version, version);
+    client
+}
+
+/// Attach `fid` to the root as glenda, authenticated by `afid`
+pub fn attach<T: Read + Write>(
+    client: &mut SyncClient<T>,
+    fid: u32,
+    afid: Fid,
+) -> Result<Qid, Error> {
+    let tattach = TAttach {
+        fid: Fid(fid),
+        afid,
+        uname: "glenda",
+        aname: "",
+    };
+    Ok(client.send(0, tattach)?.qid)
+}
+
+/// Send a request without waiting for the reply
+pub fn send<'a>(stream: &mut impl Write, tag: u16, message: impl Message<'a>) {
+    let mut buf = Vec::new();
+    Header::for_message(&message, tag).write(&mut buf).unwrap();
+    message.write(&mut buf).unwrap();
+    stream.write_all(&buf).unwrap();
+}
+
+/// Read a reply, returning only its tag
+pub fn recv_tag(stream: &mut impl Read) -> u16 {
+    let mut header = [0; 7];
+    stream.read_exact(&mut header).unwrap();
+    let header = Header::from_array(header);
+    let mut body = vec![0; header.size as usize - 7];
+    stream.read_exact(&mut body).unwrap();
+    header.tag
+}
+
+/// The error string of a server's `RError`
+pub fn ename(err: Error) -> String {
+    match err {
+        Error::Protocol(ename) => ename,
+        err => panic!("unexpected error {:?}", err),
+    }
+}
+
+pub struct Client<T: Read + Write> {
+    pub client: SyncClient<T>,
+    pub root: Qid,
+}
+
+impl Client<TcpStream> {
+    /// Connect to `addr`, with the root attached as fid 0
+    pub fn connect(addr: SocketAddr) -> Self {
+        Self::new(connect(addr))
+    }
+}
+
+impl<T: Read + Write> Client<T> {
+    /// Start a 9P2000 session over `stream`, with the root attached as fid 0
+    pub fn new(stream: T) -> Self {
+        let mut client = version(stream, 8192, "9P2000");
+        let root = attach(&mut client, 0, Fid::NOFID).unwrap();
+        Self { client, root }
+    }
+
+    pub fn walk(&mut self, fid: u32, newfid: u32, wnames: &[&str]) -> Result<Vec<Qid>, Error> {
+        let twalk = TWalk {
+            fid: Fid(fid),
+            newfid: Fid(newfid),
+            wnames: wnames.to_vec(),
+        };
+        Ok(self.client.send(0, twalk)?.qids)
+    }
+
+    pub fn open(&mut self, fid: u32, mode: u8) -> Result<Qid, Error> {
+        let topen = TOpen {
+            fid: Fid(fid),
+            mode,
+        };
+        Ok(self.client.send(0, topen)?.qid)
+    }
+
+    /// Walk `newfid` to `wnames` from the root, and open it
+    pub fn walk_open(&mut self, newfid: u32, wnames: &[&str], mode: u8) -> Result<Qid, Error> {
+        self.walk(0, newfid, wnames)?;
+        self.open(newfid, mode)
+    }
+
+    /// Create `name` in the directory `fid`, which is then the new file
+    pub fn create(&mut self, fid: u32, name: &str, perm: u32, mode: u8) -> Result<Qid, Error> {
+        let tcreate = TCreate {
+            fid: Fid(fid),
+            name,
+            perm,
+            mode,
+        };
+        Ok(self.client.send(0, tcreate)?.qid)
+    }
+
+    pub fn read(&mut self, fid: u32, offset: u64, count: u32) -> Result<Vec<u8>, Error> {
+        let tread = TRead {
+            fid: Fid(fid),
+            offset,
+            count,
+        };
+        Ok(self.client.send(0, tread)?.data.to_vec())
+    }
+
+    /// Read the open `fid` to the end, `count` bytes at a time
+    pub fn read_all(&mut self, fid: u32, count: u32) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        loop {
+            let chunk = self.read(fid, data.len() as u64, count)?;
+            if chunk.is_empty() {
+                return Ok(data);
+            }
+            data.extend_from_slice(&chunk);
+        }
+    }
+
+    pub fn write(&mut self, fid: u32, offset: u64, data: &[u8]) -> Result<u32, Error> {
+        let twrite = TWrite {
+            fid: Fid(fid),
+            offset,
+            data,
+        };
+        Ok(self.client.send(0, twrite)?.count)
+    }
+
+    pub fn stat(&mut self, fid: u32) -> Result<StatBuf, Error> {
+        Ok(StatBuf::from(
+            &self.client.send(0, TStat { fid: Fid(fid) })?.stat,
+        ))
+    }
+
+    pub fn wstat(&mut self, fid: u32, stat: &Stat) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        stat.write(&mut buf).unwrap();
+        let twstat = TWStat {
+            fid: Fid(fid),
+            stat: &buf,
+        };
+        self.client.send(0, twstat)?;
+        Ok(())
+    }
+
+    pub fn clunk(&mut self, fid: u32) -> Result<(), Error> {
+        self.client.send(0, TClunk { fid: Fid(fid) })?;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, fid: u32) -> Result<(), Error> {
+        self.client.send(0, TRemove { fid: Fid(fid) })?;
+        Ok(())
+    }
+
+    /// Read the file or directory at `path` from the root, using fid 1
+    pub fn read_file(&mut self, path: &[&str]) -> Result<Vec<u8>, Error> {
+        self.walk_open(1, path, OREAD)?;
+        // Small, so directories are listed over several reads
+        let data = self.read_all(1, 100);
+        self.clunk(1).unwrap();
+        data
+    }
+
+    pub fn cat(&mut self, path: &[&str]) -> String {
+        String::from_utf8(self.read_file(path).unwrap()).unwrap()
+    }
+
+    /// Names and qids of the entries of the directory at `path`
+    pub fn ls(&mut self, path: &[&str]) -> Vec<(String, Qid)> {
+        let data = self.read_file(path).unwrap();
+        parse_dir(&data)
+            .unwrap()
+            .iter()
+            .map(|stat| (stat.name.to_string(), stat.qid))
+            .collect()
+    }
+
+    pub fn names(&mut self, path: &[&str]) -> Vec<String> {
+        self.ls(path).into_iter().map(|(name, _)| name).collect()
+    }
+}
@@ -0,0 +1,511 @@
+// 9P2000.L against the directory export, as the Linux kernel client uses it
+
+#![cfg(all(feature = "export", target_os = "linux"))]
+
+mod common;
+
+use common::Client;
+use nine_p::{
+    parse_dir_entries, BlockingServer, Error, Export, Fid, FidServer, Ramfs, SyncClient, TFSync,
+    TGetAttr, TLAttach, TLCreate, TLOpen, TLink, TMkDir, TMkNod, TReadDir, TReadLink, TRename,
+    TRenameAt, TSetAttr, TStatFs, TSymlink, TUnlinkAt, TVersion, TWrite, TXattrCreate, TXattrWalk,
+    DOTL_AT_REMOVEDIR, DOTL_RDONLY, DOTL_RDWR, DOTL_WRONLY, GETATTR_ALL, GETATTR_BASIC, NOTAG,
+    QTSYMLINK, SETATTR_MODE, SETATTR_MTIME, SETATTR_MTIME_SET, SETATTR_SIZE,
+};
+use std::{
+    collections::BTreeSet,
+    fs,
+    os::unix::{ffi::OsStringExt, fs::MetadataExt, net::UnixStream},
+    path::PathBuf,
+    sync::Arc,
+};
+
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("nine-p-dotl-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// Serve `dir` over a socket pair, with the root attached as fid 0
+fn connect(dir: &TempDir) -> Client<UnixStream> {
+    connect_export(Export::new(&dir.0).unwrap())
+}
+
+fn connect_export(export: Export) -> Client<UnixStream> {
+    let export = Arc::new(export);
+    let stream = BlockingServer::new()
+        .serve_socketpair(move || FidServer::new(export.clone()))
+        .unwrap();
+    let mut client = common::version(stream, 8192, "9P2000.L");
+    let tlattach = TLAttach {
+        fid: Fid(0),
+        afid: Fid::NOFID,
+        uname: "",
+        aname: "",
+        n_uname: 1000,
+    };
+    let root = client.send(0, tlattach).unwrap().qid;
+    Client { client, root }
+}
+
+impl Client<UnixStream> {
+    fn lopen(&mut self, fid: u32, flags: u32) -> Result<(), Error> {
+        self.client.send(
+            0,
+            TLOpen {
+                fid: Fid(fid),
+                flags,
+            },
+        )?;
+        Ok(())
+    }
+
+    // Create `name` in the root, leaving it open as `fid`
+    fn lcreate(&mut self, fid: u32, name: &str) {
+        self.walk(0, fid, &[]).unwrap();
+        let tlcreate = TLCreate {
+            fid: Fid(fid),
+            name,
+            flags: DOTL_RDWR,
+            mode: 0o640,
+            gid: 0,
+        };
+        self.client.send(0, tlcreate).unwrap();
+    }
+
+    // Names and offsets from `TReadDir` on open directory `fid`
+    fn readdir(&mut self, fid: u32, offset: u64, count: u32) -> Vec<(String, u64)> {
+        let treaddir = TReadDir {
+            fid: Fid(fid),
+            offset,
+            count,
+        };
+        let rreaddir = self.client.send(0, treaddir).unwrap();
+        parse_dir_entries(rreaddir.data)
+            .unwrap()
+            .iter()
+            .map(|entry| (entry.name.to_string(), entry.offset))
+            .collect()
+    }
+}
+
+fn errno(err: Error) -> i32 {
+    match err {
+        Error::Io(err) => err.raw_os_error().unwrap(),
+        err => panic!("unexpected error {:?}", err),
+    }
+}
+
+#[test]
+fn version() {
+    // Handlers without 9P2000.L fall back to 9P2000
+    let ramfs = Arc::new(Ramfs::new());
+    let stream = BlockingServer::new()
+        .serve_socketpair(move || FidServer::new(ramfs.clone()))
+        .unwrap();
+    let mut client = SyncClient::new(stream);
+    let tversion = TVersion {
+        msize: 8192,
+        version: "9P2000.L",
+    };
+    assert_eq!(client.send(NOTAG, tversion).unwrap().version, "9P2000");
+}
+
+#[test]
+fn errors_are_errnos() {
+    let dir = TempDir::new("errors");
+    let mut client = connect(&dir);
+    assert_eq!(
+        errno(client.walk(0, 1, &["missing"]).unwrap_err()),
+        libc::ENOENT
+    );
+    assert_eq!(
+        errno(client.lopen(0, DOTL_WRONLY).unwrap_err()),
+        libc::EISDIR
+    );
+    assert_eq!(errno(client.clunk(5).unwrap_err()), libc::EBADF);
+}
+
+#[test]
+fn files_and_attributes() {
+    let dir = TempDir::new("attrs");
+    let mut client = connect(&dir);
+    client.lcreate(1, "file");
+    client.write(1, 0, b"hello world").unwrap();
+    let tfsync = TFSync {
+        fid: Fid(1),
+        datasync: 0,
+    };
+    client.client.send(0, tfsync).unwrap();
+    client.clunk(1).unwrap();
+    assert_eq!(fs::read(dir.0.join("file")).unwrap(), b"hello world");
+
+    client.walk(0, 1, &["file"]).unwrap();
+    let tgetattr = TGetAttr {
+        fid: Fid(1),
+        request_mask: GETATTR_ALL,
+    };
+    let attr = client.client.send(0, tgetattr).unwrap();
+    let meta = fs::metadata(dir.0.join("file")).unwrap();
+    assert_eq!(attr.valid & GETATTR_BASIC, GETATTR_BASIC);
+    assert_eq!(attr.mode, libc::S_IFREG | 0o640);
+    assert_eq!(attr.size, 11);
+    assert_eq!(attr.qid.path, meta.ino());
+    assert_eq!(attr.uid, meta.uid());
+    assert_eq!(attr.nlink, 1);
+
+    let tsetattr = TSetAttr {
+        fid: Fid(1),
+        valid: SETATTR_MODE | SETATTR_SIZE | SETATTR_MTIME | SETATTR_MTIME_SET,
+        mode: 0o600,
+        size: 5,
+        mtime_sec: 1_000_000_000,
+        mtime_nsec: 5,
+        ..TSetAttr::default()
+    };
+    client.client.send(0, tsetattr).unwrap();
+    let meta = fs::metadata(dir.0.join("file")).unwrap();
+    assert_eq!(meta.mode() & 0o7777, 0o600);
+    assert_eq!(meta.len(), 5);
+    assert_eq!(meta.mtime(), 1_000_000_000);
+    assert_eq!(meta.mtime_nsec(), 5);
+
+    client.lopen(1, DOTL_RDONLY).unwrap();
+    assert_eq!(client.read(1, 0, 4096).unwrap(), b"hello");
+    // Writing to a file opened read-only
+    let twrite = TWrite {
+        fid: Fid(1),
+        offset: 0,
+        data: b"x",
+    };
+    assert_eq!(
+        errno(client.client.send(0, twrite).unwrap_err()),
+        libc::EBADF
+    );
+
+    let rstatfs = client.client.send(0, TStatFs { fid: Fid(1) }).unwrap();
+    assert!(rstatfs.bsize > 0);
+    assert!(rstatfs.namelen > 0);
+}
+
+#[test]
+fn readdir_offsets() {
+    let dir = TempDir::new("readdir");
+    let mut expected = BTreeSet::from([".".to_string(), "..".to_string()]);
+    for i in 0..40 {
+        let name = format!("file{:02}", i);
+        fs::write(dir.0.join(&name), "").unwrap();
+        expected.insert(name);
+    }
+    let mut client = connect(&dir);
+    client.walk(0, 1, &[]).unwrap();
+    client.lopen(1, DOTL_RDONLY).unwrap();
+
+    // Read a few entries at a time, continuing from the last offset
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let batch = client.readdir(1, offset, 100);
+        let Some(last) = batch.last() else {
+            break;
+        };
+        assert!(batch.len() < 10);
+        offset = last.1;
+        entries.extend(batch);
+    }
+    let names: BTreeSet<_> = entries.iter().map(|(name, _)| name.clone()).collect();
+    assert_eq!(names.len(), entries.len());
+    assert_eq!(names, expected);
+
+    // Any entry's offset can be returned to, as with `seekdir()`
+    let (_, middle) = entries[20];
+    let rest = client.readdir(1, middle, 8192);
+    assert_eq!(rest, entries[21..]);
+    assert_eq!(client.readdir(1, 0, 8192), entries);
+}
+
+#[test]
+fn links_and_nodes() {
+    let dir = TempDir::new("links");
+    let mut client = connect(&dir);
+    client.lcreate(1, "file");
+    client.clunk(1).unwrap();
+
+    let tsymlink = TSymlink {
+        dfid: Fid(0),
+        name: "symlink",
+        symtgt: "file",
+        gid: 0,
+    };
+    let qid = client.client.send(0, tsymlink).unwrap().qid;
+    assert_eq!(qid.type_, QTSYMLINK);
+    client.walk(0, 1, &["symlink"]).unwrap();
+    let target = client
+        .client
+        .send(0, TReadLink { fid: Fid(1) })
+        .unwrap()
+        .target;
+    assert_eq!(target, "file");
+    client.clunk(1).unwrap();
+
+    client.walk(0, 1, &["file"]).unwrap();
+    let tlink = TLink {
+        dfid: Fid(0),
+        fid: Fid(1),
+        name: "hardlink",
+    };
+    client.client.send(0, tlink).unwrap();
+    let tgetattr = TGetAttr {
+        fid: Fid(1),
+        request_mask: GETATTR_BASIC,
+    };
+    assert_eq!(client.client.send(0, tgetattr).unwrap().nlink, 2);
+
+    let tmknod = TMkNod {
+        dfid: Fid(0),
+        name: "fifo",
+        mode: libc::S_IFIFO | 0o600,
+        major: 0,
+        minor: 0,
+        gid: 0,
+    };
+    client.client.send(0, tmknod).unwrap();
+    let meta = fs::symlink_metadata(dir.0.join("fifo")).unwrap();
+    assert_eq!(meta.mode() & libc::S_IFMT, libc::S_IFIFO);
+
+    let tmkdir = TMkDir {
+        dfid: Fid(0),
+        name: "sub",
+        mode: 0o750,
+        gid: 0,
+    };
+    assert!(client.client.send(0, tmkdir).unwrap().qid.is_dir());
+
+    // The fid follows the file it renames
+    client.walk(0, 2, &["sub"]).unwrap();
+    let trename = TRename {
+        fid: Fid(1),
+        dfid: Fid(2),
+        name: "moved",
+    };
+    client.client.send(0, trename).unwrap();
+    client.lopen(1, DOTL_RDWR).unwrap();
+    client.write(1, 0, b"moved").unwrap();
+    assert_eq!(fs::read(dir.0.join("sub/moved")).unwrap(), b"moved");
+
+    let trenameat = TRenameAt {
+        olddirfid: Fid(2),
+        oldname: "moved",
+        newdirfid: Fid(0),
+        newname: "back",
+    };
+    client.client.send(0, trenameat).unwrap();
+    assert_eq!(fs::read(dir.0.join("back")).unwrap(), b"moved");
+
+    let tunlinkat = TUnlinkAt {
+        dirfid: Fid(0),
+        name: "back",
+        flags: 0,
+    };
+    client.client.send(0, tunlinkat).unwrap();
+    fs::write(dir.0.join("sub/file"), "").unwrap();
+    let tunlinkat = TUnlinkAt {
+        dirfid: Fid(0),
+        name: "sub",
+        flags: DOTL_AT_REMOVEDIR,
+    };
+    let err = client.client.send(0, tunlinkat.clone()).unwrap_err();
+    assert_eq!(errno(err), libc::ENOTEMPTY);
+    fs::remove_file(dir.0.join("sub/file")).unwrap();
+    client.client.send(0, tunlinkat).unwrap();
+    assert!(!dir.0.join("sub").exists());
+}
+
+#[test]
+fn devices_and_setuid() {
+    let dir = TempDir::new("devices");
+    let mut client = connect(&dir);
+    let mode = |name: &str| fs::symlink_metadata(dir.0.join(name)).unwrap().mode();
+
+    let tmknod = TMkNod {
+        dfid: Fid(0),
+        name: "null",
+        mode: libc::S_IFCHR | 0o666,
+        major: 1,
+        minor: 3,
+        gid: 0,
+    };
+    let err = client.client.send(0, tmknod.clone()).unwrap_err();
+    assert_eq!(errno(err), libc::EPERM);
+    assert!(!dir.0.join("null").exists());
+
+    // Set-user-ID and set-group-ID bits are dropped
+    client.walk(0, 1, &[]).unwrap();
+    let tlcreate = TLCreate {
+        fid: Fid(1),
+        name: "file",
+        flags: DOTL_RDWR,
+        mode: 0o4755,
+        gid: 0,
+    };
+    client.client.send(0, tlcreate).unwrap();
+    assert_eq!(mode("file") & 0o7777, 0o755);
+    let tsetattr = TSetAttr {
+        fid: Fid(1),
+        valid: SETATTR_MODE,
+        mode: 0o6755,
+        ..TSetAttr::default()
+    };
+    client.client.send(0, tsetattr.clone()).unwrap();
+    assert_eq!(mode("file") & 0o7777, 0o755);
+    client.clunk(1).unwrap();
+    let tmkdir = TMkDir {
+        dfid: Fid(0),
+        name: "sub",
+        mode: 0o2755,
+        gid: 0,
+    };
+    client.client.send(0, tmkdir).unwrap();
+    assert_eq!(mode("sub") & 0o7777, 0o755);
+
+    // Creating devices on the host needs privilege
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let path = std::ffi::CString::new(dir.0.join("host").into_os_string().into_vec()).unwrap();
+    let dev = libc::makedev(1, 3);
+    assert_eq!(
+        unsafe { libc::mknod(path.as_ptr(), libc::S_IFCHR | 0o666, dev) },
+        0
+    );
+    client.walk(0, 1, &["host"]).unwrap();
+    assert_eq!(errno(client.lopen(1, DOTL_RDWR).unwrap_err()), libc::EPERM);
+    client.clunk(1).unwrap();
+    client.walk(0, 1, &[]).unwrap();
+    let tlcreate = TLCreate {
+        fid: Fid(1),
+        name: "host",
+        flags: DOTL_RDWR,
+        mode: 0o666,
+        gid: 0,
+    };
+    let err = client.client.send(0, tlcreate).unwrap_err();
+    assert_eq!(errno(err), libc::EPERM);
+    client.clunk(1).unwrap();
+
+    let export = Export::new(&dir.0).unwrap().devices(true).setuid(true);
+    let mut client = connect_export(export);
+    client.client.send(0, tmknod).unwrap();
+    assert_eq!(mode("null") & libc::S_IFMT, libc::S_IFCHR);
+    client.walk(0, 1, &["null"]).unwrap();
+    client.lopen(1, DOTL_RDWR).unwrap();
+    assert_eq!(client.write(1, 0, b"discarded").unwrap(), 9);
+    client.clunk(1).unwrap();
+    client.walk(0, 1, &["file"]).unwrap();
+    client.client.send(0, tsetattr).unwrap();
+    assert_eq!(mode("file") & 0o7777, 0o6755);
+}
+
+#[test]
+fn xattrs() {
+    let dir = TempDir::new("xattrs");
+    fs::write(dir.0.join("file"), "").unwrap();
+    let mut client = connect(&dir);
+    client.walk(0, 1, &["file"]).unwrap();
+    let txattrcreate = TXattrCreate {
+        fid: Fid(1),
+        name: "user.nine-p",
+        attr_size: 5,
+        flags: 0,
+    };
+    client.client.send(0, txattrcreate).unwrap();
+    client.write(1, 0, b"value").unwrap();
+    // The attribute is set when the fid is clunked
+    match client.clunk(1).map_err(errno) {
+        Err(libc::EOPNOTSUPP) => return, // Not supported by the filesystem
+        res => res.unwrap(),
+    }
+
+    client.walk(0, 1, &["file"]).unwrap();
+    let txattrwalk = TXattrWalk {
+        fid: Fid(1),
+        newfid: Fid(2),
+        name: "user.nine-p",
+    };
+    assert_eq!(client.client.send(0, txattrwalk).unwrap().size, 5);
+    assert_eq!(client.read(2, 0, 4096).unwrap(), b"value");
+    client.clunk(2).unwrap();
+
+    let txattrwalk = TXattrWalk {
+        fid: Fid(1),
+        newfid: Fid(2),
+        name: "",
+    };
+    client.client.send(0, txattrwalk).unwrap();
+    let names = client.read(2, 0, 4096).unwrap();
+    assert!(names.split(|&b| b == 0).any(|name| name == b"user.nine-p"));
+    client.clunk(2).unwrap();
+
+    // Setting no value removes it
+    let txattrcreate = TXattrCreate {
+        fid: Fid(1),
+        name: "user.nine-p",
+        attr_size: 0,
+        flags: 0,
+    };
+    client.client.send(0, txattrcreate).unwrap();
+    client.clunk(1).unwrap();
+    client.walk(0, 1, &["file"]).unwrap();
+    let txattrwalk = TXattrWalk {
+        fid: Fid(1),
+        newfid: Fid(2),
+        name: "user.nine-p",
+    };
+    let err = client.client.send(0, txattrwalk).unwrap_err();
+    assert_eq!(errno(err), libc::ENODATA);
+}
+
+#[test]
+fn xattr_limits() {
+    let dir = TempDir::new("xattr-limits");
+    fs::write(dir.0.join("file"), "").unwrap();
+    let mut client = connect(&dir);
+    client.walk(0, 1, &["file"]).unwrap();
+    let txattrcreate = TXattrCreate {
+        fid: Fid(1),
+        name: "user.nine-p",
+        attr_size: 1 << 32,
+        flags: 0,
+    };
+    let err = client.client.send(0, txattrcreate).unwrap_err();
+    assert_eq!(errno(err), libc::E2BIG);
+
+    // Writes past the size, even ones that wrap around, are refused
+    let txattrcreate = TXattrCreate {
+        fid: Fid(1),
+        name: "user.nine-p",
+        attr_size: 5,
+        flags: 0,
+    };
+    client.client.send(0, txattrcreate).unwrap();
+    for offset in [3, u64::MAX - 1] {
+        let twrite = TWrite {
+            fid: Fid(1),
+            offset,
+            data: b"value",
+        };
+        let err = client.client.send(0, twrite).unwrap_err();
+        assert_eq!(errno(err), libc::E2BIG);
+    }
+}
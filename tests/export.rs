@@ -0,0 +1,320 @@
+// Hostile walks against the directory export, which must never reach files
+// outside the exported root
+
+#![cfg(all(feature = "export", unix))]
+
+mod common;
+
+use common::Client;
+use nine_p::{
+    BlockingServer, Error, Export, FidServer, Qid, Stat, OEXEC, ORDWR, OREAD, OTRUNC, OWRITE,
+};
+use std::{
+    fs,
+    os::unix::{
+        fs::{symlink, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    sync::Arc,
+    thread,
+};
+
+// A temporary directory with an exported `root` and a sibling `outside`:
+//
+// outside/secret
+// root/file
+// root/sub/file
+// root/sub/link_up -> ../..
+// root/link_out -> ../outside
+// root/link_abs -> /
+// root/link_secret -> ../outside/secret
+struct Tree {
+    dir: PathBuf,
+}
+
+impl Tree {
+    fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("nine-p-export-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        fs::create_dir_all(dir.join("root/sub")).unwrap();
+        fs::write(dir.join("outside/secret"), "secret").unwrap();
+        fs::write(dir.join("root/file"), "file").unwrap();
+        fs::write(dir.join("root/sub/file"), "sub file").unwrap();
+        symlink("../..", dir.join("root/sub/link_up")).unwrap();
+        symlink("../outside", dir.join("root/link_out")).unwrap();
+        symlink("/", dir.join("root/link_abs")).unwrap();
+        symlink("../outside/secret", dir.join("root/link_secret")).unwrap();
+        Self { dir }
+    }
+
+    fn root(&self) -> PathBuf {
+        self.dir.join("root")
+    }
+
+    fn export(&self) -> Export {
+        Export::new(self.root()).unwrap()
+    }
+}
+
+impl Drop for Tree {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Serve `export` over a socket pair, with the root attached as fid 0
+fn connect(export: Export) -> Client<UnixStream> {
+    let (client, server) = UnixStream::pair().unwrap();
+    let export = Arc::new(export);
+    thread::spawn(move || {
+        let reader = server.try_clone().unwrap();
+        BlockingServer::new()
+            .serve_connection(reader, server, move || FidServer::new(export.clone()))
+    });
+    Client::new(client)
+}
+
+impl Client<UnixStream> {
+    // Walk from the root, and clunk the new fid if the walk succeeded
+    fn walk_root(&mut self, wnames: &[&str]) -> Result<Vec<Qid>, Error> {
+        let qids = self.walk(0, 100, wnames)?;
+        if qids.len() == wnames.len() {
+            self.clunk(100).unwrap();
+        }
+        Ok(qids)
+    }
+
+    fn rename(&mut self, fid: u32, name: &str) -> Result<(), Error> {
+        let stat = Stat {
+            name,
+            ..Stat::unchanged(false)
+        };
+        self.wstat(fid, &stat)
+    }
+}
+
+#[test]
+fn dotdot_at_root_stays_at_root() {
+    let tree = Tree::new("dotdot-root");
+    let mut client = connect(tree.export());
+
+    let qids = client.walk_root(&["..", "..", ".."]).unwrap();
+    assert!(qids.iter().all(|qid| qid.path == client.root.path));
+
+    // "outside" is next to the root, and must not be found from ".."
+    let qids = client.walk_root(&["..", "outside", "secret"]).unwrap();
+    assert_eq!(qids.len(), 1);
+    assert!(client.walk_root(&["..", "..", "root"]).unwrap().len() < 3);
+}
+
+#[test]
+fn dotdot_returns_to_walked_parent() {
+    let tree = Tree::new("dotdot-parent");
+    let mut client = connect(tree.export());
+
+    let qids = client.walk_root(&["sub", "..", "sub", "..", ".."]).unwrap();
+    assert_eq!(qids.len(), 5);
+    assert_eq!(qids[1].path, client.root.path);
+    assert_eq!(qids[4].path, client.root.path);
+}
+
+#[test]
+fn names_with_slashes_rejected() {
+    let tree = Tree::new("slashes");
+    let mut client = connect(tree.export());
+
+    for name in ["../outside", "sub/file", "/", "/etc", "sub/../..", ".", ""] {
+        assert!(client.walk_root(&[name]).is_err(), "walk to {:?}", name);
+    }
+    // Rejected names stop a walk part way
+    assert_eq!(
+        client.walk_root(&["sub", "../../outside"]).unwrap().len(),
+        1
+    );
+}
+
+#[test]
+fn symlinks_not_followed() {
+    let tree = Tree::new("symlinks");
+    let mut client = connect(tree.export());
+
+    // Links can be walked to, but not through
+    assert_eq!(client.walk_root(&["link_out", "secret"]).unwrap().len(), 1);
+    assert_eq!(
+        client
+            .walk_root(&["link_abs", "etc", "passwd"])
+            .unwrap()
+            .len(),
+        1
+    );
+    assert_eq!(
+        client
+            .walk_root(&["sub", "link_up", "outside"])
+            .unwrap()
+            .len(),
+        2
+    );
+
+    // Nor opened
+    for link in ["link_secret", "link_out", "link_abs"] {
+        client.walk(0, 1, &[link]).unwrap();
+        assert!(client.open(1, OREAD).is_err(), "open of {}", link);
+        client.clunk(1).unwrap();
+    }
+}
+
+#[test]
+fn symlink_swapped_in_after_walk() {
+    let tree = Tree::new("swap");
+    let mut client = connect(tree.export());
+
+    client.walk(0, 1, &["sub"]).unwrap();
+    // Replace the directory with a symlink out of the tree
+    fs::rename(tree.root().join("sub"), tree.root().join("moved")).unwrap();
+    symlink("../outside", tree.root().join("sub")).unwrap();
+
+    // The held fid still refers to the original directory
+    client.walk(1, 2, &["file"]).unwrap();
+    client.open(2, OREAD).unwrap();
+    assert_eq!(client.read(2, 0, 4096).unwrap(), b"sub file");
+    assert!(client.walk(1, 3, &["secret"]).is_err());
+
+    // A new walk sees the symlink, and stops there
+    assert_eq!(client.walk_root(&["sub", "secret"]).unwrap().len(), 1);
+}
+
+#[test]
+fn dotdot_after_directory_moved_out() {
+    let tree = Tree::new("moved-out");
+    let mut client = connect(tree.export());
+
+    client.walk(0, 1, &["sub"]).unwrap();
+    fs::rename(tree.root().join("sub"), tree.dir.join("outside/sub")).unwrap();
+
+    // ".." goes back the way the fid came, not to the new parent
+    let qids = client.walk(1, 2, &["..", ".."]).unwrap();
+    assert!(qids.iter().all(|qid| qid.path == client.root.path));
+    assert!(client.walk(1, 3, &["..", "secret"]).unwrap().len() < 2);
+}
+
+#[test]
+fn create_and_rename_cannot_escape() {
+    let tree = Tree::new("create");
+    let mut client = connect(tree.export());
+
+    for name in ["..", ".", "../escape", "a/b", ""] {
+        client.walk(0, 1, &[]).unwrap();
+        assert!(
+            client.create(1, name, 0o644, OWRITE).is_err(),
+            "create {:?}",
+            name
+        );
+        client.clunk(1).unwrap();
+    }
+
+    client.walk(0, 1, &["file"]).unwrap();
+    for name in ["..", "../escape", "../outside/file", "sub/file"] {
+        assert!(client.rename(1, name).is_err(), "rename to {:?}", name);
+    }
+    // Unlike rename(2), renaming doesn't replace an existing file
+    fs::write(tree.root().join("other"), "other").unwrap();
+    assert!(client.rename(1, "other").is_err());
+    // Nothing else is changed by a rename that fails
+    let stat = Stat {
+        name: "other",
+        length: 0,
+        ..Stat::unchanged(false)
+    };
+    assert!(client.wstat(1, &stat).is_err());
+    client.rename(1, "renamed").unwrap();
+    assert_eq!(fs::read(tree.root().join("renamed")).unwrap(), b"file");
+
+    assert_eq!(
+        fs::read(tree.dir.join("outside/secret")).unwrap(),
+        b"secret"
+    );
+    assert_eq!(fs::read_dir(tree.dir.join("outside")).unwrap().count(), 1);
+}
+
+#[test]
+fn removing_link_removes_link() {
+    let tree = Tree::new("remove-link");
+    let mut client = connect(tree.export());
+
+    client.walk(0, 1, &["link_secret"]).unwrap();
+    client.remove(1).unwrap();
+    assert!(fs::symlink_metadata(tree.root().join("link_secret")).is_err());
+    assert!(tree.dir.join("outside/secret").exists());
+}
+
+#[test]
+fn read_only_export() {
+    let tree = Tree::new("read-only");
+    let mut client = connect(tree.export().read_only(true));
+
+    client.walk(0, 1, &["file"]).unwrap();
+    for mode in [OWRITE, ORDWR, OREAD | OTRUNC] {
+        assert!(client.open(1, mode).is_err(), "open with mode {}", mode);
+    }
+    assert!(client.rename(1, "renamed").is_err());
+    assert!(client.remove(1).is_err());
+
+    client.walk(0, 1, &[]).unwrap();
+    assert!(client.create(1, "new", 0o644, OWRITE).is_err());
+
+    client.walk(0, 2, &["file"]).unwrap();
+    client.open(2, OREAD).unwrap();
+    assert_eq!(client.read(2, 0, 4096).unwrap(), b"file");
+
+    assert_eq!(fs::read_dir(tree.root()).unwrap().count(), 5);
+}
+
+#[test]
+fn no_exec_export() {
+    let tree = Tree::new("no-exec");
+    let script = tree.root().join("script");
+    fs::write(&script, "#!/bin/sh\n").unwrap();
+    fs::set_permissions(&script, PermissionsExt::from_mode(0o755)).unwrap();
+    let mut client = connect(tree.export().no_exec(true));
+
+    client.walk(0, 1, &["script"]).unwrap();
+    let mode = client.stat(1).unwrap().mode;
+    assert_eq!(mode & 0o777, 0o644);
+    assert!(client.open(1, OEXEC).is_err());
+    client.open(1, OREAD).unwrap();
+}
+
+#[test]
+fn chmod_without_opening() {
+    let tree = Tree::new("chmod");
+    let _socket = UnixListener::bind(tree.root().join("socket")).unwrap();
+    let mut client = connect(tree.export());
+    let mode = |name: &str| {
+        let meta = fs::metadata(tree.root().join(name)).unwrap();
+        meta.permissions().mode() & 0o777
+    };
+    let chmod = |mode| Stat {
+        mode,
+        ..Stat::unchanged(false)
+    };
+
+    // An unreadable file can be made readable again
+    client.walk(0, 1, &["file"]).unwrap();
+    client.wstat(1, &chmod(0o000)).unwrap();
+    assert_eq!(mode("file"), 0o000);
+    client.wstat(1, &chmod(0o644)).unwrap();
+    assert_eq!(mode("file"), 0o644);
+
+    // Sockets can't be opened at all
+    client.walk(0, 2, &["socket"]).unwrap();
+    client.wstat(2, &chmod(0o600)).unwrap();
+    assert_eq!(mode("socket"), 0o600);
+
+    let secret = mode("../outside/secret");
+    client.walk(0, 3, &["link_secret"]).unwrap();
+    assert!(client.wstat(3, &chmod(0o600)).is_err());
+    assert_eq!(mode("../outside/secret"), secret);
+}
@@ -0,0 +1,144 @@
+// Limits on what a client can use of a server
+
+mod common;
+
+use common::{attach, ename, listen, recv_tag, send, version, Client};
+use nine_p::{
+    BlockingServer, Error, Events, Fid, FidLimit, FidServer, Ramfs, SyncClient, SynthTree, TRead,
+    TStat, TWalk, IOHDRSZ, ORDWR, OREAD,
+};
+use std::{
+    io::Read,
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+// Connect without attaching
+fn connect(addr: SocketAddr) -> SyncClient<TcpStream> {
+    version(common::connect(addr), 8192, "9P2000")
+}
+
+// Walk `newfid` to the root
+fn walk(client: &mut SyncClient<TcpStream>, newfid: u32) -> Result<(), Error> {
+    let twalk = TWalk {
+        fid: Fid(0),
+        newfid: Fid(newfid),
+        wnames: Vec::new(),
+    };
+    client.send(0, twalk)?;
+    Ok(())
+}
+
+#[test]
+fn fids_per_connection() {
+    let ramfs = Arc::new(Ramfs::new());
+    let addr = listen(BlockingServer::new(), move || {
+        FidServer::new(ramfs.clone()).max_fids(3)
+    });
+    let mut client = connect(addr);
+    attach(&mut client, 0, Fid::NOFID).unwrap();
+    walk(&mut client, 1).unwrap();
+    walk(&mut client, 2).unwrap();
+    assert_eq!(ename(walk(&mut client, 3).unwrap_err()), "too many fids");
+    assert_eq!(
+        ename(attach(&mut client, 3, Fid::NOFID).unwrap_err()),
+        "too many fids"
+    );
+
+    // Other connections have their own fids
+    let mut other = connect(addr);
+    attach(&mut other, 0, Fid::NOFID).unwrap();
+    walk(&mut other, 1).unwrap();
+}
+
+#[test]
+fn fids_per_server() {
+    let ramfs = Arc::new(Ramfs::new());
+    let limit = FidLimit::new(3);
+    let addr = listen(BlockingServer::new(), move || {
+        FidServer::new(ramfs.clone()).fid_limit(limit.clone())
+    });
+    let mut first = connect(addr);
+    attach(&mut first, 0, Fid::NOFID).unwrap();
+    walk(&mut first, 1).unwrap();
+    let mut second = connect(addr);
+    attach(&mut second, 0, Fid::NOFID).unwrap();
+    let err = walk(&mut second, 1).unwrap_err();
+    assert_eq!(ename(err), "too many fids on server");
+
+    // Fids of closed connections are given back
+    drop(first);
+    thread::sleep(Duration::from_millis(100));
+    walk(&mut second, 1).unwrap();
+}
+
+#[test]
+fn requests_per_connection() {
+    let events = Events::new();
+    let tree = SynthTree::new("glenda");
+    tree.root()
+        .create_file("events", 0o444, events.clone())
+        .unwrap();
+    let tree = Arc::new(tree);
+    let server = BlockingServer::new().workers(2).max_requests(1);
+    let addr = listen(server, move || FidServer::new(tree.clone()));
+
+    let mut stream = common::connect(addr);
+    let mut client = Client::new(stream.try_clone().unwrap());
+    client.walk_open(1, &["events"], OREAD).unwrap();
+
+    // The read blocks, so the stat isn't read until it finishes
+    let tread = TRead {
+        fid: Fid(1),
+        offset: 0,
+        count: 1024,
+    };
+    send(&mut stream, 1, tread);
+    send(&mut stream, 2, TStat { fid: Fid(0) });
+    stream
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let mut byte = [0];
+    let err = stream.read(&mut byte).unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    ));
+
+    stream.set_read_timeout(None).unwrap();
+    events.send("event\n");
+    assert_eq!(recv_tag(&mut stream), 1);
+    assert_eq!(recv_tag(&mut stream), 2);
+}
+
+#[test]
+fn read_count() {
+    let ramfs = Arc::new(Ramfs::new());
+    let addr = listen(BlockingServer::new(), move || FidServer::new(ramfs.clone()));
+    let mut client = Client::connect(addr);
+    client.walk(0, 1, &[]).unwrap();
+    client.create(1, "file", 0o644, ORDWR).unwrap();
+    let data = vec![1; 4096];
+    for offset in [0, 4096, 8192] {
+        client.write(1, offset, &data).unwrap();
+    }
+
+    // The count is cut down to what fits in the msize of 8192
+    let data = client.read(1, 0, u32::MAX).unwrap();
+    assert_eq!(data.len() as u32, 8192 - IOHDRSZ);
+}
+
+#[test]
+fn idle_timeout() {
+    let ramfs = Arc::new(Ramfs::new());
+    let server = BlockingServer::new().idle_timeout(Duration::from_millis(100));
+    let addr = listen(server, move || FidServer::new(ramfs.clone()));
+    let mut client = connect(addr);
+    attach(&mut client, 0, Fid::NOFID).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    walk(&mut client, 1).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert!(matches!(walk(&mut client, 2), Err(Error::Io(_))));
+}
@@ -0,0 +1,74 @@
+// Counting requests, and reading the counts from /.stats
+#![cfg(unix)]
+
+mod common;
+
+use common::Client;
+use nine_p::{
+    BlockingServer, FidServer, MessageType, Metrics, MountFlag, Namespace, Ramfs, SynthTree, ORDWR,
+};
+use std::{os::unix::net::UnixStream, sync::Arc, thread, time::Duration};
+
+fn connect(metrics: &Arc<Metrics>) -> Client<UnixStream> {
+    let stats = SynthTree::new("glenda");
+    stats
+        .root()
+        .create_file(".stats", 0o444, metrics.stats_file())
+        .unwrap();
+    let ns = Arc::new(
+        Namespace::new()
+            .mount("/", Arc::new(Ramfs::new()), MountFlag::Replace)
+            .mount("/", Arc::new(stats), MountFlag::After),
+    );
+    let fid_metrics = metrics.clone();
+    let stream = BlockingServer::new()
+        .metrics(metrics.clone())
+        .serve_socketpair(move || FidServer::new(ns.clone()).metrics(fid_metrics.clone()))
+        .unwrap();
+    Client::new(stream)
+}
+
+#[test]
+fn counters() {
+    let metrics = Arc::new(Metrics::new());
+    let mut client = connect(&metrics);
+    assert_eq!(metrics.connections(), 1);
+    assert_eq!(metrics.fids(), 1);
+
+    client.walk(0, 1, &[]).unwrap();
+    client.create(1, "file", 0o644, ORDWR).unwrap();
+    assert!(client.walk(0, 2, &["missing"]).is_err());
+    assert_eq!(metrics.fids(), 2);
+    client.clunk(1).unwrap();
+    assert_eq!(metrics.fids(), 1);
+
+    let walks = metrics.op(MessageType::TWalk);
+    assert_eq!((walks.count, walks.errors, walks.flushed), (2, 1, 0));
+    assert_eq!(walks.latency.iter().sum::<u64>(), 2);
+    assert_eq!(metrics.op(MessageType::TVersion).count, 1);
+    assert_eq!(metrics.op(MessageType::TCreate).count, 1);
+    assert_eq!(metrics.op(MessageType::TRead), Default::default());
+    let bytes_in = metrics.bytes_in();
+    let bytes_out = metrics.bytes_out();
+    assert!(bytes_in > 0 && bytes_out > 0);
+
+    // The file is in the root, alongside the ramfs
+    let stats = client.cat(&[".stats"]);
+    let lines: Vec<_> = stats.lines().collect();
+    assert_eq!(lines[0], "connections 1");
+    assert_eq!(lines[1], "fids 2");
+    assert!(lines[4].starts_with("op count errors flushed total-us <100us"));
+    assert!(lines.iter().any(|line| line.starts_with("TWalk 3 1 0 ")));
+    assert!(lines.iter().any(|line| line.starts_with("TCreate 1 0 0 ")));
+    assert!(metrics.bytes_in() > bytes_in);
+    assert!(metrics.bytes_out() > bytes_out);
+
+    drop(client);
+    for _ in 0..100 {
+        if metrics.connections() == 0 && metrics.fids() == 0 {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("connection not closed");
+}
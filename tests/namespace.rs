@@ -0,0 +1,154 @@
+// Several backends mounted into one namespace
+#![cfg(unix)]
+
+mod common;
+
+use common::Client;
+use nine_p::{
+    text_file, BlockingServer, Error, FidServer, MountFlag, Namespace, Ramfs, SynthTree, ORDWR,
+};
+use std::{os::unix::net::UnixStream, sync::Arc};
+
+fn connect(ns: Namespace) -> Client<UnixStream> {
+    let ns = Arc::new(ns);
+    let stream = BlockingServer::new()
+        .serve_socketpair(move || FidServer::new(ns.clone()))
+        .unwrap();
+    Client::new(stream)
+}
+
+impl Client<UnixStream> {
+    // Create `name` in directory `dir` with `contents`
+    fn create_file(&mut self, dir: &[&str], name: &str, contents: &str) -> Result<(), Error> {
+        self.walk(0, 1, dir)?;
+        let res = self.create(1, name, 0o644, ORDWR);
+        if res.is_ok() {
+            self.write(1, 0, contents.as_bytes()).unwrap();
+        }
+        self.clunk(1).unwrap();
+        res.map(|_| ())
+    }
+}
+
+fn tree(files: &[(&str, &'static str)]) -> Arc<SynthTree> {
+    let tree = SynthTree::new("glenda");
+    for &(name, contents) in files {
+        let file = text_file(move || contents.to_string());
+        tree.root().create_file(name, 0o444, file).unwrap();
+    }
+    Arc::new(tree)
+}
+
+#[test]
+fn mounts() {
+    let ns = Namespace::new()
+        .mount("/", tree(&[("version", "1")]), MountFlag::Replace)
+        .mount("/tmp", Arc::new(Ramfs::new()), MountFlag::Replace)
+        .mount("/a/b/c", tree(&[("deep", "2")]), MountFlag::Replace);
+    let mut client = connect(ns);
+
+    assert_eq!(client.names(&[]), ["version", "tmp", "a"]);
+    assert_eq!(client.names(&["a"]), ["b"]);
+    assert_eq!(client.cat(&["version"]), "1");
+    assert_eq!(client.cat(&["a", "b", "c", "deep"]), "2");
+
+    client.create_file(&["tmp"], "file", "hello").unwrap();
+    assert_eq!(client.cat(&["tmp", "file"]), "hello");
+    assert!(client.create_file(&[], "file", "").is_err());
+
+    // The roots of the trees all have qid path 0 in their own backend
+    let root = client.stat(0).unwrap().qid;
+    let listing = client.ls(&[]);
+    assert_ne!(root.path, listing[1].1.path);
+    assert_ne!(listing[1].1.path, listing[2].1.path);
+    let tmp = client.walk(0, 1, &["tmp"]).unwrap()[0];
+    client.clunk(1).unwrap();
+    assert_eq!(listing[1].1.path, tmp.path);
+
+    // ".." leaves a mount for the directory it is mounted on
+    let qids = client
+        .walk(0, 1, &["tmp", "..", "a", "b", "..", ".."])
+        .unwrap();
+    client.clunk(1).unwrap();
+    assert_eq!(qids[1].path, root.path);
+    assert_eq!(qids[4].path, qids[2].path);
+    assert_eq!(qids[5].path, root.path);
+
+    assert!(client.walk(0, 1, &["missing"]).is_err());
+    client.walk(0, 1, &["tmp"]).unwrap();
+    assert!(client.remove(1).is_err());
+}
+
+#[test]
+fn unions() {
+    let ramfs = Arc::new(Ramfs::new());
+    let ns = Namespace::new()
+        .mount(
+            "/bin",
+            tree(&[("ls", "synth"), ("cat", "synth")]),
+            MountFlag::Replace,
+        )
+        .mount("/bin", ramfs.clone(), MountFlag::After)
+        .mount("/bin", tree(&[("ls", "first")]), MountFlag::Before)
+        .mount("/ram", ramfs, MountFlag::Replace);
+    let mut client = connect(ns);
+
+    client.create_file(&["ram"], "ls", "ramfs").unwrap();
+    client.create_file(&["ram"], "rc", "ramfs").unwrap();
+
+    assert_eq!(client.names(&["bin"]), ["ls", "cat", "rc"]);
+    assert_eq!(client.cat(&["bin", "ls"]), "first");
+    assert_eq!(client.cat(&["bin", "cat"]), "synth");
+    assert_eq!(client.cat(&["bin", "rc"]), "ramfs");
+
+    // The same file through two mounts of one backend
+    let bin = client.walk(0, 1, &["bin", "rc"]).unwrap()[1];
+    client.clunk(1).unwrap();
+    let ram = client.walk(0, 1, &["ram", "rc"]).unwrap()[1];
+    client.clunk(1).unwrap();
+    assert_eq!(
+        bin.path & 0xff_ffff_ffff_ffff,
+        ram.path & 0xff_ffff_ffff_ffff
+    );
+    assert_ne!(bin.path, ram.path);
+
+    // Created in the first member, which doesn't allow it
+    assert!(client.create_file(&["bin"], "new", "").is_err());
+}
+
+#[cfg(feature = "export")]
+#[test]
+fn export() {
+    use nine_p::Export;
+    use std::fs;
+
+    let dir = std::env::temp_dir().join(format!("nine-p-namespace-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("tmp")).unwrap();
+    fs::write(dir.join("tmp/disk"), "disk").unwrap();
+    fs::write(dir.join("motd"), "hello").unwrap();
+
+    let ns = Namespace::new()
+        .mount(
+            "/",
+            Arc::new(Export::new(&dir).unwrap()),
+            MountFlag::Replace,
+        )
+        .mount("/tmp", Arc::new(Ramfs::new()), MountFlag::Before)
+        .mount("/ctl", tree(&[("status", "ok")]), MountFlag::Replace);
+    let mut client = connect(ns);
+
+    let mut names = client.names(&[]);
+    names.sort();
+    assert_eq!(names, ["ctl", "motd", "tmp"]);
+    assert_eq!(client.cat(&["motd"]), "hello");
+    assert_eq!(client.cat(&["ctl", "status"]), "ok");
+
+    // Created in the ramfs, which is searched first
+    client.create_file(&["tmp"], "mem", "mem").unwrap();
+    assert_eq!(client.names(&["tmp"]), ["mem", "disk"]);
+    assert_eq!(client.cat(&["tmp", "disk"]), "disk");
+    assert!(!dir.join("tmp/mem").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,307 @@
+// Proxying sessions to an upstream ramfs
+#![cfg(unix)]
+
+mod common;
+
+use common::{attach, Client};
+use nine_p::{
+    BlockingServer, Error, Fid, FidServer, Policy, Proxy, Ramfs, SyncClient, TAttach, TClunk,
+    TCreate, TOpen, TRead, TRemove, TStat, TVersion, TWalk, TWrite, Upstream, NOTAG, ORCLOSE,
+    ORDWR,
+};
+use std::{
+    fmt,
+    os::unix::net::UnixStream,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Default)]
+struct Rules {
+    read_only: bool,
+    log: Mutex<Vec<String>>,
+}
+
+impl Policy for Rules {
+    fn log(&self, tag: u16, message: &dyn fmt::Debug) {
+        self.log
+            .lock()
+            .unwrap()
+            .push(format!("{} {:?}", tag, message));
+    }
+
+    fn uname(&self, uname: &str) -> String {
+        format!("proxied-{}", uname)
+    }
+
+    fn visible(&self, path: &[String]) -> bool {
+        path.first().is_none_or(|name| name != "secret")
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+fn ramfs() -> Arc<Upstream> {
+    let ramfs = Arc::new(Ramfs::new());
+    let stream = BlockingServer::new()
+        .serve_socketpair(move || FidServer::new(ramfs.clone()))
+        .unwrap();
+    Arc::new(Upstream::new(stream, 8192).unwrap())
+}
+
+fn connect<P: Policy>(upstream: &Arc<Upstream>, policy: &Arc<P>) -> Client<UnixStream> {
+    let upstream = upstream.clone();
+    let policy = policy.clone();
+    let stream = BlockingServer::new()
+        .msize(upstream.msize())
+        .workers(2)
+        .serve_socketpair(move || Proxy::with_policy(upstream.clone(), policy.clone()))
+        .unwrap();
+    let mut client = SyncClient::new(stream);
+    let tversion = TVersion {
+        msize: 65536,
+        version: "9P2000",
+    };
+    assert_eq!(client.send(NOTAG, tversion).unwrap().msize, 8192);
+    let root = attach(&mut client, 0, Fid::NOFID).unwrap();
+    Client { client, root }
+}
+
+impl Client<UnixStream> {
+    // Create `name` in the root, leaving it open as `fid`
+    fn new_file(&mut self, fid: u32, name: &str, perm: u32, mode: u8) -> Result<(), Error> {
+        self.walk(0, fid, &[])?;
+        let res = self.create(fid, name, perm, mode);
+        if res.is_err() {
+            self.clunk(fid).unwrap();
+        }
+        res.map(|_| ())
+    }
+}
+
+#[test]
+fn forwarding() {
+    let upstream = ramfs();
+    let policy = Arc::new(Rules::default());
+    let mut a = connect(&upstream, &policy);
+    let mut b = connect(&upstream, &policy);
+
+    // Both clients use the same fids
+    a.new_file(1, "a", 0o644, ORDWR).unwrap();
+    a.write(1, 0, b"from a").unwrap();
+    a.clunk(1).unwrap();
+    b.new_file(1, "b", 0o644, ORDWR).unwrap();
+    b.write(1, 0, b"from b").unwrap();
+    b.clunk(1).unwrap();
+    assert_eq!(a.read_file(&["b"]).unwrap(), b"from b");
+    assert_eq!(b.read_file(&["a"]).unwrap(), b"from a");
+
+    // Walking a fid to itself
+    a.walk(0, 2, &[]).unwrap();
+    let twalk = TWalk {
+        fid: Fid(2),
+        newfid: Fid(2),
+        wnames: vec!["a"],
+    };
+    a.client.send(0, twalk).unwrap();
+    let stat = a.client.send(0, TStat { fid: Fid(2) }).unwrap().stat;
+    assert_eq!(stat.name, "a");
+    assert_eq!(stat.uid, "proxied-glenda");
+    assert!(a.client.send(0, TRemove { fid: Fid(2) }).is_ok());
+    assert_eq!(b.names(&[]), ["b"]);
+
+    let log = policy.log.lock().unwrap();
+    assert!(log.iter().any(|line| line.starts_with("0 TCreate")));
+    assert!(log.iter().any(|line| line.starts_with("0 RStat")));
+}
+
+#[test]
+fn hidden() {
+    let upstream = ramfs();
+    let mut admin = connect(&upstream, &Arc::new(nine_p::NoPolicy));
+    for name in ["secret", "public", "more"] {
+        admin.new_file(1, name, 0o644, ORDWR).unwrap();
+        admin.clunk(1).unwrap();
+    }
+
+    let mut client = connect(&upstream, &Arc::new(Rules::default()));
+    assert!(client.walk(0, 1, &["secret"]).is_err());
+    assert_eq!(client.walk(0, 1, &["..", "secret"]).unwrap().len(), 1);
+    assert!(client.new_file(1, "secret", 0o644, ORDWR).is_err());
+    let mut names = client.names(&[]);
+    names.sort();
+    assert_eq!(names, ["more", "public"]);
+}
+
+#[test]
+fn read_only() {
+    let upstream = ramfs();
+    let mut admin = connect(&upstream, &Arc::new(nine_p::NoPolicy));
+    admin.new_file(1, "file", 0o644, ORDWR).unwrap();
+    admin.clunk(1).unwrap();
+
+    let policy = Arc::new(Rules {
+        read_only: true,
+        ..Rules::default()
+    });
+    let mut client = connect(&upstream, &policy);
+    assert!(client.new_file(1, "new", 0o644, ORDWR).is_err());
+    client.walk(0, 1, &["file"]).unwrap();
+    let topen = TOpen {
+        fid: Fid(1),
+        mode: ORDWR,
+    };
+    assert!(client.client.send(0, topen).is_err());
+    assert!(client.client.send(0, TRemove { fid: Fid(1) }).is_err());
+    assert_eq!(client.read_file(&["file"]).unwrap(), b"");
+}
+
+#[test]
+fn disconnect_clunks() {
+    let upstream = ramfs();
+    let policy = Arc::new(nine_p::NoPolicy);
+    let mut client = connect(&upstream, &policy);
+    client
+        .new_file(1, "temporary", 0o644, ORDWR | ORCLOSE)
+        .unwrap();
+    let mut other = connect(&upstream, &policy);
+    assert_eq!(other.names(&[]), ["temporary"]);
+
+    drop(client);
+    for _ in 0..100 {
+        if other.names(&[]).is_empty() {
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    panic!("fid not clunked");
+}
+
+#[test]
+fn multiplexed() {
+    let upstream = ramfs();
+    let dir = std::env::temp_dir().join(format!("nine-p-mux-{}", std::process::id()));
+    let _ = std::fs::remove_file(&dir);
+    let listener = std::os::unix::net::UnixListener::bind(&dir).unwrap();
+    let shared = upstream.clone();
+    std::thread::spawn(move || {
+        BlockingServer::new()
+            .msize(shared.msize())
+            .workers(4)
+            .serve_unix(listener, move || Proxy::new(shared.clone()))
+    });
+
+    // Clients using the same fids at once
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            let stream = UnixStream::connect(&dir).unwrap();
+            std::thread::spawn(move || {
+                let mut client = Client::new(stream);
+                for j in 0..10 {
+                    let name = format!("{}-{}", i, j);
+                    client.new_file(1, &name, 0o644, ORDWR).unwrap();
+                    client.write(1, 0, name.as_bytes()).unwrap();
+                    client.clunk(1).unwrap();
+                    assert_eq!(client.read_file(&[&name]).unwrap(), name.as_bytes());
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    std::fs::remove_file(&dir).unwrap();
+
+    let mut client = connect(&upstream, &Arc::new(nine_p::NoPolicy));
+    assert_eq!(client.names(&[]).len(), 40);
+}
+
+#[test]
+fn upstream_closed() {
+    let (client, server) = UnixStream::pair().unwrap();
+    let hangup = server.try_clone().unwrap();
+    let ramfs = Arc::new(Ramfs::new());
+    std::thread::spawn(move || {
+        let reader = server.try_clone().unwrap();
+        BlockingServer::new()
+            .serve_connection(reader, server, move || FidServer::new(ramfs.clone()))
+    });
+    let upstream = Arc::new(Upstream::new(client, 8192).unwrap());
+    let mut client = connect(&upstream, &Arc::new(nine_p::NoPolicy));
+
+    hangup.shutdown(std::net::Shutdown::Both).unwrap();
+    upstream.wait_closed();
+    assert!(client.walk(0, 1, &[]).is_err());
+}
+
+#[test]
+fn direct_sends() {
+    let ramfs = Arc::new(Ramfs::new());
+    let stream = BlockingServer::new()
+        .workers(4)
+        .serve_socketpair(move || FidServer::new(ramfs.clone()))
+        .unwrap();
+    let upstream = Upstream::with_version(stream, 8192, "9P2000.L").unwrap();
+    assert_eq!(upstream.version(), "9P2000");
+    let upstream = Arc::new(upstream);
+    let mut buf = Vec::new();
+    let tattach = TAttach {
+        fid: Fid(0),
+        afid: Fid::NOFID,
+        uname: "glenda",
+        aname: "",
+    };
+    upstream.send(tattach, &mut buf).unwrap();
+
+    // Threads waiting for their own replies at once
+    let threads: Vec<_> = (1..9)
+        .map(|i| {
+            let upstream = upstream.clone();
+            std::thread::spawn(move || {
+                let mut buf = Vec::new();
+                let name = format!("file{}", i);
+                let twalk = TWalk {
+                    fid: Fid(0),
+                    newfid: Fid(i),
+                    wnames: vec![],
+                };
+                upstream.send(twalk, &mut buf).unwrap();
+                let tcreate = TCreate {
+                    fid: Fid(i),
+                    name: &name,
+                    perm: 0o644,
+                    mode: ORDWR,
+                };
+                upstream.send(tcreate, &mut buf).unwrap();
+                let twrite = TWrite {
+                    fid: Fid(i),
+                    offset: 0,
+                    data: name.as_bytes(),
+                };
+                upstream.send(twrite, &mut buf).unwrap();
+                let tread = TRead {
+                    fid: Fid(i),
+                    offset: 0,
+                    count: 100,
+                };
+                let rread = upstream.send(tread, &mut buf).unwrap();
+                assert_eq!(rread.data, name.as_bytes());
+                upstream.send(TClunk { fid: Fid(i) }, &mut buf).unwrap();
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let twalk = TWalk {
+        fid: Fid(0),
+        newfid: Fid(1),
+        wnames: vec!["missing"],
+    };
+    match upstream.send(twalk, &mut buf) {
+        Err(Error::Protocol(ename)) => assert_eq!(ename, "file does not exist"),
+        res => panic!("unexpected reply {:?}", res),
+    }
+}
@@ -0,0 +1,270 @@
+// End-to-end tests of the client, server and fid handling against the
+// in-memory filesystem, over a real socket
+
+mod common;
+
+use common::{ename, listen, Client};
+use nine_p::{
+    parse_dir, BlockingServer, Error, FidServer, Qid, Ramfs, Stat, DMAPPEND, DMDIR, DMEXCL,
+    MAXWELEM, ORCLOSE, ORDWR, OREAD, OTRUNC, OWRITE,
+};
+use std::{net::TcpStream, sync::Arc};
+
+// Start a server for a new `Ramfs`, and attach to its root as fid 0
+fn connect() -> Client<TcpStream> {
+    let ramfs = Arc::new(Ramfs::new());
+    Client::connect(listen(BlockingServer::new(), move || {
+        FidServer::new(ramfs.clone())
+    }))
+}
+
+impl Client<TcpStream> {
+    // Create `name` in the root as `fid`
+    fn new_file(&mut self, fid: u32, name: &str, perm: u32, mode: u8) -> Result<Qid, Error> {
+        self.walk(0, fid, &[])?;
+        self.create(fid, name, perm, mode)
+    }
+
+    // `TWStat` changing only what `f` sets
+    fn change(&mut self, fid: u32, f: impl FnOnce(&mut Stat)) -> Result<(), Error> {
+        let mut stat = Stat::unchanged(false);
+        f(&mut stat);
+        self.wstat(fid, &stat)
+    }
+}
+
+#[test]
+fn write_and_read_back() {
+    let mut client = connect();
+    let qid = client.new_file(1, "file", 0o644, ORDWR).unwrap();
+    assert_eq!(client.write(1, 0, b"hello world").unwrap(), 11);
+    assert_eq!(client.write(1, 6, b"there").unwrap(), 5);
+    assert_eq!(client.read(1, 0, 100).unwrap(), b"hello there");
+    assert_eq!(client.read(1, 6, 3).unwrap(), b"the");
+    assert_eq!(client.read(1, 100, 3).unwrap(), b"");
+
+    let stat = client.stat(1).unwrap();
+    assert_eq!(stat.length, 11);
+    assert_eq!(stat.name, "file");
+    assert_eq!(stat.muid, "glenda");
+    assert_eq!(stat.qid.path, qid.path);
+    assert_ne!(stat.qid.vers, qid.vers);
+}
+
+#[test]
+fn version_changes_on_write() {
+    let mut client = connect();
+    client.new_file(1, "file", 0o644, OWRITE).unwrap();
+    let mut last = client.stat(1).unwrap().qid.vers;
+    for _ in 0..3 {
+        client.write(1, 0, b"x").unwrap();
+        let vers = client.stat(1).unwrap().qid.vers;
+        assert_ne!(vers, last);
+        last = vers;
+    }
+    client.clunk(1).unwrap();
+
+    client.walk(0, 2, &["file"]).unwrap();
+    let qid = client.open(2, OWRITE | OTRUNC).unwrap();
+    assert_ne!(qid.vers, last);
+    assert_eq!(client.stat(2).unwrap().length, 0);
+}
+
+#[test]
+fn directory_listing() {
+    let mut client = connect();
+    client.new_file(1, "dir", DMDIR | 0o755, OREAD).unwrap();
+    client.clunk(1).unwrap();
+    for i in 0..50 {
+        client.walk(0, 2, &["dir"]).unwrap();
+        let name = format!("file{}", i);
+        client.create(2, &name, 0o644, OWRITE).unwrap();
+        client.clunk(2).unwrap();
+    }
+
+    client.walk(0, 3, &["dir"]).unwrap();
+    client.open(3, OREAD).unwrap();
+    let mut names = Vec::new();
+    let mut offset = 0;
+    loop {
+        // Small reads, which must only return whole entries
+        let data = client.read(3, offset, 100).unwrap();
+        if data.is_empty() {
+            break;
+        }
+        for stat in parse_dir(&data).unwrap() {
+            names.push(stat.name.to_string());
+        }
+        offset += data.len() as u64;
+    }
+    names.sort();
+    let mut expected = (0..50).map(|i| format!("file{}", i)).collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(names, expected);
+}
+
+#[test]
+fn directory_offsets() {
+    let mut client = connect();
+    for name in ["a", "b", "c"] {
+        client.new_file(1, name, 0o644, OWRITE).unwrap();
+        client.clunk(1).unwrap();
+    }
+
+    client.walk(0, 2, &[]).unwrap();
+    client.open(2, OREAD).unwrap();
+    let first = client.read(2, 0, 100).unwrap();
+    assert_eq!(parse_dir(&first).unwrap().len(), 1);
+    // Only the end of the last read, or 0, are valid offsets
+    let err = client.read(2, 1, 100).unwrap_err();
+    assert_eq!(ename(err), "bad offset in directory read");
+    let rest = client.read(2, first.len() as u64, 1000).unwrap();
+    assert_eq!(parse_dir(&rest).unwrap().len(), 2);
+    assert_eq!(
+        client
+            .read(2, (first.len() + rest.len()) as u64, 1000)
+            .unwrap(),
+        b""
+    );
+    assert_eq!(
+        client.read(2, 0, 1000).unwrap().len(),
+        first.len() + rest.len()
+    );
+
+    let err = client.read(2, 0, 10).unwrap_err();
+    assert_eq!(ename(err), "directory read count too small");
+}
+
+#[test]
+fn rename_and_truncate() {
+    let mut client = connect();
+    client.new_file(1, "old", 0o644, OWRITE).unwrap();
+    client.write(1, 0, b"some data").unwrap();
+    client.new_file(2, "other", 0o644, OWRITE).unwrap();
+
+    let err = client.change(1, |stat| stat.name = "other").unwrap_err();
+    assert_eq!(ename(err), "file already exists");
+    client.change(1, |stat| stat.name = "new").unwrap();
+    assert!(client.walk(0, 3, &["old"]).is_err());
+    client.walk(0, 3, &["new"]).unwrap();
+
+    client.change(1, |stat| stat.length = 4).unwrap();
+    client.open(3, OREAD).unwrap();
+    assert_eq!(client.read(3, 0, 100).unwrap(), b"some");
+
+    let err = client
+        .change(1, |stat| stat.mode = DMDIR | 0o755)
+        .unwrap_err();
+    assert_eq!(ename(err), "can't change directory bit");
+    client.change(1, |stat| stat.mode = 0o600).unwrap();
+    assert_eq!(client.stat(3).unwrap().mode, 0o600);
+}
+
+#[test]
+fn size_limit() {
+    let mut client = connect();
+    client.new_file(1, "file", 0o644, ORDWR).unwrap();
+    for offset in [1 << 40, u64::MAX] {
+        let err = client.write(1, offset, b"data").unwrap_err();
+        assert_eq!(ename(err), "file too large");
+    }
+    let err = client.change(1, |stat| stat.length = 1 << 40).unwrap_err();
+    assert_eq!(ename(err), "file too large");
+
+    // The server carries on as before
+    assert_eq!(client.write(1, 0, b"data").unwrap(), 4);
+    assert_eq!(client.read(1, 0, 100).unwrap(), b"data");
+}
+
+#[test]
+fn remove() {
+    let mut client = connect();
+    client.new_file(1, "dir", DMDIR | 0o755, OREAD).unwrap();
+    client.walk(0, 2, &["dir"]).unwrap();
+    client.create(2, "file", 0o644, OWRITE).unwrap();
+
+    let err = client.remove(1).unwrap_err();
+    assert_eq!(ename(err), "directory not empty");
+    // The fid is clunked even though remove failed
+    assert_eq!(ename(client.stat(1).unwrap_err()), "unknown fid");
+
+    client.walk(0, 3, &["dir", "file"]).unwrap();
+    client.remove(2).unwrap();
+    // Other fids for the removed file are left dangling
+    assert!(client.stat(3).is_err());
+    client.walk(0, 4, &["dir"]).unwrap();
+    client.remove(4).unwrap();
+    assert!(client.walk(0, 5, &["dir"]).is_err());
+}
+
+#[test]
+fn remove_on_clunk() {
+    let mut client = connect();
+    client.new_file(1, "temp", 0o644, OWRITE | ORCLOSE).unwrap();
+    client.walk(0, 2, &["temp"]).unwrap();
+    client.clunk(1).unwrap();
+    assert!(client.walk(0, 3, &["temp"]).is_err());
+    assert!(client.stat(2).is_err());
+}
+
+#[test]
+fn append_only() {
+    let mut client = connect();
+    let qid = client.new_file(1, "log", DMAPPEND | 0o644, ORDWR).unwrap();
+    assert_eq!(qid.type_, nine_p::QTAPPEND);
+    client.write(1, 0, b"one ").unwrap();
+    client.write(1, 0, b"two ").unwrap();
+    client.write(1, 2, b"three").unwrap();
+    assert_eq!(client.read(1, 0, 100).unwrap(), b"one two three");
+}
+
+#[test]
+fn exclusive_use() {
+    let mut client = connect();
+    client.new_file(1, "lock", DMEXCL | 0o644, OWRITE).unwrap();
+    client.walk(0, 2, &["lock"]).unwrap();
+    let err = client.open(2, OREAD).unwrap_err();
+    assert_eq!(ename(err), "exclusive use file already open");
+    client.clunk(1).unwrap();
+    client.open(2, OREAD).unwrap();
+}
+
+#[test]
+fn walk_semantics() {
+    let mut client = connect();
+    client.new_file(1, "dir", DMDIR | 0o755, OREAD).unwrap();
+    client.clunk(1).unwrap();
+    client.new_file(1, "file", 0o644, OREAD).unwrap();
+    client.clunk(1).unwrap();
+
+    // Partial walk doesn't create newfid
+    let qids = client.walk(0, 2, &["dir", "missing", "x"]).unwrap();
+    assert_eq!(qids.len(), 1);
+    assert_eq!(ename(client.stat(2).unwrap_err()), "unknown fid");
+    // Failure on the first element is an error
+    assert!(client.walk(0, 2, &["missing"]).is_err());
+
+    let err = client.walk(0, 2, &["file", "x"]).unwrap();
+    assert_eq!(err.len(), 1);
+    let names = vec!["dir"; MAXWELEM + 1];
+    assert!(client.walk(0, 2, &names).is_err());
+
+    // Walking a fid to itself moves it
+    client.walk(0, 2, &[]).unwrap();
+    assert_eq!(ename(client.walk(0, 2, &[]).unwrap_err()), "duplicate fid");
+    client.walk(2, 2, &["dir"]).unwrap();
+    assert_eq!(client.stat(2).unwrap().name, "dir");
+    let qids = client.walk(2, 2, &["..", "file"]).unwrap();
+    assert_eq!(qids.len(), 2);
+    assert_eq!(client.stat(2).unwrap().name, "file");
+
+    // Open fids can't be walked, and need a suitable mode
+    assert_eq!(ename(client.read(2, 0, 10).unwrap_err()), "fid not open");
+    client.open(2, OWRITE).unwrap();
+    assert!(client.walk(2, 3, &[]).is_err());
+    assert!(client.read(2, 0, 10).is_err());
+    assert!(client.open(2, OREAD).is_err());
+
+    client.walk(0, 3, &["dir"]).unwrap();
+    assert_eq!(ename(client.open(3, OWRITE).unwrap_err()), "is a directory");
+}
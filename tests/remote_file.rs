@@ -0,0 +1,83 @@
+// Reading and writing a ramfs file through a `RemoteFile`
+#![cfg(all(feature = "futures-io", feature = "tokio"))]
+
+mod common;
+
+use common::async_client::{connect, run};
+use nine_p::{Fid, RemoteFile, TCreate, TokioCompat, ORDWR};
+use std::io::SeekFrom;
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+};
+
+type File = RemoteFile<TokioCompat<OwnedReadHalf>, TokioCompat<OwnedWriteHalf>>;
+
+// Small, so reads are split into many `TRead`s
+const IOUNIT: u32 = 1000;
+
+// Create a file in the root of a ramfs, open for reading and writing
+async fn create(iounit: u32) -> File {
+    let client = connect().await;
+    let mut buf = Vec::new();
+    let tcreate = TCreate {
+        fid: Fid(0),
+        name: "file",
+        perm: 0o644,
+        mode: ORDWR,
+    };
+    client.send(tcreate, &mut buf).await.unwrap();
+    RemoteFile::new(client, Fid(0), iounit).readahead(4)
+}
+
+#[test]
+fn readahead_across_seeks() {
+    run(async {
+        let mut file = create(IOUNIT).await;
+        let data: Vec<u8> = (0..10_500u32).map(|i| (i % 251) as u8).collect();
+        file.write_all(&data).await.unwrap();
+
+        file.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut read = Vec::new();
+        file.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, data);
+
+        // Seeking back discards the read-ahead in flight, and reads
+        // resume from the new position
+        file.seek(SeekFrom::Start(2500)).await.unwrap();
+        let mut read = vec![0; 3000];
+        file.read_exact(&mut read[..10]).await.unwrap();
+        file.seek(SeekFrom::Current(-10)).await.unwrap();
+        file.read_exact(&mut read).await.unwrap();
+        assert_eq!(read, data[2500..5500]);
+
+        // Forward past some of the read-ahead
+        file.seek(SeekFrom::Current(1234)).await.unwrap();
+        let mut read = vec![0; 2000];
+        file.read_exact(&mut read).await.unwrap();
+        assert_eq!(read, data[6734..8734]);
+
+        file.seek(SeekFrom::End(-100)).await.unwrap();
+        let mut read = Vec::new();
+        file.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, data[data.len() - 100..]);
+
+        // A write in the middle is seen by the next read
+        file.seek(SeekFrom::Start(4999)).await.unwrap();
+        file.write_all(b"written").await.unwrap();
+        file.seek(SeekFrom::Start(4990)).await.unwrap();
+        let mut read = vec![0; 30];
+        file.read_exact(&mut read).await.unwrap();
+        assert_eq!(read[..9], data[4990..4999]);
+        assert_eq!(&read[9..16], b"written");
+        assert_eq!(read[16..], data[5006..5020]);
+
+        file.clunk().await.unwrap();
+    });
+}
+
+#[test]
+#[should_panic(expected = "iounit must not be 0")]
+fn zero_iounit() {
+    run(create(0));
+}
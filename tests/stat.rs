@@ -0,0 +1,104 @@
+// Serializing stats and errors, with and without the fields of 9P2000.u
+#![cfg(unix)]
+
+use nine_p::{
+    ename_errno, parse_dir, Error, Fid, Header, Message, Qid, RError, Stat, StatDotu, SyncClient,
+    TStat, DMSYMLINK,
+};
+use std::{io::Read, os::unix::net::UnixStream, thread};
+
+#[test]
+fn dotu() {
+    let plain = Stat {
+        qid: Qid {
+            type_: 0,
+            vers: 1,
+            path: 2,
+        },
+        mode: 0o644,
+        length: 10,
+        name: "file",
+        uid: "glenda",
+        gid: "sys",
+        ..Stat::default()
+    };
+    let link = Stat {
+        mode: DMSYMLINK | 0o777,
+        name: "link",
+        dotu: Some(StatDotu {
+            extension: "file",
+            n_uid: 1000,
+            n_gid: 100,
+            n_muid: 1000,
+        }),
+        ..plain
+    };
+
+    let mut buf = Vec::new();
+    link.write(&mut buf).unwrap();
+    assert_eq!(buf.len(), link.size());
+    plain.write(&mut buf).unwrap();
+    link.write(&mut buf).unwrap();
+
+    let stats = parse_dir(&buf).unwrap();
+    assert_eq!(stats.len(), 3);
+    assert_eq!(stats[0].name, "link");
+    let dotu = stats[0].dotu.unwrap();
+    assert_eq!(dotu.extension, "file");
+    assert_eq!((dotu.n_uid, dotu.n_gid), (1000, 100));
+    assert!(stats[1].dotu.is_none());
+    assert_eq!(stats[1].uid, "glenda");
+    assert_eq!(stats[2].dotu.unwrap().n_muid, 1000);
+}
+
+#[test]
+fn unchanged() {
+    // 9P2000.u servers expect the extra fields, also set to "don't touch"
+    let mut buf = Vec::new();
+    Stat::unchanged(true).write(&mut buf).unwrap();
+    let stat = Stat::from_bytes(&buf).unwrap();
+    assert_eq!((stat.mode, stat.length, stat.name), (!0, !0, ""));
+    let dotu = stat.dotu.unwrap();
+    assert_eq!((dotu.n_uid, dotu.n_gid, dotu.n_muid), (!0, !0, !0));
+    assert_eq!(buf.len(), Stat::unchanged(false).size() + 14);
+}
+
+#[test]
+fn errno() {
+    let (client, mut server) = UnixStream::pair().unwrap();
+    let server = thread::spawn(move || {
+        let replies = [
+            RError {
+                ename: "file does not exist",
+                errno: Some(2),
+            },
+            RError {
+                ename: "permission denied",
+                errno: None,
+            },
+        ];
+        for rerror in replies {
+            let mut header = [0; 7];
+            server.read_exact(&mut header).unwrap();
+            let header = Header::from_array(header);
+            let mut body = vec![0; header.size as usize - 7];
+            server.read_exact(&mut body).unwrap();
+            Header::for_message(&rerror, header.tag)
+                .write(&mut server)
+                .unwrap();
+            rerror.write(&mut server).unwrap();
+        }
+    });
+
+    // The errno is used when there is one, and the string otherwise
+    let mut client = SyncClient::new(client);
+    match client.send(0, TStat { fid: Fid(0) }) {
+        Err(Error::Io(err)) => assert_eq!(err.raw_os_error(), Some(2)),
+        res => panic!("unexpected reply {:?}", res),
+    }
+    match client.send(0, TStat { fid: Fid(0) }) {
+        Err(Error::Protocol(ename)) => assert_eq!(ename_errno(&ename), 13),
+        res => panic!("unexpected reply {:?}", res),
+    }
+    server.join().unwrap();
+}
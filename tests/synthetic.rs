@@ -0,0 +1,212 @@
+// Synthetic file trees served over a socket
+
+mod common;
+
+use common::{listen, send, Client};
+use nine_p::{
+    ctl_file, parse_dir, text_file, BlockingServer, Error, Events, Fid, FidServer, MountFlag,
+    Namespace, Ramfs, SynthTree, TAttach, TFlush, TRead, TVersion, TWalk, TWrite, NOTAG, ORCLOSE,
+    OREAD, OWRITE,
+};
+use std::{
+    net::{Shutdown, TcpStream},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+fn connect(tree: SynthTree) -> Client<TcpStream> {
+    let (client, _) = connect_stream(tree);
+    client
+}
+
+// Also returns a handle to the stream, to send requests without waiting
+fn connect_stream(tree: SynthTree) -> (Client<TcpStream>, TcpStream) {
+    let tree = Arc::new(tree);
+    let addr = listen(BlockingServer::new().workers(4), move || {
+        FidServer::new(tree.clone())
+    });
+    let stream = common::connect(addr);
+    (Client::new(stream.try_clone().unwrap()), stream)
+}
+
+#[test]
+fn ctl_and_status() {
+    let state = Arc::new(Mutex::new(0));
+    let tree = SynthTree::new("glenda");
+    let dir = tree.root().create_dir("counter", 0o555).unwrap();
+    let status_state = state.clone();
+    dir.create_file(
+        "status",
+        0o444,
+        text_file(move || format!("{}\n", status_state.lock().unwrap())),
+    )
+    .unwrap();
+    let ctl_state = state.clone();
+    dir.create_file(
+        "ctl",
+        0o222,
+        ctl_file(move |command| match command {
+            "incr" => {
+                *ctl_state.lock().unwrap() += 1;
+                Ok(())
+            }
+            _ => Err(Error::Protocol("unknown command".to_string())),
+        }),
+    )
+    .unwrap();
+    let mut client = connect(tree);
+
+    client.walk_open(1, &["counter"], OREAD).unwrap();
+    let names = client.read(1, 0, 1024).unwrap();
+    let names = parse_dir(&names).unwrap();
+    let names = names.iter().map(|stat| stat.name).collect::<Vec<_>>();
+    assert_eq!(names, ["ctl", "status"]);
+
+    client.walk_open(2, &["counter", "ctl"], OWRITE).unwrap();
+    assert_eq!(client.write(2, 0, b"incr\n").unwrap(), 5);
+    client.write(2, 0, b"incr").unwrap();
+    assert!(client.write(2, 0, b"decr").is_err());
+    assert!(client.walk_open(3, &["counter", "ctl"], OREAD).is_err());
+
+    // Contents are rendered at open
+    client.walk_open(4, &["counter", "status"], OREAD).unwrap();
+    client.write(2, 0, b"incr").unwrap();
+    assert_eq!(client.read(4, 0, 1024).unwrap(), b"2\n");
+    assert_eq!(client.read(4, 1, 1024).unwrap(), b"\n");
+    assert!(client.walk_open(5, &["counter", "status"], OWRITE).is_err());
+}
+
+#[test]
+fn events() {
+    let events = Events::new();
+    let tree = SynthTree::new("glenda");
+    tree.root()
+        .create_file("events", 0o444, events.clone())
+        .unwrap();
+    let mut client = connect(tree);
+
+    events.send("missed\n");
+    client.walk_open(1, &["events"], OREAD).unwrap();
+    let sender = thread::spawn(move || {
+        events.send("one\n");
+        events.send("two\n");
+    });
+    assert_eq!(client.read(1, 0, 1024).unwrap(), b"one\n");
+    assert_eq!(client.read(1, 0, 1024).unwrap(), b"two\n");
+    sender.join().unwrap();
+}
+
+#[test]
+fn flush_blocked_read() {
+    let events = Events::new();
+    let tree = SynthTree::new("glenda");
+    tree.root()
+        .create_file("events", 0o444, events.clone())
+        .unwrap();
+    let (mut client, mut stream) = connect_stream(tree);
+    client.walk_open(1, &["events"], OREAD).unwrap();
+
+    // Blocks until an event is sent
+    let tread = TRead {
+        fid: Fid(1),
+        offset: 0,
+        count: 1024,
+    };
+    send(&mut stream, 1, tread);
+
+    // The next reply must be the `RFlush`, without one for the read
+    client.client.send(2, TFlush { oldtag: 1 }).unwrap();
+    // Flushing a tag with nothing outstanding succeeds immediately
+    client.client.send(3, TFlush { oldtag: 1 }).unwrap();
+
+    events.send("after\n");
+    assert_eq!(client.read(1, 0, 1024).unwrap(), b"after\n");
+}
+
+#[test]
+fn disconnect_during_blocked_read() {
+    let events = Events::new();
+    let tree = SynthTree::new("glenda");
+    tree.root().create_file("events", 0o444, events).unwrap();
+    let ns = Namespace::new()
+        .mount("/", Arc::new(tree), MountFlag::Replace)
+        .mount("/tmp", Arc::new(Ramfs::new()), MountFlag::Replace);
+    let ns = Arc::new(ns);
+    let addr = listen(BlockingServer::new().workers(4), move || {
+        FidServer::new(ns.clone())
+    });
+    let mut stream = common::connect(addr);
+    let mut client = Client::new(stream.try_clone().unwrap());
+    client.walk(0, 1, &["tmp"]).unwrap();
+    client.create(1, "file", 0o644, OWRITE | ORCLOSE).unwrap();
+    client.walk_open(2, &["events"], OREAD).unwrap();
+    let tread = TRead {
+        fid: Fid(2),
+        offset: 0,
+        count: 1024,
+    };
+    send(&mut stream, 1, tread);
+    // Requests are read in order, so the read has been received
+    client.stat(2).unwrap();
+    stream.shutdown(Shutdown::Both).unwrap();
+
+    // The read is cancelled, and the fids clunked with the session
+    let mut client = Client::connect(addr);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while client.walk(0, 1, &["tmp", "file"]).unwrap().len() == 2 {
+        client.clunk(1).unwrap();
+        assert!(Instant::now() < deadline, "file not removed");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn reuse_tag_after_version() {
+    // The write blocks its worker without yielding, so it outlives the
+    // session it was made in
+    let (started, wait_started) = mpsc::channel();
+    let (unblock, blocked) = mpsc::channel();
+    let channels = Mutex::new((started, blocked));
+    let ctl = ctl_file(move |_| {
+        let channels = channels.lock().unwrap();
+        channels.0.send(()).unwrap();
+        channels.1.recv().unwrap();
+        Ok(())
+    });
+    let tree = SynthTree::new("glenda");
+    tree.root().create_file("ctl", 0o222, ctl).unwrap();
+    let (mut client, mut stream) = connect_stream(tree);
+    client.walk_open(1, &["ctl"], OWRITE).unwrap();
+
+    let twrite = TWrite {
+        fid: Fid(1),
+        offset: 0,
+        data: b"block",
+    };
+    send(&mut stream, 1, twrite);
+    wait_started.recv().unwrap();
+
+    // The new session has no requests outstanding, so tag 1 is free
+    let tversion = TVersion {
+        msize: 8192,
+        version: "9P2000",
+    };
+    client.client.send(NOTAG, tversion).unwrap();
+    let tattach = TAttach {
+        fid: Fid(0),
+        afid: Fid::NOFID,
+        uname: "glenda",
+        aname: "",
+    };
+    client.client.send(1, tattach).unwrap();
+
+    // Nothing is sent for the aborted write once it finishes
+    unblock.send(()).unwrap();
+    let twalk = TWalk {
+        fid: Fid(0),
+        newfid: Fid(1),
+        wnames: Vec::new(),
+    };
+    client.client.send(2, twalk).unwrap();
+}
@@ -0,0 +1,40 @@
+// Serving connections that don't come from a listener
+#![cfg(unix)]
+
+mod common;
+
+use common::Client;
+use nine_p::{BlockingServer, FidServer, Ramfs, OWRITE};
+use std::{
+    io::{Read, Write},
+    os::{fd::OwnedFd, unix::net::UnixStream},
+    sync::Arc,
+    thread,
+};
+
+fn check<T: Read + Write>(stream: T) {
+    let mut client = Client::new(stream);
+    client.create(0, "file", 0o644, OWRITE).unwrap();
+    assert_eq!(client.stat(0).unwrap().name, "file");
+}
+
+#[test]
+fn socketpair() {
+    let ramfs = Arc::new(Ramfs::new());
+    let stream = BlockingServer::new()
+        .serve_socketpair(move || FidServer::new(ramfs.clone()))
+        .unwrap();
+    check(stream);
+}
+
+#[test]
+fn fds() {
+    let (client, server) = UnixStream::pair().unwrap();
+    let rfd = OwnedFd::from(server);
+    let wfd = rfd.try_clone().unwrap();
+    let ramfs = Arc::new(Ramfs::new());
+    thread::spawn(move || {
+        BlockingServer::new().serve_fds(rfd, wfd, move || FidServer::new(ramfs.clone()))
+    });
+    check(client);
+}